@@ -0,0 +1,62 @@
+use crate::download::{download_file, Downloaded};
+use crate::http::HttpError;
+use crate::pipeline::Context;
+use crate::uniprot;
+use anyhow::Result;
+use reqwest::{StatusCode, Url};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+const ALPHAFOLD_URL: &str = "https://alphafold.ebi.ac.uk/files/";
+
+/// Urls of the AlphaFold model of `accession` and its PAE, with the files they are saved to.
+///
+/// AlphaFold only models canonical sequences, so isoforms get the canonical model.
+pub(crate) fn model_files(accession: &str, save_path: &Path) -> Result<Vec<(Url, PathBuf)>> {
+    let (accession, _) = uniprot::split_isoform(accession);
+    let path_alphafold = save_path.join("alphafold");
+    let mut files = Vec::new();
+    for file_name in [
+        format!("AF-{}-F1-model_v4.pdb", accession),
+        format!("AF-{}-F1-predicted_aligned_error_v4.json", accession),
+    ] {
+        let url: Url = format!("{}{}", ALPHAFOLD_URL, file_name).parse()?;
+        files.push((url, path_alphafold.join(file_name)));
+    }
+    Ok(files)
+}
+
+/// Download the AlphaFold model of `accession` and its PAE into `save_path/alphafold/`, none if
+/// AlphaFold has no model of it.
+///
+/// Files already there are left out.
+pub(crate) async fn download_model(
+    ctx: &Context,
+    accession: &str,
+    save_path: &Path,
+) -> Result<Option<Vec<Downloaded>>> {
+    let mut downloaded = Vec::new();
+    create_dir_all(save_path.join("alphafold"))?;
+    for (i, (url, save_filepath)) in model_files(accession, save_path)?.into_iter().enumerate() {
+        if ctx.storage.exists(&save_filepath).await? {
+            continue;
+        }
+        debug!(target:"debug","AlphaFold url : {}", url);
+        match download_file(ctx, &url, &save_filepath).await {
+            Ok(file) => downloaded.push(file),
+            Err(e)
+                if matches!(
+                    e.downcast_ref::<HttpError>(),
+                    Some(HttpError::Status {
+                        status: StatusCode::NOT_FOUND,
+                        ..
+                    })
+                ) && i == 0 =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(downloaded))
+}
@@ -0,0 +1,170 @@
+use crate::config::ArchiveFormat;
+use crate::pipeline::{Context, Target};
+use anyhow::Result;
+use std::path::Path;
+#[cfg(feature = "archive")]
+use std::path::PathBuf;
+
+//The archive the folder `path_target` of a target is packaged into
+#[cfg(feature = "archive")]
+fn archive_path(format: ArchiveFormat, path_target: &Path) -> PathBuf {
+    let mut path = path_target.as_os_str().to_owned();
+    path.push(match format {
+        ArchiveFormat::TarZst => ".tar.zst",
+        ArchiveFormat::Zip => ".zip",
+    });
+    PathBuf::from(path)
+}
+
+/// Fail unless the build can write archives.
+#[cfg(feature = "archive")]
+pub(crate) fn check() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(feature = "archive"))]
+pub(crate) fn check() -> Result<()> {
+    anyhow::bail!("archive needs a build with the \"archive\" feature")
+}
+
+/// Move the files of `target` in `path_target` into its archive, recording them in the manifest
+/// as members of it.
+///
+/// Members are named by their path relative to the save path, files downloaded again replace
+/// the members of the same name.
+#[cfg(feature = "archive")]
+pub(crate) async fn archive_target(
+    ctx: &Context,
+    target: &Target,
+    format: ArchiveFormat,
+    path_target: &Path,
+) -> Result<()> {
+    if !path_target.exists() {
+        return Ok(());
+    }
+    let save_path = PathBuf::from(&ctx.config.save_path);
+    let path_target = path_target.to_path_buf();
+    let archive = archive_path(format, &path_target);
+    let span = tracing::Span::current();
+    let members = {
+        let archive = archive.clone();
+        tokio::task::spawn_blocking(move || -> Result<Vec<String>> {
+            let _span = span.enter();
+            let mut files = Vec::new();
+            collect_files(&path_target, &save_path, &mut files)?;
+            if files.is_empty() {
+                return Ok(Vec::new());
+            }
+            write(format, &archive, &save_path, &files)?;
+            std::fs::remove_dir_all(&path_target)?;
+            Ok(files)
+        })
+        .await??
+    };
+    if members.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "{} files of {} archived into {}",
+        members.len(),
+        target.chembl_id,
+        archive.display()
+    );
+    let archive = archive
+        .strip_prefix(&ctx.config.save_path)
+        .unwrap_or(&archive)
+        .to_string_lossy()
+        .into_owned();
+    for record in ctx.state.files() {
+        if members.contains(&record.path) {
+            ctx.state.record_file(crate::manifest::ManifestEntry {
+                archive: Some(archive.clone()),
+                ..record
+            })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "archive"))]
+pub(crate) async fn archive_target(
+    _ctx: &Context,
+    _target: &Target,
+    _format: ArchiveFormat,
+    _path_target: &Path,
+) -> Result<()> {
+    check()
+}
+
+//Complete files below `dir`, by path relative to `save_path`
+#[cfg(feature = "archive")]
+fn collect_files(dir: &Path, save_path: &Path, files: &mut Vec<String>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, save_path, files)?;
+        } else if path.extension().is_none_or(|ext| ext != "part") {
+            let member = path.strip_prefix(save_path).unwrap_or(&path);
+            files.push(member.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+//Write `files` and the members of the archive already there into a new one
+#[cfg(feature = "archive")]
+fn write(format: ArchiveFormat, archive: &Path, save_path: &Path, files: &[String]) -> Result<()> {
+    use crate::download::part_path;
+    use std::fs::File;
+    use std::io::{BufReader, BufWriter, Write};
+
+    let part = part_path(archive);
+    let replaced = |name: &str| files.iter().any(|file| file == name);
+    match format {
+        ArchiveFormat::TarZst => {
+            let encoder = zstd::Encoder::new(BufWriter::new(File::create(&part)?), 0)?;
+            let mut builder = tar::Builder::new(encoder);
+            if archive.exists() {
+                let decoder = zstd::Decoder::new(File::open(archive)?)?;
+                let mut old = tar::Archive::new(decoder);
+                for entry in old.entries()? {
+                    let mut entry = entry?;
+                    let name = entry.path()?.to_string_lossy().into_owned();
+                    if !replaced(&name) {
+                        let mut header = entry.header().clone();
+                        builder.append_data(&mut header, &name, &mut entry)?;
+                    }
+                }
+            }
+            for file in files {
+                builder.append_path_with_name(save_path.join(file), file)?;
+            }
+            builder.into_inner()?.finish()?.flush()?;
+        }
+        ArchiveFormat::Zip => {
+            let mut writer = zip::ZipWriter::new(BufWriter::new(File::create(&part)?));
+            if archive.exists() {
+                let mut old = zip::ZipArchive::new(BufReader::new(File::open(archive)?))?;
+                for i in 0..old.len() {
+                    let member = old.by_index_raw(i)?;
+                    if !replaced(member.name()) {
+                        writer.raw_copy_file(member)?;
+                    }
+                }
+            }
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                .large_file(true);
+            for file in files {
+                writer.start_file(file.as_str(), options)?;
+                std::io::copy(
+                    &mut BufReader::new(File::open(save_path.join(file))?),
+                    &mut writer,
+                )?;
+            }
+            writer.finish()?.flush()?;
+        }
+    }
+    std::fs::rename(&part, archive)?;
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use crate::config::{self, Assemblies};
+use crate::download::{fetch, format, stored_path, Downloaded};
+use crate::pipeline::Context;
+use crate::rcsb;
+use anyhow::Result;
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+
+/// Ids of the biological assemblies of `pdb_id` wanted by the config.
+pub(crate) async fn assembly_ids(ctx: &Context, pdb_id: &str) -> Result<Vec<String>> {
+    match ctx.config.assemblies {
+        Assemblies::None => Ok(Vec::new()),
+        Assemblies::First => Ok(vec!["1".to_string()]),
+        Assemblies::All => rcsb::entry_identifiers(ctx, pdb_id, "assembly_ids").await,
+    }
+}
+
+/// Urls of the wanted assemblies of `pdb_id`, with the files they are saved to in `save_path`.
+pub(crate) async fn assembly_files(
+    ctx: &Context,
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<Vec<(Url, PathBuf)>> {
+    let mut files = Vec::new();
+    for assembly_id in assembly_ids(ctx, pdb_id).await? {
+        //'#' is the assembly, '%' the PDB ID
+        let template = ctx.config.assembly_url.replace('#', &assembly_id);
+        let url: Url = format(&template, pdb_id)?.parse()?;
+        let file_name = match Path::new(url.path()).file_name() {
+            Some(file_name) => file_name.to_owned(),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "Check your assembly url",
+                )
+                .into())
+            }
+        };
+        files.push((url, save_path.join(file_name)));
+    }
+    Ok(files)
+}
+
+/// Download the wanted assemblies of `pdb_id` next to its asymmetric unit in `save_path`.
+pub(crate) async fn download_assemblies(
+    ctx: &Context,
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<Vec<Downloaded>> {
+    let mut downloaded = Vec::new();
+    for (url, save_filepath) in assembly_files(ctx, pdb_id, save_path).await? {
+        if ctx
+            .storage
+            .exists(&stored_path(&ctx.config, &save_filepath))
+            .await?
+        {
+            continue;
+        }
+        debug!(target:"debug","Assembly url : {}", url);
+        let assembly = fetch(ctx, &url, &save_filepath).await?;
+        downloaded.push(Downloaded {
+            format: config::format_of(&ctx.config.assembly_url),
+            ..assembly
+        });
+    }
+    Ok(downloaded)
+}
@@ -0,0 +1,63 @@
+use crate::config::{Bandwidth, BandwidthWindow, UserConfig};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket of bytes shared by every download, refilled at the limit of the hour.
+pub(crate) struct Throttle {
+    max_bandwidth: Option<Bandwidth>,
+    schedule: Vec<BandwidthWindow>,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(config: &UserConfig) -> Self {
+        Throttle {
+            max_bandwidth: config.max_bandwidth,
+            schedule: config.bandwidth_schedule.clone(),
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    //Bytes per second allowed now, None when unlimited
+    fn rate(&self) -> Option<f64> {
+        let now = chrono::Local::now().time();
+        let limit = match self.schedule.iter().find(|window| window.contains(now)) {
+            Some(window) => window.max_bandwidth,
+            None => self.max_bandwidth,
+        };
+        limit
+            .filter(|limit| limit.0 > 0)
+            .map(|limit| limit.0 as f64)
+    }
+
+    /// Wait until `bytes` just received fit in the bandwidth.
+    ///
+    /// The bytes are taken at once, so concurrent downloads wait for the debt of all of them.
+    pub async fn consume(&self, bytes: u64) {
+        let Some(rate) = self.rate() else {
+            return;
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            //At most a second of bandwidth is saved up by idle downloads
+            bucket.tokens =
+                (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(rate);
+            bucket.updated = now;
+            bucket.tokens -= bytes as f64;
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / rate),
+                false => return,
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,91 @@
+use crate::config::BreakerPolicy;
+use crate::http::HttpError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+enum Circuit {
+    /// Consecutive failures so far
+    Closed(u32),
+    /// Skipped until then
+    Open(Instant),
+    /// One request is probing the mirror
+    Probing,
+}
+
+/// Mirrors paused after repeated failures, by url template.
+pub(crate) struct Breaker {
+    policy: BreakerPolicy,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl Breaker {
+    pub fn new(policy: BreakerPolicy) -> Self {
+        Breaker {
+            policy,
+            circuits: Mutex::default(),
+        }
+    }
+
+    /// Whether to try `mirror` now, letting a single request through once its cooldown is over.
+    pub fn allows(&self, mirror: &str) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        match circuits.get(mirror) {
+            None | Some(Circuit::Closed(_)) => true,
+            Some(Circuit::Open(until)) if Instant::now() >= *until => {
+                info!("Probing paused mirror {}", mirror);
+                circuits.insert(mirror.to_string(), Circuit::Probing);
+                true
+            }
+            Some(Circuit::Open(_) | Circuit::Probing) => false,
+        }
+    }
+
+    pub fn success(&self, mirror: &str) {
+        let previous = self
+            .circuits
+            .lock()
+            .unwrap()
+            .insert(mirror.to_string(), Circuit::Closed(0));
+        if matches!(previous, Some(Circuit::Probing)) {
+            info!("Mirror {} is back", mirror);
+        }
+    }
+
+    /// Count a failed download from `mirror`, pausing it once failures in a row reach the limit.
+    ///
+    /// Only transient errors count, a missing file shows the mirror answers.
+    pub fn failure(&self, mirror: &str, error: &anyhow::Error) {
+        if self.policy.failures == 0 {
+            return;
+        }
+        let transient = error.chain().any(|source| {
+            source
+                .downcast_ref::<HttpError>()
+                .is_some_and(HttpError::is_transient)
+        });
+        if !transient {
+            self.success(mirror);
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let failures = match circuits.get(mirror) {
+            Some(Circuit::Closed(failures)) => failures + 1,
+            None => 1,
+            //A failed probe pauses it again right away
+            Some(Circuit::Open(_) | Circuit::Probing) => self.policy.failures,
+        };
+        if failures < self.policy.failures {
+            circuits.insert(mirror.to_string(), Circuit::Closed(failures));
+            return;
+        }
+        warn!(
+            "Pausing mirror {} for {}s after {} failures in a row",
+            mirror, self.policy.cooldown_secs, failures
+        );
+        circuits.insert(
+            mirror.to_string(),
+            Circuit::Open(Instant::now() + Duration::from_secs(self.policy.cooldown_secs)),
+        );
+    }
+}
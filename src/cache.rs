@@ -0,0 +1,81 @@
+use crate::checksum::hash_file;
+use crate::config::LinkMode;
+use crate::download::{download_file, stored_path, Downloaded};
+use crate::pipeline::Context;
+use anyhow::Result;
+use reqwest::Url;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use tokio::task;
+
+pub(crate) const CACHE_DIR: &str = "cache";
+
+/// Download `url` into the shared cache unless it is there already, then place it at
+/// `save_filepath` according to `link_mode`.
+pub(crate) async fn fetch_linked(
+    ctx: &Context,
+    url: &Url,
+    save_filepath: &Path,
+) -> Result<Downloaded> {
+    let cached = cached_file(ctx, save_filepath);
+    create_dir_all(cached.parent().unwrap_or(Path::new("")))?;
+
+    //Targets sharing a PDB entry must not download it at the same time
+    let lock = ctx.lock(&cached);
+    let _guard = lock.lock().await;
+    let stored_cached = stored_path(&ctx.config, &cached);
+    let downloaded = if stored_cached.exists() {
+        debug!(target:"debug","Cached : {}", stored_cached.display());
+        let path = stored_cached.clone();
+        let (size, sha256) = task::spawn_blocking(move || hash_file(&path)).await??;
+        Downloaded {
+            url: url.clone(),
+            format: None,
+            path: stored_cached,
+            size,
+            sha256,
+            etag: None,
+            last_modified: None,
+        }
+    } else {
+        download_file(ctx, url, &cached).await?
+    };
+
+    let stored_filepath = stored_path(&ctx.config, save_filepath);
+    link(ctx.config.link_mode, &downloaded.path, &stored_filepath)?;
+    Ok(Downloaded {
+        path: stored_filepath,
+        ..downloaded
+    })
+}
+
+//Where the shared copy of a file saved as `save_filepath` is kept
+fn cached_file(ctx: &Context, save_filepath: &Path) -> PathBuf {
+    Path::new(&ctx.config.save_path)
+        .join(CACHE_DIR)
+        .join("pdb")
+        .join(save_filepath.file_name().unwrap_or_default())
+}
+
+/// Remove the shared copy of a file saved as `save_filepath`, so it is downloaded again.
+pub(crate) fn evict(ctx: &Context, save_filepath: &Path) -> std::io::Result<()> {
+    let cached = stored_path(&ctx.config, &cached_file(ctx, save_filepath));
+    match std::fs::remove_file(cached) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn link(mode: LinkMode, cached: &Path, to: &Path) -> std::io::Result<()> {
+    match mode {
+        LinkMode::None | LinkMode::Copy => std::fs::copy(cached, to).map(|_| ()),
+        LinkMode::Hardlink => std::fs::hard_link(cached, to),
+        LinkMode::Symlink => {
+            let cached = cached.canonicalize()?;
+            #[cfg(unix)]
+            return std::os::unix::fs::symlink(cached, to);
+            #[cfg(windows)]
+            return std::os::windows::fs::symlink_file(cached, to);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Size and hex sha256 of a file.
+#[tracing::instrument(skip_all)]
+pub(crate) fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let (hasher, size) = hash_prefix(path)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Hasher fed with the content of a file, to continue hashing whatever is appended to it.
+pub(crate) fn hash_prefix(path: &Path) -> Result<(Sha256, u64)> {
+    hash_content(BufReader::new(File::open(path)?))
+}
+
+/// Size and hex sha256 of what `reader` reads, e.g. the content of a compressed file.
+pub(crate) fn hash_reader(reader: impl Read) -> Result<(u64, String)> {
+    let (hasher, size) = hash_content(reader)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+fn hash_content(mut reader: impl Read) -> Result<(Sha256, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((hasher, size))
+}
@@ -0,0 +1,318 @@
+use crate::checksum;
+use crate::config::{CompoundFormat, DataFormat};
+use crate::download::{part_path, Downloaded};
+use crate::pipeline::{Context, Target};
+use anyhow::Result;
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const CHEMBL_HOST: &str = "https://www.ebi.ac.uk";
+const CHEMBL_URL: &str = "https://www.ebi.ac.uk/chembl/api/data/";
+const PAGE_SIZE: usize = 1000;
+const MOLECULE_CHUNK: usize = 50;
+
+/// A bioactivity measured against a ChEMBL target, values kept as ChEMBL writes them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Activity {
+    pub activity_id: u64,
+    pub molecule_chembl_id: String,
+    pub canonical_smiles: Option<String>,
+    pub standard_type: Option<String>,
+    pub standard_relation: Option<String>,
+    pub standard_value: Option<String>,
+    pub standard_units: Option<String>,
+    pub pchembl_value: Option<String>,
+    pub assay_chembl_id: Option<String>,
+    pub document_chembl_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PageMeta {
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ActivityPage {
+    activities: Vec<Activity>,
+    page_meta: PageMeta,
+}
+
+#[derive(Deserialize)]
+struct TargetComponent {
+    accession: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChemblTarget {
+    target_chembl_id: String,
+    pref_name: Option<String>,
+    #[serde(default)]
+    target_components: Vec<TargetComponent>,
+}
+
+#[derive(Deserialize)]
+struct TargetPage {
+    targets: Vec<ChemblTarget>,
+    page_meta: PageMeta,
+}
+
+/// Targets matching the ChEMBL API filters of `query`, e.g. `organism=Homo sapiens`, page by page.
+pub(crate) async fn fetch_targets(
+    ctx: &Context,
+    query: &BTreeMap<String, String>,
+) -> Result<Vec<Target>> {
+    let mut url: Url = format!("{}target.json", CHEMBL_URL).parse()?;
+    url.query_pairs_mut()
+        .extend_pairs(query)
+        .append_pair("limit", &PAGE_SIZE.to_string());
+    let mut targets = Vec::new();
+    loop {
+        debug!(target:"debug","ChEMBL url : {}", url);
+        let page: TargetPage = serde_json::from_str(&ctx.http.get_text(&url).await?)?;
+        for target in page.targets {
+            let accessions = target
+                .target_components
+                .into_iter()
+                .filter_map(|component| component.accession)
+                .collect::<Vec<_>>();
+            targets.push(Target {
+                target_name: target
+                    .pref_name
+                    .unwrap_or_else(|| target.target_chembl_id.clone()),
+                chembl_id: target.target_chembl_id,
+                uniprot_accession: accessions.join("|"),
+                priority: None,
+            });
+        }
+        match page.page_meta.next {
+            Some(next) => url = format!("{}{}", CHEMBL_HOST, next).parse()?,
+            None => break,
+        }
+    }
+    info!("{} ChEMBL targets matching {:?}", targets.len(), query);
+    Ok(targets)
+}
+
+/// First page of the activities against `chembl_id`.
+pub(crate) fn activity_url(ctx: &Context, chembl_id: &str) -> Result<Url> {
+    let mut url: Url = format!("{}activity.json", CHEMBL_URL).parse()?;
+    url.query_pairs_mut()
+        .append_pair("target_chembl_id", chembl_id)
+        .append_pair("limit", &PAGE_SIZE.to_string());
+    if !ctx.config.chembl.activity_types.is_empty() {
+        url.query_pairs_mut().append_pair(
+            "standard_type__in",
+            &ctx.config.chembl.activity_types.join(","),
+        );
+    }
+    Ok(url)
+}
+
+/// Every activity against `chembl_id` of the types wanted by the config, page by page.
+pub(crate) async fn fetch_activities(ctx: &Context, chembl_id: &str) -> Result<Vec<Activity>> {
+    let mut activities = Vec::new();
+    let mut url = activity_url(ctx, chembl_id)?;
+    loop {
+        debug!(target:"debug","ChEMBL url : {}", url);
+        let page: ActivityPage = serde_json::from_str(&ctx.http.get_text(&url).await?)?;
+        activities.extend(page.activities);
+        match page.page_meta.next {
+            //Next pages are given relative to the host
+            Some(next) => url = format!("{}{}", CHEMBL_HOST, next).parse()?,
+            None => break,
+        }
+    }
+    Ok(activities)
+}
+
+/// File the activities of a target are written to in its folder.
+pub(crate) fn activities_file(ctx: &Context, path_target: &Path) -> PathBuf {
+    match ctx.config.chembl.activity_format {
+        DataFormat::Csv => path_target.join("activities.csv"),
+        DataFormat::Json => path_target.join("activities.json"),
+    }
+}
+
+/// File the structures of the actives against a target are written to in its folder.
+pub(crate) fn compounds_file(ctx: &Context, path_target: &Path) -> PathBuf {
+    match ctx.config.chembl.compound_format {
+        CompoundFormat::Sdf => path_target.join("compounds.sdf"),
+        CompoundFormat::Smiles => path_target.join("compounds.smi"),
+    }
+}
+
+/// Write the activities measured against `target` and the structures of its actives into its
+/// folder, as the config asks and unless they are there already.
+pub(crate) async fn download_target_data(
+    ctx: &Context,
+    target: &Target,
+    path_target: &Path,
+) -> Result<Vec<Downloaded>> {
+    let config = &ctx.config.chembl;
+    let activities_path = activities_file(ctx, path_target);
+    let compounds_path = compounds_file(ctx, path_target);
+    let write_activities = config.activities && !ctx.storage.exists(&activities_path).await?;
+    let write_compounds = config.compounds && !ctx.storage.exists(&compounds_path).await?;
+    if !write_activities && !write_compounds {
+        return Ok(Vec::new());
+    }
+
+    let activities = fetch_activities(ctx, &target.chembl_id).await?;
+    info!(
+        "{} ({}): {} activities",
+        target.target_name,
+        target.chembl_id,
+        activities.len()
+    );
+    let mut downloaded = Vec::new();
+    if write_activities {
+        let part = part_path(&activities_path);
+        match config.activity_format {
+            DataFormat::Csv => {
+                let mut writer = csv::Writer::from_path(&part)?;
+                for activity in &activities {
+                    writer.serialize(activity)?;
+                }
+                writer.flush()?;
+            }
+            DataFormat::Json => {
+                let mut writer = BufWriter::new(File::create(&part)?);
+                serde_json::to_writer_pretty(&mut writer, &activities)?;
+                writer.flush()?;
+            }
+        }
+        let url = activity_url(ctx, &target.chembl_id)?;
+        downloaded.push(written(url, &part, activities_path)?);
+    }
+    if write_compounds {
+        let actives = actives(config.min_pchembl, &activities);
+        let molecules = fetch_molecules(ctx, &actives).await?;
+        info!(
+            "{} ({}): {} active compounds",
+            target.target_name,
+            target.chembl_id,
+            molecules.len()
+        );
+        let part = part_path(&compounds_path);
+        let mut writer = BufWriter::new(File::create(&part)?);
+        for (chembl_id, pchembl) in &actives {
+            let structures = match molecules.get(chembl_id) {
+                Some(structures) => structures,
+                None => continue,
+            };
+            match config.compound_format {
+                CompoundFormat::Sdf => {
+                    let Some(molfile) = &structures.molfile else {
+                        continue;
+                    };
+                    writeln!(writer, "{}", molfile.trim_end())?;
+                    writeln!(writer, "> <chembl_id>\n{}\n", chembl_id)?;
+                    if let Some(pchembl) = pchembl {
+                        writeln!(writer, "> <pchembl_value>\n{}\n", pchembl)?;
+                    }
+                    writeln!(writer, "$$$$")?;
+                }
+                CompoundFormat::Smiles => {
+                    let Some(smiles) = &structures.canonical_smiles else {
+                        continue;
+                    };
+                    writeln!(writer, "{}\t{}", smiles, chembl_id)?;
+                }
+            }
+        }
+        writer.flush()?;
+        let url = format!("{}molecule.json", CHEMBL_URL).parse()?;
+        downloaded.push(written(url, &part, compounds_path)?);
+    }
+    Ok(downloaded)
+}
+
+//Molecules of the activities passing the threshold, with their best pChEMBL value
+fn actives(min_pchembl: Option<f64>, activities: &[Activity]) -> Vec<(String, Option<f64>)> {
+    let mut actives: Vec<(String, Option<f64>)> = Vec::new();
+    for activity in activities {
+        let pchembl = activity
+            .pchembl_value
+            .as_deref()
+            .and_then(|value| value.parse::<f64>().ok());
+        if let Some(min_pchembl) = min_pchembl {
+            if !pchembl.is_some_and(|pchembl| pchembl >= min_pchembl) {
+                continue;
+            }
+        }
+        match actives
+            .iter_mut()
+            .find(|(chembl_id, _)| *chembl_id == activity.molecule_chembl_id)
+        {
+            Some((_, best)) => {
+                if pchembl > *best {
+                    *best = pchembl;
+                }
+            }
+            None => actives.push((activity.molecule_chembl_id.clone(), pchembl)),
+        }
+    }
+    actives
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct MoleculeStructures {
+    canonical_smiles: Option<String>,
+    molfile: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Molecule {
+    molecule_chembl_id: String,
+    molecule_structures: Option<MoleculeStructures>,
+}
+
+#[derive(Deserialize)]
+struct MoleculePage {
+    molecules: Vec<Molecule>,
+}
+
+//Structures of molecules by ChEMBL ID, asked for a page at a time
+async fn fetch_molecules(
+    ctx: &Context,
+    actives: &[(String, Option<f64>)],
+) -> Result<HashMap<String, MoleculeStructures>> {
+    let mut molecules = HashMap::new();
+    for chunk in actives.chunks(MOLECULE_CHUNK) {
+        let ids = chunk
+            .iter()
+            .map(|(chembl_id, _)| chembl_id.as_str())
+            .collect::<Vec<_>>();
+        let mut url: Url = format!("{}molecule.json", CHEMBL_URL).parse()?;
+        url.query_pairs_mut()
+            .append_pair("molecule_chembl_id__in", &ids.join(","))
+            .append_pair("limit", &MOLECULE_CHUNK.to_string());
+        debug!(target:"debug","ChEMBL url : {}", url);
+        let page: MoleculePage = serde_json::from_str(&ctx.http.get_text(&url).await?)?;
+        for molecule in page.molecules {
+            if let Some(structures) = molecule.molecule_structures {
+                molecules.insert(molecule.molecule_chembl_id, structures);
+            }
+        }
+    }
+    Ok(molecules)
+}
+
+//Move a written part into place
+fn written(url: Url, part: &Path, path: PathBuf) -> Result<Downloaded> {
+    std::fs::rename(part, &path)?;
+    let (size, sha256) = checksum::hash_file(&path)?;
+    Ok(Downloaded {
+        url,
+        format: None,
+        path,
+        size,
+        sha256,
+        etag: None,
+        last_modified: None,
+    })
+}
@@ -0,0 +1,115 @@
+use crate::cache::CACHE_DIR;
+use crate::daemon::UPDATES_FOLDER;
+use crate::export::STATS_DIR;
+use crate::pdb_index::PDB_INDEX_DIR;
+use crate::pipeline::Pipeline;
+use crate::server::JOBS_FOLDER;
+use crate::sums::SUMS_FILE;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fs::{read_dir, remove_dir, remove_file};
+use std::path::{Path, PathBuf};
+
+//Folders of the save path holding no target, left as they are
+const KEPT_FOLDERS: &[&str] = &[
+    //The index of PDB entries only holds links, written with the manifest
+    PDB_INDEX_DIR,
+    //Timings of the downloads of past runs
+    STATS_DIR,
+    //What each update of the daemon found
+    UPDATES_FOLDER,
+    //Jobs of the APIs are save paths of their own, with their own journal
+    JOBS_FOLDER,
+];
+
+impl Pipeline {
+    /// Remove stale `.part` files, files of the target folders missing from the manifest (such
+    /// as the ChEMBL ID markers, logs of targets and `SHA256SUMS` aside) and the folders left empty.
+    ///
+    /// Folders of the save path that hold no target, such as `by_pdb/` and `jobs/`, are left
+    /// alone.
+    ///
+    /// With `dry_run` nothing is removed, what would be is only logged. Returns the number of
+    /// files and folders concerned.
+    pub fn clean(&self, dry_run: bool) -> Result<usize> {
+        let save_path = Path::new(&self.config().save_path);
+        let tracked = self
+            .ctx
+            .state
+            .files()
+            .iter()
+            //Archived members are only listed by path within the archive they are in
+            .map(|record| match &record.archive {
+                Some(archive) => save_path.join(archive),
+                None => save_path.join(&record.path),
+            })
+            .collect::<HashSet<_>>();
+        let mut cleaned = 0;
+        for entry in read_dir(save_path)? {
+            let path = entry?.path();
+            let kept = path
+                .file_name()
+                .is_some_and(|name| KEPT_FOLDERS.iter().any(|folder| name == *folder));
+            if !path.is_dir() || kept {
+                continue;
+            }
+            //The cache is only ever left with parts, its files being linked from target folders
+            let in_cache = path.file_name().is_some_and(|name| name == CACHE_DIR);
+            cleaned += clean_dir(&path, &tracked, in_cache, dry_run)?.0;
+        }
+        if dry_run {
+            info!("{} files and folders would be removed", cleaned);
+        } else {
+            info!("{} files and folders removed", cleaned);
+        }
+        Ok(cleaned)
+    }
+}
+
+//Number of files and folders cleaned below `dir`, and whether `dir` itself was emptied
+fn clean_dir(
+    dir: &Path,
+    tracked: &HashSet<PathBuf>,
+    parts_only: bool,
+    dry_run: bool,
+) -> Result<(usize, bool)> {
+    let mut cleaned = 0;
+    let mut kept = 0;
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let (below, emptied) = clean_dir(&path, tracked, parts_only, dry_run)?;
+            cleaned += below;
+            if !emptied {
+                kept += 1;
+            }
+            continue;
+        }
+        let part = path.extension().is_some_and(|ext| ext == "part");
+        //Logs of targets and checksum lists aren't in the manifest but are kept
+        let listing = path.extension().is_some_and(|ext| ext == "log")
+            || path.file_name().is_some_and(|name| name == SUMS_FILE);
+        if !part && listing {
+            kept += 1;
+            continue;
+        }
+        if part || (!parts_only && !tracked.contains(&path)) {
+            let kind = if part { "Stale part" } else { "Orphaned file" };
+            info!("{} : {}", kind, path.display());
+            cleaned += 1;
+            if !dry_run {
+                remove_file(&path)?;
+            }
+        } else {
+            kept += 1;
+        }
+    }
+    if kept > 0 {
+        return Ok((cleaned, false));
+    }
+    info!("Empty folder : {}", dir.display());
+    if !dry_run {
+        remove_dir(dir)?;
+    }
+    Ok((cleaned + 1, true))
+}
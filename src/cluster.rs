@@ -0,0 +1,117 @@
+use crate::config::ClusterConfig;
+use crate::pipeline::Context;
+use crate::rcsb;
+use crate::select;
+use crate::sifts;
+use crate::uniprot::PdbReference;
+use anyhow::{bail, Result};
+
+const IDENTITIES: &[u8] = &[30, 50, 70, 90, 95, 100];
+
+/// The structures of `references` standing for their cluster, in their order.
+///
+/// Two structures are redundant when chains mapped to `accession` share an RCSB sequence
+/// cluster and cover enough of the same residues. The best resolved one of each cluster is
+/// kept, the others are added to `left_out`.
+pub(crate) async fn representatives(
+    ctx: &Context,
+    clustering: &ClusterConfig,
+    accession: &str,
+    references: Vec<PdbReference>,
+    left_out: &mut Vec<(String, String)>,
+) -> Result<Vec<PdbReference>> {
+    if !IDENTITIES.contains(&clustering.identity) {
+        bail!(
+            "RCSB has no sequence clusters at {}% identity, use one of {:?}",
+            clustering.identity,
+            IDENTITIES
+        );
+    }
+    if references.len() < 2 {
+        return Ok(references);
+    }
+    let mut members = Vec::new();
+    for reference in references {
+        let mut clusters = Vec::new();
+        for entity in sifts::entities(ctx, &reference.pdb_id, accession).await? {
+            if let Some(cluster) =
+                rcsb::sequence_cluster(ctx, &reference.pdb_id, entity, clustering.identity).await?
+            {
+                clusters.push(cluster);
+            }
+        }
+        let ranges = reference.ranges();
+        members.push((reference, clusters, ranges));
+    }
+
+    let mut order = (0..members.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| select::by_resolution(&members[a].0, &members[b].0));
+    let mut representatives: Vec<usize> = Vec::new();
+    let mut redundant_with = vec![None; members.len()];
+    for i in order {
+        let (_, clusters, ranges) = &members[i];
+        let representative = representatives.iter().copied().find(|&r| {
+            let (_, r_clusters, r_ranges) = &members[r];
+            clusters.iter().any(|cluster| r_clusters.contains(cluster))
+                && overlap(ranges, r_ranges) >= clustering.min_overlap
+        });
+        match representative {
+            Some(r) => redundant_with[i] = Some(members[r].0.pdb_id.clone()),
+            None => representatives.push(i),
+        }
+    }
+
+    let total = members.len();
+    let mut kept = Vec::new();
+    for ((reference, _, _), redundant_with) in members.into_iter().zip(redundant_with) {
+        match redundant_with {
+            Some(representative) => {
+                debug!(target:"debug","{} is redundant with {}", reference.pdb_id, representative);
+                left_out.push((
+                    reference.pdb_id,
+                    format!("redundant with {}", representative),
+                ));
+            }
+            None => kept.push(reference),
+        }
+    }
+    info!(
+        "{} of {} structures of {} represent their clusters",
+        kept.len(),
+        total,
+        accession
+    );
+    Ok(kept)
+}
+
+//Residues shared by both range sets, as a fraction of the smaller one
+fn overlap(a: &[(u64, u64)], b: &[(u64, u64)]) -> f64 {
+    let length = |ranges: &[(u64, u64)]| {
+        ranges
+            .iter()
+            .map(|(start, end)| end - start + 1)
+            .sum::<u64>()
+    };
+    let smaller = length(a).min(length(b));
+    //Without ranges to tell them apart, shared clusters are enough
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    if smaller == 0 {
+        return 0.0;
+    }
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        let start = a[i].0.max(b[j].0);
+        let end = a[i].1.min(b[j].1);
+        if start <= end {
+            shared += end - start + 1;
+        }
+        if a[i].1 < b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    shared as f64 / smaller as f64
+}
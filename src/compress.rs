@@ -0,0 +1,107 @@
+use crate::config::Compression;
+use crate::download::part_path;
+use crate::storage::Storage;
+use anyhow::Result;
+use async_trait::async_trait;
+use flate2::bufread::MultiGzDecoder;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+//Files already compressed are stored as they are
+const COMPRESSED: &[&str] = &["gz", "zst", "zip", "bz2", "xz"];
+
+/// Where a file saved as `path` is stored with `compression`, none if it is compressed already.
+pub(crate) fn compressed_path(path: &Path, compression: Compression) -> Option<PathBuf> {
+    if path
+        .extension()
+        .is_some_and(|ext| COMPRESSED.iter().any(|compressed| ext == *compressed))
+    {
+        return None;
+    }
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(match compression {
+        Compression::Gzip => ".gz",
+        Compression::Zstd => ".zst",
+    });
+    Some(PathBuf::from(compressed))
+}
+
+/// Fail unless the build can write `compression`.
+pub(crate) fn check(compression: Compression) -> Result<()> {
+    if compression == Compression::Zstd && cfg!(not(feature = "zstd")) {
+        anyhow::bail!("compress_output = \"zstd\" needs a build with the \"zstd\" feature");
+    }
+    Ok(())
+}
+
+/// Replace the file at `path` by its compressed copy at `to`, returning its size and sha256.
+pub(crate) fn compress(path: &Path, to: &Path, compression: Compression) -> Result<(u64, String)> {
+    let part = part_path(to);
+    let mut input = BufReader::new(File::open(path)?);
+    let output = BufWriter::new(File::create(&part)?);
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+            std::io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(output, 0)?;
+            std::io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => check(compression)?,
+    }
+    std::fs::rename(&part, to)?;
+    std::fs::remove_file(path)?;
+    crate::checksum::hash_file(to)
+}
+
+/// Content of the file at `path`, decompressed if it ends in `.gz` or `.zst`.
+pub(crate) fn reader(path: &Path) -> Result<Box<dyn Read + Send>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Box::new(MultiGzDecoder::new(file)),
+        #[cfg(feature = "zstd")]
+        Some("zst") => Box::new(zstd::Decoder::with_buffer(file)?),
+        _ => Box::new(file),
+    })
+}
+
+/// A storage where downloads may be kept compressed, tried last by [`Storage::exists`].
+pub(crate) struct CompressedStorage {
+    pub inner: Box<dyn Storage>,
+    pub compression: Compression,
+}
+
+#[async_trait]
+impl Storage for CompressedStorage {
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        if self.inner.exists(path).await? {
+            return Ok(true);
+        }
+        match compressed_path(path, self.compression) {
+            Some(compressed) => self.inner.exists(&compressed).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn store(&self, path: &Path, sha256: &str) -> Result<()> {
+        self.inner.store(path, sha256).await
+    }
+
+    async fn release(&self, path: &Path) -> Result<()> {
+        self.inner.release(path).await
+    }
+
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        self.inner.read(path).await
+    }
+
+    fn is_local(&self) -> bool {
+        self.inner.is_local()
+    }
+}
@@ -0,0 +1,1486 @@
+use anyhow::{bail, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub save_path: String,
+    /// One target list or several, which may be glob patterns such as "exports/*.csv"
+    #[serde(deserialize_with = "one_or_many")]
+    pub read_path: Vec<String>,
+    /// Format of `read_path`, told by its extension by default
+    #[serde(default)]
+    pub input_format: InputFormat,
+    /// Delimiter of CSV input, guessed from the header line by default
+    #[serde(default)]
+    pub input_delimiter: Option<char>,
+    /// Columns of `read_path` holding each field of the targets
+    #[serde(default)]
+    pub columns: Columns,
+    /// Path of downloaded files below `save_path`, see config.toml for the placeholders
+    #[serde(default = "default_path_template")]
+    pub path_template: String,
+    /// Replaces characters of path components that are invalid on some platform
+    #[serde(default = "default_path_replacement")]
+    pub path_replacement: char,
+    /// Longer path components are cut and end with a hash of the full name, in bytes
+    #[serde(default = "default_max_name_length")]
+    pub max_name_length: usize,
+    /// Field telling targets listed more than once apart
+    #[serde(default)]
+    pub dedup_key: DedupKey,
+    /// Order in which targets are processed
+    #[serde(default)]
+    pub order: TargetOrder,
+    /// Part of the input to process
+    #[serde(default)]
+    pub filter: TargetFilter,
+    pub log_config: String,
+    /// Levels by target in the syntax of `RUST_LOG`, which overrides it, instead of those of
+    /// `log_config`
+    #[serde(default)]
+    pub log_filter: Option<String>,
+    /// `json` logs one event per line to `json_log` instead of the root appenders of
+    /// `log_config`, `both` next to them
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default = "default_json_log")]
+    pub json_log: String,
+    /// Copy the log events of each target into `download.log` of its folder, or `<folder>.log`
+    /// next to it with `archive`
+    #[serde(default)]
+    pub target_logs: bool,
+    pub processor_limit: usize,
+    pub downloader_limit: usize,
+    pub download_url: Vec<String>,
+    /// Structure formats in order of preference, e.g. ["cif", "pdb"], replacing `download_url`
+    #[serde(default)]
+    pub formats: Vec<String>,
+    /// Mirrors of each format, using '%' for the PDB ID, defaulting to RCSB and wwPDB
+    #[serde(default)]
+    pub format_urls: HashMap<String, Vec<String>>,
+    /// Whether isoform accessions such as `P12345-2` are treated as their canonical entry
+    #[serde(default)]
+    pub isoforms: IsoformPolicy,
+    /// Seconds between looks at the target lists with `--watch`
+    #[serde(default = "default_watch_interval")]
+    pub watch_interval_secs: u64,
+    /// When the `daemon` command runs `update`, as a cron expression or an alias like "@weekly"
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+    /// Fail every request at once, working from the UniProt cache and `file://` mirrors alone
+    #[serde(default)]
+    pub offline: bool,
+    /// Draw a live dashboard of the run on the terminal, with the console logs in a pane of it
+    #[serde(default)]
+    pub tui: bool,
+    /// Hours UniProt entries are kept in `cache/uniprot/` of the save path, 0 not to keep them
+    #[serde(default)]
+    pub uniprot_cache_hours: u64,
+    #[serde(default = "default_uniprot_batch_size")]
+    pub uniprot_batch_size: usize,
+    /// Skip structures with a worse (or without) resolution, in Å
+    #[serde(default)]
+    pub max_resolution: Option<f64>,
+    /// Only download structures solved by these methods, e.g. ["X-ray", "EM"]
+    #[serde(default)]
+    pub allowed_methods: Option<Vec<String>>,
+    #[serde(default)]
+    pub max_structures_per_accession: Option<usize>,
+    #[serde(default)]
+    pub structure_ranking: Ranking,
+    /// Fraction of the binding and active site residues of the accession a structure must cover
+    #[serde(default)]
+    pub min_site_coverage: Option<f64>,
+    #[serde(default)]
+    pub clustering: Option<ClusterConfig>,
+    #[serde(default)]
+    pub allowed_taxa: Option<Vec<u64>>,
+    #[serde(default)]
+    pub check_chain_taxa: bool,
+    /// Write the residues of each accession covered by downloaded structures to `coverage.csv`
+    #[serde(default)]
+    pub coverage_report: bool,
+    /// Download the canonical UniProt sequence of each accession as `<accession>.fasta`
+    #[serde(default)]
+    pub fasta: bool,
+    /// Download the AlphaFold model of accessions without (wanted) PDB entries
+    #[serde(default)]
+    pub alphafold_fallback: bool,
+    /// Fold the sequence of accessions without (wanted) PDB entries nor AlphaFold model with ESMFold
+    #[serde(default)]
+    pub esmfold_fallback: bool,
+    /// Download the best SWISS-MODEL homology models of accessions without (wanted) PDB entries
+    #[serde(default)]
+    pub swissmodel_fallback: bool,
+    /// Models downloaded by `swissmodel_fallback`, by GMQE
+    #[serde(default = "default_swissmodel_models")]
+    pub swissmodel_models: usize,
+    /// Unpack `.gz` downloads, keeping the name without the extension
+    #[serde(default)]
+    pub decompress: bool,
+    /// Have `update` ask again for the coordinates of entries done, downloading changed ones
+    #[serde(default)]
+    pub revalidate_on_update: bool,
+    /// Parse new PDB and mmCIF coordinates, falling back to the next mirror when they can't be
+    #[serde(default = "default_validate_structures")]
+    pub validate_structures: bool,
+    #[serde(default)]
+    pub cleanups: Vec<Cleanup>,
+    /// Write the ligands of new coordinates into `ligands/`, one PDB file each
+    #[serde(default)]
+    pub extract_ligands: bool,
+    /// Write a copy of new coordinates with the chains mapped to the accession alone
+    #[serde(default)]
+    pub chain_selection: Option<ChainSelection>,
+    /// Write the manifest as Parquet too, with the timing of each PDB entry in `stats/`
+    #[serde(default)]
+    pub parquet: bool,
+    /// Keep the state in `state.sqlite` too, with tables of targets, accessions, PDB entries,
+    /// files and runs
+    #[serde(default)]
+    pub sqlite: bool,
+    /// List the checksums of the files of each folder in its `SHA256SUMS`
+    #[serde(default)]
+    pub sha256sums: bool,
+    /// Keep downloads compressed, stored with the checksum of their content
+    #[serde(default)]
+    pub compress_output: Option<Compression>,
+    /// Package the files of each target into one archive next to its folder
+    #[serde(default)]
+    pub archive: Option<ArchiveFormat>,
+    /// How structures in the shared cache are placed into target folders
+    #[serde(default)]
+    pub link_mode: LinkMode,
+    /// Keep `by_pdb/<pdb_id>/` with symlinks to every copy of the files of each PDB entry
+    #[serde(default)]
+    pub pdb_index: bool,
+    /// What becomes of obsolete PDB entries
+    #[serde(default)]
+    pub obsolete: ObsoletePolicy,
+    /// Biological assemblies to download next to each asymmetric unit
+    #[serde(default)]
+    pub assemblies: Assemblies,
+    /// Template of assembly files, using '%' for the PDB ID and '#' for the assembly
+    #[serde(default = "default_assembly_url")]
+    pub assembly_url: String,
+    /// wwPDB validation reports to download next to each entry, "pdf" and/or "xml"
+    #[serde(default)]
+    pub validation_reports: Vec<String>,
+    /// Download the EMDB maps of cryo-EM entries next to their coordinates
+    #[serde(default)]
+    pub emdb_maps: bool,
+    /// Download the SIFTS mapping of chains and residues to UniProt next to each entry
+    #[serde(default)]
+    pub sifts_mapping: bool,
+    /// Address serving Prometheus metrics at `/metrics` during runs, e.g. "127.0.0.1:9898"
+    #[serde(default)]
+    pub metrics_addr: Option<String>,
+    /// OTLP/HTTP endpoint traces are exported to, e.g. "http://localhost:4318/v1/traces"
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// Webhook called with a Slack-compatible payload when runs finish
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+    /// SMTP server the report of each run is emailed through
+    #[serde(default)]
+    pub email: Option<EmailConfig>,
+    /// Bucket access when `save_path` is `s3://bucket/prefix`
+    #[serde(default)]
+    pub s3: S3Config,
+    /// Seconds running downloads get to finish after Ctrl+C
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default)]
+    pub chembl: ChemblConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub circuit_breaker: BreakerPolicy,
+    #[serde(default)]
+    pub disk_space: DiskSpacePolicy,
+    /// Fetch large files as several ranges at once
+    #[serde(default)]
+    pub segmented_download: Option<SegmentedDownload>,
+    /// Try the mirrors of each format fastest first, as measured by a probe
+    #[serde(default)]
+    pub mirror_probe: Option<MirrorProbe>,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    /// Bytes per second shared by every download, e.g. "50MiB/s", unlimited if missing or 0
+    #[serde(default)]
+    pub max_bandwidth: Option<Bandwidth>,
+    /// Other limits at some hours of the local time, the first window containing it applies
+    #[serde(default)]
+    pub bandwidth_schedule: Vec<BandwidthWindow>,
+    /// Overrides the HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY environment variables
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    #[serde(default)]
+    pub auth: Vec<AuthConfig>,
+    /// Named sets of fields, which `load` layers over the others
+    #[serde(default, rename = "profile")]
+    pub profiles: BTreeMap<String, serde::de::IgnoredAny>,
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(
+        match <OneOrMany as serde::Deserialize>::deserialize(deserializer)? {
+            OneOrMany::One(path) => vec![path],
+            OneOrMany::Many(paths) => paths,
+        },
+    )
+}
+
+fn default_path_template() -> String {
+    "{chembl_id}/{target_name}/{uniprot}/{file}".to_string()
+}
+
+fn default_path_replacement() -> char {
+    '_'
+}
+
+fn default_max_name_length() -> usize {
+    200
+}
+
+fn default_json_log() -> String {
+    "log/prog_med.jsonl".to_string()
+}
+
+fn default_uniprot_batch_size() -> usize {
+    500
+}
+
+fn default_watch_interval() -> u64 {
+    10
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_swissmodel_models() -> usize {
+    1
+}
+
+fn default_validate_structures() -> bool {
+    true
+}
+
+fn default_assembly_url() -> String {
+    "https://files.rcsb.org/download/%-assembly#.cif.gz".to_string()
+}
+
+/// Where run notifications are posted, as `{"text": ...}`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Also post errors that abort the run or a target
+    #[serde(default)]
+    pub on_error: bool,
+}
+
+/// Where the bucket of an `s3://` save path is and how files get to it.
+///
+/// Credentials come from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`,
+/// or from the credentials endpoint of ECS and AWS Batch containers.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct S3Config {
+    /// Defaults to AWS, set it for other S3-compatible stores such as MinIO
+    pub endpoint: Option<String>,
+    /// Defaults to `AWS_REGION`, then "us-east-1"
+    pub region: Option<String>,
+    /// Address buckets as `endpoint/bucket` rather than `bucket.endpoint`
+    pub path_style: bool,
+    /// Local folder files stay in until they are uploaded, a temporary folder by default
+    pub staging_path: Option<String>,
+}
+
+/// Recipients of the run report and the SMTP server sending it.
+///
+/// Credentials come from the `SMTP_USERNAME` and `SMTP_PASSWORD` environment variables.
+#[derive(Deserialize, Debug, Clone)]
+pub struct EmailConfig {
+    pub host: String,
+    /// Defaults to the usual port of `tls`
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// How the connection to the SMTP server is encrypted.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrade a plain connection, port 587
+    #[default]
+    Starttls,
+    /// TLS from the start, port 465
+    Tls,
+    /// Unencrypted, port 25, for a relay on the local network
+    None,
+}
+
+/// Format of the log lines written through the root logger.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// The encoders of `log_config` only
+    #[default]
+    Text,
+    Json,
+    Both,
+}
+
+/// Format of the target list.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    /// `.tsv`, `.jsonl` and `.xlsx` by extension, CSV otherwise
+    #[default]
+    Auto,
+    Csv,
+    Tsv,
+    /// One JSON object with the fields of a target per line
+    Jsonl,
+    /// The first sheet of a workbook, below its header row
+    Xlsx,
+}
+
+/// Field by which duplicate targets are dropped.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupKey {
+    #[default]
+    ChemblId,
+    UniprotAccession,
+    /// Keep every target
+    None,
+}
+
+/// Part of the input to process, the other targets being skipped as if they weren't listed.
+///
+/// Positions count the targets of the input once duplicates are dropped, from 0.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TargetFilter {
+    /// Targets skipped at the start of the input
+    pub offset: usize,
+    /// Targets kept at most after `offset`, before the other filters
+    pub limit: Option<usize>,
+    /// Only the targets of these ChEMBL IDs
+    pub chembl_ids: Vec<String>,
+    /// Only the targets whose name matches this regular expression
+    pub name_pattern: Option<String>,
+}
+
+/// Order in which targets are processed, all but `input` reading the whole input first.
+///
+/// Folders named by `{index}` keep the position of their target in the input.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetOrder {
+    /// As listed
+    #[default]
+    Input,
+    /// Highest value of the priority column first, targets without one last
+    Priority,
+    /// Fewest PDB entries listed by UniProt first, after the filters on them
+    FewestStructures,
+    /// In random order
+    Shuffle,
+}
+
+/// Columns of the target list, found by header name or usual position when unset.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Columns {
+    pub chembl_id: Option<Column>,
+    pub target_name: Option<Column>,
+    pub uniprot_accession: Option<Column>,
+    /// Found by the header name `priority` alone when unset
+    pub priority: Option<Column>,
+}
+
+/// A column by header name, or by index from 0.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+/// Handling of isoform accessions, e.g. `P12345-2`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IsoformPolicy {
+    /// Use the structures and sequence of the canonical entry
+    #[default]
+    Canonical,
+    /// Fetch the sequence of the isoform and keep the structures SIFTS maps to it
+    Include,
+}
+
+/// Records removed from the coordinates of PDB entries into a `_clean` copy.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Cleanup {
+    /// HOH, DOD and WAT residues
+    Waters,
+    /// Single atom ions such as Na⁺, Cl⁻, Mg²⁺ and Zn²⁺
+    Ions,
+    /// Alternate locations but the first of each residue
+    Altlocs,
+}
+
+/// Order structures are kept in by `max_structures_per_accession`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Ranking {
+    /// Best resolution first, structures without one last
+    #[default]
+    Resolution,
+    /// Latest release first, told by RCSB
+    Released,
+    /// Most residues of the accession covered first
+    Coverage,
+}
+
+/// Grouping of redundant structures of an accession, of which only the best is downloaded.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Percent sequence identity of the RCSB clusters: 30, 50, 70, 90, 95 or 100
+    pub identity: u8,
+    /// Fraction of the smaller residue range two structures must share
+    pub min_overlap: f64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            identity: 95,
+            min_overlap: 0.9,
+        }
+    }
+}
+
+/// What is kept of a structure besides the chains SIFTS maps to the accession.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ChainSelection {
+    /// Distance in angstroms to the chains within which ligands are kept too
+    pub ligand_cutoff: Option<f64>,
+}
+
+/// Compression of stored files.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// Format of the archive of each target.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+/// Handling of obsolete PDB entries, told by the RCSB holdings status.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ObsoletePolicy {
+    /// Download the latest entry superseding it instead
+    #[default]
+    Follow,
+    /// Leave it out
+    Skip,
+    /// Download it as it is, without asking for its status
+    Keep,
+}
+
+/// Biological assemblies fetched for every PDB entry.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Assemblies {
+    /// Only the asymmetric unit
+    #[default]
+    None,
+    /// The first assembly, the one usually meant as the biological unit
+    First,
+    /// Every assembly listed by RCSB
+    All,
+}
+
+/// Placement of structures downloaded once into `save_path/cache/pdb/`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// No cache, every target folder downloads its own copy
+    #[default]
+    None,
+    Copy,
+    Hardlink,
+    Symlink,
+}
+
+/// Data fetched from the ChEMBL API for every target.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChemblConfig {
+    /// Write the bioactivities measured against each target into its folder
+    pub activities: bool,
+    /// Activity types kept, every type if empty
+    pub activity_types: Vec<String>,
+    pub activity_format: DataFormat,
+    /// Write the structures of the compounds active against each target into its folder
+    pub compounds: bool,
+    /// Compounds count as active from this pChEMBL value (6 is 1 µM), every measured one if unset
+    pub min_pchembl: Option<f64>,
+    pub compound_format: CompoundFormat,
+    /// Filters of the ChEMBL target API selecting the targets instead of `read_path`
+    pub target_query: BTreeMap<String, String>,
+}
+
+impl Default for ChemblConfig {
+    fn default() -> Self {
+        ChemblConfig {
+            activities: false,
+            activity_types: vec!["IC50".to_string(), "Ki".to_string()],
+            activity_format: DataFormat::Csv,
+            compounds: false,
+            min_pchembl: None,
+            compound_format: CompoundFormat::Sdf,
+            target_query: BTreeMap::new(),
+        }
+    }
+}
+
+/// Format of tables written by the pipeline.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+/// Format of the compound structures written for each target.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CompoundFormat {
+    /// `compounds.sdf`, the ChEMBL molfiles tagged with their ID and pChEMBL value
+    #[default]
+    Sdf,
+    /// `compounds.smi`, a canonical SMILES and ID per line
+    Smiles,
+}
+
+/// Timeouts and connection pool of the HTTP client, in seconds, 0 to disable a timeout.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub connect_timeout: u64,
+    /// Covers the whole request, including the download of the body
+    pub request_timeout: u64,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout: 30,
+            request_timeout: 600,
+            pool_max_idle_per_host: 16,
+            pool_idle_timeout: 90,
+        }
+    }
+}
+
+/// Proxy every request goes through.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProxyConfig {
+    /// e.g. "http://proxy:3128" or "socks5://proxy:1080"
+    pub url: String,
+    /// Hosts, domains (".example.org") and IP ranges reached without the proxy
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+}
+
+/// Credentials sent with every request to urls starting with `prefix`.
+///
+/// `${NAME}` in values is replaced by the environment variable NAME, so secrets stay out of
+/// the config.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuthConfig {
+    /// e.g. "https://go.drugbank.com/", or a url template up to its '%'
+    pub prefix: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// HTTP basic authentication
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Requests per second allowed to each host, unlimited if missing or 0.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RateLimit {
+    /// For hosts without their own limit
+    pub default: Option<f64>,
+    /// By host name, e.g. "rest.uniprot.org"
+    #[serde(flatten)]
+    pub hosts: HashMap<String, f64>,
+}
+
+impl RateLimit {
+    pub fn for_host(&self, host: &str) -> Option<f64> {
+        self.hosts.get(host).copied().or(self.default)
+    }
+}
+
+/// Bytes per second, written like "50MiB/s", "500 KB/s" or a plain number of bytes.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Bandwidth(pub u64);
+
+impl TryFrom<String> for Bandwidth {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let text = value.trim();
+        let text = text.strip_suffix("/s").unwrap_or(text).trim_end();
+        let split = text
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("{:?} doesn't start with a number", value))?;
+        let unit = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "kb" | "k" => 1000,
+            "kib" => 1 << 10,
+            "mb" | "m" => 1000 * 1000,
+            "mib" => 1 << 20,
+            "gb" | "g" => 1000 * 1000 * 1000,
+            "gib" => 1 << 30,
+            _ => {
+                return Err(format!(
+                    "{:?} has an unknown unit, use B, KB, KiB, MB, MiB, GB or GiB",
+                    value
+                ))
+            }
+        };
+        Ok(Bandwidth((number * unit as f64) as u64))
+    }
+}
+
+/// Hours of the day with their own bandwidth, e.g. full speed at night.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BandwidthWindow {
+    /// Local time the window starts at, e.g. "20:00"
+    pub from: chrono::NaiveTime,
+    /// Local time it ends at, before `from` for windows over midnight
+    pub to: chrono::NaiveTime,
+    /// Unlimited if missing or 0
+    #[serde(default)]
+    pub max_bandwidth: Option<Bandwidth>,
+}
+
+impl BandwidthWindow {
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        match self.from <= self.to {
+            true => self.from <= time && time < self.to,
+            false => self.from <= time || time < self.to,
+        }
+    }
+}
+
+/// Minutes of the local time matched by a cron expression, "minute hour day month weekday".
+///
+/// Fields are `*`, numbers, ranges `a-b`, steps `*/n` or `a-b/n` and lists of those, weekdays
+/// from 0 (Sunday) to 7 (Sunday again). As in cron, a day matches either of day and weekday when
+/// both are restricted. "@hourly", "@daily", "@weekly", "@monthly" and "@yearly" stand for their
+/// usual expressions.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    //Whether `*` was given, which decides how days and weekdays combine
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let expression = match value.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "{:?} doesn't have the 5 fields minute hour day month weekday",
+                value
+            ));
+        };
+        let field = |text: &str, name: &str, min: u32, max: u32| {
+            cron_field(text, min, max).map_err(|e| format!("{:?} has a bad {}, {}", value, name, e))
+        };
+        let mut weekday_bits = field(weekdays, "weekday", 0, 7)?;
+        //Sunday is both 0 and 7
+        if weekday_bits & 1 << 7 != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Schedule {
+            expression: value.trim().to_string(),
+            minutes: field(minutes, "minute", 0, 59)?,
+            hours: field(hours, "hour", 0, 23)?,
+            days: field(days, "day", 1, 31)?,
+            months: field(months, "month", 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+fn cron_field(text: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("{:?} isn't a step", step)),
+            },
+            None => (part, 1),
+        };
+        let number = |text: &str| match text.parse::<u32>() {
+            Ok(number) if (min..=max).contains(&number) => Ok(number),
+            _ => Err(format!("{:?} isn't a number from {} to {}", text, min, max)),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (number(from)?, number(to)?),
+            //A step from a single value runs to the end, as in "5/15"
+            None if step > 1 => (number(range)?, max),
+            None => {
+                let value = number(range)?;
+                (value, value)
+            }
+        };
+        if from > to {
+            return Err(format!("{:?} is an empty range", range));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Schedule {
+    /// The first matching minute of the local time after `time`, None if none comes within years.
+    pub fn next_after(
+        &self,
+        time: chrono::DateTime<chrono::Local>,
+    ) -> Option<chrono::DateTime<chrono::Local>> {
+        use chrono::{Datelike, Duration, TimeZone};
+
+        let has = |bits: u64, value: u32| bits & 1 << value != 0;
+        let start = time.naive_local();
+        //Every combination of day and weekday comes back within 28 years
+        for day in 0..366 * 28 {
+            let date = start.date() + Duration::days(day);
+            let weekday = date.weekday().num_days_from_sunday();
+            let day_matches = match (self.any_day, self.any_weekday) {
+                (false, false) => has(self.days, date.day()) || has(self.weekdays, weekday),
+                _ => has(self.days, date.day()) && has(self.weekdays, weekday),
+            };
+            if !has(self.months, date.month()) || !day_matches {
+                continue;
+            }
+            for hour in (0..24).filter(|hour| has(self.hours, *hour)) {
+                for minute in (0..60).filter(|minute| has(self.minutes, *minute)) {
+                    let Some(candidate) = date.and_hms_opt(hour, minute, 0) else {
+                        continue;
+                    };
+                    //Minutes skipped by a daylight saving change don't exist
+                    if let Some(next) = chrono::Local.from_local_datetime(&candidate).earliest() {
+                        if next > time {
+                            return Some(next);
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+/// How failed HTTP requests are retried.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts per request, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every further one
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    /// Up to this fraction of the delay is randomly added to it
+    pub jitter: f64,
+    /// Longest delay honored of those asked for by Retry-After on 429 and 503 answers
+    pub max_retry_after_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            backoff_base_ms: 500,
+            backoff_max_ms: 30_000,
+            jitter: 0.5,
+            max_retry_after_secs: 120,
+        }
+    }
+}
+
+/// When a failing mirror of `download_url` is skipped for the next ones.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BreakerPolicy {
+    /// Failed downloads in a row pausing a mirror, 0 to never pause
+    pub failures: u32,
+    /// Seconds before a paused mirror is tried again
+    pub cooldown_secs: u64,
+}
+
+impl Default for BreakerPolicy {
+    fn default() -> Self {
+        BreakerPolicy {
+            failures: 5,
+            cooldown_secs: 60,
+        }
+    }
+}
+
+/// When downloads pause for lack of free space on `save_path`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DiskSpacePolicy {
+    /// MiB below which no download starts until space is freed, 0 to never pause
+    pub min_free_mb: u64,
+    /// Seconds between checks, 0 to check when the run starts only
+    pub interval_secs: u64,
+}
+
+impl Default for DiskSpacePolicy {
+    fn default() -> Self {
+        DiskSpacePolicy {
+            min_free_mb: 1024,
+            interval_secs: 30,
+        }
+    }
+}
+
+/// Which files are downloaded in segments, and how many.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SegmentedDownload {
+    /// Smallest Content-Length in MiB downloaded in segments
+    pub min_size_mb: u64,
+    /// Ranges fetched at once
+    pub segments: usize,
+}
+
+impl Default for SegmentedDownload {
+    fn default() -> Self {
+        SegmentedDownload {
+            min_size_mb: 100,
+            segments: 4,
+        }
+    }
+}
+
+/// How the mirrors of each format are probed and ranked.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MirrorProbe {
+    /// Entry fetched from every mirror
+    pub pdb_id: String,
+    /// Bytes read from it
+    pub bytes: u64,
+    /// Seconds between probes, 0 to probe when the run starts only
+    pub interval_secs: u64,
+    /// Url templates tried first in this order, whatever the probe measures
+    pub pinned: Vec<String>,
+}
+
+impl Default for MirrorProbe {
+    fn default() -> Self {
+        MirrorProbe {
+            pdb_id: "1crn".to_string(),
+            bytes: 65536,
+            interval_secs: 0,
+            pinned: Vec::new(),
+        }
+    }
+}
+
+/// A url template of a structure format.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub format: Option<String>,
+    pub template: String,
+}
+
+fn default_format_urls(format: &str) -> &'static [&'static str] {
+    match format {
+        "cif" => &[
+            "https://files.rcsb.org/download/%.cif.gz",
+            "https://ftp.wwpdb.org/pub/pdb/data/structures/all/mmCIF/%.cif.gz",
+        ],
+        "pdb" => &[
+            "https://files.rcsb.org/download/%.pdb.gz",
+            "https://ftp.wwpdb.org/pub/pdb/data/structures/all/pdb/pdb%.ent.gz",
+        ],
+        "bcif" => &["https://models.rcsb.org/%.bcif.gz"],
+        _ => &[],
+    }
+}
+
+//Format of a legacy `download_url` or an assembly template, told by its extension
+pub(crate) fn format_of(template: &str) -> Option<String> {
+    let name = template.trim_end_matches(".gz");
+    [
+        ("bcif", ".bcif"),
+        ("cif", ".cif"),
+        ("pdb", ".ent"),
+        ("pdb", ".pdb"),
+        ("pdb", ".pdb#"),
+    ]
+    .iter()
+    .find(|(_, extension)| name.ends_with(extension))
+    .map(|(format, _)| format.to_string())
+}
+
+impl UserConfig {
+    /// Url templates to try for every PDB entry, in order.
+    pub fn sources(&self) -> Vec<Source> {
+        if self.formats.is_empty() {
+            return self
+                .download_url
+                .iter()
+                .map(|template| Source {
+                    format: format_of(template),
+                    template: template.clone(),
+                })
+                .collect();
+        }
+        let mut sources = Vec::new();
+        for format in &self.formats {
+            let templates = match self.format_urls.get(format) {
+                Some(templates) => templates.clone(),
+                None => default_format_urls(format)
+                    .iter()
+                    .map(|template| template.to_string())
+                    .collect(),
+            };
+            for template in templates {
+                sources.push(Source {
+                    format: Some(format.clone()),
+                    template,
+                });
+            }
+        }
+        sources
+    }
+
+    /// The config at `path` with the `PROG_MED_` environment variables layered over it.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load(path, None)
+    }
+
+    /// The config at `path`, in TOML, YAML or JSON as told by its extension, with the fields of
+    /// `[profile.<name>]` over it, the profile being
+    /// `profile` or else the one named by `PROG_MED_PROFILE`, and the `PROG_MED_` environment
+    /// variables over both.
+    pub fn load(path: impl AsRef<Path>, profile: Option<&str>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the config {}", path.display()))?;
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| std::env::var(PROFILE_VAR).ok());
+        let overrides = std::env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != PROFILE_VAR)
+            .collect::<Vec<_>>();
+        let format = ConfigFormat::of(path);
+        //Parsed as they are, errors keep their line
+        if profile.is_none() && overrides.is_empty() {
+            return format
+                .parse(&contents)
+                .with_context(|| format!("Invalid config {}", path.display()));
+        }
+        let mut config = format
+            .parse_value(&contents)
+            .with_context(|| format!("Invalid config {}", path.display()))?;
+        let mut layers = Vec::new();
+        let profiles = match &mut config {
+            toml::Value::Table(table) => table.remove(PROFILES),
+            _ => None,
+        };
+        if let Some(profile) = &profile {
+            let mut profiles = match profiles {
+                Some(toml::Value::Table(profiles)) => profiles,
+                _ => Default::default(),
+            };
+            let Some(fields) = profiles.remove(profile) else {
+                bail!(
+                    "No profile \"{}\" in {}, it has {:?}",
+                    profile,
+                    path.display(),
+                    profiles.keys().collect::<Vec<_>>()
+                );
+            };
+            merge(&mut config, fields);
+            layers.push(format!("the profile {}", profile));
+        }
+        for (name, value) in &overrides {
+            apply_env(&mut config, name, value)?;
+            layers.push(name.clone());
+        }
+        config.try_into().with_context(|| {
+            format!(
+                "Invalid config {} with {}",
+                path.display(),
+                layers.join(", ")
+            )
+        })
+    }
+
+    /// Check what the config refers to before anything runs, listing every problem by field.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut problem =
+            |field: &str, message: String| problems.push(format!("{}: {}", field, message));
+
+        if !self.save_path.starts_with("s3://") {
+            if let Some(message) = creatable_folder(Path::new(&self.save_path)) {
+                problem("save_path", message);
+            }
+        }
+        if self.chembl.target_query.is_empty() {
+            for path in &self.read_path {
+                if path != "-" && !path.contains(['*', '?', '[']) && !Path::new(path).exists() {
+                    problem("read_path", format!("{} doesn't exist", path));
+                }
+            }
+        }
+        for message in crate::logging::check_log_config(&self.log_config) {
+            problem("log_config", message);
+        }
+        if let Some(pattern) = &self.filter.name_pattern {
+            if let Err(e) = grep::regex::RegexMatcher::new(pattern) {
+                problem(
+                    "filter.name_pattern",
+                    format!("{} isn't a valid regular expression: {}", pattern, e),
+                );
+            }
+        }
+        if let Some(filter) = &self.log_filter {
+            if let Err(e) = filter.parse::<tracing_subscriber::filter::Targets>() {
+                problem(
+                    "log_filter",
+                    format!("{} isn't a valid filter: {}", filter, e),
+                );
+            }
+        }
+        for kind in &self.validation_reports {
+            if !crate::validation::REPORT_KINDS.contains(&kind.as_str()) {
+                problem(
+                    "validation_reports",
+                    format!(
+                        "unknown report \"{}\", use one of {}",
+                        kind,
+                        crate::validation::REPORT_KINDS.join(", ")
+                    ),
+                );
+            }
+        }
+
+        for (field, value) in [
+            ("processor_limit", self.processor_limit),
+            ("downloader_limit", self.downloader_limit),
+            ("uniprot_batch_size", self.uniprot_batch_size),
+            ("retry.max_attempts", self.retry.max_attempts as usize),
+        ] {
+            if value == 0 {
+                problem(field, "must be at least 1".to_string());
+            }
+        }
+        if let Some(segmented) = &self.segmented_download {
+            if segmented.segments == 0 {
+                problem(
+                    "segmented_download.segments",
+                    "must be at least 1".to_string(),
+                );
+            }
+        }
+        if let Some(coverage) = self.min_site_coverage {
+            if !(0.0..=1.0).contains(&coverage) {
+                problem(
+                    "min_site_coverage",
+                    format!("{} isn't a fraction between 0 and 1", coverage),
+                );
+            }
+        }
+
+        let field = match self.formats.is_empty() {
+            true => "download_url",
+            false => "format_urls",
+        };
+        for format in &self.formats {
+            if !self.format_urls.contains_key(format) && default_format_urls(format).is_empty() {
+                problem(
+                    "formats",
+                    format!("no mirror of \"{}\", list some in format_urls", format),
+                );
+            }
+        }
+        let sources = self.sources();
+        if sources.is_empty() {
+            problem(field, "no mirror to download from".to_string());
+        }
+        for source in &sources {
+            if let Some(message) = template_problem(&source.template, &['%']) {
+                problem(field, message);
+            }
+        }
+        if self.assemblies != Assemblies::None {
+            if let Some(message) = template_problem(&self.assembly_url, &['%', '#']) {
+                problem("assembly_url", message);
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => bail!("Invalid config\n  {}", problems.join("\n  ")),
+        }
+    }
+}
+
+//Prefix of the environment variables overriding fields, `__` separating the keys of sections
+const ENV_PREFIX: &str = "PROG_MED_";
+
+#[derive(Clone, Copy)]
+enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    fn of(path: &Path) -> Self {
+        match path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .as_deref()
+        {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    fn parse<T: serde::de::DeserializeOwned>(self, contents: &str) -> Result<T> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::from_str(contents)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+            ConfigFormat::Json => serde_json::from_str(contents)?,
+        })
+    }
+
+    //The fields as TOML values to layer profiles and variables over, nulls being left out as
+    //TOML has none
+    fn parse_value(self, contents: &str) -> Result<toml::Value> {
+        fn drop_nulls(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(object) => {
+                    object.retain(|_, value| !value.is_null());
+                    object.values_mut().for_each(drop_nulls);
+                }
+                serde_json::Value::Array(values) => values.iter_mut().for_each(drop_nulls),
+                _ => {}
+            }
+        }
+        if let ConfigFormat::Toml = self {
+            return self.parse(contents);
+        }
+        let mut value: serde_json::Value = self.parse(contents)?;
+        drop_nulls(&mut value);
+        Ok(toml::Value::try_from(value)?)
+    }
+}
+
+//Variable naming the profile to load when none is given
+const PROFILE_VAR: &str = "PROG_MED_PROFILE";
+//Section of the named profiles, each with fields replacing those of the file
+const PROFILES: &str = "profile";
+
+//Fields of `layer` into `config`, sections being merged and other values replaced
+fn merge(config: &mut toml::Value, layer: toml::Value) {
+    match (config, layer) {
+        (toml::Value::Table(config), toml::Value::Table(layer)) => {
+            for (key, value) in layer {
+                match config.get_mut(&key) {
+                    Some(field) => merge(field, value),
+                    None => {
+                        config.insert(key, value);
+                    }
+                }
+            }
+        }
+        (config, layer) => *config = layer,
+    }
+}
+
+//Set the field named by the variable `name`, e.g. PROG_MED_RETRY__MAX_ATTEMPTS, to `value` read
+//as a TOML value, or as a string when it isn't one
+fn apply_env(config: &mut toml::Value, name: &str, value: &str) -> Result<()> {
+    let mut sections = name[ENV_PREFIX.len()..]
+        .to_lowercase()
+        .split("__")
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let field = sections.pop().unwrap_or_default();
+    let value = match toml::from_str::<toml::Value>(&format!("value = {}", value)) {
+        Ok(toml::Value::Table(mut table)) => table.remove("value"),
+        _ => None,
+    }
+    .unwrap_or_else(|| toml::Value::String(value.to_string()));
+    let mut table = config;
+    for section in sections {
+        table = match table {
+            toml::Value::Table(table) => table
+                .entry(section)
+                .or_insert_with(|| toml::Value::Table(Default::default())),
+            _ => bail!("{} names a field below one that isn't a section", name),
+        };
+    }
+    match table {
+        toml::Value::Table(table) => {
+            table.insert(field, value);
+            Ok(())
+        }
+        _ => bail!("{} names a field below one that isn't a section", name),
+    }
+}
+
+//Why `path` can't be made the save path, if it can't
+fn creatable_folder(path: &Path) -> Option<String> {
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    match std::fs::metadata(existing) {
+        Ok(metadata) if !metadata.is_dir() && existing == path => {
+            Some(format!("{} isn't a folder", path.display()))
+        }
+        Ok(metadata) if !metadata.is_dir() => Some(format!(
+            "{} can't be created below the file {}",
+            path.display(),
+            existing.display()
+        )),
+        Ok(metadata) if metadata.permissions().readonly() => {
+            Some(format!("{} isn't writable", existing.display()))
+        }
+        Ok(_) => None,
+        Err(e) => Some(format!(
+            "Failed to read {} due to \"{}\"",
+            existing.display(),
+            e
+        )),
+    }
+}
+
+//Whether `template` has its placeholders and makes a url
+fn template_problem(template: &str, placeholders: &[char]) -> Option<String> {
+    if let Some(missing) = placeholders
+        .iter()
+        .find(|placeholder| !template.contains(**placeholder))
+    {
+        return Some(format!("\"{}\" has no '{}' placeholder", template, missing));
+    }
+    let url = template.replace(placeholders, "1");
+    match reqwest::Url::parse(&url) {
+        Ok(_) => None,
+        Err(e) => Some(format!("\"{}\" isn't a url: {}", template, e)),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+
+    /// The documented config of the repository, saving into `save_path`.
+    pub(crate) fn config(save_path: &Path) -> UserConfig {
+        let mut config = toml::from_str::<toml::Value>(include_str!("../config.toml")).unwrap();
+        config["save_path"] = save_path.to_string_lossy().into_owned().into();
+        config.try_into().unwrap()
+    }
+
+    fn schedule(expression: &str) -> Schedule {
+        Schedule::try_from(expression.to_string()).unwrap()
+    }
+
+    //Times of January 2024, clear of daylight saving changes, the 1st being a Monday
+    fn january(day: u32, hour: u32, minute: u32) -> chrono::DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 1, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn next_after_is_the_next_matching_minute() {
+        let daily = schedule("30 9 * * *");
+        assert_eq!(daily.next_after(january(1, 8, 0)), Some(january(1, 9, 30)));
+        assert_eq!(daily.next_after(january(1, 9, 30)), Some(january(2, 9, 30)));
+        let quarters = schedule("*/15 * * * *");
+        assert_eq!(
+            quarters.next_after(january(1, 10, 7)),
+            Some(january(1, 10, 15))
+        );
+        assert_eq!(
+            quarters.next_after(january(1, 23, 50)),
+            Some(january(2, 0, 0))
+        );
+        //A step from a single value runs to the end
+        let from_five = schedule("5/20 * * * *");
+        assert_eq!(
+            from_five.next_after(january(1, 10, 30)),
+            Some(january(1, 10, 45))
+        );
+    }
+
+    #[test]
+    fn day_and_weekday_match_either_when_both_are_restricted() {
+        //The 13th or any Friday
+        let either = schedule("0 0 13 * 5");
+        assert_eq!(either.next_after(january(1, 0, 0)), Some(january(5, 0, 0)));
+        assert_eq!(either.next_after(january(5, 0, 0)), Some(january(12, 0, 0)));
+        assert_eq!(
+            either.next_after(january(12, 0, 0)),
+            Some(january(13, 0, 0))
+        );
+        //Only the one restricted counts otherwise
+        let day = schedule("0 0 13 * *");
+        assert_eq!(day.next_after(january(1, 0, 0)), Some(january(13, 0, 0)));
+        let weekday = schedule("0 0 * * 5");
+        assert_eq!(weekday.next_after(january(1, 0, 0)), Some(january(5, 0, 0)));
+    }
+
+    #[test]
+    fn aliases_and_sunday_as_seven() {
+        let weekly = schedule("@weekly");
+        assert_eq!(weekly.next_after(january(1, 12, 0)), Some(january(7, 0, 0)));
+        assert_eq!(weekly.to_string(), "@weekly");
+        assert_eq!(
+            schedule("0 0 * * 7").next_after(january(1, 12, 0)),
+            Some(january(7, 0, 0))
+        );
+        assert_eq!(
+            schedule("@monthly").next_after(january(1, 12, 0)),
+            Some(Local.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn bad_expressions_are_rejected() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "10-5 * * * *",
+            "@often",
+        ] {
+            assert!(
+                Schedule::try_from(expression.to_string()).is_err(),
+                "{}",
+                expression
+            );
+        }
+        //Nothing ever matches the 31st of February
+        assert_eq!(schedule("0 0 31 2 *").next_after(january(1, 0, 0)), None);
+    }
+
+    #[test]
+    fn unknown_fields_are_refused_by_name() {
+        let contents = format!("save_pth = \"out\"\n{}", include_str!("../config.toml"));
+        let error = toml::from_str::<UserConfig>(&contents).unwrap_err();
+        assert!(error.to_string().contains("unknown field `save_pth`"));
+    }
+
+    #[test]
+    fn profiles_are_no_unknown_fields() {
+        let contents = format!(
+            "{}\n[profile.test]\nprocessor_limit = 1\n",
+            include_str!("../config.toml")
+        );
+        let config = toml::from_str::<UserConfig>(&contents).unwrap();
+        assert!(config.profiles.contains_key("test"));
+    }
+
+    #[test]
+    fn log_config_is_checked_as_log4rs_reads_it() {
+        let path = std::env::temp_dir().join(format!("prog_med_log_{}.yml", std::process::id()));
+        let appenders = "appenders:\n  stdout:\n    kind: console\nroot:\n  level: info\n";
+        std::fs::write(&path, format!("{}  appenders: [stdout]\n", appenders)).unwrap();
+        let valid = crate::logging::check_log_config(&path.to_string_lossy());
+        std::fs::write(&path, format!("{}  appenders: [missing]\n", appenders)).unwrap();
+        let missing = crate::logging::check_log_config(&path.to_string_lossy());
+        std::fs::remove_file(&path).unwrap();
+        assert!(valid.is_empty());
+        assert_eq!(missing.len(), 1);
+        assert!(missing[0].contains("`missing`"));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::pipeline::Pipeline;
+use crate::uniprot;
+use anyhow::Result;
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub(crate) const COVERAGE_FILE: &str = "coverage.csv";
+
+//Residues of an accession covered by its downloaded structures, as listed in `coverage.csv`
+#[derive(Serialize, Debug)]
+struct Coverage {
+    accession: String,
+    length: Option<u64>,
+    structures: usize,
+    covered: u64,
+    percent: Option<f64>,
+    /// Residue ranges no structure covers, e.g. "1-20;300-350"
+    gaps: String,
+}
+
+impl Pipeline {
+    /// Write the residues of each accession covered by its downloaded PDB entries to
+    /// `coverage.csv`, told by the chain ranges of the UniProt cross-references.
+    pub async fn write_coverage(&self) -> Result<()> {
+        let mut structures: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for entry in self.ctx.state.files() {
+            let Some(pdb_id) = entry.superseded_by.or(entry.pdb_id) else {
+                continue;
+            };
+            let ids = structures.entry(entry.accession).or_default();
+            if !ids.iter().any(|id| id.eq_ignore_ascii_case(&pdb_id)) {
+                ids.push(pdb_id);
+            }
+        }
+
+        let mut writer =
+            csv::Writer::from_path(Path::new(&self.config().save_path).join(COVERAGE_FILE))?;
+        for (accession, ids) in structures {
+            let entry = match uniprot::fetch_entry(&self.ctx, &accession).await {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("No coverage of {} due to \"{}\"", accession, e);
+                    continue;
+                }
+            };
+            let merged = uniprot::merge_ranges(
+                entry
+                    .pdb_references()
+                    .iter()
+                    .filter(|reference| {
+                        ids.iter()
+                            .any(|id| id.eq_ignore_ascii_case(&reference.pdb_id))
+                    })
+                    .flat_map(|reference| reference.ranges())
+                    .collect(),
+            );
+            let covered = merged.iter().map(|(start, end)| end - start + 1).sum();
+            let length = entry.sequence.map(|sequence| sequence.length);
+            let mut gaps = Vec::new();
+            if let Some(length) = length {
+                let mut next = 1;
+                for &(start, end) in &merged {
+                    if start > next {
+                        gaps.push(format!("{}-{}", next, start - 1));
+                    }
+                    next = next.max(end + 1);
+                }
+                if next <= length {
+                    gaps.push(format!("{}-{}", next, length));
+                }
+            }
+            writer.serialize(Coverage {
+                accession,
+                length,
+                structures: ids.len(),
+                covered,
+                percent: length
+                    .filter(|length| *length > 0)
+                    .map(|length| (1000.0 * covered as f64 / length as f64).round() / 10.0),
+                gaps: gaps.join(";"),
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
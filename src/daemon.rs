@@ -0,0 +1,64 @@
+use crate::pipeline::Pipeline;
+use crate::update::UPDATE_FILE;
+use crate::{notify, shutdown};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+//Where the new files of every update are kept, `update.csv` only has the last ones
+pub(crate) const UPDATES_FOLDER: &str = "updates";
+
+impl Pipeline {
+    /// Run `update` at every time of `schedule` until Ctrl+C or SIGTERM.
+    ///
+    /// The files each update adds are logged, kept in `updates/<time>.csv` of the save path and
+    /// sent to the webhook. A failed update is logged and the next one still runs.
+    pub async fn daemon(&self) -> Result<()> {
+        let Some(schedule) = self.config().schedule.clone() else {
+            bail!("The daemon needs a schedule in the config, e.g. schedule = \"@weekly\"");
+        };
+        loop {
+            let now = chrono::Local::now();
+            let Some(next) = schedule.next_after(now) else {
+                bail!("The schedule \"{}\" never matches", schedule);
+            };
+            info!("Next update at {}", next.format("%Y-%m-%d %H:%M %Z"));
+            //Slept in steps, a suspended machine doesn't count the time it slept
+            while chrono::Local::now() < next {
+                let left = (next - chrono::Local::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .min(std::time::Duration::from_secs(60));
+                tokio::select! {
+                    _ = tokio::time::sleep(left) => {}
+                    _ = shutdown::signal() => {
+                        info!("Stopped the daemon. Exiting...");
+                        return Ok(());
+                    }
+                }
+            }
+            match self.update().await {
+                Ok(added) => {
+                    if let Err(e) = self.keep_update(&next) {
+                        warn!("Failed to keep the update report due to \"{}\"", e);
+                    }
+                    notify::update_finished(&self.ctx, &added).await;
+                }
+                Err(e) => error!("Failed to update due to \"{}\"", e),
+            }
+            if self.ctx.is_stopping() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn keep_update(&self, time: &chrono::DateTime<chrono::Local>) -> Result<()> {
+        let save_path = Path::new(&self.config().save_path);
+        let folder = save_path.join(UPDATES_FOLDER);
+        std::fs::create_dir_all(&folder)?;
+        std::fs::copy(
+            save_path.join(UPDATE_FILE),
+            folder.join(format!("{}.csv", time.format("%Y-%m-%d_%H%M"))),
+        )?;
+        Ok(())
+    }
+}
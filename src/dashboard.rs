@@ -0,0 +1,447 @@
+use crate::pipeline::{Context, RunEvent};
+use indicatif::HumanBytes;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::crossterm::{cursor, execute};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Gauge, List, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{stderr, stdout, Stderr, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+//Lines kept for the log pane
+const LOG_LINES: usize = 1000;
+const REDRAW: Duration = Duration::from_millis(500);
+//How often keys are read between redraws
+const KEYS: Duration = Duration::from_millis(50);
+const KEYS_HELP: &str = " ↑↓ PgUp PgDn scroll, End follow, q close, Ctrl+C stop ";
+
+//Lines logged to the console, drawn in the log pane instead while the dashboard is open
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+struct Transfer {
+    started: Instant,
+    bytes: u64,
+    //The same file may be fetched by two tasks at once
+    count: usize,
+}
+
+/// What the live view of `--tui` shows, fed as the run goes.
+#[derive(Default)]
+pub(crate) struct Dashboard {
+    running: Mutex<BTreeMap<String, Instant>>,
+    outcomes: Mutex<BTreeMap<String, u64>>,
+    transfers: Mutex<HashMap<String, Transfer>>,
+}
+
+impl Dashboard {
+    pub fn event(&self, event: &RunEvent) {
+        match event {
+            RunEvent::TargetStarted { chembl_id } => {
+                self.running
+                    .lock()
+                    .unwrap()
+                    .insert(chembl_id.clone(), Instant::now());
+            }
+            RunEvent::TargetFinished {
+                chembl_id, outcome, ..
+            } => {
+                self.running.lock().unwrap().remove(chembl_id);
+                *self
+                    .outcomes
+                    .lock()
+                    .unwrap()
+                    .entry(outcome.clone())
+                    .or_default() += 1;
+            }
+            RunEvent::FileDownloaded { .. } => {}
+        }
+    }
+
+    pub fn start_run(&self) {
+        self.running.lock().unwrap().clear();
+        self.outcomes.lock().unwrap().clear();
+    }
+
+    pub fn transfer_started(&self, url: &str) {
+        self.transfers
+            .lock()
+            .unwrap()
+            .entry(url.to_string())
+            .or_insert(Transfer {
+                started: Instant::now(),
+                bytes: 0,
+                count: 0,
+            })
+            .count += 1;
+    }
+
+    pub fn transfer_finished(&self, url: &str) {
+        let mut transfers = self.transfers.lock().unwrap();
+        if let Some(transfer) = transfers.get_mut(url) {
+            transfer.count -= 1;
+            if transfer.count == 0 {
+                transfers.remove(url);
+            }
+        }
+    }
+
+    pub fn bytes(&self, url: &str, bytes: u64) {
+        if let Some(transfer) = self.transfers.lock().unwrap().get_mut(url) {
+            transfer.bytes += bytes;
+        }
+    }
+}
+
+/// The dashboard drawn for a run, closed if it's dropped before the run finishes, e.g. when a
+/// panicking task ends the run early, so that the terminal is never left in the alternate screen.
+pub(crate) struct Drawing(JoinHandle<()>);
+
+impl Drawing {
+    pub fn spawn(ctx: Arc<Context>) -> Self {
+        Drawing(tokio::task::spawn(async move { draw(&ctx).await }))
+    }
+
+    /// Wait for the dashboard to close, once the progress of the run is finished.
+    pub async fn finish(mut self) {
+        let _ = (&mut self.0).await;
+    }
+}
+
+impl Drop for Drawing {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+//The terminal taken by the dashboard, given back when dropped however the drawing ends
+struct Screen {
+    terminal: Terminal<CrosstermBackend<Stderr>>,
+    _restore: Restore,
+}
+
+impl Screen {
+    fn open() -> std::io::Result<Self> {
+        //Made first, so that what's been set up is undone if the rest fails
+        let restore = Restore;
+        enable_raw_mode()?;
+        execute!(stderr(), EnterAlternateScreen, cursor::Hide)?;
+        OPEN.store(true, Ordering::SeqCst);
+        Ok(Screen {
+            terminal: Terminal::new(CrosstermBackend::new(stderr()))?,
+            _restore: restore,
+        })
+    }
+}
+
+//Leaves the alternate screen and sends the console output of the log back to stdout, with the
+//lines it got while the dashboard was open, which the alternate screen took with it
+struct Restore;
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(stderr(), LeaveAlternateScreen, cursor::Show);
+        let mut log = LOG.lock().unwrap();
+        OPEN.store(false, Ordering::SeqCst);
+        let mut out = stdout().lock();
+        for line in log.drain(..) {
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+}
+
+#[derive(Default)]
+struct View {
+    //Lines the log pane is scrolled up by, following the log at 0
+    scroll: usize,
+    closed: bool,
+}
+
+/// Draw the dashboard of `ctx` on the alternate screen of stderr until the run finishes or it's
+/// closed with `q`. The console output of the log comes back to stdout once it's closed.
+async fn draw(ctx: &Context) {
+    let Some(dashboard) = ctx.progress.dashboard() else {
+        return;
+    };
+    let mut screen = match Screen::open() {
+        Ok(screen) => screen,
+        Err(e) => {
+            warn!("Failed to open the dashboard due to \"{}\"", e);
+            return;
+        }
+    };
+    let started = Instant::now();
+    let mut last = (Instant::now(), 0);
+    let mut rate = 0.0;
+    let mut view = View::default();
+    let mut redraw = Instant::now();
+    while !ctx.progress.is_finished() {
+        if redraw <= Instant::now() {
+            let bytes = ctx.metrics.downloaded_bytes();
+            let since = last.0.elapsed().as_secs_f64();
+            if since > 0.0 {
+                rate = (bytes - last.1) as f64 / since;
+            }
+            last = (Instant::now(), bytes);
+            redraw = Instant::now() + REDRAW;
+            if let Err(e) = screen
+                .terminal
+                .draw(|frame| render(frame, ctx, dashboard, &view, started, rate))
+            {
+                warn!("Failed to draw the dashboard due to \"{}\"", e);
+                break;
+            }
+        }
+        match keys(ctx, &mut view) {
+            Ok(true) => redraw = Instant::now(),
+            Ok(false) => {}
+            Err(_) => break,
+        }
+        if view.closed {
+            break;
+        }
+        tokio::time::sleep(KEYS).await;
+    }
+    drop(screen);
+    //The last state stays on the screen once the run is over
+    let mut err = stderr().lock();
+    for line in summary(ctx, dashboard, started, 0.0) {
+        let _ = writeln!(err, "{}", line);
+    }
+}
+
+//Handle the keys pressed since the last call, true if the view changed
+fn keys(ctx: &Context, view: &mut View) -> std::io::Result<bool> {
+    let mut changed = false;
+    while event::poll(Duration::ZERO)? {
+        let Event::Key(key) = event::read()? else {
+            changed = true;
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let lines = LOG.lock().unwrap().len();
+        match key.code {
+            //Raw mode keeps Ctrl+C from being a signal
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                if !ctx.is_stopping() {
+                    warn!("Interrupted, waiting for running tasks before exiting...");
+                    ctx.stop();
+                }
+            }
+            KeyCode::Char('q') | KeyCode::Esc => {
+                view.closed = true;
+                return Ok(true);
+            }
+            KeyCode::Up => view.scroll = (view.scroll + 1).min(lines),
+            KeyCode::Down => view.scroll = view.scroll.saturating_sub(1),
+            KeyCode::PageUp => view.scroll = (view.scroll + 10).min(lines),
+            KeyCode::PageDown => view.scroll = view.scroll.saturating_sub(10),
+            KeyCode::End => view.scroll = 0,
+            _ => continue,
+        }
+        changed = true;
+    }
+    Ok(changed)
+}
+
+fn summary(ctx: &Context, dashboard: &Dashboard, started: Instant, rate: f64) -> [String; 3] {
+    let metrics = &ctx.metrics;
+    let outcomes = dashboard.outcomes.lock().unwrap();
+    let outcome = |name: &str| outcomes.get(name).copied().unwrap_or_default();
+    [
+        format!(
+            "prog_med {}  {}",
+            ctx.config.save_path,
+            clock(started.elapsed())
+        ),
+        format!(
+            "Targets {}/{}, {} running, {} complete, {} incomplete, {} failed",
+            metrics.processed(),
+            metrics.targets(),
+            dashboard.running.lock().unwrap().len(),
+            outcome("complete"),
+            outcome("incomplete"),
+            outcome("failed")
+        ),
+        format!(
+            "Downloaded {} at {}/s, {} downloads, {} retries, {} failed requests",
+            HumanBytes(metrics.downloaded_bytes()),
+            HumanBytes(rate as u64),
+            dashboard.transfers.lock().unwrap().len(),
+            metrics.retries(),
+            metrics.failed_requests()
+        ),
+    ]
+}
+
+fn render(
+    frame: &mut Frame,
+    ctx: &Context,
+    dashboard: &Dashboard,
+    view: &View,
+    started: Instant,
+    rate: f64,
+) {
+    //The log gets what the lists leave of the screen, each list up to a quarter of it
+    let listed = frame.area().height.saturating_sub(9) / 4 + 2;
+    let [header, gauge, lists, log] = Layout::vertical([
+        Constraint::Length(4),
+        Constraint::Length(1),
+        Constraint::Length(listed),
+        Constraint::Min(3),
+    ])
+    .areas(frame.area());
+    let [title, targets, downloads] = summary(ctx, dashboard, started, rate);
+    let failed = |line: String| {
+        //The counts of failures are shown in red
+        match line.rfind(", ") {
+            Some(at) => Line::from(vec![
+                Span::raw(line[..at + 2].to_string()),
+                Span::raw(line[at + 2..].to_string()).red(),
+            ]),
+            None => Line::from(line),
+        }
+    };
+    frame.render_widget(
+        Paragraph::new(vec![failed(targets), failed(downloads)])
+            .block(Block::bordered().title(title.bold())),
+        header,
+    );
+    let metrics = &ctx.metrics;
+    let ratio = match metrics.targets() {
+        0 => 0.0,
+        total => (metrics.processed() as f64 / total as f64).min(1.0),
+    };
+    frame.render_widget(
+        Gauge::default()
+            .gauge_style(Style::new().cyan())
+            .ratio(ratio)
+            .label(format!("{}/{}", metrics.processed(), metrics.targets())),
+        gauge,
+    );
+
+    let [running, transfers] =
+        Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)]).areas(lists);
+    let mut targets = dashboard
+        .running
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(chembl_id, started)| (chembl_id.clone(), *started))
+        .collect::<Vec<_>>();
+    targets.sort_by_key(|(_, started)| *started);
+    let count = targets.len();
+    frame.render_widget(
+        List::new(
+            targets.into_iter().map(|(chembl_id, started)| {
+                format!("{:<16} {}", chembl_id, clock(started.elapsed()))
+            }),
+        )
+        .block(Block::bordered().title(format!(" Running targets ({}) ", count).cyan().bold())),
+        running,
+    );
+    render_transfers(frame, dashboard, transfers);
+    render_log(frame, view, log);
+}
+
+fn render_transfers(frame: &mut Frame, dashboard: &Dashboard, area: Rect) {
+    let transfers = dashboard.transfers.lock().unwrap();
+    let mut files = transfers.iter().collect::<Vec<_>>();
+    files.sort_by_key(|(_, transfer)| transfer.started);
+    let rows = files.iter().map(|(url, transfer)| {
+        let name = url.rsplit('/').next().unwrap_or(url);
+        let secs = transfer.started.elapsed().as_secs_f64().max(0.001);
+        Row::new(vec![
+            name.to_string(),
+            HumanBytes(transfer.bytes).to_string(),
+            format!("{}/s", HumanBytes((transfer.bytes as f64 / secs) as u64)),
+        ])
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Min(16),
+                Constraint::Length(12),
+                Constraint::Length(14),
+            ],
+        )
+        .block(
+            Block::bordered().title(
+                format!(" Active downloads ({}) ", transfers.len())
+                    .cyan()
+                    .bold(),
+            ),
+        ),
+        area,
+    );
+}
+
+fn render_log(frame: &mut Frame, view: &View, area: Rect) {
+    let log = LOG.lock().unwrap();
+    let fit = area.height.saturating_sub(2) as usize;
+    let end = log.len().saturating_sub(view.scroll.min(log.len()));
+    let lines = log
+        .iter()
+        .take(end)
+        .skip(end.saturating_sub(fit))
+        .map(|line| Line::from(line.as_str()))
+        .collect::<Vec<_>>();
+    let title = match view.scroll {
+        0 => " Log ".to_string(),
+        scroll => format!(" Log, {} lines up ", scroll),
+    };
+    frame.render_widget(
+        Paragraph::new(lines).block(
+            Block::bordered()
+                .title(title.cyan().bold())
+                .title_bottom(KEYS_HELP),
+        ),
+        area,
+    );
+}
+
+fn clock(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Where console output of the log goes with `tui`: the log pane while the dashboard is open, as
+/// it would tear it, and stdout before and after.
+pub(crate) struct LogPane;
+
+impl Write for LogPane {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut log = LOG.lock().unwrap();
+        if !OPEN.load(Ordering::SeqCst) {
+            drop(log);
+            return stdout().write(buf);
+        }
+        log.extend(String::from_utf8_lossy(buf).lines().map(str::to_string));
+        while log.len() > LOG_LINES {
+            log.pop_front();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if OPEN.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        stdout().flush()
+    }
+}
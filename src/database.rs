@@ -0,0 +1,392 @@
+use crate::state::Event;
+use crate::summary::RunSummary;
+use anyhow::Result;
+use std::path::Path;
+
+pub(crate) const DATABASE_FILE: &str = "state.sqlite";
+
+/// Fail unless the build can keep the SQLite database.
+pub(crate) fn check() -> Result<()> {
+    if cfg!(not(feature = "sqlite")) {
+        anyhow::bail!("sqlite needs a build with the \"sqlite\" feature");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+CREATE TABLE IF NOT EXISTS targets (
+    chembl_id TEXT PRIMARY KEY,
+    target_name TEXT NOT NULL DEFAULT '',
+    done INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS accessions (
+    chembl_id TEXT NOT NULL REFERENCES targets (chembl_id),
+    accession TEXT NOT NULL,
+    mapped_from TEXT,
+    PRIMARY KEY (chembl_id, accession)
+);
+CREATE TABLE IF NOT EXISTS pdb_entries (
+    chembl_id TEXT NOT NULL,
+    accession TEXT NOT NULL,
+    pdb_id TEXT NOT NULL,
+    superseded_by TEXT,
+    done INTEGER NOT NULL DEFAULT 0,
+    skipped TEXT,
+    PRIMARY KEY (chembl_id, accession, pdb_id),
+    FOREIGN KEY (chembl_id, accession) REFERENCES accessions (chembl_id, accession)
+);
+CREATE TABLE IF NOT EXISTS files (
+    path TEXT PRIMARY KEY,
+    chembl_id TEXT NOT NULL,
+    accession TEXT NOT NULL,
+    pdb_id TEXT,
+    predicted_by TEXT,
+    format TEXT,
+    archive TEXT,
+    size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    compression TEXT,
+    source_url TEXT NOT NULL,
+    etag TEXT,
+    last_modified TEXT,
+    downloaded_at TEXT NOT NULL,
+    FOREIGN KEY (chembl_id, accession) REFERENCES accessions (chembl_id, accession)
+);
+CREATE INDEX IF NOT EXISTS files_by_pdb ON files (pdb_id);
+CREATE TABLE IF NOT EXISTS jobs (
+    id TEXT PRIMARY KEY,
+    status TEXT NOT NULL,
+    job TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started_at TEXT NOT NULL,
+    finished_at TEXT NOT NULL,
+    elapsed_secs REAL NOT NULL,
+    interrupted INTEGER NOT NULL,
+    targets_processed INTEGER NOT NULL,
+    failures INTEGER NOT NULL,
+    failed_targets INTEGER NOT NULL
+);
+";
+
+/// Normalized copy of the journal at `save_path/state.sqlite`, with a row for each run.
+#[cfg(feature = "sqlite")]
+pub(crate) struct Database {
+    connection: std::sync::Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Database {
+    pub fn open(save_path: &Path) -> Result<Self> {
+        let connection = rusqlite::Connection::open(save_path.join(DATABASE_FILE))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Database {
+            connection: std::sync::Mutex::new(connection),
+        })
+    }
+
+    /// Apply `event` as the journal does, in one transaction.
+    pub fn apply(&self, event: &Event) -> Result<()> {
+        use rusqlite::params;
+
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        match event {
+            Event::Target { chembl_id } => {
+                transaction.execute(
+                    "INSERT INTO targets (chembl_id, done) VALUES (?1, 1)
+                     ON CONFLICT (chembl_id) DO UPDATE SET done = 1",
+                    params![chembl_id],
+                )?;
+            }
+            Event::Pdb {
+                chembl_id,
+                accession,
+                pdb_id,
+            } => {
+                transaction.execute(
+                    "INSERT OR IGNORE INTO targets (chembl_id) VALUES (?1)",
+                    params![chembl_id],
+                )?;
+                transaction.execute(
+                    "INSERT OR IGNORE INTO accessions (chembl_id, accession) VALUES (?1, ?2)",
+                    params![chembl_id, accession],
+                )?;
+                transaction.execute(
+                    "INSERT INTO pdb_entries (chembl_id, accession, pdb_id, done)
+                     VALUES (?1, ?2, ?3, 1)
+                     ON CONFLICT (chembl_id, accession, pdb_id) DO UPDATE SET done = 1",
+                    params![chembl_id, accession, pdb_id],
+                )?;
+            }
+            Event::File(record) | Event::Skipped(record) => {
+                transaction.execute(
+                    "INSERT INTO targets (chembl_id, target_name) VALUES (?1, ?2)
+                     ON CONFLICT (chembl_id) DO UPDATE SET target_name = excluded.target_name",
+                    params![record.chembl_id, record.target_name],
+                )?;
+                transaction.execute(
+                    "INSERT INTO accessions (chembl_id, accession, mapped_from) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (chembl_id, accession)
+                     DO UPDATE SET mapped_from = excluded.mapped_from",
+                    params![record.chembl_id, record.accession, record.mapped_from],
+                )?;
+                //A file clears the reason its entry was skipped
+                if let Some(pdb_id) = &record.pdb_id {
+                    transaction.execute(
+                        "INSERT INTO pdb_entries (chembl_id, accession, pdb_id, superseded_by, skipped)
+                         VALUES (?1, ?2, ?3, ?4, ?5)
+                         ON CONFLICT (chembl_id, accession, pdb_id)
+                         DO UPDATE SET superseded_by = excluded.superseded_by,
+                                       skipped = excluded.skipped",
+                        params![
+                            record.chembl_id,
+                            record.accession,
+                            pdb_id,
+                            record.superseded_by,
+                            record.skipped
+                        ],
+                    )?;
+                }
+                if matches!(event, Event::File(_)) {
+                    let compression = record.compression.map(serde_json::to_value).transpose()?;
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO files (path, chembl_id, accession, pdb_id,
+                         predicted_by, format, archive, size, sha256, compression, source_url,
+                         etag, last_modified, downloaded_at)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+                        params![
+                            record.path,
+                            record.chembl_id,
+                            record.accession,
+                            record.pdb_id,
+                            record.predicted_by,
+                            record.format,
+                            record.archive,
+                            record.size,
+                            record.sha256,
+                            compression.as_ref().and_then(|value| value.as_str()),
+                            record.source_url,
+                            record.etag,
+                            record.last_modified,
+                            record.downloaded_at
+                        ],
+                    )?;
+                }
+            }
+            Event::Redo {
+                chembl_id,
+                accession,
+                pdb_id,
+                path,
+            } => {
+                transaction.execute(
+                    "UPDATE targets SET done = 0 WHERE chembl_id = ?1",
+                    params![chembl_id],
+                )?;
+                if let Some(pdb_id) = pdb_id {
+                    transaction.execute(
+                        "UPDATE pdb_entries SET done = 0
+                         WHERE chembl_id = ?1 AND accession = ?2 AND pdb_id = ?3",
+                        params![chembl_id, accession, pdb_id],
+                    )?;
+                }
+                transaction.execute("DELETE FROM files WHERE path = ?1", params![path])?;
+            }
+            Event::Moved { from, record } => {
+                transaction.execute(
+                    "UPDATE files SET path = ?2, archive = ?3 WHERE path = ?1",
+                    params![from, record.path, record.archive],
+                )?;
+            }
+            Event::Job(job) => {
+                transaction.execute(
+                    "INSERT INTO jobs (id, status, job) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (id) DO UPDATE SET status = ?2, job = ?3",
+                    params![
+                        job.id,
+                        serde_json::to_value(job.status)?.as_str(),
+                        serde_json::to_string(job)?
+                    ],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Events rebuilding the state held, files first.
+    pub fn events(&self) -> Result<Vec<Event>> {
+        use crate::manifest::ManifestEntry;
+
+        let connection = self.connection.lock().unwrap();
+        let mut events = Vec::new();
+        let mut files = connection.prepare(
+            "SELECT f.chembl_id, t.target_name, f.accession, a.mapped_from, f.pdb_id,
+                    p.superseded_by, f.predicted_by, f.format, f.path, f.archive, f.size,
+                    f.sha256, f.compression, f.source_url, f.etag, f.last_modified,
+                    f.downloaded_at
+             FROM files f
+             JOIN targets t ON t.chembl_id = f.chembl_id
+             JOIN accessions a ON a.chembl_id = f.chembl_id AND a.accession = f.accession
+             LEFT JOIN pdb_entries p
+                 ON p.chembl_id = f.chembl_id AND p.accession = f.accession AND p.pdb_id = f.pdb_id",
+        )?;
+        let mut rows = files.query([])?;
+        while let Some(row) = rows.next()? {
+            let compression: Option<String> = row.get(12)?;
+            events.push(Event::File(ManifestEntry {
+                chembl_id: row.get(0)?,
+                target_name: row.get(1)?,
+                accession: row.get(2)?,
+                mapped_from: row.get(3)?,
+                pdb_id: row.get(4)?,
+                superseded_by: row.get(5)?,
+                predicted_by: row.get(6)?,
+                format: row.get(7)?,
+                path: row.get(8)?,
+                archive: row.get(9)?,
+                size: row.get(10)?,
+                sha256: row.get(11)?,
+                compression: compression
+                    .map(|compression| serde_json::from_value(compression.into()))
+                    .transpose()?,
+                source_url: row.get(13)?,
+                etag: row.get(14)?,
+                last_modified: row.get(15)?,
+                downloaded_at: row.get(16)?,
+                skipped: None,
+            }));
+        }
+
+        let mut skipped = connection.prepare(
+            "SELECT p.chembl_id, t.target_name, p.accession, a.mapped_from, p.pdb_id, p.skipped
+             FROM pdb_entries p
+             JOIN targets t ON t.chembl_id = p.chembl_id
+             JOIN accessions a ON a.chembl_id = p.chembl_id AND a.accession = p.accession
+             WHERE p.skipped IS NOT NULL",
+        )?;
+        let mut rows = skipped.query([])?;
+        while let Some(row) = rows.next()? {
+            events.push(Event::Skipped(ManifestEntry {
+                chembl_id: row.get(0)?,
+                target_name: row.get(1)?,
+                accession: row.get(2)?,
+                mapped_from: row.get(3)?,
+                pdb_id: row.get(4)?,
+                superseded_by: None,
+                predicted_by: None,
+                format: None,
+                path: String::new(),
+                archive: None,
+                size: 0,
+                sha256: String::new(),
+                compression: None,
+                source_url: String::new(),
+                etag: None,
+                last_modified: None,
+                downloaded_at: String::new(),
+                skipped: row.get(5)?,
+            }));
+        }
+
+        let mut pdbs = connection
+            .prepare("SELECT chembl_id, accession, pdb_id FROM pdb_entries WHERE done = 1")?;
+        let mut rows = pdbs.query([])?;
+        while let Some(row) = rows.next()? {
+            events.push(Event::Pdb {
+                chembl_id: row.get(0)?,
+                accession: row.get(1)?,
+                pdb_id: row.get(2)?,
+            });
+        }
+
+        let mut targets = connection.prepare("SELECT chembl_id FROM targets WHERE done = 1")?;
+        let mut rows = targets.query([])?;
+        while let Some(row) = rows.next()? {
+            events.push(Event::Target {
+                chembl_id: row.get(0)?,
+            });
+        }
+
+        let mut jobs = connection.prepare("SELECT job FROM jobs")?;
+        let mut rows = jobs.query([])?;
+        while let Some(row) = rows.next()? {
+            let job: String = row.get(0)?;
+            events.push(Event::Job(serde_json::from_str(&job)?));
+        }
+        Ok(events)
+    }
+
+    /// Forget what was done and skipped, keeping the files.
+    pub fn start_over(&self) -> Result<()> {
+        self.connection.lock().unwrap().execute_batch(
+            "UPDATE targets SET done = 0;
+             UPDATE pdb_entries SET done = 0, skipped = NULL;",
+        )?;
+        Ok(())
+    }
+
+    pub fn record_run(&self, summary: &RunSummary) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO runs (started_at, finished_at, elapsed_secs, interrupted,
+             targets_processed, failures, failed_targets)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![
+                summary.started_at,
+                summary.finished_at,
+                summary.elapsed_secs,
+                summary.interrupted,
+                summary.targets_processed,
+                summary.failures.values().sum::<usize>(),
+                summary.failed_targets.len()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Fold the write-ahead log into the database file, so it can be copied alone.
+    pub fn sync(&self) -> Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        Ok(())
+    }
+}
+
+//Never built, check() stops the pipeline first
+#[cfg(not(feature = "sqlite"))]
+pub(crate) enum Database {}
+
+#[cfg(not(feature = "sqlite"))]
+impl Database {
+    pub fn open(_save_path: &Path) -> Result<Self> {
+        check()?;
+        unreachable!()
+    }
+
+    pub fn apply(&self, _event: &Event) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn events(&self) -> Result<Vec<Event>> {
+        match *self {}
+    }
+
+    pub fn start_over(&self) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn record_run(&self, _summary: &RunSummary) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        match *self {}
+    }
+}
@@ -0,0 +1,95 @@
+use crate::pipeline::Context;
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Whether downloads are paused for lack of free space on the save path.
+pub(crate) struct DiskMonitor {
+    paused: watch::Sender<bool>,
+    //The estimate is warned about once a run
+    warned: AtomicBool,
+}
+
+impl Default for DiskMonitor {
+    fn default() -> Self {
+        DiskMonitor {
+            paused: watch::Sender::new(false),
+            warned: AtomicBool::new(false),
+        }
+    }
+}
+
+impl DiskMonitor {
+    /// Wait until downloads aren't paused, failing if the run stops in the meantime.
+    pub async fn wait(&self, ctx: &Context) -> Result<()> {
+        let mut paused = self.paused.subscribe();
+        tokio::select! {
+            _ = paused.wait_for(|paused| !*paused) => Ok(()),
+            _ = ctx.stopped() => Err(anyhow!("stopped while downloads were paused for disk space")),
+        }
+    }
+}
+
+/// Bytes available to unprivileged users on the file system of `path`.
+#[cfg(unix)]
+pub(crate) fn available(path: &Path) -> Option<u64> {
+    let stat = rustix::fs::statvfs(path).ok()?;
+    Some(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn available(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Compare the free space on the save path with `min_free_mb` and the estimate of what the
+/// targets left need, pausing or resuming downloads.
+pub(crate) fn check(ctx: &Context) {
+    let save_path = Path::new(&ctx.config.save_path);
+    let Some(available) = available(save_path) else {
+        debug!(target:"debug","Not checking the free space of {}", save_path.display());
+        return;
+    };
+    let min_free = ctx.config.disk_space.min_free_mb.saturating_mul(MIB);
+    if let Some(needed) = ctx.metrics.remaining_bytes() {
+        if needed.saturating_add(min_free) > available
+            && !ctx.disk.warned.swap(true, Ordering::Relaxed)
+        {
+            warn!(
+                "The targets left need about {} MiB at the size of those done, {} MiB are free on {}",
+                needed / MIB,
+                available / MIB,
+                save_path.display()
+            );
+        }
+    }
+    let low = available < min_free;
+    match (ctx.disk.paused.send_replace(low), low) {
+        (false, true) => warn!(
+            "Pausing downloads, {} MiB are free on {}",
+            available / MIB,
+            save_path.display()
+        ),
+        (true, false) => info!(
+            "Resuming downloads, {} MiB are free on {}",
+            available / MIB,
+            save_path.display()
+        ),
+        _ => {}
+    }
+}
+
+pub(crate) async fn keep_checking(ctx: &Context) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(ctx.config.disk_space.interval_secs));
+    //The first tick is immediate and the run already checked
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        check(ctx);
+    }
+}
@@ -0,0 +1,728 @@
+use crate::cache;
+use crate::checksum;
+use crate::compress;
+use crate::config::{LinkMode, Source, UserConfig};
+use crate::http::HttpError;
+use crate::layout::Layout;
+use crate::logging;
+use crate::pipeline::Context;
+use crate::structure;
+use anyhow::{anyhow, Result};
+use flate2::bufread::MultiGzDecoder;
+use futures_util::StreamExt;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, ETAG, LAST_MODIFIED};
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::io::{BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::task;
+
+pub(crate) fn format(url: &str, formatter: &str) -> Result<String, std::fmt::Error> {
+    if let Some(url) = url.split_once('%') {
+        Ok(format!("{}{}{}", url.0, formatter, url.1))
+    } else {
+        Err(std::fmt::Error)
+    }
+}
+
+/// Url of `pdb_id` on a mirror and the file it is saved to in `save_path`, named by `layout`.
+pub(crate) fn mirror_file(
+    layout: &Layout,
+    source: &Source,
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<(Url, PathBuf)> {
+    let url: Url = format(&source.template, pdb_id)?.parse()?;
+    debug!(target:"debug","Formatted url : {}", url);
+    let save_filepath = save_path.join({
+        if let Some(file_name) = Path::new(url.path()).file_name() {
+            layout.file_name(
+                pdb_id,
+                source.format.as_deref(),
+                &file_name.to_string_lossy(),
+            )
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Check your config urls",
+            )
+            .into());
+        }
+    });
+    Ok((url, save_filepath))
+}
+
+/// A file written by a download.
+#[derive(Debug, Clone)]
+pub struct Downloaded {
+    pub url: Url,
+    /// Structure format, if the file is a structure
+    pub format: Option<String>,
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    /// Validators of the response, to ask for the file again only if it changed
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+//Using config.sources() as ranked by the mirror probe, returns None if the file is already there
+#[tracing::instrument(skip(ctx, save_path))]
+pub(crate) async fn download_pdb(
+    ctx: &Context,
+    pdb_id: String,
+    save_path: PathBuf,
+) -> Result<Option<Downloaded>> {
+    let mut last_error = None;
+    let mut paused = false;
+    let pinned = ctx
+        .config
+        .mirror_probe
+        .as_ref()
+        .map(|probe| probe.pinned.as_slice())
+        .unwrap_or_default();
+    for source in ctx.mirrors.order(ctx.config.sources(), pinned) {
+        let (url, save_filepath) = mirror_file(&ctx.layout, &source, &pdb_id, &save_path)?;
+        if ctx
+            .storage
+            .exists(&stored_path(&ctx.config, &save_filepath))
+            .await?
+        {
+            let downloaded = match revalidate(ctx, &url, &save_filepath).await {
+                Ok(Some(downloaded)) => downloaded,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    warn!("Failed to revalidate {} due to \"{}\"", pdb_id, e);
+                    return Ok(None);
+                }
+            };
+            let downloaded = Downloaded {
+                format: source.format,
+                ..downloaded
+            };
+            if ctx.config.validate_structures {
+                validate(ctx, &save_filepath, &downloaded).await?;
+            }
+            return Ok(Some(downloaded));
+        }
+        if !ctx.breaker.allows(&source.template) {
+            debug!(target:"debug","Skipping paused mirror {} for {}", source.template, pdb_id);
+            paused = true;
+            continue;
+        }
+
+        //Fall back to the next mirror once retries are used up
+        let downloaded = match fetch(ctx, &url, &save_filepath).await {
+            Ok(downloaded) => {
+                ctx.breaker.success(&source.template);
+                Downloaded {
+                    format: source.format,
+                    ..downloaded
+                }
+            }
+            Err(e) => {
+                warn!("Failed to download {} due to \"{}\"", pdb_id, e);
+                ctx.breaker.failure(&source.template, &e);
+                last_error = Some(e);
+                continue;
+            }
+        };
+        if !ctx.config.validate_structures {
+            return Ok(Some(downloaded));
+        }
+        //Truncated files and error pages are thrown away like failed downloads
+        match validate(ctx, &save_filepath, &downloaded).await {
+            Ok(()) => return Ok(Some(downloaded)),
+            Err(e) => {
+                logging::invalid_file(&pdb_id, url.as_str(), &e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e),
+        None if paused => Err(anyhow!("every mirror of {} is paused", pdb_id)),
+        None => Ok(None),
+    }
+}
+
+//In an update revalidating files, download `url` again if it changed since it was recorded
+async fn revalidate(ctx: &Context, url: &Url, save_filepath: &Path) -> Result<Option<Downloaded>> {
+    //Shared copies belong to every target linking them, local mirrors have no validators
+    if !ctx.is_revalidating() || ctx.config.link_mode != LinkMode::None || url.scheme() == "file" {
+        return Ok(None);
+    }
+    let stored = stored_path(&ctx.config, save_filepath);
+    let mut recorded = vec![stored.clone()];
+    if let Some(compression) = ctx.config.compress_output {
+        recorded.extend(compress::compressed_path(&stored, compression));
+    }
+    let Some(record) = recorded.iter().find_map(|path| {
+        ctx.state.file(
+            &path
+                .strip_prefix(&ctx.config.save_path)
+                .unwrap_or(path)
+                .to_string_lossy(),
+        )
+    }) else {
+        return Ok(None);
+    };
+    let validators = Validators {
+        etag: record.etag,
+        last_modified: record.last_modified,
+    };
+    if validators.etag.is_none() && validators.last_modified.is_none() {
+        return Ok(None);
+    }
+    let downloaded = download_changed(ctx, url, save_filepath, &validators).await?;
+    if downloaded.is_none() {
+        debug!(target:"debug","Unchanged : {}", stored.display());
+    }
+    Ok(downloaded)
+}
+
+//Parse the coordinates of `downloaded`, removing them if they can't be parsed
+async fn validate(ctx: &Context, save_filepath: &Path, downloaded: &Downloaded) -> Result<()> {
+    let (path, format) = (downloaded.path.clone(), downloaded.format.clone());
+    let parsed =
+        task::spawn_blocking(move || structure::read(&path, format.as_deref()).map(|_| ())).await?;
+    if parsed.is_err() {
+        std::fs::remove_file(&downloaded.path)?;
+        if ctx.config.link_mode != LinkMode::None {
+            cache::evict(ctx, save_filepath)?;
+        }
+    }
+    parsed
+}
+
+/// Download `url` into `save_filepath`, through the shared cache when `link_mode` asks for it.
+pub(crate) async fn fetch(ctx: &Context, url: &Url, save_filepath: &Path) -> Result<Downloaded> {
+    match ctx.config.link_mode {
+        LinkMode::None => download_file(ctx, url, save_filepath).await,
+        _ => cache::fetch_linked(ctx, url, save_filepath).await,
+    }
+}
+
+/// Where a download saved as `save_filepath` ends up.
+pub(crate) fn stored_path(config: &UserConfig, save_filepath: &Path) -> PathBuf {
+    if config.decompress && save_filepath.extension().is_some_and(|ext| ext == "gz") {
+        save_filepath.with_extension("")
+    } else {
+        save_filepath.to_path_buf()
+    }
+}
+
+/// Path a download is written to until it is complete.
+pub(crate) fn part_path(save_filepath: &Path) -> PathBuf {
+    let mut part = save_filepath.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+//Version of the content a `.part` was started from, kept next to it as `.validator.part`, so
+//that it's only continued with the same bytes and removed with stale parts
+fn validator_path(part: &Path) -> PathBuf {
+    part.with_extension("validator.part")
+}
+
+/// ETag and Last-Modified of a response, sent back to learn whether the content changed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    //What If-Range takes, a strong ETag or else the Last-Modified date
+    fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+}
+
+/// Stream `url` into `save_filepath` chunk by chunk, hashing it on the way.
+///
+/// Data goes to a `.part` file renamed once complete, so `save_filepath` never holds a
+/// partial download. A `.part` left by an interrupted run is continued where the server can
+/// tell the file didn't change since, restarted otherwise. Files as large as
+/// `segmented_download` asks for are fetched as several ranges at once instead.
+pub(crate) async fn download_file(
+    ctx: &Context,
+    url: &Url,
+    save_filepath: &Path,
+) -> Result<Downloaded> {
+    download(ctx, url, save_filepath, None).await
+}
+
+/// Download `url` into `save_filepath` as [`download_file`] does, unless the server answers
+/// that it didn't change since the response `validators` came from.
+pub(crate) async fn download_changed(
+    ctx: &Context,
+    url: &Url,
+    save_filepath: &Path,
+    validators: &Validators,
+) -> Result<Option<Downloaded>> {
+    match download(ctx, url, save_filepath, Some(validators)).await {
+        Err(e)
+            if matches!(
+                e.downcast_ref::<HttpError>(),
+                Some(HttpError::Status {
+                    status: StatusCode::NOT_MODIFIED,
+                    ..
+                })
+            ) =>
+        {
+            Ok(None)
+        }
+        result => result.map(Some),
+    }
+}
+
+#[tracing::instrument(skip_all, fields(url = %url))]
+async fn download(
+    ctx: &Context,
+    url: &Url,
+    save_filepath: &Path,
+    validators: Option<&Validators>,
+) -> Result<Downloaded> {
+    ctx.disk.wait(ctx).await?;
+    let part = part_path(save_filepath);
+    let _active = ctx.progress.download(url);
+    let _in_flight = ctx.metrics.download();
+    let _part = PartGuard { ctx, part: &part };
+    //Conditional requests are single streams from the start
+    let segmented = match validators {
+        _ if url.scheme() == "file" => None,
+        Some(_) => None,
+        None => segmented_length(ctx, url).await,
+    };
+    let (size, sha256, validators) = match segmented {
+        _ if url.scheme() == "file" => {
+            let (size, sha256) = copy_local(ctx, url, &part).await?;
+            (size, sha256, Validators::default())
+        }
+        Some((length, segments, validators)) => {
+            //Written out of order, such a part is never continued as a stream
+            remove_validator(&part).await?;
+            let (size, sha256) = download_segments(ctx, url, &part, length, segments).await?;
+            (size, sha256, validators)
+        }
+        None => download_stream(ctx, url, &part, validators).await?,
+    };
+    tokio::fs::rename(&part, save_filepath).await?;
+
+    let stored_filepath = stored_path(&ctx.config, save_filepath);
+    if stored_filepath == save_filepath {
+        return Ok(Downloaded {
+            url: url.clone(),
+            format: None,
+            path: stored_filepath,
+            size,
+            sha256,
+            etag: validators.etag,
+            last_modified: validators.last_modified,
+        });
+    }
+    let (from, to) = (save_filepath.to_path_buf(), stored_filepath.clone());
+    let url = url.clone();
+    let span = tracing::Span::current();
+    task::spawn_blocking(move || {
+        let _span = span.enter();
+        decompress(&from, &to)?;
+        let (size, sha256) = checksum::hash_file(&to)?;
+        Ok(Downloaded {
+            url,
+            format: None,
+            path: to,
+            size,
+            sha256,
+            etag: validators.etag,
+            last_modified: validators.last_modified,
+        })
+    })
+    .await?
+}
+
+//A `file://` mirror, copied without the network
+async fn copy_local(ctx: &Context, url: &Url, part: &Path) -> Result<(u64, String)> {
+    let path = url
+        .to_file_path()
+        .map_err(|_| anyhow!("{} isn't a local path", url))?;
+    tokio::fs::copy(&path, part).await?;
+    let part = part.to_path_buf();
+    let (size, sha256) = task::spawn_blocking(move || checksum::hash_file(&part)).await??;
+    ctx.progress.bytes(url, size);
+    Ok((size, sha256))
+}
+
+//Data of `url` into `part`, continuing it if the server allows, unless it didn't change since
+//the response of `validators`
+async fn download_stream(
+    ctx: &Context,
+    url: &Url,
+    part: &Path,
+    validators: Option<&Validators>,
+) -> Result<(u64, String, Validators)> {
+    ctx.http
+        .with_retry(url, || async {
+            let offset = match tokio::fs::metadata(part).await {
+                Ok(metadata) if validators.is_none() => metadata.len(),
+                _ => 0,
+            };
+            //A part of a version that wasn't recorded may not be the start of the file now
+            let if_range = match offset {
+                0 => None,
+                _ => tokio::fs::read_to_string(validator_path(part))
+                    .await
+                    .ok()
+                    .filter(|if_range| !if_range.is_empty()),
+            };
+            let offset = if if_range.is_some() { offset } else { 0 };
+            let response = match validators {
+                Some(validators) => {
+                    ctx.http
+                        .get_if_changed(
+                            url,
+                            validators.etag.as_deref(),
+                            validators.last_modified.as_deref(),
+                        )
+                        .await
+                }
+                None => ctx.http.get_from(url, offset, if_range.as_deref()).await,
+            };
+            let response = match response {
+                //The part is already complete or larger than the file
+                Err(HttpError::Status {
+                    status: StatusCode::RANGE_NOT_SATISFIABLE,
+                    ..
+                }) if offset > 0 => ctx.http.get(url).await?,
+                response => response?,
+            };
+            let (mut file, mut hasher, mut size) = if offset > 0
+                && response.status() == StatusCode::PARTIAL_CONTENT
+            {
+                debug!(target:"debug","Continuing {} from byte {}", part.display(), offset);
+                let prefix = part.to_path_buf();
+                let (hasher, size) = task::spawn_blocking(move || checksum::hash_prefix(&prefix))
+                    .await
+                    .map_err(std::io::Error::other)?
+                    .map_err(std::io::Error::other)?;
+                let file = OpenOptions::new().append(true).open(part).await?;
+                (file, hasher, size)
+            } else {
+                let started = Validators::from_headers(response.headers());
+                match started.if_range() {
+                    Some(if_range) => tokio::fs::write(validator_path(part), if_range).await?,
+                    None => remove_validator(part).await?,
+                }
+                (File::create(part).await?, Sha256::new(), 0)
+            };
+            //Servers may leave the length out, of the range for continued parts
+            let expected = response.content_length().map(|length| size + length);
+            let received = Validators::from_headers(response.headers());
+            let mut stream = response.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|source| HttpError::Transport {
+                    url: url.clone(),
+                    source,
+                })?;
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+                ctx.progress.bytes(url, chunk.len() as u64);
+                ctx.metrics.bytes(chunk.len() as u64);
+                ctx.throttle.consume(chunk.len() as u64).await;
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            //A connection closed early otherwise looks like a complete file
+            if let Some(expected) = expected.filter(|expected| *expected != size) {
+                return Err(HttpError::Truncated {
+                    url: url.clone(),
+                    expected,
+                    received: size,
+                });
+            }
+            remove_validator(part).await?;
+            Ok((size, format!("{:x}", hasher.finalize()), received))
+        })
+        .await
+        .map_err(Into::into)
+}
+
+//Length of `url`, the segments to fetch it in and its validators, if it is large enough to be
+//segmented
+async fn segmented_length(ctx: &Context, url: &Url) -> Option<(u64, u64, Validators)> {
+    let segmented = ctx.config.segmented_download.as_ref()?;
+    let response = match ctx.http.head(url).await {
+        Ok(response) => response,
+        Err(e) => {
+            debug!(target:"debug","Not segmenting {} due to \"{}\"", url, e);
+            return None;
+        }
+    };
+    let headers = response.headers();
+    let ranges = headers
+        .get(ACCEPT_RANGES)
+        .is_some_and(|ranges| ranges.as_bytes() == b"bytes");
+    let length = headers
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()?;
+    (ranges && length >= segmented.min_size_mb * 1024 * 1024 && segmented.segments > 1).then(|| {
+        (
+            length,
+            segmented.segments as u64,
+            Validators::from_headers(headers),
+        )
+    })
+}
+
+//`length` bytes of `url` fetched as `segments` ranges at once, each written in place into `part`
+#[tracing::instrument(skip(ctx, part))]
+async fn download_segments(
+    ctx: &Context,
+    url: &Url,
+    part: &Path,
+    length: u64,
+    segments: u64,
+) -> Result<(u64, String)> {
+    debug!(target:"debug","Downloading {} in {} segments", url, segments);
+    File::create(part).await?.set_len(length).await?;
+    let segment = length.div_ceil(segments);
+    let ranges = (0..segments)
+        .map(|i| (i * segment, ((i + 1) * segment).min(length)))
+        .filter(|(start, end)| start < end);
+    futures_util::future::try_join_all(
+        ranges.map(|(start, end)| download_segment(ctx, url, part, start, end)),
+    )
+    .await?;
+    let part = part.to_path_buf();
+    task::spawn_blocking(move || checksum::hash_file(&part)).await?
+}
+
+//Bytes `start..end` of `url` into the same place of `part`
+async fn download_segment(
+    ctx: &Context,
+    url: &Url,
+    part: &Path,
+    start: u64,
+    end: u64,
+) -> Result<(), HttpError> {
+    ctx.http
+        .with_retry(url, || async {
+            let response = ctx.http.get_range(url, start, end - 1).await?;
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(HttpError::Io(std::io::Error::other(format!(
+                    "{} ignored the range {}-{}",
+                    url,
+                    start,
+                    end - 1
+                ))));
+            }
+            let mut file = OpenOptions::new().write(true).open(part).await?;
+            file.seek(SeekFrom::Start(start)).await?;
+            let mut written = 0;
+            let mut stream = response.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|source| HttpError::Transport {
+                    url: url.clone(),
+                    source,
+                })?;
+                //A longer answer than asked for is cut to the segment
+                let chunk = &chunk[..chunk.len().min((end - start - written) as usize)];
+                ctx.progress.bytes(url, chunk.len() as u64);
+                ctx.metrics.bytes(chunk.len() as u64);
+                ctx.throttle.consume(chunk.len() as u64).await;
+                file.write_all(chunk).await?;
+                written += chunk.len() as u64;
+            }
+            file.flush().await?;
+            if written < end - start {
+                return Err(HttpError::Truncated {
+                    url: url.clone(),
+                    expected: end - start,
+                    received: written,
+                });
+            }
+            Ok(())
+        })
+        .await
+}
+
+//Data is written verbatim, gzip members are only unpacked on request
+#[tracing::instrument(skip_all)]
+fn decompress(from: &Path, to: &Path) -> Result<()> {
+    let mut decoder = MultiGzDecoder::new(BufReader::new(std::fs::File::open(from)?));
+    let part = part_path(to);
+    let mut file = std::fs::File::create(&part)?;
+    std::io::copy(&mut decoder, &mut file)?;
+    std::fs::rename(&part, to)?;
+    std::fs::remove_file(from)?;
+    Ok(())
+}
+
+//Leftover parts are continued by the next run, except those of downloads aborted on shutdown
+struct PartGuard<'a> {
+    ctx: &'a Context,
+    part: &'a Path,
+}
+
+impl Drop for PartGuard<'_> {
+    fn drop(&mut self) {
+        if self.ctx.is_stopping() && self.part.exists() {
+            debug!(target:"debug","Removing partial file : {}", self.part.display());
+            let _ = std::fs::remove_file(self.part);
+            let _ = std::fs::remove_file(validator_path(self.part));
+        }
+    }
+}
+
+async fn remove_validator(part: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(validator_path(part)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::pipeline::Pipeline;
+    use hyper::header::{IF_RANGE, RANGE};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    const CONTENT: &[u8] = b"ATOM      1  N   MET A   1\nEND\n";
+
+    //Serves CONTENT with the ETag "v2", honoring ranges as If-Range allows, and records the
+    //Range header of every request
+    async fn serve(ranges: Arc<Mutex<Vec<Option<String>>>>) -> Url {
+        let make_service = make_service_fn(move |_| {
+            let ranges = ranges.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let header = |name| {
+                        request
+                            .headers()
+                            .get(name)
+                            .and_then(|value: &HeaderValue| value.to_str().ok())
+                            .map(str::to_string)
+                    };
+                    let range = header(RANGE);
+                    ranges.lock().unwrap().push(range.clone());
+                    let offset = range
+                        .filter(|_| header(IF_RANGE).as_deref() == Some("\"v2\""))
+                        .and_then(|range| {
+                            range
+                                .strip_prefix("bytes=")?
+                                .strip_suffix('-')?
+                                .parse()
+                                .ok()
+                        });
+                    let response = match offset {
+                        Some(offset) => Response::builder()
+                            .status(StatusCode::PARTIAL_CONTENT)
+                            .body(Body::from(&CONTENT[offset..])),
+                        None => Response::builder().body(Body::from(CONTENT)),
+                    };
+                    let response = response.map(|mut response| {
+                        response
+                            .headers_mut()
+                            .insert(ETAG, HeaderValue::from_static("\"v2\""));
+                        response
+                    });
+                    async move { Ok::<_, Infallible>(response.unwrap()) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/1abc.pdb", server.local_addr());
+        tokio::spawn(server);
+        url.parse().unwrap()
+    }
+
+    fn pipeline(name: &str) -> (Pipeline, PathBuf) {
+        let save_path =
+            std::env::temp_dir().join(format!("prog_med_download_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&save_path);
+        let pipeline = Pipeline::builder(config::tests::config(&save_path))
+            .build()
+            .unwrap();
+        (pipeline, save_path)
+    }
+
+    //Downloads `url` over a part holding the start of CONTENT and the version `validator`
+    async fn resume(name: &str, validator: Option<&str>) -> (Vec<Option<String>>, Vec<u8>) {
+        let ranges = Arc::default();
+        let url = serve(Arc::clone(&ranges)).await;
+        let (pipeline, save_path) = pipeline(name);
+        let file = save_path.join("1abc.pdb");
+        let part = part_path(&file);
+        //The start of the file, as an older version could have had it
+        std::fs::write(&part, b"HETATM    1").unwrap();
+        if let Some(validator) = validator {
+            std::fs::write(validator_path(&part), validator).unwrap();
+        }
+        let downloaded = download_file(&pipeline.ctx, &url, &file).await.unwrap();
+        let content = std::fs::read(&file).unwrap();
+        assert_eq!(downloaded.size, CONTENT.len() as u64);
+        assert_eq!(downloaded.sha256, format!("{:x}", Sha256::digest(CONTENT)));
+        assert!(!part.exists() && !validator_path(&part).exists());
+        let _ = std::fs::remove_dir_all(&save_path);
+        let ranges = ranges.lock().unwrap().clone();
+        (ranges, content)
+    }
+
+    #[tokio::test]
+    async fn part_of_another_version_is_restarted() {
+        let (ranges, content) = resume("changed", Some("\"v1\"")).await;
+        assert_eq!(ranges, [Some("bytes=11-".to_string())]);
+        assert_eq!(content, CONTENT);
+    }
+
+    #[tokio::test]
+    async fn part_of_an_unknown_version_is_restarted_without_a_range() {
+        let (ranges, content) = resume("unknown", None).await;
+        assert_eq!(ranges, [None]);
+        assert_eq!(content, CONTENT);
+    }
+
+    #[tokio::test]
+    async fn part_of_the_same_version_is_continued() {
+        let ranges = Arc::default();
+        let url = serve(Arc::clone(&ranges)).await;
+        let (pipeline, save_path) = pipeline("same");
+        let file = save_path.join("1abc.pdb");
+        let part = part_path(&file);
+        std::fs::write(&part, &CONTENT[..11]).unwrap();
+        std::fs::write(validator_path(&part), "\"v2\"").unwrap();
+        let downloaded = download_file(&pipeline.ctx, &url, &file).await.unwrap();
+        assert_eq!(*ranges.lock().unwrap(), [Some("bytes=11-".to_string())]);
+        assert_eq!(std::fs::read(&file).unwrap(), CONTENT);
+        assert_eq!(downloaded.sha256, format!("{:x}", Sha256::digest(CONTENT)));
+        let _ = std::fs::remove_dir_all(&save_path);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::pipeline::Context;
+use crate::summary::RunSummary;
+use anyhow::Result;
+
+/// Email the report of the run and its failed targets, when `[email]` is set.
+///
+/// A failing email is logged, it doesn't fail the run.
+pub(crate) async fn send_report(ctx: &Context, summary: &RunSummary) {
+    if ctx.config.email.is_none() {
+        return;
+    }
+    if let Err(e) = send(ctx, summary).await {
+        warn!("Failed to email the report due to \"{}\"", e);
+    }
+}
+
+#[cfg(feature = "email")]
+async fn send(ctx: &Context, summary: &RunSummary) -> Result<()> {
+    use crate::config::SmtpTls;
+    use crate::report;
+    use lettre::message::header::ContentType;
+    use lettre::message::{Attachment, MultiPart, SinglePart};
+    use lettre::transport::smtp::authentication::Credentials;
+    use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+    let Some(config) = &ctx.config.email else {
+        return Ok(());
+    };
+    let body = report::markdown(Some(summary), &ctx.state.files())?;
+    //The header is written even without failed targets
+    let mut failed = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    failed.write_record(["chembl_id", "target_name", "error"])?;
+    for target in &summary.failed_targets {
+        failed.serialize(target)?;
+    }
+    let failed = failed.into_inner()?;
+
+    let mut message = Message::builder()
+        .from(config.from.parse()?)
+        .subject(format!(
+            "prog_med run on {} {}",
+            ctx.config.save_path,
+            if summary.interrupted {
+                "interrupted"
+            } else if summary.failed_targets.is_empty() {
+                "completed"
+            } else {
+                "completed with failures"
+            }
+        ));
+    for to in &config.to {
+        message = message.to(to.parse()?);
+    }
+    let message = message.multipart(
+        MultiPart::mixed()
+            .singlepart(SinglePart::plain(body))
+            .singlepart(
+                Attachment::new("failed_targets.csv".to_string())
+                    .body(failed, ContentType::parse("text/csv")?),
+            ),
+    )?;
+
+    let mut transport = match config.tls {
+        SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    };
+    if let Some(port) = config.port {
+        transport = transport.port(port);
+    }
+    //Credentials stay out of the config file
+    if let (Ok(username), Ok(password)) = (
+        std::env::var("SMTP_USERNAME"),
+        std::env::var("SMTP_PASSWORD"),
+    ) {
+        transport = transport.credentials(Credentials::new(username, password));
+    }
+    transport.build().send(message).await?;
+    info!("Report emailed to {}", config.to.join(", "));
+    Ok(())
+}
+
+#[cfg(not(feature = "email"))]
+async fn send(_ctx: &Context, _summary: &RunSummary) -> Result<()> {
+    anyhow::bail!("[email] needs a build with the \"email\" feature")
+}
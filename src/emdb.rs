@@ -0,0 +1,49 @@
+use crate::download::{fetch, stored_path, Downloaded};
+use crate::pipeline::Context;
+use crate::rcsb;
+use anyhow::Result;
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+
+const EMDB_URL: &str = "https://files.wwpdb.org/pub/emdb/structures/";
+
+/// Urls of the EMDB maps of `pdb_id`, with the files they are saved to, if the config asks for them.
+pub(crate) async fn map_files(
+    ctx: &Context,
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<Vec<(Url, PathBuf)>> {
+    if !ctx.config.emdb_maps {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for emdb_id in rcsb::entry_identifiers(ctx, pdb_id, "emdb_ids").await? {
+        info!("{} : EMDB map {}", pdb_id, emdb_id);
+        //EMD-1234 is stored as emd_1234.map.gz
+        let file_name = format!("{}.map.gz", emdb_id.to_lowercase().replace('-', "_"));
+        let url: Url = format!("{}{}/map/{}", EMDB_URL, emdb_id, file_name).parse()?;
+        files.push((url, save_path.join(file_name)));
+    }
+    Ok(files)
+}
+
+/// Download the EMDB maps of `pdb_id` next to its coordinates in `save_path`.
+pub(crate) async fn download_maps(
+    ctx: &Context,
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<Vec<Downloaded>> {
+    let mut downloaded = Vec::new();
+    for (url, save_filepath) in map_files(ctx, pdb_id, save_path).await? {
+        if ctx
+            .storage
+            .exists(&stored_path(&ctx.config, &save_filepath))
+            .await?
+        {
+            continue;
+        }
+        debug!(target:"debug","EMDB map url : {}", url);
+        downloaded.push(fetch(ctx, &url, &save_filepath).await?);
+    }
+    Ok(downloaded)
+}
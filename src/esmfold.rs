@@ -0,0 +1,68 @@
+use crate::checksum;
+use crate::download::{part_path, Downloaded};
+use crate::pipeline::Context;
+use crate::uniprot;
+use anyhow::Result;
+use reqwest::Url;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+const FOLD_URL: &str = "https://api.esmatlas.com/foldSequence/v1/pdb/";
+//Longest sequence the ESM Atlas API folds
+const MAX_LENGTH: usize = 400;
+
+/// Url folding the sequence of `accession` and the file its ESMFold model is saved to.
+pub(crate) fn model_file(accession: &str, save_path: &Path) -> Result<(Url, PathBuf)> {
+    Ok((
+        FOLD_URL.parse()?,
+        save_path
+            .join("esmfold")
+            .join(format!("ESM-{}.pdb", accession)),
+    ))
+}
+
+/// Fold the UniProt sequence of `accession` with the ESM Atlas API into
+/// `save_path/esmfold/`, none if it is already there or too long to be folded.
+pub(crate) async fn download_model(
+    ctx: &Context,
+    accession: &str,
+    save_path: &Path,
+) -> Result<Option<Downloaded>> {
+    let (url, save_filepath) = model_file(accession, save_path)?;
+    if ctx.storage.exists(&save_filepath).await? {
+        return Ok(None);
+    }
+    let Some(sequence) = uniprot::fetch_entry(ctx, accession)
+        .await?
+        .sequence
+        .and_then(|sequence| sequence.value)
+    else {
+        warn!("No sequence of {} to fold", accession);
+        return Ok(None);
+    };
+    if sequence.len() > MAX_LENGTH {
+        warn!(
+            "{} has {} residues, ESMFold only folds up to {}",
+            accession,
+            sequence.len(),
+            MAX_LENGTH
+        );
+        return Ok(None);
+    }
+    debug!(target:"debug","Folding {} with ESMFold", accession);
+    let model = ctx.http.post_text(&url, sequence).await?;
+    create_dir_all(save_filepath.parent().unwrap_or(Path::new("")))?;
+    let part = part_path(&save_filepath);
+    std::fs::write(&part, model)?;
+    std::fs::rename(&part, &save_filepath)?;
+    let (size, sha256) = checksum::hash_file(&save_filepath)?;
+    Ok(Some(Downloaded {
+        url,
+        format: Some("pdb".to_string()),
+        path: save_filepath,
+        size,
+        sha256,
+        etag: None,
+        last_modified: None,
+    }))
+}
@@ -0,0 +1,222 @@
+use crate::manifest::ManifestEntry;
+use anyhow::Result;
+use std::path::Path;
+
+#[cfg(feature = "parquet")]
+const MANIFEST_PARQUET: &str = "manifest.parquet";
+pub(crate) const STATS_DIR: &str = "stats";
+
+/// How long a PDB entry took and what became of it, for `stats/downloads_<time>.parquet`.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+pub(crate) struct DownloadStat {
+    pub chembl_id: String,
+    pub accession: String,
+    pub pdb_id: String,
+    pub url: Option<String>,
+    pub size: Option<u64>,
+    pub duration_ms: u64,
+    pub outcome: String,
+    /// RFC 3339
+    pub finished_at: String,
+}
+
+/// Fail unless the build can write Parquet files.
+pub(crate) fn check() -> Result<()> {
+    if cfg!(not(feature = "parquet")) {
+        anyhow::bail!("parquet needs a build with the \"parquet\" feature");
+    }
+    Ok(())
+}
+
+/// Write `entries` to `manifest.parquet` and `stats` to a new file of `stats/`.
+#[cfg(feature = "parquet")]
+pub(crate) fn write(
+    save_path: &Path,
+    entries: &[ManifestEntry],
+    stats: &[DownloadStat],
+) -> Result<()> {
+    let text = |value: &dyn Fn(&ManifestEntry) -> Option<String>| {
+        Column::Text(entries.iter().map(value).collect())
+    };
+    let integer = |value: &dyn Fn(&ManifestEntry) -> u64| {
+        Column::Integer(
+            entries
+                .iter()
+                .map(|entry| Some(value(entry) as i64))
+                .collect(),
+        )
+    };
+    table::write(
+        &save_path.join(MANIFEST_PARQUET),
+        vec![
+            ("chembl_id", text(&|entry| Some(entry.chembl_id.clone()))),
+            (
+                "target_name",
+                text(&|entry| Some(entry.target_name.clone())),
+            ),
+            ("accession", text(&|entry| Some(entry.accession.clone()))),
+            ("mapped_from", text(&|entry| entry.mapped_from.clone())),
+            ("pdb_id", text(&|entry| entry.pdb_id.clone())),
+            ("superseded_by", text(&|entry| entry.superseded_by.clone())),
+            ("predicted_by", text(&|entry| entry.predicted_by.clone())),
+            ("format", text(&|entry| entry.format.clone())),
+            ("path", text(&|entry| Some(entry.path.clone()))),
+            ("archive", text(&|entry| entry.archive.clone())),
+            ("size", integer(&|entry| entry.size)),
+            ("sha256", text(&|entry| Some(entry.sha256.clone()))),
+            (
+                "compression",
+                text(&|entry| {
+                    entry
+                        .compression
+                        .map(|compression| format!("{:?}", compression).to_lowercase())
+                }),
+            ),
+            ("source_url", text(&|entry| Some(entry.source_url.clone()))),
+            ("etag", text(&|entry| entry.etag.clone())),
+            ("last_modified", text(&|entry| entry.last_modified.clone())),
+            (
+                "downloaded_at",
+                text(&|entry| Some(entry.downloaded_at.clone())),
+            ),
+            ("skipped", text(&|entry| entry.skipped.clone())),
+        ],
+    )?;
+
+    if stats.is_empty() {
+        return Ok(());
+    }
+    let stats_dir = save_path.join(STATS_DIR);
+    std::fs::create_dir_all(&stats_dir)?;
+    let text = |value: &dyn Fn(&DownloadStat) -> Option<String>| {
+        Column::Text(stats.iter().map(value).collect())
+    };
+    table::write(
+        &stats_dir.join(format!(
+            "downloads_{}.parquet",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S")
+        )),
+        vec![
+            ("chembl_id", text(&|stat| Some(stat.chembl_id.clone()))),
+            ("accession", text(&|stat| Some(stat.accession.clone()))),
+            ("pdb_id", text(&|stat| Some(stat.pdb_id.clone()))),
+            ("url", text(&|stat| stat.url.clone())),
+            (
+                "size",
+                Column::Integer(
+                    stats
+                        .iter()
+                        .map(|stat| stat.size.map(|size| size as i64))
+                        .collect(),
+                ),
+            ),
+            (
+                "duration_ms",
+                Column::Integer(
+                    stats
+                        .iter()
+                        .map(|stat| Some(stat.duration_ms as i64))
+                        .collect(),
+                ),
+            ),
+            ("outcome", text(&|stat| Some(stat.outcome.clone()))),
+            ("finished_at", text(&|stat| Some(stat.finished_at.clone()))),
+        ],
+    )
+}
+
+#[cfg(not(feature = "parquet"))]
+pub(crate) fn write(
+    _save_path: &Path,
+    _entries: &[ManifestEntry],
+    _stats: &[DownloadStat],
+) -> Result<()> {
+    check()
+}
+
+#[cfg(feature = "parquet")]
+enum Column {
+    Text(Vec<Option<String>>),
+    Integer(Vec<Option<i64>>),
+}
+
+#[cfg(feature = "parquet")]
+mod table {
+    use super::Column;
+    use crate::download::part_path;
+    use anyhow::Result;
+    use parquet::basic::{Compression, LogicalType, Repetition, Type as PhysicalType};
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::types::Type;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    //Optional columns of one row group, through a part file
+    pub(super) fn write(path: &Path, columns: Vec<(&str, Column)>) -> Result<()> {
+        let mut fields = Vec::new();
+        for (name, column) in &columns {
+            let field = match column {
+                Column::Text(_) => Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY)
+                    .with_logical_type(Some(LogicalType::String)),
+                Column::Integer(_) => Type::primitive_type_builder(name, PhysicalType::INT64),
+            };
+            fields.push(Arc::new(
+                field.with_repetition(Repetition::OPTIONAL).build()?,
+            ));
+        }
+        let schema = Arc::new(
+            Type::group_type_builder("schema")
+                .with_fields(fields)
+                .build()?,
+        );
+        let properties = Arc::new(
+            WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build(),
+        );
+        let part = part_path(path);
+        let mut writer =
+            SerializedFileWriter::new(std::fs::File::create(&part)?, schema, properties)?;
+        let mut row_group = writer.next_row_group()?;
+        for (_, column) in columns {
+            let Some(mut writer) = row_group.next_column()? else {
+                break;
+            };
+            //Nulls are told by definition levels, values only list the others
+            match column {
+                Column::Text(values) => {
+                    let levels = values
+                        .iter()
+                        .map(|value| value.is_some() as i16)
+                        .collect::<Vec<_>>();
+                    let values = values
+                        .into_iter()
+                        .flatten()
+                        .map(|value| ByteArray::from(value.into_bytes()))
+                        .collect::<Vec<_>>();
+                    writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+                Column::Integer(values) => {
+                    let levels = values
+                        .iter()
+                        .map(|value| value.is_some() as i16)
+                        .collect::<Vec<_>>();
+                    let values = values.into_iter().flatten().collect::<Vec<_>>();
+                    writer
+                        .typed::<Int64Type>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+            }
+            writer.close()?;
+        }
+        row_group.close()?;
+        writer.close()?;
+        std::fs::rename(&part, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+use crate::pipeline::Pipeline;
+use anyhow::Result;
+
+impl Pipeline {
+    /// Serve the gRPC API of `proto/prog_med.proto` on `addr`, until Ctrl+C or SIGTERM.
+    ///
+    /// Every `Run` call downloads its targets into `jobs/<id>/` of the save path with the config
+    /// of the pipeline, one call at a time, and streams the events of its run back.
+    #[cfg(feature = "grpc")]
+    pub async fn serve_grpc(&self, addr: &str) -> Result<()> {
+        server::serve(self.config().clone(), addr).await
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    pub async fn serve_grpc(&self, _addr: &str) -> Result<()> {
+        anyhow::bail!("The gRPC API needs a build with the \"grpc\" feature");
+    }
+}
+
+//Messages of proto/prog_med.proto
+#[cfg(feature = "grpc")]
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Target {
+        #[prost(string, tag = "1")]
+        pub chembl_id: String,
+        #[prost(string, tag = "2")]
+        pub target_name: String,
+        #[prost(string, tag = "3")]
+        pub uniprot_accession: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RunRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub targets: Vec<Target>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Event {
+        #[prost(oneof = "Kind", tags = "1, 2, 3, 4, 5")]
+        pub event: Option<Kind>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(message, tag = "1")]
+        RunStarted(RunStarted),
+        #[prost(message, tag = "2")]
+        TargetStarted(TargetStarted),
+        #[prost(message, tag = "3")]
+        FileDownloaded(FileDownloaded),
+        #[prost(message, tag = "4")]
+        TargetFinished(TargetFinished),
+        #[prost(message, tag = "5")]
+        RunFinished(RunFinished),
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RunStarted {
+        #[prost(string, tag = "1")]
+        pub job_id: String,
+        #[prost(string, tag = "2")]
+        pub save_path: String,
+        #[prost(uint64, tag = "3")]
+        pub targets: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TargetStarted {
+        #[prost(string, tag = "1")]
+        pub chembl_id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FileDownloaded {
+        #[prost(string, tag = "1")]
+        pub chembl_id: String,
+        #[prost(string, tag = "2")]
+        pub accession: String,
+        #[prost(string, tag = "3")]
+        pub pdb_id: String,
+        #[prost(string, tag = "4")]
+        pub path: String,
+        #[prost(uint64, tag = "5")]
+        pub size: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TargetFinished {
+        #[prost(string, tag = "1")]
+        pub chembl_id: String,
+        #[prost(string, tag = "2")]
+        pub outcome: String,
+        #[prost(string, tag = "3")]
+        pub error: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RunFinished {
+        #[prost(string, tag = "1")]
+        pub status: String,
+        #[prost(string, tag = "2")]
+        pub error: String,
+        #[prost(uint64, tag = "3")]
+        pub targets_processed: u64,
+        #[prost(uint64, tag = "4")]
+        pub downloaded_bytes: u64,
+    }
+}
+
+#[cfg(feature = "grpc")]
+mod server {
+    use super::proto::{self, Kind};
+    use crate::config::UserConfig;
+    use crate::pipeline::{InputSource, Pipeline, RunEvent, Target};
+    use crate::server::{self, job_id, JOBS_FOLDER, MAX_BODY};
+    use crate::shutdown;
+    use anyhow::Result;
+    use bytes::{BufMut, Bytes, BytesMut};
+    use hyper::body::{HttpBody, Sender};
+    use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response};
+    use prost::Message;
+    use std::convert::Infallible;
+    use std::path::Path;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Semaphore};
+
+    const RUN_PATH: &str = "/prog_med.ProgMed/Run";
+    //Status codes of the gRPC protocol
+    const OK: u32 = 0;
+    const INVALID_ARGUMENT: u32 = 3;
+    const RESOURCE_EXHAUSTED: u32 = 8;
+    const UNIMPLEMENTED: u32 = 12;
+    const INTERNAL: u32 = 13;
+
+    struct Server {
+        config: UserConfig,
+        //Runs go one at a time, as jobs of the REST API do
+        running: Semaphore,
+        submitted: AtomicUsize,
+    }
+
+    pub(super) async fn serve(config: UserConfig, addr: &str) -> Result<()> {
+        let server = Arc::new(Server {
+            config,
+            running: Semaphore::new(1),
+            submitted: AtomicUsize::new(0),
+        });
+        let make_service = make_service_fn(move |_| {
+            let server = server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(respond(server, request).await) }
+                }))
+            }
+        });
+        let bound = hyper::Server::from_tcp(server::listen(addr)?)?
+            .http2_only(true)
+            .serve(make_service);
+        info!("Serving the gRPC API on {}", bound.local_addr());
+        bound
+            .with_graceful_shutdown(async {
+                let _ = shutdown::signal().await;
+                info!("Stopped serving. Waiting for the running calls...");
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn respond(server: Arc<Server>, request: Request<Body>) -> Response<Body> {
+        if request.method() != Method::POST || request.uri().path() != RUN_PATH {
+            return status_only(UNIMPLEMENTED, "Unknown method");
+        }
+        let request = match read_request(request.into_body()).await {
+            Ok(request) => request,
+            Err((status, message)) => return status_only(status, &message),
+        };
+        if request.targets.is_empty() {
+            return status_only(INVALID_ARGUMENT, "The request lists no target");
+        }
+        let (sender, body) = Body::channel();
+        tokio::spawn(run(server, request, sender));
+        Response::builder()
+            .header(CONTENT_TYPE, "application/grpc")
+            .body(body)
+            .unwrap_or_default()
+    }
+
+    //The single message of a call, not compressed, as large as a REST request body at most
+    async fn read_request(mut body: Body) -> Result<proto::RunRequest, (u32, String)> {
+        let invalid = |message: String| (INVALID_ARGUMENT, message);
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| invalid(e.to_string()))?;
+            if data.len() + chunk.len() > MAX_BODY {
+                return Err((
+                    RESOURCE_EXHAUSTED,
+                    format!("The request is larger than {} bytes", MAX_BODY),
+                ));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        match data.first() {
+            None => return Err(invalid("Empty request".to_string())),
+            Some(0) => {}
+            Some(_) => return Err(invalid("Compressed messages aren't supported".to_string())),
+        }
+        let length = data
+            .get(1..5)
+            .map(|length| u32::from_be_bytes([length[0], length[1], length[2], length[3]]))
+            .unwrap_or_default() as usize;
+        let message = data
+            .get(5..5 + length)
+            .ok_or_else(|| invalid("Truncated message".to_string()))?;
+        proto::RunRequest::decode(message).map_err(|e| invalid(e.to_string()))
+    }
+
+    async fn run(server: Arc<Server>, request: proto::RunRequest, mut sender: Sender) {
+        let Ok(_permit) = server.running.acquire().await else {
+            return;
+        };
+        let (status, message) = match run_job(&server, request, &mut sender).await {
+            Ok(()) => (OK, String::new()),
+            Err(e) => (INTERNAL, format!("{:#}", e)),
+        };
+        let _ = sender.send_trailers(trailers(status, &message)).await;
+    }
+
+    async fn run_job(
+        server: &Server,
+        request: proto::RunRequest,
+        sender: &mut Sender,
+    ) -> Result<()> {
+        let id = job_id(&server.submitted);
+        let save_path = Path::new(&server.config.save_path)
+            .join(JOBS_FOLDER)
+            .join(&id);
+        let targets = request
+            .targets
+            .into_iter()
+            .map(|target| Target {
+                chembl_id: target.chembl_id,
+                target_name: target.target_name,
+                uniprot_accession: target.uniprot_accession,
+                priority: None,
+            })
+            .collect::<Vec<_>>();
+        let count = targets.len() as u64;
+        let (events, mut receiver) = mpsc::unbounded_channel();
+        let pipeline = Pipeline::builder(server.config.clone())
+            .save_path(save_path.to_string_lossy())
+            .input(InputSource::Targets(targets))
+            .events(events)
+            .build()?;
+        info!("Running gRPC job {}", id);
+        send(
+            sender,
+            Kind::RunStarted(proto::RunStarted {
+                job_id: id.clone(),
+                save_path: save_path.to_string_lossy().into_owned(),
+                targets: count,
+            }),
+        )
+        .await?;
+
+        let running = pipeline.run();
+        tokio::pin!(running);
+        let mut cancelled = false;
+        let result = loop {
+            tokio::select! {
+                result = &mut running => break result,
+                Some(event) = receiver.recv() => {
+                    //A client gone cancels its run
+                    if send(sender, kind(event)).await.is_err() && !cancelled {
+                        info!("gRPC job {} was cancelled", id);
+                        pipeline.stop();
+                        cancelled = true;
+                    }
+                }
+            }
+        };
+        while let Ok(event) = receiver.try_recv() {
+            let _ = send(sender, kind(event)).await;
+        }
+        let metrics = &pipeline.ctx.metrics;
+        let (status, error) = match &result {
+            Ok(()) if pipeline.ctx.is_stopping() => ("interrupted", String::new()),
+            Ok(()) => ("completed", String::new()),
+            Err(e) => ("failed", format!("{:#}", e)),
+        };
+        info!("gRPC job {} {}", id, status);
+        let _ = send(
+            sender,
+            Kind::RunFinished(proto::RunFinished {
+                status: status.to_string(),
+                error,
+                targets_processed: metrics.processed(),
+                downloaded_bytes: metrics.downloaded_bytes(),
+            }),
+        )
+        .await;
+        result
+    }
+
+    fn kind(event: RunEvent) -> Kind {
+        match event {
+            RunEvent::TargetStarted { chembl_id } => {
+                Kind::TargetStarted(proto::TargetStarted { chembl_id })
+            }
+            RunEvent::FileDownloaded {
+                chembl_id,
+                accession,
+                pdb_id,
+                path,
+                size,
+            } => Kind::FileDownloaded(proto::FileDownloaded {
+                chembl_id,
+                accession,
+                pdb_id: pdb_id.unwrap_or_default(),
+                path,
+                size,
+            }),
+            RunEvent::TargetFinished {
+                chembl_id,
+                outcome,
+                error,
+            } => Kind::TargetFinished(proto::TargetFinished {
+                chembl_id,
+                outcome,
+                error: error.unwrap_or_default(),
+            }),
+        }
+    }
+
+    //An event as a length-prefixed message of the response stream
+    async fn send(sender: &mut Sender, event: Kind) -> Result<()> {
+        let message = proto::Event { event: Some(event) }.encode_to_vec();
+        let mut frame = BytesMut::with_capacity(5 + message.len());
+        frame.put_u8(0);
+        frame.put_u32(message.len() as u32);
+        frame.put_slice(&message);
+        sender.send_data(frame.freeze()).await?;
+        Ok(())
+    }
+
+    fn trailers(status: u32, message: &str) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from(status));
+        //The message goes percent-encoded, as the protocol has it
+        let message = message
+            .bytes()
+            .map(|byte| match byte {
+                b' '..=b'~' if byte != b'%' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect::<String>();
+        if let Ok(message) = HeaderValue::from_str(&message) {
+            if !message.is_empty() {
+                trailers.insert("grpc-message", message);
+            }
+        }
+        trailers
+    }
+
+    //Errors before any message go in the headers alone
+    fn status_only(status: u32, message: &str) -> Response<Body> {
+        let mut response = Response::new(Body::from(Bytes::new()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+        response.headers_mut().extend(trailers(status, message));
+        response
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn framed(message: &[u8]) -> Body {
+            let mut frame = vec![0];
+            frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            frame.extend_from_slice(message);
+            Body::from(frame)
+        }
+
+        #[tokio::test]
+        async fn requests_are_read_from_their_frame() {
+            let request = proto::RunRequest {
+                targets: vec![proto::Target {
+                    chembl_id: "CHEMBL203".to_string(),
+                    target_name: "EGFR".to_string(),
+                    uniprot_accession: "P00533".to_string(),
+                }],
+            };
+            let read = read_request(framed(&request.encode_to_vec())).await;
+            assert_eq!(read.unwrap(), request);
+        }
+
+        #[tokio::test]
+        async fn bad_frames_are_invalid() {
+            let message = proto::RunRequest::default().encode_to_vec();
+            let mut truncated = vec![0, 0, 0, 0, 9];
+            truncated.extend_from_slice(&message);
+            for body in [
+                Body::empty(),
+                Body::from(vec![1, 0, 0, 0, 0]),
+                Body::from(truncated),
+            ] {
+                assert_eq!(read_request(body).await.unwrap_err().0, INVALID_ARGUMENT);
+            }
+        }
+
+        #[tokio::test]
+        async fn requests_larger_than_rest_bodies_are_refused() {
+            let body = framed(&vec![0; MAX_BODY]);
+            assert_eq!(read_request(body).await.unwrap_err().0, RESOURCE_EXHAUSTED);
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use crate::config::{AuthConfig, RateLimit, RetryPolicy, UserConfig};
+use crate::metrics::Metrics;
+use anyhow::{anyhow, Context as _, Result};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, RANGE,
+    RETRY_AFTER,
+};
+use reqwest::{Client, NoProxy, Proxy, RequestBuilder, Response, StatusCode, Url};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpError {
+    #[error("request to {url} failed: {source}")]
+    Transport { url: Url, source: reqwest::Error },
+    #[error("{url} returned {status}")]
+    Status {
+        url: Url,
+        status: StatusCode,
+        /// Delay asked for by the Retry-After header of a 429 or 503 answer
+        retry_after: Option<Duration>,
+    },
+    /// A request made while `offline` is set
+    #[error("{url} needs the network, which offline turns off")]
+    Offline { url: Url },
+    /// Fewer or more bytes than the Content-Length announced
+    #[error("{url} sent {received} bytes of {expected}")]
+    Truncated {
+        url: Url,
+        expected: u64,
+        received: u64,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl HttpError {
+    /// Whether trying the same request again may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            HttpError::Transport { source, .. } => {
+                source.is_timeout() || source.is_connect() || source.is_body()
+            }
+            HttpError::Status { status, .. } => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            HttpError::Truncated { .. } => true,
+            HttpError::Offline { .. } | HttpError::Io(_) => false,
+        }
+    }
+
+    /// Delay the server asked for before trying again, if it did.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            HttpError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+//Retry-After in seconds or as an HTTP date, counted from `now`
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Client set up according to the config.
+pub(crate) fn build_client(config: &UserConfig) -> reqwest::Result<Client> {
+    let http = &config.http;
+    let mut builder = Client::builder().pool_max_idle_per_host(http.pool_max_idle_per_host);
+    if http.connect_timeout > 0 {
+        builder = builder.connect_timeout(Duration::from_secs(http.connect_timeout));
+    }
+    if http.request_timeout > 0 {
+        builder = builder.timeout(Duration::from_secs(http.request_timeout));
+    }
+    if http.pool_idle_timeout > 0 {
+        builder = builder.pool_idle_timeout(Duration::from_secs(http.pool_idle_timeout));
+    }
+    //Without a proxy in the config reqwest reads the usual environment variables
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(
+            Proxy::all(&proxy.url)?.no_proxy(NoProxy::from_string(&proxy.no_proxy.join(","))),
+        );
+    }
+    builder.build()
+}
+
+/// Credentials of the `auth` config, with the environment variables they use filled in.
+pub(crate) struct Credentials {
+    prefix: String,
+    headers: HeaderMap,
+    basic: Option<(String, Option<String>)>,
+}
+
+impl Credentials {
+    pub fn from_config(auth: &[AuthConfig]) -> Result<Vec<Credentials>> {
+        auth.iter()
+            .map(|auth| {
+                let mut headers = HeaderMap::new();
+                for (name, value) in &auth.headers {
+                    let mut value = HeaderValue::from_str(&expand(value)?)
+                        .with_context(|| format!("Invalid value of header {}", name))?;
+                    value.set_sensitive(true);
+                    headers.insert(HeaderName::from_bytes(name.as_bytes())?, value);
+                }
+                let basic = match &auth.username {
+                    Some(username) => Some((
+                        expand(username)?,
+                        auth.password.as_deref().map(expand).transpose()?,
+                    )),
+                    None => None,
+                };
+                Ok(Credentials {
+                    prefix: auth.prefix.clone(),
+                    headers,
+                    basic,
+                })
+            })
+            .collect()
+    }
+}
+
+//Replace every `${NAME}` by the environment variable NAME
+fn expand(value: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed ${{ in auth value \"{}\"", value))?;
+        let name = &rest[start + 2..start + end];
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(
+            &std::env::var(name)
+                .with_context(|| format!("Environment variable {} of auth is not set", name))?,
+        );
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// HTTP layer shared by every request of a run, applying retries and rate limits.
+pub(crate) struct Http {
+    pub client: Client,
+    retry: RetryPolicy,
+    limiter: RateLimiter,
+    credentials: Vec<Credentials>,
+    metrics: Arc<Metrics>,
+    offline: bool,
+}
+
+impl Http {
+    pub fn new(
+        client: Client,
+        retry: RetryPolicy,
+        rate_limit: RateLimit,
+        credentials: Vec<Credentials>,
+        metrics: Arc<Metrics>,
+        offline: bool,
+    ) -> Self {
+        Http {
+            client,
+            retry,
+            limiter: RateLimiter::new(rate_limit),
+            credentials,
+            metrics,
+            offline,
+        }
+    }
+
+    /// Send a GET request, treating non-success status codes as errors.
+    pub async fn get(&self, url: &Url) -> Result<Response, HttpError> {
+        self.get_from(url, 0, None).await
+    }
+
+    /// Send a GET request for the content from byte `offset` on, as long as it still has the
+    /// ETag or Last-Modified date `if_range`.
+    ///
+    /// Servers answer the whole content with `200 OK` instead when it changed, and may also
+    /// ignore the range.
+    pub async fn get_from(
+        &self,
+        url: &Url,
+        offset: u64,
+        if_range: Option<&str>,
+    ) -> Result<Response, HttpError> {
+        let mut request = self.client.get(url.clone());
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+            if let Some(if_range) = if_range {
+                request = request.header(IF_RANGE, if_range);
+            }
+        }
+        self.send(url, request).await
+    }
+
+    /// Send a GET request for bytes `start` to `end` included, which servers may ignore.
+    pub async fn get_range(&self, url: &Url, start: u64, end: u64) -> Result<Response, HttpError> {
+        let request = self
+            .client
+            .get(url.clone())
+            .header(RANGE, format!("bytes={}-{}", start, end));
+        self.send(url, request).await
+    }
+
+    /// Send a GET request answered with `304 Not Modified`, an error, if the content still has
+    /// the `etag` or didn't change since `last_modified`.
+    pub async fn get_if_changed(
+        &self,
+        url: &Url,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+    ) -> Result<Response, HttpError> {
+        let mut request = self.client.get(url.clone());
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        self.send(url, request).await
+    }
+
+    /// Send a HEAD request, treating non-success status codes as errors.
+    pub async fn head(&self, url: &Url) -> Result<Response, HttpError> {
+        self.send(url, self.client.head(url.clone())).await
+    }
+
+    /// POST `form` to `url` and read the body as text, retrying transient failures.
+    pub async fn post_form(&self, url: &Url, form: &[(&str, &str)]) -> Result<String, HttpError> {
+        self.with_retry(url, || async {
+            self.send(url, self.client.post(url.clone()).form(form))
+                .await?
+                .text()
+                .await
+                .map_err(|source| HttpError::Transport {
+                    url: url.clone(),
+                    source,
+                })
+        })
+        .await
+    }
+
+    /// POST the text `body` to `url` and read the body as text, retrying transient failures.
+    pub async fn post_text(&self, url: &Url, body: String) -> Result<String, HttpError> {
+        self.with_retry(url, || async {
+            self.send(url, self.client.post(url.clone()).body(body.clone()))
+                .await?
+                .text()
+                .await
+                .map_err(|source| HttpError::Transport {
+                    url: url.clone(),
+                    source,
+                })
+        })
+        .await
+    }
+
+    async fn send(&self, url: &Url, mut request: RequestBuilder) -> Result<Response, HttpError> {
+        //The longest matching prefix wins
+        if let Some(credentials) = self
+            .credentials
+            .iter()
+            .filter(|credentials| url.as_str().starts_with(&credentials.prefix))
+            .max_by_key(|credentials| credentials.prefix.len())
+        {
+            request = request.headers(credentials.headers.clone());
+            if let Some((username, password)) = &credentials.basic {
+                request = request.basic_auth(username, password.as_ref());
+            }
+        }
+        self.send_signed(url, request).await
+    }
+
+    /// Send `request` without the credentials of `auth`, for requests signing their own
+    /// headers, treating non-success status codes as errors.
+    pub async fn send_signed(
+        &self,
+        url: &Url,
+        request: RequestBuilder,
+    ) -> Result<Response, HttpError> {
+        if self.offline {
+            return Err(HttpError::Offline { url: url.clone() });
+        }
+        self.limiter.acquire(url).await;
+        let response = request
+            .send()
+            .await
+            .map_err(|source| HttpError::Transport {
+                url: url.clone(),
+                source,
+            })?;
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = match status {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| parse_retry_after(value, Utc::now())),
+                _ => None,
+            };
+            return Err(HttpError::Status {
+                url: url.clone(),
+                status,
+                retry_after,
+            });
+        }
+        Ok(response)
+    }
+
+    /// GET `url` and read the body as text, retrying transient failures.
+    pub async fn get_text(&self, url: &Url) -> Result<String, HttpError> {
+        self.with_retry(url, || async {
+            self.get(url)
+                .await?
+                .text()
+                .await
+                .map_err(|source| HttpError::Transport {
+                    url: url.clone(),
+                    source,
+                })
+        })
+        .await
+    }
+
+    /// Run `op` until it succeeds, fails permanently, or runs out of attempts.
+    pub async fn with_retry<T, F, Fut>(&self, url: &Url, op: F) -> Result<T, HttpError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, HttpError>>,
+    {
+        self.with_retry_expecting(url, &[], op).await
+    }
+
+    /// Like [`Http::with_retry`], not counting the `expected` statuses as failed requests, such
+    /// as a 404 telling an object isn't there yet.
+    pub async fn with_retry_expecting<T, F, Fut>(
+        &self,
+        url: &Url,
+        expected: &[StatusCode],
+        mut op: F,
+    ) -> Result<T, HttpError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, HttpError>>,
+    {
+        let policy = &self.retry;
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if e.is_transient() && attempt < policy.max_attempts => {
+                    let delay = policy.delay(attempt, e.retry_after());
+                    warn!(
+                        "Attempt {} of {} failed due to \"{}\", retrying in {:?}",
+                        attempt, policy.max_attempts, e, delay
+                    );
+                    debug!(target:"debug","Retrying url : {}", url);
+                    self.metrics.retry();
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                //Not modified only answers a conditional request
+                Err(e @ HttpError::Status { status, .. })
+                    if status == StatusCode::NOT_MODIFIED || expected.contains(&status) =>
+                {
+                    return Err(e)
+                }
+                Err(e) => {
+                    self.metrics.failure(url);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets of requests per second, one for every host.
+struct RateLimiter {
+    config: RateLimit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    fn new(config: RateLimit) -> Self {
+        RateLimiter {
+            config,
+            buckets: Mutex::default(),
+        }
+    }
+
+    //Wait until the host of `url` has a token left
+    async fn acquire(&self, url: &Url) {
+        let host = url.host_str().unwrap_or_default();
+        let rate = match self.config.for_host(host) {
+            Some(rate) if rate > 0.0 => rate,
+            _ => return,
+        };
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap();
+                let bucket = buckets.entry(host.to_string()).or_insert(Bucket {
+                    tokens: rate.max(1.0),
+                    updated: Instant::now(),
+                });
+                let now = Instant::now();
+                bucket.tokens = (bucket.tokens
+                    + now.duration_since(bucket.updated).as_secs_f64() * rate)
+                    .min(rate.max(1.0));
+                bucket.updated = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl RetryPolicy {
+    //Exponential backoff with up to `jitter` of it randomly added, or the longer `retry_after`
+    //the server asked for up to `max_retry_after_secs`
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let backoff = self
+            .backoff_base_ms
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.backoff_max_ms);
+        let jitter = rand::thread_rng().gen_range(0.0..=self.jitter.max(0.0));
+        let asked = retry_after.map_or(Duration::ZERO, |retry_after| {
+            retry_after.min(Duration::from_secs(self.max_retry_after_secs))
+        });
+        Duration::from_millis(backoff + (backoff as f64 * jitter) as u64).max(asked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn retry_after_is_read_in_seconds_or_as_a_date() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        //A date gone by asks for no delay
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn delay_waits_as_asked_up_to_the_cap() {
+        let policy = policy();
+        assert_eq!(policy.delay(1, None), Duration::from_millis(500));
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        //Shorter than the backoff, which still applies
+        assert_eq!(
+            policy.delay(3, Some(Duration::from_secs(1))),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(3600))),
+            Duration::from_secs(policy.max_retry_after_secs)
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use crate::pipeline::{Context, Target};
+use anyhow::{bail, Result};
+use reqwest::Url;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const IDMAPPING_URL: &str = "https://rest.uniprot.org/idmapping/";
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: u32 = 150;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Job {
+    job_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JobStatus {
+    job_status: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct JobDetails {
+    redirect_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    primary_accession: String,
+}
+
+//Accessions as such or whole entries, depending on the database mapped to
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Mapped {
+    Accession(String),
+    Entry(Entry),
+}
+
+#[derive(Deserialize, Debug)]
+struct Mapping {
+    from: String,
+    to: Mapped,
+}
+
+#[derive(Deserialize, Debug)]
+struct Results {
+    results: Vec<Mapping>,
+}
+
+/// Whether `id` looks like a UniProt accession, isoforms included.
+pub fn is_uniprot(id: &str) -> bool {
+    let (id, isoform) = match id.split_once('-') {
+        Some((id, isoform)) => (id, Some(isoform)),
+        None => (id, None),
+    };
+    if isoform
+        .is_some_and(|isoform| isoform.is_empty() || !isoform.chars().all(|c| c.is_ascii_digit()))
+    {
+        return false;
+    }
+    let chars = id.as_bytes();
+    let alnum = |c: &u8| c.is_ascii_uppercase() || c.is_ascii_digit();
+    //[OPQ][0-9][A-Z0-9]{3}[0-9] or [A-NR-Z][0-9]([A-Z][A-Z0-9]{2}[0-9]){1,2}
+    match chars {
+        [first, digit, middle @ .., last] if chars.len() == 6 && b"OPQ".contains(first) => {
+            digit.is_ascii_digit() && middle.iter().all(alnum) && last.is_ascii_digit()
+        }
+        [first, digit, rest @ ..]
+            if first.is_ascii_uppercase()
+                && !b"OPQ".contains(first)
+                && digit.is_ascii_digit()
+                && (rest.len() == 4 || rest.len() == 8) =>
+        {
+            rest.chunks(4).all(|chunk| {
+                chunk[0].is_ascii_uppercase()
+                    && chunk[1..3].iter().all(alnum)
+                    && chunk[3].is_ascii_digit()
+            })
+        }
+        _ => false,
+    }
+}
+
+/// Database of the ID mapping service `id` belongs to, if it is known and not UniProt.
+pub fn id_type(id: &str) -> Option<&'static str> {
+    if is_uniprot(id) {
+        return None;
+    }
+    let prefix = id.split_once('_').map(|(prefix, _)| prefix);
+    match prefix {
+        Some("NP" | "XP" | "YP" | "WP" | "AP") => Some("RefSeq_Protein"),
+        _ if id.starts_with("ENSP") => Some("Ensembl_Protein"),
+        _ if id.starts_with("ENST") => Some("Ensembl_Transcript"),
+        _ if id.starts_with("ENSG") => Some("Ensembl"),
+        _ => None,
+    }
+}
+
+/// Replace the accessions of `targets` that aren't UniProt accessions by the ones UniProt
+/// maps them to, remembering where each came from for the manifest.
+///
+/// IDs UniProt can't map are dropped with a warning, unknown ones are kept as they are.
+pub(crate) async fn map_targets(ctx: &Context, targets: &mut [Target]) -> Result<()> {
+    let mut by_type: HashMap<&'static str, Vec<String>> = HashMap::new();
+    for target in targets.iter() {
+        for id in target.uniprot_accession.split('|') {
+            if let Some(id_type) = id_type(id) {
+                let ids = by_type.entry(id_type).or_default();
+                if !ids.iter().any(|known| known == id) {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+    }
+    if by_type.is_empty() {
+        return Ok(());
+    }
+
+    let mut mapped: HashMap<String, Vec<String>> = HashMap::new();
+    for (id_type, ids) in by_type {
+        info!("Mapping {} {} IDs to UniProt", ids.len(), id_type);
+        for mapping in run_job(ctx, id_type, &ids).await? {
+            let accession = match mapping.to {
+                Mapped::Accession(accession) => accession,
+                Mapped::Entry(entry) => entry.primary_accession,
+            };
+            mapped.entry(mapping.from).or_default().push(accession);
+        }
+    }
+    for target in targets.iter_mut() {
+        let mut accessions = Vec::new();
+        for id in target.uniprot_accession.split('|') {
+            if id_type(id).is_none() {
+                accessions.push(id.to_string());
+                continue;
+            }
+            match mapped.get(id) {
+                Some(mapped) => {
+                    for accession in mapped {
+                        debug!(target:"debug","{} maps to {}", id, accession);
+                        ctx.record_mapping(accession, id);
+                        if !accessions.contains(accession) {
+                            accessions.push(accession.clone());
+                        }
+                    }
+                }
+                None => warn!(
+                    "{} of {} has no UniProt accession, skipped",
+                    id, target.chembl_id
+                ),
+            }
+        }
+        target.uniprot_accession = accessions.join("|");
+    }
+    Ok(())
+}
+
+//Submit a mapping job, wait for it and read all of its results
+async fn run_job(ctx: &Context, id_type: &str, ids: &[String]) -> Result<Vec<Mapping>> {
+    let run: Url = format!("{}run", IDMAPPING_URL).parse()?;
+    let ids = ids.join(",");
+    let job: Job = serde_json::from_str(
+        &ctx.http
+            .post_form(
+                &run,
+                &[("from", id_type), ("to", "UniProtKB"), ("ids", &ids)],
+            )
+            .await?,
+    )?;
+
+    let status: Url = format!("{}status/{}", IDMAPPING_URL, job.job_id).parse()?;
+    let mut polls = 0;
+    loop {
+        //Finished jobs redirect to their results, which have no status
+        let page = ctx.http.get_text(&status).await?;
+        match serde_json::from_str::<JobStatus>(&page)?
+            .job_status
+            .as_deref()
+        {
+            None | Some("FINISHED") => break,
+            Some("NEW" | "RUNNING") if polls < MAX_POLLS => {
+                polls += 1;
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            Some("NEW" | "RUNNING") => bail!("ID mapping job {} is taking too long", job.job_id),
+            Some(status) => bail!("ID mapping job {} ended with {}", job.job_id, status),
+        }
+    }
+
+    let details: Url = format!("{}details/{}", IDMAPPING_URL, job.job_id).parse()?;
+    let details: JobDetails = serde_json::from_str(&ctx.http.get_text(&details).await?)?;
+    //The stream holds every result, where pages don't
+    let mut results: Url = details
+        .redirect_url
+        .replacen("/results/", "/results/stream/", 1)
+        .parse()?;
+    results
+        .query_pairs_mut()
+        .append_pair("format", "json")
+        .append_pair("fields", "accession");
+    let results: Results = serde_json::from_str(&ctx.http.get_text(&results).await?)?;
+    Ok(results.results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessions_of_both_formats_are_uniprot() {
+        for id in [
+            "P12345",
+            "Q9UHC1",
+            "O00141",
+            "A0A022YWF9",
+            "P12345-2",
+            "Q9UHC1-12",
+        ] {
+            assert!(is_uniprot(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn other_ids_are_not_uniprot() {
+        for id in [
+            "",
+            "p12345",
+            "P1234",
+            "P123456",
+            "O1234A",
+            "A0A022YWF",
+            "P12345-",
+            "P12345-a",
+            "CHEMBL203",
+            "ENSG00000146648",
+            "NP_005219",
+        ] {
+            assert!(!is_uniprot(id), "{}", id);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+use crate::config::{Column, Columns, DedupKey, InputFormat, TargetFilter, UserConfig};
+use crate::idmapping;
+use crate::pipeline::{Context, Target};
+use anyhow::{anyhow, bail, Result};
+use calamine::{Data, Reader, Xlsx};
+use csv::ReaderBuilder;
+use grep::matcher::Matcher;
+use grep::regex::RegexMatcher;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+//Header names of each field in ChEMBL exports and API results, matched ignoring case
+const CHEMBL_ID: &[&str] = &["chembl_id", "ChEMBL ID", "target_chembl_id"];
+const TARGET_NAME: &[&str] = &["target_name", "Name", "pref_name"];
+const UNIPROT_ACCESSION: &[&str] = &["uniprot_accession", "UniProt Accessions", "accession"];
+const PRIORITY: &str = "priority";
+
+/// Files matched by `patterns`, in order, patterns without wildcards being taken as they are
+/// and folders standing for the files in them.
+pub(crate) fn expand_paths(patterns: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if pattern.is_dir() {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(pattern)? {
+                let path = entry?.path();
+                let hidden = path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+                if path.is_file() && !hidden {
+                    files.push(path);
+                }
+            }
+            files.sort();
+            paths.extend(files);
+            continue;
+        }
+        let pattern = pattern.to_string_lossy();
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(PathBuf::from(pattern.as_ref()));
+            continue;
+        }
+        let matched = glob::glob(&pattern)?.collect::<Result<Vec<_>, _>>()?;
+        if matched.is_empty() {
+            warn!("No target list matches \"{}\"", pattern);
+        }
+        paths.extend(matched);
+    }
+    Ok(paths)
+}
+
+/// Keeps the first of the targets sharing a `key`, those without one being kept.
+pub(crate) struct Dedup {
+    key: DedupKey,
+    seen: HashSet<String>,
+    /// Targets dropped so far
+    pub collapsed: usize,
+    /// Targets kept so far
+    pub kept: usize,
+}
+
+impl Dedup {
+    pub fn new(key: DedupKey) -> Self {
+        Dedup {
+            key,
+            seen: HashSet::new(),
+            collapsed: 0,
+            kept: 0,
+        }
+    }
+
+    pub fn keep(&mut self, target: &Target) -> bool {
+        let key = match self.key {
+            DedupKey::None => String::new(),
+            DedupKey::ChemblId => target.chembl_id.trim().to_string(),
+            //The same accessions in any order are the same target
+            DedupKey::UniprotAccession => {
+                let mut accessions = target
+                    .uniprot_accession
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|accession| !accession.is_empty())
+                    .collect::<Vec<_>>();
+                accessions.sort_unstable();
+                accessions.dedup();
+                accessions.join("|")
+            }
+        };
+        let kept = key.is_empty() || self.seen.insert(key);
+        match kept {
+            true => self.kept += 1,
+            false => self.collapsed += 1,
+        }
+        kept
+    }
+}
+
+/// Targets of the input in batches, read as they are needed so work starts before a long list
+/// is read through.
+pub(crate) struct TargetInput {
+    receiver: mpsc::Receiver<Result<Target>>,
+    dedup: Dedup,
+    selection: Selection,
+    finished: bool,
+    /// Targets kept so far
+    pub count: usize,
+}
+
+impl TargetInput {
+    pub fn new(receiver: mpsc::Receiver<Result<Target>>, config: &UserConfig) -> Result<Self> {
+        Ok(TargetInput {
+            receiver,
+            dedup: Dedup::new(config.dedup_key),
+            selection: Selection::new(&config.filter)?,
+            finished: false,
+            count: 0,
+        })
+    }
+
+    /// Up to `uniprot_batch_size` more targets mapped to UniProt, none once the input is read
+    /// through, each with its position in the input.
+    ///
+    /// Positions count the targets left once duplicates are dropped, before those outside of
+    /// `filter` or not retried are skipped.
+    pub async fn next_batch(&mut self, ctx: &Context) -> Result<Vec<(usize, Target)>> {
+        let mut batch = Vec::new();
+        while !self.finished && batch.len() < ctx.config.uniprot_batch_size.max(1) {
+            let Some(target) = self.receiver.recv().await else {
+                self.finished = true;
+                if self.dedup.collapsed > 0 {
+                    info!(
+                        "Collapsed {} duplicate targets, {} left",
+                        self.dedup.collapsed, self.dedup.kept
+                    );
+                }
+                break;
+            };
+            let target = target?;
+            if !self.dedup.keep(&target) {
+                continue;
+            }
+            let position = self.dedup.kept - 1;
+            if self.selection.is_past(position) {
+                //Nothing further is wanted, the reader stops with the receiver
+                self.finished = true;
+                break;
+            }
+            if !self.selection.contains(position, &target) {
+                continue;
+            }
+            if let Some(retrying) = &*ctx.retrying.lock().unwrap() {
+                if !retrying.contains(&target.chembl_id) {
+                    continue;
+                }
+            }
+            batch.push((position, target));
+        }
+        let (positions, mut targets): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        idmapping::map_targets(ctx, &mut targets).await?;
+        self.count += targets.len();
+        Ok(positions.into_iter().zip(targets).collect())
+    }
+}
+
+//The part of the input `filter` keeps
+struct Selection {
+    offset: usize,
+    end: Option<usize>,
+    chembl_ids: HashSet<String>,
+    name_pattern: Option<RegexMatcher>,
+}
+
+impl Selection {
+    fn new(filter: &TargetFilter) -> Result<Self> {
+        Ok(Selection {
+            offset: filter.offset,
+            end: filter.limit.map(|limit| filter.offset + limit),
+            chembl_ids: filter
+                .chembl_ids
+                .iter()
+                .map(|id| id.trim().to_string())
+                .collect(),
+            name_pattern: filter
+                .name_pattern
+                .as_deref()
+                .map(RegexMatcher::new)
+                .transpose()?,
+        })
+    }
+
+    fn is_past(&self, position: usize) -> bool {
+        self.end.is_some_and(|end| position >= end)
+    }
+
+    fn contains(&self, position: usize, target: &Target) -> bool {
+        position >= self.offset
+            && (self.chembl_ids.is_empty() || self.chembl_ids.contains(&target.chembl_id))
+            && self.name_pattern.as_ref().is_none_or(|pattern| {
+                pattern
+                    .is_match(target.target_name.as_bytes())
+                    .unwrap_or_default()
+            })
+    }
+}
+
+/// Send the targets of the lists at `paths` one by one, stopping at the first error or once
+/// nobody receives them.
+pub(crate) fn send_targets(
+    config: &UserConfig,
+    paths: &[PathBuf],
+    sender: &mpsc::Sender<Result<Target>>,
+) {
+    for path in paths {
+        //"-" is read from stdin, so targets can be piped in
+        let reader: Box<dyn Read> = match path == Path::new("-") {
+            true => Box::new(std::io::stdin()),
+            false => match File::open(path) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    let _ = sender.blocking_send(Err(e.into()));
+                    return;
+                }
+            },
+        };
+        let targets = match read_targets(
+            reader,
+            resolve_format(config.input_format, path),
+            config.input_delimiter,
+            &config.columns,
+        ) {
+            Ok(targets) => targets,
+            Err(e) => {
+                let _ = sender.blocking_send(Err(e));
+                return;
+            }
+        };
+        for target in targets {
+            let failed = target.is_err();
+            if sender.blocking_send(target).is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+/// Format of the target list at `path`, told by its extension unless the config sets it.
+pub(crate) fn resolve_format(format: InputFormat, path: &Path) -> InputFormat {
+    if format != InputFormat::Auto {
+        return format;
+    }
+    match path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .as_deref()
+    {
+        Some("tsv" | "tab") => InputFormat::Tsv,
+        Some("jsonl" | "ndjson") => InputFormat::Jsonl,
+        Some("xlsx" | "xlsm") => InputFormat::Xlsx,
+        _ => InputFormat::Csv,
+    }
+}
+
+/// Targets of a list in `format` whose first row is a header, parsed as they are read.
+///
+/// Fields are found by the columns of the config, by their usual header names, or by position
+/// as chembl_id, target_name and uniprot_accession when the header has none of them.
+///
+/// CSV is split on `delimiter`, or when unset on whichever of `;` (as ChEMBL exports it), `,`
+/// and tab the header line has most of. Workbooks are read whole.
+pub(crate) fn read_targets<'a>(
+    reader: impl Read + 'a,
+    format: InputFormat,
+    delimiter: Option<char>,
+    columns: &Columns,
+) -> Result<Box<dyn Iterator<Item = Result<Target>> + 'a>> {
+    let mut reader = BufReader::with_capacity(1 << 16, reader);
+    let delimiter = match (format, delimiter) {
+        (InputFormat::Jsonl, _) => return Ok(Box::new(read_jsonl(reader, columns.clone()))),
+        (InputFormat::Xlsx, _) => {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            let mut rows = read_xlsx(&data)?.into_iter();
+            let Some(header) = rows.next() else {
+                return Ok(Box::new(std::iter::empty()));
+            };
+            let fields = Fields::find(&header, columns)?;
+            return Ok(Box::new(rows.map(move |row| {
+                Ok(fields.target(|i| row.get(i).map(String::as_str)))
+            })));
+        }
+        (InputFormat::Tsv, _) => b'\t',
+        (_, Some(delimiter)) => delimiter as u8,
+        (_, None) => sniff_delimiter(reader.fill_buf()?),
+    };
+    let mut rows = ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader)
+        .into_records();
+    let header = match rows.next() {
+        Some(header) => header?.iter().map(str::to_string).collect::<Vec<_>>(),
+        None => return Ok(Box::new(std::iter::empty())),
+    };
+    let fields = Fields::find(&header, columns)?;
+    Ok(Box::new(rows.map(move |row| {
+        let row = row?;
+        Ok(fields.target(|i| row.get(i)))
+    })))
+}
+
+//Column of each field in the rows of a list
+struct Fields {
+    chembl_id: usize,
+    target_name: usize,
+    uniprot_accession: usize,
+    priority: Option<usize>,
+}
+
+impl Fields {
+    fn find(header: &[String], columns: &Columns) -> Result<Self> {
+        Ok(Fields {
+            chembl_id: column_index(header, columns.chembl_id.as_ref(), CHEMBL_ID, 0)?,
+            target_name: column_index(header, columns.target_name.as_ref(), TARGET_NAME, 1)?,
+            uniprot_accession: column_index(
+                header,
+                columns.uniprot_accession.as_ref(),
+                UNIPROT_ACCESSION,
+                2,
+            )?,
+            priority: match &columns.priority {
+                Some(column) => Some(column_index(header, Some(column), &[], 0)?),
+                None => header
+                    .iter()
+                    .position(|cell| cell.trim().eq_ignore_ascii_case(PRIORITY)),
+            },
+        })
+    }
+
+    fn target<'a>(&self, cell: impl Fn(usize) -> Option<&'a str>) -> Target {
+        let cell = |i: usize| cell(i).unwrap_or_default().to_string();
+        Target {
+            chembl_id: cell(self.chembl_id),
+            target_name: cell(self.target_name),
+            uniprot_accession: cell(self.uniprot_accession),
+            priority: self.priority.and_then(|i| cell(i).trim().parse().ok()),
+        }
+    }
+}
+
+fn column_index(
+    header: &[String],
+    column: Option<&Column>,
+    names: &[&str],
+    position: usize,
+) -> Result<usize> {
+    let find = |name: &str| {
+        header
+            .iter()
+            .position(|cell| cell.trim().eq_ignore_ascii_case(name))
+    };
+    match column {
+        Some(Column::Index(index)) => Ok(*index),
+        Some(Column::Name(name)) => {
+            find(name).ok_or_else(|| anyhow!("No column \"{}\" in the header {:?}", name, header))
+        }
+        None => Ok(names.iter().find_map(|name| find(name)).unwrap_or(position)),
+    }
+}
+
+//The most frequent of ';', ',' and tab on the header line, ';' on ties
+fn sniff_delimiter(data: &[u8]) -> u8 {
+    let header = data.split(|byte| *byte == b'\n').next().unwrap_or_default();
+    let count = |delimiter: u8| header.iter().filter(|byte| **byte == delimiter).count();
+    [b';', b',', b'\t']
+        .into_iter()
+        .rev()
+        .max_by_key(|delimiter| count(*delimiter))
+        .unwrap_or(b';')
+}
+
+//Rows of the first sheet
+fn read_xlsx(data: &[u8]) -> Result<Vec<Vec<String>>> {
+    let mut workbook = Xlsx::new(Cursor::new(data))?;
+    let range = match workbook.worksheet_range_at(0) {
+        Some(range) => range?,
+        None => bail!("The workbook has no sheet"),
+    };
+    Ok(range
+        .rows()
+        .map(|row| {
+            row.iter()
+                .map(|cell| match cell {
+                    Data::Empty => String::new(),
+                    cell => cell.to_string(),
+                })
+                .collect()
+        })
+        .collect())
+}
+
+//One object per line, fields found by the names of the columns
+fn read_jsonl(reader: impl BufRead, columns: Columns) -> impl Iterator<Item = Result<Target>> {
+    reader
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(move |line| {
+            let object: Value = serde_json::from_str(&line?)?;
+            let field = |column: Option<&Column>, names: &[&str]| -> Result<String> {
+                let value = match column {
+                    Some(Column::Name(name)) => &object[name.as_str()],
+                    Some(Column::Index(_)) => bail!("JSON Lines columns are picked by name"),
+                    None => names
+                        .iter()
+                        .map(|name| &object[*name])
+                        .find(|value| !value.is_null())
+                        .unwrap_or(&Value::Null),
+                };
+                Ok(match value {
+                    Value::Null => String::new(),
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                })
+            };
+            Ok(Target {
+                chembl_id: field(columns.chembl_id.as_ref(), CHEMBL_ID)?,
+                target_name: field(columns.target_name.as_ref(), TARGET_NAME)?,
+                uniprot_accession: field(columns.uniprot_accession.as_ref(), UNIPROT_ACCESSION)?,
+                priority: field(columns.priority.as_ref(), &[PRIORITY])?
+                    .trim()
+                    .parse()
+                    .ok(),
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_delimiter_counts_the_header_only() {
+        assert_eq!(sniff_delimiter(b"a;b;c\n1,2,3,4,5\n"), b';');
+        assert_eq!(sniff_delimiter(b"a,b,c\n"), b',');
+        assert_eq!(sniff_delimiter(b"a\tb\tc"), b'\t');
+        assert_eq!(sniff_delimiter(b"Name, with comma;Id;Type\n"), b';');
+    }
+
+    #[test]
+    fn sniff_delimiter_prefers_semicolons_then_commas() {
+        //ChEMBL exports use semicolons
+        assert_eq!(sniff_delimiter(b"chembl_id\n"), b';');
+        assert_eq!(sniff_delimiter(b""), b';');
+        assert_eq!(sniff_delimiter(b"a;b,c\n"), b';');
+        assert_eq!(sniff_delimiter(b"a,b\tc\n"), b',');
+    }
+
+    fn target(chembl_id: &str, uniprot_accession: &str) -> Target {
+        Target {
+            chembl_id: chembl_id.to_string(),
+            target_name: String::new(),
+            uniprot_accession: uniprot_accession.to_string(),
+            priority: None,
+        }
+    }
+
+    #[test]
+    fn dedup_keeps_the_first_of_a_key() {
+        let mut dedup = Dedup::new(DedupKey::ChemblId);
+        assert!(dedup.keep(&target("CHEMBL1", "P12345")));
+        assert!(!dedup.keep(&target("CHEMBL1", "Q9UHC1")));
+        assert!(dedup.keep(&target("CHEMBL2", "P12345")));
+        assert_eq!((dedup.kept, dedup.collapsed), (2, 1));
+        let mut dedup = Dedup::new(DedupKey::None);
+        assert!(dedup.keep(&target("CHEMBL1", "P12345")));
+        assert!(dedup.keep(&target("CHEMBL1", "P12345")));
+    }
+
+    #[test]
+    fn dedup_of_accessions_ignores_their_order_and_keeps_targets_without() {
+        let mut dedup = Dedup::new(DedupKey::UniprotAccession);
+        assert!(dedup.keep(&target("CHEMBL1", "P12345|Q9UHC1")));
+        assert!(!dedup.keep(&target("CHEMBL2", "Q9UHC1|P12345")));
+        assert!(!dedup.keep(&target("CHEMBL3", " P12345 | Q9UHC1 ")));
+        assert!(dedup.keep(&target("CHEMBL4", "")));
+        assert!(dedup.keep(&target("CHEMBL5", "")));
+        assert!(dedup.keep(&target("CHEMBL6", "|")));
+        assert_eq!((dedup.kept, dedup.collapsed), (4, 2));
+    }
+}
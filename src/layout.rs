@@ -0,0 +1,419 @@
+use crate::config::UserConfig;
+use crate::pipeline::Target;
+use crate::state::StateStore;
+use anyhow::{bail, Result};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Record of the `path_template` a save path was laid out by.
+pub(crate) const LAYOUT_FILE: &str = "layout.json";
+//What save paths were laid out by before the template was recorded
+const LEGACY_TEMPLATE: &str = "{index}/{target_name}/{uniprot}/{file}";
+
+const TARGET_FIELDS: &[&str] = &["index", "chembl_id", "target_name"];
+const ACCESSION_FIELDS: &[&str] = &["uniprot"];
+const FILE_FIELDS: &[&str] = &["pdb_id", "format", "file", "stem", "ext"];
+const HASH_LENGTH: usize = 8;
+//Names Windows keeps for devices, whatever the extension
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM0", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7",
+    "COM8", "COM9", "COM¹", "COM²", "COM³", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9", "LPT¹", "LPT²", "LPT³",
+];
+
+/// Where files go below the save path, parsed from `path_template`.
+///
+/// The last component names the coordinate files of PDB entries, the one before is the folder
+/// of a UniProt accession and the rest is the folder of a target.
+#[derive(Debug, Clone)]
+pub(crate) struct Layout {
+    target: Vec<String>,
+    accession: String,
+    file: String,
+    replacement: char,
+    max_name_length: usize,
+}
+
+impl Layout {
+    pub fn parse(config: &UserConfig) -> Result<Layout> {
+        let template = config.path_template.as_str();
+        if is_forbidden(config.path_replacement) {
+            bail!(
+                "path_replacement {:?} can't be used in file names",
+                config.path_replacement
+            );
+        }
+        if config.max_name_length < HASH_LENGTH + 2 {
+            bail!("max_name_length must be at least {}", HASH_LENGTH + 2);
+        }
+        let mut components = template.split('/').map(str::to_string).collect::<Vec<_>>();
+        if components.len() < 3 {
+            bail!(
+                "path_template \"{}\" needs a target folder, an accession folder and a file name",
+                template
+            );
+        }
+        let file = components.pop().unwrap_or_default();
+        let accession = components.pop().unwrap_or_default();
+        for component in &components {
+            check(template, component, TARGET_FIELDS)?;
+        }
+        check(template, &accession, ACCESSION_FIELDS)?;
+        check(template, &file, FILE_FIELDS)?;
+        if !components.iter().any(|component| component.contains('{')) {
+            bail!(
+                "The target folder of path_template \"{}\" must tell targets apart with {{index}}, {{chembl_id}} or {{target_name}}",
+                template
+            );
+        }
+        if !accession.contains("{uniprot}") {
+            bail!(
+                "The accession folder of path_template \"{}\" must use {{uniprot}}",
+                template
+            );
+        }
+        if !["{file}", "{pdb_id}", "{stem}"]
+            .iter()
+            .any(|field| file.contains(field))
+        {
+            bail!(
+                "The file name of path_template \"{}\" must use {{file}}, {{pdb_id}} or {{stem}}",
+                template
+            );
+        }
+        Ok(Layout {
+            target: components,
+            accession,
+            file,
+            replacement: config.path_replacement,
+            max_name_length: config.max_name_length,
+        })
+    }
+
+    /// Number of folders between the save path and the files of an accession.
+    pub fn depth(&self) -> usize {
+        self.target.len() + 1
+    }
+
+    /// Whether the target folders depend on the position of targets in the input.
+    pub fn uses_index(&self) -> bool {
+        self.target
+            .iter()
+            .any(|component| component.contains("{index}"))
+    }
+
+    /// Whether files are named the same as by `other` within the folders of targets.
+    pub fn same_below_target(&self, other: &Layout) -> bool {
+        self.accession == other.accession
+            && self.file == other.file
+            && self.replacement == other.replacement
+            && self.max_name_length == other.max_name_length
+    }
+
+    /// Split `path`, relative to the save path, into its target folder and the rest.
+    pub fn split_target<'a>(&self, path: &'a str) -> Option<(PathBuf, &'a Path)> {
+        let path = Path::new(path);
+        let target = path
+            .components()
+            .take(self.target.len())
+            .collect::<PathBuf>();
+        if target.components().count() < self.target.len() {
+            return None;
+        }
+        Some((target.clone(), path.strip_prefix(target).ok()?))
+    }
+
+    /// Folder of the `index`th target of the input.
+    pub fn target_dir(&self, save_path: &Path, index: usize, target: &Target) -> PathBuf {
+        let index = index.to_string();
+        let fields = [
+            ("index", index.as_str()),
+            ("chembl_id", target.chembl_id.as_str()),
+            ("target_name", target.target_name.as_str()),
+        ];
+        let mut path = save_path.to_path_buf();
+        for component in &self.target {
+            path.push(self.sanitize(&render(component, &fields)));
+        }
+        path
+    }
+
+    /// Folder of `accession` in the folder of its target.
+    pub fn accession_dir(&self, target_dir: &Path, accession: &str) -> PathBuf {
+        target_dir.join(self.sanitize(&render(&self.accession, &[("uniprot", accession)])))
+    }
+
+    /// Name the coordinates of `pdb_id` are saved under, `file` being the name on the mirror.
+    pub fn file_name(&self, pdb_id: &str, format: Option<&str>, file: &str) -> String {
+        let (stem, ext) = file.split_once('.').unwrap_or((file, ""));
+        self.sanitize(&render(
+            &self.file,
+            &[
+                ("pdb_id", pdb_id),
+                ("format", format.unwrap_or_default()),
+                ("file", file),
+                ("stem", stem),
+                ("ext", ext),
+            ],
+        ))
+    }
+
+    /// Make `name` a valid file name on Linux, macOS and Windows alike.
+    ///
+    /// Forbidden characters become the replacement, trailing dots and spaces are dropped,
+    /// reserved Windows names get the replacement after their stem, which devices are told by,
+    /// and names longer than `max_name_length` bytes are cut, ending with a hash of the full
+    /// name instead.
+    pub fn sanitize(&self, name: &str) -> String {
+        let mut sanitized = name
+            .chars()
+            .map(|c| if is_forbidden(c) { self.replacement } else { c })
+            .collect::<String>();
+        sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+        let stem = sanitized.split('.').next().unwrap_or_default();
+        if sanitized.is_empty() {
+            sanitized.push(self.replacement);
+        } else if RESERVED.contains(&stem.to_uppercase().as_str()) {
+            sanitized.insert(stem.len(), self.replacement);
+        }
+        if sanitized.len() > self.max_name_length {
+            let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+            let mut end = self.max_name_length - HASH_LENGTH - 1;
+            while !sanitized.is_char_boundary(end) {
+                end -= 1;
+            }
+            sanitized = format!("{}-{}", &sanitized[..end], &hash[..HASH_LENGTH]);
+        }
+        sanitized
+    }
+}
+
+//Components must only use the `fields` known at their level
+fn check(template: &str, component: &str, fields: &[&str]) -> Result<()> {
+    if component.is_empty() || component == "." || component == ".." {
+        bail!(
+            "path_template \"{}\" has an empty, \".\" or \"..\" component",
+            template
+        );
+    }
+    let mut rest = component;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed placeholder in path_template \"{}\"", template);
+        };
+        let field = &rest[start + 1..start + end];
+        if !fields.contains(&field) {
+            bail!(
+                "{{{}}} can't be used in \"{}\" of path_template \"{}\", use one of {:?}",
+                field,
+                component,
+                template,
+                fields
+            );
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+fn is_forbidden(c: char) -> bool {
+    c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+}
+
+fn render(component: &str, fields: &[(&str, &str)]) -> String {
+    let mut rendered = component.to_string();
+    for (field, value) in fields {
+        rendered = rendered.replace(&format!("{{{}}}", field), value);
+    }
+    rendered
+}
+
+/// The naming options a save path was laid out by, as kept in its `layout.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct LayoutRecord {
+    pub path_template: String,
+    //Those of the config when not recorded
+    #[serde(default)]
+    pub path_replacement: Option<char>,
+    #[serde(default)]
+    pub max_name_length: Option<usize>,
+}
+
+impl LayoutRecord {
+    pub fn of(config: &UserConfig) -> LayoutRecord {
+        LayoutRecord {
+            path_template: config.path_template.clone(),
+            path_replacement: Some(config.path_replacement),
+            max_name_length: Some(config.max_name_length),
+        }
+    }
+
+    /// The layout recorded, with the naming options of `config` for those that aren't.
+    pub fn layout(&self, config: &UserConfig) -> Result<Layout> {
+        Layout::parse(&UserConfig {
+            path_template: self.path_template.clone(),
+            path_replacement: self.path_replacement.unwrap_or(config.path_replacement),
+            max_name_length: self.max_name_length.unwrap_or(config.max_name_length),
+            ..config.clone()
+        })
+    }
+
+    /// Whether files are named as `config` names them.
+    pub fn matches(&self, config: &UserConfig) -> bool {
+        self.path_template == config.path_template
+            && self
+                .path_replacement
+                .is_none_or(|replacement| replacement == config.path_replacement)
+            && self
+                .max_name_length
+                .is_none_or(|length| length == config.max_name_length)
+    }
+}
+
+impl std::fmt::Display for LayoutRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "path_template \"{}\"", self.path_template)?;
+        if let Some(replacement) = self.path_replacement {
+            write!(f, ", path_replacement {:?}", replacement)?;
+        }
+        if let Some(length) = self.max_name_length {
+            write!(f, ", max_name_length {}", length)?;
+        }
+        Ok(())
+    }
+}
+
+/// The layout `save_path` was laid out by, if anything was downloaded to it yet.
+///
+/// Save paths from before the layout was recorded are taken as laid out by `config`, or by
+/// the `{index}` folders of old when their target folders are all numbers.
+pub(crate) fn recorded(
+    save_path: &Path,
+    config: &UserConfig,
+    state: &StateStore,
+) -> Result<Option<LayoutRecord>> {
+    let path = save_path.join(LAYOUT_FILE);
+    if path.exists() {
+        return Ok(Some(serde_json::from_slice(&fs::read(path)?)?));
+    }
+    let files = state.files();
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let numbered = files
+        .iter()
+        .all(|record| match Path::new(&record.path).components().next() {
+            Some(Component::Normal(first)) => first
+                .to_str()
+                .is_some_and(|first| first.bytes().all(|byte| byte.is_ascii_digit())),
+            _ => false,
+        });
+    if numbered && !config.path_template.starts_with("{index}") {
+        return Ok(Some(LayoutRecord {
+            path_template: LEGACY_TEMPLATE.to_string(),
+            path_replacement: None,
+            max_name_length: None,
+        }));
+    }
+    Ok(Some(LayoutRecord::of(config)))
+}
+
+/// Record `layout` as what `save_path` is laid out by.
+pub(crate) fn record(save_path: &Path, layout: &LayoutRecord) -> Result<()> {
+    fs::write(
+        save_path.join(LAYOUT_FILE),
+        serde_json::to_string_pretty(layout)?,
+    )?;
+    Ok(())
+}
+
+/// Make sure `save_path` is laid out as `config` lays files out, as they would otherwise be
+/// downloaded again next to those of the old layout.
+pub(crate) fn check_recorded(
+    save_path: &Path,
+    config: &UserConfig,
+    state: &StateStore,
+) -> Result<()> {
+    match recorded(save_path, config, state)? {
+        Some(recorded) if !recorded.matches(config) => bail!(
+            "{} is laid out by {}, run migrate-layout to move its files to {} or set the config back",
+            save_path.display(),
+            recorded,
+            LayoutRecord::of(config)
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(max_name_length: usize) -> Layout {
+        Layout {
+            target: vec!["{chembl_id}".to_string()],
+            accession: "{uniprot}".to_string(),
+            file: "{file}".to_string(),
+            replacement: '_',
+            max_name_length,
+        }
+    }
+
+    #[test]
+    fn sanitize_replaces_forbidden_characters() {
+        let layout = layout(200);
+        assert_eq!(layout.sanitize("a:b/c*d?e"), "a_b_c_d_e");
+        assert_eq!(layout.sanitize("tab\there"), "tab_here");
+        assert_eq!(layout.sanitize("Kinase (human)"), "Kinase (human)");
+    }
+
+    #[test]
+    fn sanitize_avoids_names_windows_refuses() {
+        let layout = layout(200);
+        assert_eq!(layout.sanitize("name. ."), "name");
+        assert_eq!(layout.sanitize(""), "_");
+        assert_eq!(layout.sanitize("..."), "_");
+        assert_eq!(layout.sanitize("CON"), "CON_");
+        assert_eq!(layout.sanitize("com1.txt"), "com1_.txt");
+        assert_eq!(layout.sanitize("nul.tar.gz"), "nul_.tar.gz");
+        assert_eq!(layout.sanitize("LPT0"), "LPT0_");
+        assert_eq!(layout.sanitize("com².log"), "com²_.log");
+        assert_eq!(layout.sanitize("CONSOLE"), "CONSOLE");
+    }
+
+    #[test]
+    fn sanitize_cuts_long_names_with_a_hash() {
+        let layout = layout(20);
+        let long = "a".repeat(30);
+        let cut = layout.sanitize(&long);
+        assert_eq!(cut.len(), 20);
+        assert!(cut.starts_with(&"a".repeat(11)));
+        assert_eq!(cut.as_bytes()[11], b'-');
+        //Names cut to the same start still differ
+        assert_ne!(cut, layout.sanitize(&format!("{}b", long)));
+        //Within the limit, counted in bytes, on a character boundary
+        let cut = layout.sanitize(&"é".repeat(15));
+        assert!(cut.len() <= 20);
+        assert!(cut.starts_with("ééééé-"));
+        assert_eq!(layout.sanitize(&"a".repeat(20)), "a".repeat(20));
+    }
+
+    #[test]
+    fn check_allows_only_the_fields_of_the_level() {
+        let template = "{chembl_id}/{uniprot}/{file}";
+        assert!(check(template, "{chembl_id}_{target_name}", TARGET_FIELDS).is_ok());
+        assert!(check(template, "targets", TARGET_FIELDS).is_ok());
+        assert!(check(template, "{pdb_id}.{ext}", FILE_FIELDS).is_ok());
+        let error = check(template, "{pdb_id}", TARGET_FIELDS).unwrap_err();
+        assert!(error.to_string().contains("{pdb_id} can't be used"));
+        assert!(check(template, "{uniprot", ACCESSION_FIELDS)
+            .unwrap_err()
+            .to_string()
+            .contains("Unclosed placeholder"));
+        for component in ["", ".", ".."] {
+            assert!(check(template, component, TARGET_FIELDS).is_err());
+        }
+    }
+}
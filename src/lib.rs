@@ -0,0 +1,103 @@
+//! Download PDB structures for ChEMBL targets.
+//!
+//! The binary is a thin CLI over [`Pipeline`], which can be embedded directly:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use project_med::{Pipeline, UserConfig};
+//!
+//! let config = UserConfig::from_file("./config.toml")?;
+//! let pipeline = Pipeline::builder(config).save_path("./out").build()?;
+//! pipeline.run().await?;
+//! # Ok(())
+//! # }
+//! ```
+#[macro_use]
+extern crate log;
+
+mod alphafold;
+mod archive;
+mod assembly;
+mod bandwidth;
+mod breaker;
+mod cache;
+mod checksum;
+mod chembl;
+mod clean;
+mod cluster;
+mod compress;
+mod config;
+mod coverage;
+mod daemon;
+mod dashboard;
+mod database;
+mod disk;
+mod download;
+mod email;
+mod emdb;
+mod esmfold;
+mod export;
+mod grpc;
+mod http;
+mod idmapping;
+mod input;
+mod layout;
+mod lock;
+mod logging;
+mod manifest;
+mod metrics;
+mod migrate;
+mod mirrors;
+mod notify;
+mod order;
+mod pdb_index;
+mod pipeline;
+mod plan;
+mod progress;
+mod rcsb;
+mod report;
+mod retry;
+mod s3;
+mod select;
+mod server;
+mod shutdown;
+mod sifts;
+mod source;
+mod state;
+mod status;
+mod storage;
+mod structure;
+mod summary;
+mod sums;
+mod swissmodel;
+mod telemetry;
+mod transform;
+mod uniprot;
+mod update;
+mod validation;
+mod verify;
+mod watch;
+
+pub use chembl::Activity;
+pub use config::{
+    ArchiveFormat, Assemblies, AuthConfig, Bandwidth, BandwidthWindow, BreakerPolicy,
+    ChainSelection, ChemblConfig, Cleanup, ClusterConfig, Column, Columns, CompoundFormat,
+    DataFormat, DedupKey, DiskSpacePolicy, EmailConfig, HttpConfig, InputFormat, IsoformPolicy,
+    LinkMode, LogFormat, MirrorProbe, ObsoletePolicy, ProxyConfig, Ranking, RateLimit, RetryPolicy,
+    S3Config, Schedule, SegmentedDownload, SmtpTls, Source, TargetFilter, TargetOrder, UserConfig,
+    WebhookConfig,
+};
+pub use download::Downloaded;
+pub use http::HttpError;
+pub use logging::{init_logging, log_filter, set_log_filter};
+pub use manifest::ManifestEntry;
+pub use pipeline::{InputSource, Pipeline, PipelineBuilder, RunEvent, Target};
+pub use plan::PlannedFile;
+pub use report::ReportFormat;
+pub use source::{SourceContext, StructureSource, UniprotPdb};
+pub use storage::{LocalStorage, Storage};
+pub use summary::{FailedItem, FailedTarget, RunSummary};
+pub use telemetry::{init_telemetry, Telemetry};
+pub use uniprot::{
+    split_isoform, CrossReference, Organism, PdbReference, Property, Sequence, UniprotEntry,
+};
@@ -0,0 +1,120 @@
+use anyhow::{bail, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+#[cfg(not(unix))]
+use std::path::PathBuf;
+
+pub(crate) const LOCK_FILE: &str = "prog_med.lock";
+
+/// Lock of a save path, held by the pipeline using it and released when dropped.
+///
+/// The file keeps the PID of the holder for the message of the instances refused. Instances
+/// going on with `--force` leave it to the holder.
+pub(crate) struct RunLock {
+    _file: Option<File>,
+    //Removed on drop where the lock is the file itself, unless another instance holds it
+    #[cfg(not(unix))]
+    path: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl RunLock {
+    //An advisory lock, which the system releases with the process however it ends
+    pub fn acquire(save_path: &Path, force: bool) -> Result<Self> {
+        use rustix::fs::{flock, FlockOperation};
+        let path = save_path.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match flock(&file, FlockOperation::NonBlockingLockExclusive) {
+            Ok(()) => {}
+            Err(rustix::io::Errno::WOULDBLOCK) => {
+                refuse(&path, force)?;
+                return Ok(RunLock { _file: None });
+            }
+            Err(e) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(RunLock { _file: Some(file) })
+    }
+}
+
+#[cfg(not(unix))]
+impl RunLock {
+    //A file created by the holder, left behind by a crash until --force or its removal
+    pub fn acquire(save_path: &Path, force: bool) -> Result<Self> {
+        let path = save_path.join(LOCK_FILE);
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                refuse(&path, force)?;
+                return Ok(RunLock {
+                    _file: None,
+                    path: None,
+                });
+            }
+            Err(e) => return Err(e.into()),
+        };
+        write!(file, "{}", std::process::id())?;
+        Ok(RunLock {
+            _file: Some(file),
+            path: Some(path),
+        })
+    }
+}
+
+#[cfg(not(unix))]
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+//Fail on a save path held by another instance, unless forced
+fn refuse(path: &Path, force: bool) -> Result<()> {
+    let holder = std::fs::read_to_string(path).unwrap_or_default();
+    let holder = match holder.trim() {
+        "" => "another instance".to_string(),
+        pid => format!("process {}", pid),
+    };
+    if !force {
+        bail!(
+            "The save path is in use, {} is held by {}. Pass --force to run anyway",
+            path.display(),
+            holder
+        );
+    }
+    warn!(
+        "The save path is in use by {}, going on as --force asks",
+        holder
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forced_instances_leave_the_lock_file_to_its_holder() {
+        let save_path = std::env::temp_dir().join(format!("prog_med_lock_{}", std::process::id()));
+        std::fs::create_dir_all(&save_path).unwrap();
+        let path = save_path.join(LOCK_FILE);
+        let held = RunLock::acquire(&save_path, false).unwrap();
+        //As another process holding it would have written
+        std::fs::write(&path, "1").unwrap();
+        assert!(RunLock::acquire(&save_path, false).is_err());
+        let forced = RunLock::acquire(&save_path, true).unwrap();
+        drop(forced);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1");
+        drop(held);
+        std::fs::remove_dir_all(&save_path).unwrap();
+    }
+}
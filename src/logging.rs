@@ -0,0 +1,572 @@
+use crate::config::{LogFormat, UserConfig};
+use crate::dashboard;
+use crate::download::Downloaded;
+use crate::export::DownloadStat;
+use crate::pipeline::{Context, Target};
+use crate::telemetry;
+use anyhow::Result;
+use chrono::{Local, Utc};
+use log::LevelFilter;
+use log4rs::config::{Config, Deserializers, Logger, RawConfig, Root};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_log::{AsLog, AsTrace, NormalizeEvent};
+use tracing_subscriber::filter::{self, Targets};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Log of the events of a target, in its folder.
+pub(crate) const TARGET_LOG: &str = "download.log";
+
+//Levels by target, which set_log_filter changes at runtime
+static FILTER: RwLock<Option<Targets>> = RwLock::new(None);
+//Logs of the targets being processed with target_logs, by ChEMBL ID
+static TARGET_LOGS: Mutex<BTreeMap<String, File>> = Mutex::new(BTreeMap::new());
+
+/// Set up logging from `log_config`, adding the JSON event log `log_format` asks for.
+///
+/// Records of the `log` macros and `tracing` events go through one subscriber, with the fields
+/// of the spans they happen in (chembl_id, accession, pdb_id...) as context. Console appenders
+/// of `log_config` are replaced by its console output, written to the log pane of the dashboard
+/// while it's open with `tui`, while its other appenders and loggers keep writing as they did. Levels come from
+/// `RUST_LOG`, `log_filter` or else `log_config`.
+pub fn init_logging(config: &UserConfig) -> Result<()> {
+    let log_config = LogConfig::read(config)?;
+    let levels = match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.trim().is_empty() => directives.parse()?,
+        _ => match &config.log_filter {
+            Some(directives) => directives.parse()?,
+            None => log_config.levels,
+        },
+    };
+    *FILTER.write().unwrap() = Some(levels);
+
+    let console = log_config.console.then(|| {
+        let (writer, ansi) = if config.tui {
+            (BoxMakeWriter::new(|| dashboard::LogPane), false)
+        } else {
+            (
+                BoxMakeWriter::new(std::io::stdout),
+                console::Term::stdout().is_term(),
+            )
+        };
+        let separate = log_config.separate;
+        tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(ansi)
+            .with_filter(filter::filter_fn(move |metadata| {
+                metadata.is_span() || !separate.iter().any(|name| within(metadata.target(), name))
+            }))
+    });
+    let json = match config.log_format {
+        LogFormat::Text => None,
+        _ => Some(JsonLog::open(&config.json_log)?),
+    };
+    tracing_subscriber::registry()
+        .with(telemetry::layer(config)?)
+        .with(filter::dynamic_filter_fn(|metadata, _| {
+            //Spans are always there, for exported traces and the context of events
+            metadata.is_span()
+                || FILTER
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .is_none_or(|levels| levels.would_enable(metadata.target(), metadata.level()))
+        }))
+        .with(SpanFields)
+        .with(console)
+        .with(Appenders(log_config.logger))
+        .with(json)
+        .with(TargetLogs)
+        .try_init()?;
+    Ok(())
+}
+
+/// Copy the events of `target` into its log until the returned guard is dropped, in the folder
+/// `path_target` or next to its archive.
+pub(crate) fn target_log(ctx: &Context, target: &Target, path_target: &Path) -> Option<TargetLog> {
+    //Archiving removes the folder, the log of its files stays next to the archive
+    let path = match ctx.config.archive {
+        Some(_) => {
+            let mut path = path_target.as_os_str().to_owned();
+            path.push(".log");
+            PathBuf::from(path)
+        }
+        None => path_target.join(TARGET_LOG),
+    };
+    let opened = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path));
+    match opened {
+        Ok(file) => {
+            TARGET_LOGS
+                .lock()
+                .unwrap()
+                .insert(target.chembl_id.clone(), file);
+            Some(TargetLog(target.chembl_id.clone()))
+        }
+        Err(e) => {
+            warn!(
+                "Failed to open the log of {} due to \"{}\"",
+                target.chembl_id, e
+            );
+            None
+        }
+    }
+}
+
+/// Closes the log of a target when dropped.
+pub(crate) struct TargetLog(String);
+
+impl TargetLog {
+    /// End the log with the outcome of the target, its error being logged outside of it.
+    pub fn finished(&self, outcome: &str, error: Option<&anyhow::Error>) {
+        if let Some(file) = TARGET_LOGS.lock().unwrap().get_mut(&self.0) {
+            let error = error.map(|e| format!(" due to \"{:#}\"", e));
+            let _ = writeln!(
+                file,
+                "{} - INFO - {} - {} {}{}",
+                Local::now().to_rfc3339(),
+                module_path!(),
+                self.0,
+                outcome,
+                error.unwrap_or_default()
+            );
+        }
+    }
+}
+
+impl Drop for TargetLog {
+    fn drop(&mut self) {
+        TARGET_LOGS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Replace the levels of the log with `directives`, in the syntax of `RUST_LOG`.
+pub fn set_log_filter(directives: &str) -> Result<()> {
+    let levels = directives.parse::<Targets>()?;
+    info!("Log filter set to {}", directives);
+    *FILTER.write().unwrap() = Some(levels);
+    Ok(())
+}
+
+/// The levels of the log, in the syntax of `RUST_LOG`.
+pub fn log_filter() -> String {
+    FILTER
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(ToString::to_string)
+        .unwrap_or_default()
+}
+
+//Whether `target` is logged by the logger `name`, as log4rs matches them
+fn within(target: &str, name: &str) -> bool {
+    target
+        .strip_prefix(name)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Why the log4rs config at `path` can't set up logging, read as `init_logging` reads it.
+pub(crate) fn check_log_config(path: &str) -> Vec<String> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {} due to \"{}\"", path, e))
+        .and_then(|contents| {
+            serde_yaml::from_str::<RawConfig>(&contents)
+                .map_err(|e| format!("{} isn't a log4rs config: {}", path, e))
+        });
+    let raw = match raw {
+        Ok(raw) => raw,
+        Err(message) => return vec![message],
+    };
+    let (appenders, errors) = raw.appenders_lossy(&Deserializers::default());
+    if !errors.is_empty() {
+        return vec![format!("{} has invalid appenders: {:?}", path, errors)];
+    }
+    match Config::builder()
+        .appenders(appenders)
+        .loggers(raw.loggers())
+        .build(raw.root())
+    {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors
+            .errors()
+            .iter()
+            .map(|e| format!("{}: {}", path, e))
+            .collect(),
+    }
+}
+
+//What of log_config is left to log4rs, its console appenders being a layer of their own
+struct LogConfig {
+    logger: log4rs::Logger,
+    levels: Targets,
+    //Whether the root writes to the console
+    console: bool,
+    //Loggers not passing their records to the root
+    separate: Vec<String>,
+}
+
+impl LogConfig {
+    fn read(config: &UserConfig) -> Result<Self> {
+        let mut yaml: serde_yaml::Value =
+            serde_yaml::from_str(&std::fs::read_to_string(&config.log_config)?)?;
+        let mut consoles = Vec::new();
+        if let Some(appenders) = yaml
+            .get_mut("appenders")
+            .and_then(serde_yaml::Value::as_mapping_mut)
+        {
+            appenders.retain(|name, appender| {
+                let console =
+                    appender.get("kind").and_then(serde_yaml::Value::as_str) == Some("console");
+                if console {
+                    consoles.extend(name.as_str().map(str::to_string));
+                }
+                !console
+            });
+        }
+        let raw: RawConfig = serde_yaml::from_value(yaml)?;
+        let (appenders, mut errors) = raw.appenders_lossy(&Deserializers::default());
+        errors.handle();
+        let kept = |names: &[String]| {
+            names
+                .iter()
+                .filter(|name| !consoles.contains(name))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        //Levels are up to the filter, log4rs only routes records to appenders
+        let root = raw.root();
+        let mut levels = Targets::new().with_default(root.level().as_trace());
+        let mut separate = Vec::new();
+        let mut loggers = Vec::new();
+        for logger in raw.loggers() {
+            levels = levels.with_target(logger.name(), logger.level().as_trace());
+            if !logger.additive() {
+                separate.push(logger.name().to_string());
+            }
+            loggers.push(
+                Logger::builder()
+                    .appenders(kept(logger.appenders()))
+                    .additive(logger.additive())
+                    .build(logger.name(), LevelFilter::Trace),
+            );
+        }
+        //JSON logs take the place of the root appenders
+        let json_only = config.log_format == LogFormat::Json;
+        let mut builder = Root::builder();
+        if !json_only {
+            builder = builder.appenders(kept(root.appenders()));
+        }
+        let (log_config, mut errors) = Config::builder()
+            .appenders(appenders)
+            .loggers(loggers)
+            .build_lossy(builder.build(LevelFilter::Trace));
+        errors.handle();
+        Ok(LogConfig {
+            logger: log4rs::Logger::new(log_config),
+            levels,
+            console: !json_only && root.appenders().iter().any(|name| consoles.contains(name)),
+            separate,
+        })
+    }
+}
+
+//Fields of an event or a span, with the message apart
+#[derive(Default)]
+struct Values {
+    message: String,
+    fields: Vec<(&'static str, Value)>,
+}
+
+impl Values {
+    fn push(&mut self, field: &Field, value: Value) {
+        //Records of the log macros carry where they come from as fields
+        if !field.name().starts_with("log.") {
+            self.fields.push((field.name(), value));
+        }
+    }
+}
+
+impl Visit for Values {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            _ => self.push(field, value.into()),
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            _ => self.push(field, format!("{:?}", value).into()),
+        }
+    }
+}
+
+//Fields of a span, kept in its extensions for the events in it
+struct SpanValues(Vec<(&'static str, Value)>);
+
+struct SpanFields;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanFields {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let mut values = Values::default();
+        attrs.record(&mut values);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanValues(values.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, record: &Record<'_>, ctx: LayerContext<'_, S>) {
+        let mut values = Values::default();
+        record.record(&mut values);
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanValues>() {
+                fields.0.extend(values.fields);
+            }
+        }
+    }
+}
+
+/// The fields of the spans `event` happens in, outer spans first and each field once.
+pub(crate) fn span_fields<S>(ctx: &LayerContext<'_, S>, event: &Event) -> Vec<(&'static str, Value)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let mut fields: Vec<(&'static str, Value)> = Vec::new();
+    for span in ctx
+        .event_scope(event)
+        .into_iter()
+        .flat_map(|scope| scope.from_root())
+    {
+        if let Some(values) = span.extensions().get::<SpanValues>() {
+            for (name, value) in &values.0 {
+                if !fields.iter().any(|(known, _)| known == name) {
+                    fields.push((name, value.clone()));
+                }
+            }
+        }
+    }
+    fields
+}
+
+//The span fields of an event before its message, for the text of the log
+fn with_context(message: &str, fields: &[(&'static str, Value)]) -> String {
+    if fields.is_empty() {
+        return message.to_string();
+    }
+    let context = fields
+        .iter()
+        .map(|(name, value)| match value {
+            Value::String(value) => format!("{}={}", name, value),
+            value => format!("{}={}", name, value),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("{}: {}", context, message)
+}
+
+//The appenders of log_config, which events reach as records
+struct Appenders(log4rs::Logger);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Appenders {
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut values = Values::default();
+        event.record(&mut values);
+        let message = with_context(&values.message, &span_fields(&ctx, event));
+        log::Log::log(
+            &self.0,
+            &log::Record::builder()
+                .level(metadata.level().as_log())
+                .target(metadata.target())
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+}
+
+//One JSON object per event, with the fields of the event and its spans as attributes
+struct JsonLog(Mutex<File>);
+
+impl JsonLog {
+    fn open(path: &str) -> Result<Self> {
+        if let Some(parent) = Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLog(Mutex::new(file)))
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for JsonLog {
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut values = Values::default();
+        event.record(&mut values);
+        let attributes = span_fields(&ctx, event)
+            .into_iter()
+            .chain(values.fields)
+            .map(|(name, value)| (name.to_string(), value))
+            .collect::<Map<_, _>>();
+        let line = serde_json::json!({
+            "time": Local::now().to_rfc3339(),
+            "level": metadata.level().to_string(),
+            "target": metadata.target(),
+            "module_path": metadata.module_path(),
+            "file": metadata.file(),
+            "line": metadata.line(),
+            "thread": std::thread::current().name(),
+            "message": values.message,
+            "attributes": attributes,
+        });
+        let _ = writeln!(self.0.lock().unwrap(), "{}", line);
+    }
+}
+
+//Events of the targets with a log, found by the chembl_id of their spans or their own
+struct TargetLogs;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for TargetLogs {
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let mut logs = TARGET_LOGS.lock().unwrap();
+        if logs.is_empty() {
+            return;
+        }
+        let mut values = Values::default();
+        event.record(&mut values);
+        let mut fields = span_fields(&ctx, event);
+        let chembl_id = fields
+            .iter()
+            .chain(&values.fields)
+            .find(|(name, _)| *name == "chembl_id")
+            .and_then(|(_, value)| value.as_str().map(str::to_string));
+        let Some(file) = chembl_id.and_then(|chembl_id| logs.get_mut(&chembl_id)) else {
+            return;
+        };
+        //What the log is of goes without saying
+        fields.retain(|(name, _)| !matches!(*name, "chembl_id" | "target_name"));
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let _ = writeln!(
+            file,
+            "{} - {} - {} - {}",
+            Local::now().to_rfc3339(),
+            metadata.level(),
+            metadata.target(),
+            with_context(&values.message, &fields)
+        );
+    }
+}
+
+/// Log the outcome of a PDB entry of `target`, with its fields as attributes of the JSON event,
+/// and keep it for the Parquet stats.
+///
+/// Events are only logged with JSON logs, text logs stay as they were.
+pub(crate) fn pdb_event(
+    ctx: &Context,
+    target: &Target,
+    accession: &str,
+    pdb_id: &str,
+    downloaded: Option<&Downloaded>,
+    elapsed: Duration,
+    outcome: &str,
+) {
+    let url = downloaded.map(|downloaded| downloaded.url.as_str());
+    if ctx.config.parquet {
+        ctx.downloads.lock().unwrap().push(DownloadStat {
+            chembl_id: target.chembl_id.clone(),
+            accession: accession.to_string(),
+            pdb_id: pdb_id.to_string(),
+            url: url.map(str::to_string),
+            size: downloaded.map(|downloaded| downloaded.size),
+            duration_ms: elapsed.as_millis() as u64,
+            outcome: outcome.to_string(),
+            finished_at: Utc::now().to_rfc3339(),
+        });
+    }
+    if ctx.config.log_format == LogFormat::Text {
+        return;
+    }
+    tracing::info!(
+        chembl_id = target.chembl_id.as_str(),
+        target_name = target.target_name.as_str(),
+        uniprot = accession,
+        pdb_id = pdb_id,
+        url = url.unwrap_or_default(),
+        duration_ms = elapsed.as_millis() as u64,
+        outcome = outcome,
+        "{} of {} : {}",
+        pdb_id,
+        accession,
+        outcome
+    );
+}
+
+/// Warn that the file downloaded from `url` for `pdb_id` isn't a structure, with the error as
+/// an attribute of the JSON event.
+pub(crate) fn invalid_file(pdb_id: &str, url: &str, error: &anyhow::Error) {
+    let error = error.to_string();
+    tracing::warn!(
+        pdb_id = pdb_id,
+        url = url,
+        error = error.as_str(),
+        outcome = "invalid",
+        "Invalid file of {} from {} due to \"{}\"",
+        pdb_id,
+        url,
+        error
+    );
+}
+
+/// Log that `target` has been processed, `outcome` telling whether anything is left for resume.
+pub(crate) fn target_event(ctx: &Context, target: &Target, elapsed: Duration, outcome: &str) {
+    if ctx.config.log_format == LogFormat::Text {
+        return;
+    }
+    tracing::info!(
+        chembl_id = target.chembl_id.as_str(),
+        target_name = target.target_name.as_str(),
+        duration_ms = elapsed.as_millis() as u64,
+        outcome = outcome,
+        "{} : {}",
+        target.target_name,
+        outcome
+    );
+}
@@ -1,200 +1,322 @@
-use anyhow::Result;
-use csv::ReaderBuilder;
-use reqwest::{Client, Url};
-use serde_derive::Deserialize;
-use std::fs::{create_dir, create_dir_all};
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use project_med::{Pipeline, ReportFormat, TargetOrder, UserConfig};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Semaphore;
-use tokio::task;
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate lazy_static;
 
-#[derive(Deserialize, Debug)]
-struct UserConfig {
-    save_path: String,
-    read_path: String,
-    log_config: String,
-    processor_limit: i64,
-    downloader_limit: i64,
-    download_url: Vec<String>,
+/// Download PDB structures for the ChEMBL targets listed in a CSV export.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Path of the config file, TOML or by its extension YAML or JSON
+    #[arg(short, long, global = true, default_value = "./config.toml")]
+    config: PathBuf,
+    /// Load the fields of `[profile.<name>]` of the config file over the others
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Override `save_path` of the config file
+    #[arg(long, global = true)]
+    save_path: Option<String>,
+    /// Override `read_path` of the config file, repeated for several lists
+    #[arg(long, global = true)]
+    read_path: Vec<String>,
+    /// Read the targets from stdin, as `--read-path -` does
+    #[arg(long, global = true, conflicts_with = "read_path")]
+    stdin: bool,
+    /// Override `processor_limit` of the config file
+    #[arg(long, global = true)]
+    processor_limit: Option<usize>,
+    /// Override `downloader_limit` of the config file
+    #[arg(long, global = true)]
+    downloader_limit: Option<usize>,
+    /// Skip the first targets of the input, as `filter.offset` does
+    #[arg(long, global = true)]
+    offset: Option<usize>,
+    /// Process this many targets at most after `--offset`, as `filter.limit` does
+    #[arg(long, global = true)]
+    limit: Option<usize>,
+    /// Only process these ChEMBL IDs, comma-separated or repeated
+    #[arg(long = "chembl-id", global = true, value_delimiter = ',')]
+    chembl_ids: Vec<String>,
+    /// Only process the targets whose name matches this regular expression
+    #[arg(long, global = true)]
+    name_pattern: Option<String>,
+    /// Override `order` of the config file
+    #[arg(long, global = true, value_enum)]
+    order: Option<OrderKind>,
+    /// Select targets from the ChEMBL API instead of `read_path`, e.g. "organism=Homo sapiens"
+    #[arg(long = "chembl-query", global = true, value_name = "FILTER=VALUE", value_parser = parse_filter)]
+    chembl_query: Vec<(String, String)>,
+    /// Show progress bars on stderr
+    #[arg(long, global = true)]
+    progress: bool,
+    /// Make no request, working from the UniProt cache and local mirrors, as `offline` does
+    #[arg(long, global = true)]
+    offline: bool,
+    /// Show a live dashboard of the run on the terminal, as `tui` does
+    #[arg(long, global = true)]
+    tui: bool,
+    /// Fetch UniProt entries anew instead of reading them from the cache
+    #[arg(long, global = true)]
+    refresh: bool,
+    /// Keep processing the targets appended to the lists of `read_path` (download and resume)
+    #[arg(long, global = true)]
+    watch: bool,
+    /// Run even if another instance holds the save path, let `init` overwrite files
+    #[arg(long, global = true)]
+    force: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-lazy_static! {
-static ref CONFIG: UserConfig = {
-    use std::fs;
-    //Enter your config file path here.
-    let config_path: &Path = Path::new("./config.toml");
-    let contents = fs::read_to_string(config_path).unwrap();
-    toml::from_str(&contents).unwrap()
-};
-static ref CLIENT:Client= Client::new();}
+//Documented config and log config written by `init`
+const STARTER_CONFIG: &str = include_str!("../config.toml");
+const STARTER_LOG_CONFIG: &str = include_str!("../log.yml");
+//Where the starter config looks for the log config
+const LOG_CONFIG_PATH: &str = "log.yml";
 
-#[derive(Deserialize, Debug)]
-struct Target {
-    chembl_id: String,
-    target_name: String,
-    uniprot_accession: String,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write a documented config (at `--config`) and log config into the current folder
+    Init,
+    /// Download structures for every target of `read_path` (default)
+    Download {
+        /// Skip the work recorded as done in the save path, as `resume` does
+        #[arg(long)]
+        resume: bool,
+        /// Only list the files that would be downloaded, into `plan.csv` of the save path
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Continue an interrupted download, skipping the work recorded as done
+    Resume,
+    /// Query every target again and download only the structures released since
+    Update,
+    /// Run `update` at every time of `schedule` in the config, until Ctrl+C
+    Daemon,
+    /// Process again only what failed in the last run, as listed in `failures.csv`
+    RetryFailed,
+    /// Re-hash downloaded files and flag missing, truncated, changed or corrupt ones
+    Verify {
+        /// Remove bad files so that `resume` downloads them again
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Remove stale parts, files missing from the manifest and empty folders
+    Clean {
+        /// Only list what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move target folders made by an older `path_template`, such as `{index}`, to the current one
+    MigrateTargetDirs {
+        /// Only list what would be moved
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move and rename downloaded files laid out by other naming options to the current ones
+    MigrateLayout {
+        /// The `path_template` the files were laid out by, if `layout.json` doesn't record it
+        #[arg(long)]
+        from: Option<String>,
+        /// Only list what would be moved
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Serve a REST API queueing downloads of the targets posted to `/jobs`, until Ctrl+C
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8686")]
+        addr: String,
+    },
+    /// Serve the gRPC API of proto/prog_med.proto, streaming the events of every run, until Ctrl+C
+    Grpc {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        addr: String,
+    },
+    /// Print a summary of the save path
+    Report {
+        /// Also write a report of the last run and the downloads into the save path
+        #[arg(long, value_enum)]
+        format: Option<ReportKind>,
+    },
 }
 
-//Using CONFIG.read_path
-#[tokio::main]
-async fn main() -> Result<()> {
-    log4rs::init_file(&CONFIG.log_config, Default::default()).unwrap();
-    debug!(target:"debug","Config : {:?}", *CONFIG);
-
-    let mut data_bank = File::open(&CONFIG.read_path).await?;
-    let mut data = Vec::new();
-    data_bank.read_to_end(&mut data).await?;
-    let mut rdr = ReaderBuilder::new().delimiter(b';').from_reader(&*data);
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ReportKind {
+    Markdown,
+    Html,
+}
 
-    let mut tasks = Vec::new();
-    let processor_limit = Arc::new(Semaphore::new(CONFIG.processor_limit as usize));
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OrderKind {
+    Input,
+    Priority,
+    FewestStructures,
+    Shuffle,
+}
 
-    for (i, result) in rdr.records().enumerate() {
-        let record = result?;
-        let target: Target = record.deserialize(None)?;
-        let semaphore = processor_limit.clone();
-        let path_grouped = Path::new(&CONFIG.save_path).join(format!("{}", i));
-        if !path_grouped.exists() {
-            create_dir_all(&path_grouped)?;
-        }
-        tasks.push(task::spawn(async move {
-            let permit = semaphore.acquire_owned().await.unwrap();
-            process_data(target, path_grouped).await?;
-            drop(permit);
-            Result::<()>::Ok(())
-        }));
+fn parse_filter(filter: &str) -> Result<(String, String), String> {
+    match filter.split_once('=') {
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => Err(format!("\"{}\" is not FILTER=VALUE", filter)),
     }
+}
 
-    for task in tasks {
-        if let Err(e) = task.await? {
-            error!("Failed to process data due to \"{}\"", e);
+fn init(config: &Path, force: bool) -> Result<()> {
+    let log_config = Path::new(LOG_CONFIG_PATH);
+    for path in [config, log_config] {
+        if path.exists() && !force {
+            bail!("{} exists, pass --force to overwrite it", path.display());
         }
     }
-    info!("Procedure completed successfully. Exiting...");
+    //Placeholders of the reference config replaced by paths that work once the list is there
+    let starter = STARTER_CONFIG
+        .replacen("save_path= \"./\"", "save_path = \"./downloads\"", 1)
+        .replacen(
+            "read_path = \"Your [chembl.csv] which downloaded form https://www.ebi.ac.uk/chembl/\"",
+            "read_path = \"targets.csv\"",
+            1,
+        );
+    std::fs::write(config, starter)?;
+    std::fs::write(log_config, STARTER_LOG_CONFIG)?;
+    println!(
+        "Wrote {} and {}, put the ChEMBL target export in targets.csv or set read_path to it",
+        config.display(),
+        log_config.display()
+    );
     Ok(())
 }
 
-async fn format(url: &str, formatter: &str) -> Result<String, std::fmt::Error> {
-    if let Some(url) = url.split_once('%') {
-        Ok(format!("{}{}{}", url.0, formatter, url.1))
-    } else {
-        Err(std::fmt::Error)
+fn load_config(cli: &Cli) -> Result<UserConfig> {
+    let mut config = UserConfig::load(&cli.config, cli.profile.as_deref())?;
+    if cli.stdin {
+        config.read_path = vec!["-".to_string()];
+        config.chembl.target_query.clear();
+    } else if !cli.read_path.is_empty() {
+        config.read_path = cli.read_path.clone();
+        config.chembl.target_query.clear();
     }
-}
-
-//Using CONFIG.save_path
-async fn process_data(target: Target, save_path: PathBuf) -> Result<()> {
-    let path_target = save_path.join(&target.target_name.replace('/', "|"));
-    if !path_target.exists() {
-        if let Err(e) = create_dir(&path_target) {
-            error!("Failed to create directory: {}", &path_target.display());
-            return Err(e.into());
-        }
+    if let Some(save_path) = &cli.save_path {
+        config.save_path = save_path.clone();
     }
-
-    let id_file = path_target.join(&target.chembl_id);
-    if !id_file.exists() {
-        if let Err(e) = File::create(&id_file).await {
-            error!("Failed to create file: {}", &id_file.display());
-            return Err(e.into());
-        }
+    if let Some(limit) = cli.processor_limit {
+        config.processor_limit = limit;
+    }
+    if let Some(limit) = cli.downloader_limit {
+        config.downloader_limit = limit;
+    }
+    if let Some(offset) = cli.offset {
+        config.filter.offset = offset;
+    }
+    if let Some(limit) = cli.limit {
+        config.filter.limit = Some(limit);
+    }
+    if !cli.chembl_ids.is_empty() {
+        config.filter.chembl_ids = cli.chembl_ids.clone();
+    }
+    if let Some(pattern) = &cli.name_pattern {
+        config.filter.name_pattern = Some(pattern.clone());
+    }
+    if let Some(order) = cli.order {
+        config.order = match order {
+            OrderKind::Input => TargetOrder::Input,
+            OrderKind::Priority => TargetOrder::Priority,
+            OrderKind::FewestStructures => TargetOrder::FewestStructures,
+            OrderKind::Shuffle => TargetOrder::Shuffle,
+        };
+    }
+    if cli.tui {
+        config.tui = true;
     }
+    if cli.offline {
+        config.offline = true;
+    }
+    if !cli.chembl_query.is_empty() {
+        config.chembl.target_query = cli.chembl_query.iter().cloned().collect();
+    }
+    config.validate()?;
+    Ok(config)
+}
 
-    if target.uniprot_accession.is_empty() {
-        info!("No Uniprot data for {}", target.target_name);
-        return Ok(());
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    //Nothing is there to load yet
+    if let Some(Command::Init) = cli.command {
+        return init(&cli.config, cli.force);
     }
+    let config = load_config(&cli)?;
+    project_med::init_logging(&config)?;
+    let _telemetry = project_med::init_telemetry(&config)?;
+    debug!(target:"debug","Config : {:?}", config);
 
-    let uniprot_accessions = target.uniprot_accession.split('|').collect::<Vec<_>>();
-    for uniprot_accession in uniprot_accessions {
-        let url: Url =
-            format!("https://www.uniprot.org/uniprot/{}.txt", uniprot_accession).parse()?;
-        let page = CLIENT.get(url).send().await?.text().await?;
+    let command = cli.command.unwrap_or(Command::Download {
+        resume: false,
+        dry_run: false,
+    });
+    if cli.watch
+        && !matches!(
+            command,
+            Command::Download { dry_run: false, .. } | Command::Resume
+        )
+    {
+        bail!("--watch only applies to download and resume");
+    }
+    //Only a fresh download starts the state of the save path over
+    let resume = !matches!(
+        command,
+        Command::Download {
+            resume: false,
+            dry_run: false
+        }
+    );
 
-        let lines = page
-            //split into line
-            .split('\n')
-            //find PDB ID
-            .filter(|slice| slice.starts_with("DR   PDB;"))
-            //extract PDB ID
-            .map(|slice| slice[10..14].to_lowercase())
-            //collect PDB IDs
-            .collect::<Vec<_>>();
+    let pipeline = Pipeline::builder(config)
+        .resume(resume)
+        .progress(cli.progress)
+        .refresh(cli.refresh)
+        .force(cli.force)
+        .build()?;
 
-        //Check if there is no PDB data
-        if lines.is_empty() {
-            info!(
-                "No PDB data found for {}:{}",
-                &target.target_name, uniprot_accession
-            );
-            continue;
+    match command {
+        Command::Download { dry_run: true, .. } => {
+            let plan = pipeline.plan().await?;
+            pipeline.write_plan(&plan)?;
         }
-
-        //Crating folder for target
-        let path_uniprot = path_target.join(&uniprot_accession);
-        if !path_uniprot.exists() {
-            create_dir(&path_uniprot)?;
+        Command::Download { .. } | Command::Resume if cli.watch => pipeline.watch().await?,
+        Command::Download { .. } | Command::Resume => pipeline.run().await?,
+        Command::Update => {
+            pipeline.update().await?;
         }
-
-        //Spawn download tasks
-        let downloader_limit = Arc::new(Semaphore::new(CONFIG.downloader_limit as usize));
-        let mut tasks: Vec<task::JoinHandle<Result<(), anyhow::Error>>> = Vec::new();
-        for pdb_id in lines {
-            debug!(target:"debug","PDB ID : {}", pdb_id);
-            let semaphore = downloader_limit.clone();
-            let path_uniprot = path_uniprot.clone();
-            tasks.push(task::spawn(async move {
-                let permit = semaphore.acquire_owned().await.unwrap();
-                download_pdb(pdb_id, path_uniprot).await?;
-                drop(permit);
-                Result::<()>::Ok(())
-            }));
+        Command::Daemon => pipeline.daemon().await?,
+        Command::Serve { addr } => pipeline.serve(&addr).await?,
+        Command::Grpc { addr } => pipeline.serve_grpc(&addr).await?,
+        Command::RetryFailed => pipeline.retry_failed().await?,
+        Command::Verify { repair } => {
+            pipeline.verify(repair)?;
         }
-
-        //Wait until download done
-        for task in tasks {
-            if let Err(e) = task.await? {
-                error!("Failed to download due to \"{}\"", e);
-            }
+        Command::Clean { dry_run } => {
+            pipeline.clean(dry_run)?;
         }
-    }
-    Ok(())
-}
-
-//Using CONFIG.download_url
-async fn download_pdb(pdb_id: String, save_path: PathBuf) -> Result<()> {
-    for url in &CONFIG.download_url {
-        let url: Url = format(url, &pdb_id).await?.parse()?;
-        debug!(target:"debug","Formatted url : {}", url.to_string());
-        let save_filepath = save_path.join({
-            if let Some(file_name) = Path::new(url.path()).file_name() {
-                file_name
-            } else {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    "Check your config urls",
-                )
-                .into());
+        Command::MigrateTargetDirs { dry_run } => {
+            pipeline.migrate_target_dirs(dry_run)?;
+        }
+        Command::MigrateLayout { from, dry_run } => {
+            pipeline.migrate_layout(from.as_deref(), dry_run)?;
+        }
+        Command::Init => unreachable!("init runs before the config is loaded"),
+        Command::Report { format } => {
+            pipeline.report()?;
+            if let Some(format) = format {
+                pipeline.write_report(match format {
+                    ReportKind::Markdown => ReportFormat::Markdown,
+                    ReportKind::Html => ReportFormat::Html,
+                })?;
             }
-        });
-        if save_filepath.exists() {
-            return Ok(());
         }
-
-        let data = match CLIENT.get(url).send().await {
-            Ok(data) => data.text().await?,
-            Err(_) => continue,
-        };
-        let mut file = File::create(&save_filepath).await?;
-        file.write_all(data.as_bytes()).await?;
-        break;
     }
-
     Ok(())
 }
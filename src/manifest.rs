@@ -0,0 +1,62 @@
+use crate::config::Compression;
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+
+pub(crate) const MANIFEST_FILE: &str = "manifest.csv";
+
+/// A downloaded file or skipped structure, as listed in `save_path/manifest.csv`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub chembl_id: String,
+    #[serde(default)]
+    pub target_name: String,
+    /// Uniprot accession
+    pub accession: String,
+    /// ID of the input `accession` was mapped from, e.g. a RefSeq protein
+    #[serde(default)]
+    pub mapped_from: Option<String>,
+    pub pdb_id: Option<String>,
+    /// Replacement downloaded for an obsolete `pdb_id`
+    #[serde(default)]
+    pub superseded_by: Option<String>,
+    /// Method of predicted models, e.g. "alphafold", none for experimental structures
+    #[serde(default)]
+    pub predicted_by: Option<String>,
+    /// Structure format obtained, e.g. "cif"
+    #[serde(default)]
+    pub format: Option<String>,
+    /// Relative to the save path, or the name of the member of `archive`
+    pub path: String,
+    /// Archive holding the file, relative to the save path
+    #[serde(default)]
+    pub archive: Option<String>,
+    /// Of the content, before any `compression`
+    pub size: u64,
+    pub sha256: String,
+    /// Compression the file is stored with, e.g. "gzip"
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub source_url: String,
+    /// Validators of the response, for revalidation by updates
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    /// RFC 3339
+    #[serde(default)]
+    pub downloaded_at: String,
+    /// Why the structure was left out, for rows without a file
+    #[serde(default)]
+    pub skipped: Option<String>,
+}
+
+pub(crate) fn write(save_path: &Path, entries: &[ManifestEntry]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(save_path.join(MANIFEST_FILE))?;
+    for entry in entries {
+        writer.serialize(entry)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
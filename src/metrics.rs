@@ -0,0 +1,216 @@
+use anyhow::Result;
+use reqwest::Url;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Counters and gauges of a run, published in the Prometheus text format.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    targets: AtomicU64,
+    targets_processed: AtomicU64,
+    in_flight: AtomicU64,
+    bytes: AtomicU64,
+    retries: AtomicU64,
+    //Requests that failed for good, by host
+    failures: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    /// Start counting anew for the next run of the same pipeline, as watch and the daemon do.
+    pub fn start_run(&self) {
+        self.targets.store(0, Ordering::Relaxed);
+        self.targets_processed.store(0, Ordering::Relaxed);
+        self.bytes.store(0, Ordering::Relaxed);
+        self.retries.store(0, Ordering::Relaxed);
+        self.failures.lock().unwrap().clear();
+    }
+
+    pub fn set_targets(&self, total: usize) {
+        self.targets.store(total as u64, Ordering::Relaxed);
+    }
+
+    pub fn target_processed(&self) {
+        self.targets_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a download as in flight until the guard is dropped.
+    pub fn download(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+
+    pub fn bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn targets(&self) -> u64 {
+        self.targets.load(Ordering::Relaxed)
+    }
+
+    pub fn processed(&self) -> u64 {
+        self.targets_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes the targets left would take at the size of those processed, once one is.
+    pub fn remaining_bytes(&self) -> Option<u64> {
+        let processed = self.targets_processed.load(Ordering::Relaxed);
+        let left = self
+            .targets
+            .load(Ordering::Relaxed)
+            .saturating_sub(processed);
+        (processed > 0).then(|| self.bytes.load(Ordering::Relaxed) / processed * left)
+    }
+
+    pub fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Requests that failed for good, of every host.
+    pub fn failed_requests(&self) -> u64 {
+        self.failures.lock().unwrap().values().sum()
+    }
+
+    pub fn failure(&self, url: &Url) {
+        let host = url.host_str().unwrap_or_default().to_string();
+        *self.failures.lock().unwrap().entry(host).or_default() += 1;
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = write!(
+                out,
+                "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}\n",
+                name, help, kind, value
+            );
+        };
+        metric(
+            "prog_med_targets",
+            "gauge",
+            "Targets of the run",
+            self.targets.load(Ordering::Relaxed),
+        );
+        metric(
+            "prog_med_targets_processed_total",
+            "counter",
+            "Targets processed so far",
+            self.targets_processed.load(Ordering::Relaxed),
+        );
+        metric(
+            "prog_med_downloads_in_flight",
+            "gauge",
+            "Downloads running",
+            self.in_flight.load(Ordering::Relaxed),
+        );
+        metric(
+            "prog_med_downloaded_bytes_total",
+            "counter",
+            "Bytes downloaded",
+            self.bytes.load(Ordering::Relaxed),
+        );
+        metric(
+            "prog_med_retries_total",
+            "counter",
+            "Requests retried after a transient failure",
+            self.retries.load(Ordering::Relaxed),
+        );
+        out.push_str(
+            "# HELP prog_med_failures_total Requests failed after all attempts\n\
+             # TYPE prog_med_failures_total counter\n",
+        );
+        for (host, count) in self.failures.lock().unwrap().iter() {
+            let host = host.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                out,
+                "prog_med_failures_total{{host=\"{}\"}} {}",
+                host, count
+            );
+        }
+        out
+    }
+}
+
+pub(crate) struct InFlight<'a>(&'a Metrics);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Endpoint serving `/metrics`, shut down when dropped.
+pub(crate) struct Server(JoinHandle<()>);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Serve `metrics` on `addr` for as long as the returned server is kept.
+pub(crate) async fn serve(metrics: Arc<Metrics>, addr: &str) -> Result<Server> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    Ok(Server(tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection due to \"{}\"", e);
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &metrics).await {
+                    debug!(target:"debug","Metrics connection failed : {}", e);
+                }
+            });
+        }
+    })))
+}
+
+//Scrapers only send a GET request line and headers, there is no body to wait for
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let response = if path == "/metrics" || path.starts_with("/metrics?") {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
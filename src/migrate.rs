@@ -0,0 +1,497 @@
+use crate::checksum::{hash_file, hash_reader};
+use crate::compress;
+use crate::config::Source;
+use crate::download;
+use crate::layout::{self, Layout, LayoutRecord};
+use crate::manifest::ManifestEntry;
+use crate::pipeline::{Pipeline, Target};
+use crate::sums::{self, SUMS_FILE};
+use anyhow::{bail, Result};
+use reqwest::Url;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{create_dir_all, read_dir, remove_dir, rename};
+use std::path::{Path, PathBuf};
+
+impl Pipeline {
+    /// Move the target folders of a save path laid out by another `path_template`, such as the
+    /// `{index}` folders of old, to those of the current template, updating the records of
+    /// their files.
+    ///
+    /// Only target folders can differ between the two, and the current template can't use
+    /// `{index}` as records don't keep the position of targets. With `dry_run` nothing is
+    /// moved, what would be is only logged. Returns the number of folders concerned.
+    pub fn migrate_target_dirs(&self, dry_run: bool) -> Result<usize> {
+        let config = self.config();
+        let save_path = Path::new(&config.save_path);
+        let Some(recorded) = self.recorded_layout(None)? else {
+            return Ok(0);
+        };
+        let old = recorded.layout(config)?;
+        let new = &self.ctx.layout;
+        if !new.same_below_target(&old) {
+            bail!(
+                "{} and {} differ below the target folders, run migrate-layout to rename their files",
+                recorded,
+                LayoutRecord::of(config)
+            );
+        }
+
+        //Target folders to where they go, with the records of their files
+        let mut moves = BTreeMap::<PathBuf, (PathBuf, Vec<ManifestEntry>)>::new();
+        for record in self.ctx.state.files() {
+            if record.archive.is_some() {
+                bail!(
+                    "{} is archived, archived targets can't be moved",
+                    record.path
+                );
+            }
+            let Some((from, _)) = old.split_target(&record.path) else {
+                continue;
+            };
+            let to = new.target_dir(Path::new(""), 0, &record_target(&record));
+            let (dir, records) = moves
+                .entry(from.clone())
+                .or_insert((to.clone(), Vec::new()));
+            if *dir != to {
+                bail!("{} holds the files of several targets", from.display());
+            }
+            records.push(record);
+        }
+        moves.retain(|from, (to, _)| from != to);
+        let mut taken = HashSet::new();
+        for (from, (to, _)) in &moves {
+            if !taken.insert(to) || save_path.join(to).exists() {
+                bail!(
+                    "{} can't be moved to {}, which is taken",
+                    from.display(),
+                    to.display()
+                );
+            }
+        }
+
+        if !dry_run {
+            //Until all is moved, so that a migration stopped midway goes on from the same layout
+            layout::record(save_path, &recorded)?;
+        }
+        for (from, (to, records)) in &moves {
+            info!("Moving {} to {}", from.display(), to.display());
+            if dry_run {
+                continue;
+            }
+            let path = save_path.join(to);
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            rename(save_path.join(from), &path)?;
+            //Recorded at once, so that a migration stopped midway can be run again
+            for record in records {
+                let Some((_, rest)) = old.split_target(&record.path) else {
+                    continue;
+                };
+                let moved = ManifestEntry {
+                    path: to.join(rest).to_string_lossy().into_owned(),
+                    ..record.clone()
+                };
+                self.ctx.state.move_file(&record.path, moved)?;
+            }
+            remove_empty(save_path, from.parent())?;
+        }
+        if dry_run {
+            info!("{} target folders would be moved", moves.len());
+            return Ok(moves.len());
+        }
+        self.write_manifest()?;
+        layout::record(save_path, &LayoutRecord::of(config))?;
+        info!("{} target folders moved", moves.len());
+        Ok(moves.len())
+    }
+
+    /// Move every downloaded file of a save path laid out by other naming options to where the
+    /// config puts it, updating its record, then hash the moved files against their records.
+    ///
+    /// The old options are read from `layout.json`, or `from` gives the old `path_template`.
+    /// Coordinate files get the names of the new template, other files keep theirs, and the
+    /// files the manifest doesn't list, such as logs and `SHA256SUMS`, follow the folders they
+    /// are in. With `dry_run` nothing is moved, what would be is only logged. Returns the
+    /// number of files concerned.
+    pub fn migrate_layout(&self, from: Option<&str>, dry_run: bool) -> Result<usize> {
+        let config = self.config();
+        let save_path = Path::new(&config.save_path);
+        let Some(recorded) = self.recorded_layout(from)? else {
+            return Ok(0);
+        };
+        let old = recorded.layout(config)?;
+        let new = &self.ctx.layout;
+        let sources = config.sources();
+
+        let mut moves = Vec::new();
+        //Folders files move out of, to the folder they move to
+        let mut dirs = BTreeMap::<PathBuf, PathBuf>::new();
+        //Coordinate files renamed in each folder they move out of
+        let mut renamed = BTreeMap::<PathBuf, BTreeMap<String, String>>::new();
+        for record in self.ctx.state.files() {
+            if record.archive.is_some() {
+                bail!(
+                    "{} is archived, archived targets can't be moved",
+                    record.path
+                );
+            }
+            let Some(relocated) = relocate(&old, new, &sources, &record) else {
+                warn!("{} isn't laid out by {}, leaving it", record.path, recorded);
+                continue;
+            };
+            for (from, to) in relocated.dirs {
+                match dirs.get(&from) {
+                    Some(other) if *other != to => {
+                        bail!("{} holds the files of several targets", from.display())
+                    }
+                    _ => {
+                        dirs.insert(from, to);
+                    }
+                }
+            }
+            if let Some((from, to)) = relocated.renamed {
+                renamed
+                    .entry(relocated.accession_dir)
+                    .or_default()
+                    .insert(from, to);
+            }
+            if relocated.path != Path::new(&record.path) {
+                moves.push((record, relocated.path));
+            }
+        }
+        dirs.retain(|from, to| from != to);
+        let mut taken = HashSet::new();
+        for (record, to) in &moves {
+            if !taken.insert(to) || save_path.join(to).exists() {
+                bail!(
+                    "{} can't be moved to {}, which is taken",
+                    record.path,
+                    to.display()
+                );
+            }
+        }
+        if dry_run {
+            for (record, to) in &moves {
+                info!("Moving {} to {}", record.path, to.display());
+            }
+            info!("{} files would be moved", moves.len());
+            return Ok(moves.len());
+        }
+
+        //Until all is moved, so that a migration stopped midway goes on from the same layout
+        layout::record(save_path, &recorded)?;
+        let mut moved = Vec::new();
+        for (record, to) in moves {
+            debug!(target:"debug","Moving {} to {}", record.path, to.display());
+            let path = save_path.join(&to);
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            rename(save_path.join(&record.path), &path)?;
+            let entry = ManifestEntry {
+                path: to.to_string_lossy().into_owned(),
+                ..record.clone()
+            };
+            self.ctx.state.move_file(&record.path, entry.clone())?;
+            moved.push(entry);
+        }
+        for (dir, names) in &renamed {
+            let sums = save_path.join(dir).join(SUMS_FILE);
+            if !dirs.contains_key(dir) && sums.exists() {
+                sums::rename(&sums, &sums, names)?;
+            }
+        }
+        //Deepest first, so accession folders are emptied before their target folder
+        let mut dirs = dirs.into_iter().collect::<Vec<_>>();
+        dirs.sort_by_key(|(from, _)| std::cmp::Reverse(from.components().count()));
+        for (from, to) in &dirs {
+            let empty = BTreeMap::new();
+            let names = renamed.get(from).unwrap_or(&empty);
+            move_untracked(&save_path.join(from), &save_path.join(to), names)?;
+            remove_empty(save_path, Some(from))?;
+        }
+        self.write_manifest()?;
+        layout::record(save_path, &LayoutRecord::of(config))?;
+        info!("{} files moved", moved.len());
+
+        let mut bad = 0;
+        for record in &moved {
+            let path = save_path.join(&record.path);
+            let (size, sha256) = match record.compression {
+                Some(_) => hash_reader(compress::reader(&path)?)?,
+                None => hash_file(&path)?,
+            };
+            if size != record.size || sha256 != record.sha256 {
+                warn!("Moved file {} doesn't match its checksum", path.display());
+                bad += 1;
+            }
+        }
+        if bad == 0 {
+            info!("All {} moved files match their checksums", moved.len());
+        } else {
+            warn!(
+                "{} moved files don't match their checksums, run verify --repair to download them again",
+                bad
+            );
+        }
+        Ok(moved.len())
+    }
+
+    //The layout recorded for the save path, unless it is what the config lays out already
+    fn recorded_layout(&self, from: Option<&str>) -> Result<Option<LayoutRecord>> {
+        let config = self.config();
+        let save_path = Path::new(&config.save_path);
+        let recorded = match from {
+            Some(template) => Some(LayoutRecord {
+                path_template: template.to_string(),
+                path_replacement: None,
+                max_name_length: None,
+            }),
+            None => layout::recorded(save_path, config, &self.ctx.state)?,
+        };
+        let Some(recorded) = recorded else {
+            info!("Nothing was downloaded to {} yet", save_path.display());
+            return Ok(None);
+        };
+        if recorded.matches(config) {
+            info!(
+                "{} is laid out by {} already",
+                save_path.display(),
+                recorded
+            );
+            layout::record(save_path, &LayoutRecord::of(config))?;
+            return Ok(None);
+        }
+        if self.ctx.layout.uses_index() {
+            bail!("Files can't be moved to {{index}} folders, the records don't keep it");
+        }
+        Ok(Some(recorded))
+    }
+}
+
+//Where a recorded file goes in the new layout
+struct Relocated {
+    path: PathBuf,
+    //Its target and accession folders in the old layout, with those in the new one
+    dirs: [(PathBuf, PathBuf); 2],
+    accession_dir: PathBuf,
+    //Old and new names of a coordinate file
+    renamed: Option<(String, String)>,
+}
+
+fn relocate(
+    old: &Layout,
+    new: &Layout,
+    sources: &[Source],
+    record: &ManifestEntry,
+) -> Option<Relocated> {
+    let (old_target, _) = old.split_target(&record.path)?;
+    let old_accession = old.accession_dir(&old_target, &record.accession);
+    let rest = Path::new(&record.path).strip_prefix(&old_accession).ok()?;
+    let new_target = new.target_dir(Path::new(""), 0, &record_target(record));
+    let new_accession = new.accession_dir(&new_target, &record.accession);
+    let name = rest.to_str().unwrap_or_default();
+    let renamed = coordinate_name(old, new, sources, record, name);
+    let path = match &renamed {
+        Some(renamed) => new_accession.join(renamed),
+        None => new_accession.join(rest),
+    };
+    Some(Relocated {
+        path,
+        accession_dir: old_accession.clone(),
+        renamed: renamed.map(|renamed| (name.to_string(), renamed)),
+        dirs: [(old_target, new_target), (old_accession, new_accession)],
+    })
+}
+
+/// The name `new` gives the coordinate file saved as `name` by `old`, none for other files.
+fn coordinate_name(
+    old: &Layout,
+    new: &Layout,
+    sources: &[Source],
+    record: &ManifestEntry,
+    name: &str,
+) -> Option<String> {
+    if record.predicted_by.is_some() {
+        return None;
+    }
+    let pdb_id = record.superseded_by.as_ref().or(record.pdb_id.as_ref())?;
+    //The name on the mirror, which assemblies and other files of the entry don't come from
+    let url = Url::parse(&record.source_url).ok()?;
+    let mirror_name = url.path_segments()?.next_back()?;
+    let from_mirror = sources.iter().any(|source| {
+        download::format(&source.template, pdb_id)
+            .is_ok_and(|url| url.rsplit('/').next() == Some(mirror_name))
+    });
+    if !from_mirror {
+        return None;
+    }
+    let format = record.format.as_deref();
+    let old_name = old.file_name(pdb_id, format, mirror_name);
+    let new_name = new.file_name(pdb_id, format, mirror_name);
+    //Compression adds an extension to the name and decompression takes ".gz" off
+    if let Some(suffix) = name.strip_prefix(&old_name) {
+        return Some(format!("{}{}", new_name, suffix));
+    }
+    let suffix = name.strip_prefix(old_name.strip_suffix(".gz")?)?;
+    Some(format!("{}{}", new_name.strip_suffix(".gz")?, suffix))
+}
+
+//Files left in `from` once the recorded ones moved, renaming those listed in its SHA256SUMS
+fn move_untracked(from: &Path, to: &Path, renamed: &BTreeMap<String, String>) -> Result<()> {
+    if !from.is_dir() {
+        return Ok(());
+    }
+    for entry in read_dir(from)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        if !path.is_file() {
+            continue;
+        }
+        create_dir_all(to)?;
+        if name == SUMS_FILE {
+            sums::rename(&path, &to.join(name), renamed)?;
+        } else if !to.join(name).exists() {
+            rename(&path, to.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+//The target a file was downloaded for, as much as its record tells
+fn record_target(record: &ManifestEntry) -> Target {
+    Target {
+        chembl_id: record.chembl_id.clone(),
+        target_name: record.target_name.clone(),
+        uniprot_accession: record.accession.clone(),
+        priority: None,
+    }
+}
+
+//`dir` and its parents up to the save path, as long as they are empty
+fn remove_empty(save_path: &Path, mut dir: Option<&Path>) -> Result<()> {
+    while let Some(parent) = dir.filter(|parent| !parent.as_os_str().is_empty()) {
+        let path = save_path.join(parent);
+        if path.exists() {
+            if read_dir(&path)?.next().is_some() {
+                break;
+            }
+            remove_dir(&path)?;
+        }
+        dir = parent.parent();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn pipeline(name: &str) -> (Pipeline, PathBuf) {
+        let save_path =
+            std::env::temp_dir().join(format!("prog_med_migrate_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&save_path);
+        let pipeline = Pipeline::builder(config::tests::config(&save_path))
+            .build()
+            .unwrap();
+        (pipeline, save_path)
+    }
+
+    //A file of the `index`th target saved by the template of old
+    fn download(pipeline: &Pipeline, save_path: &Path, index: usize) -> String {
+        let path = format!("{}/T{}/P1234{}/1ab{}.cif", index, index, index, index);
+        let file = save_path.join(&path);
+        create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, b"data_1abc\n").unwrap();
+        let (size, sha256) = hash_file(&file).unwrap();
+        let record = serde_json::from_value(serde_json::json!({
+            "chembl_id": format!("CHEMBL{}", index),
+            "target_name": format!("T{}", index),
+            "accession": format!("P1234{}", index),
+            "pdb_id": format!("1AB{}", index),
+            "path": path,
+            "size": size,
+            "sha256": sha256,
+        }))
+        .unwrap();
+        pipeline.ctx.state.record_file(record).unwrap();
+        path
+    }
+
+    fn paths(pipeline: &Pipeline) -> Vec<String> {
+        let mut paths = pipeline
+            .ctx
+            .state
+            .files()
+            .into_iter()
+            .map(|record| record.path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn index_folders_move_to_chembl_id_folders() {
+        let (pipeline, save_path) = pipeline("dirs");
+        download(&pipeline, &save_path, 1);
+        download(&pipeline, &save_path, 2);
+        assert_eq!(pipeline.migrate_target_dirs(true).unwrap(), 2);
+        assert!(save_path.join("1/T1/P12341/1ab1.cif").exists());
+
+        assert_eq!(pipeline.migrate_target_dirs(false).unwrap(), 2);
+        assert_eq!(
+            paths(&pipeline),
+            ["CHEMBL1/T1/P12341/1ab1.cif", "CHEMBL2/T2/P12342/1ab2.cif"]
+        );
+        for path in paths(&pipeline) {
+            assert!(save_path.join(path).exists());
+        }
+        assert!(!save_path.join("1").exists() && !save_path.join("2").exists());
+        assert_eq!(pipeline.migrate_target_dirs(false).unwrap(), 0);
+        let _ = std::fs::remove_dir_all(&save_path);
+    }
+
+    #[test]
+    fn stopped_migration_goes_on_when_run_again() {
+        let (pipeline, save_path) = pipeline("stopped");
+        download(&pipeline, &save_path, 1);
+        download(&pipeline, &save_path, 2);
+        //The folder of the second target can't be made
+        std::fs::write(save_path.join("CHEMBL2"), b"").unwrap();
+        assert!(pipeline.migrate_target_dirs(false).is_err());
+        assert_eq!(
+            paths(&pipeline),
+            ["2/T2/P12342/1ab2.cif", "CHEMBL1/T1/P12341/1ab1.cif"]
+        );
+
+        std::fs::remove_file(save_path.join("CHEMBL2")).unwrap();
+        assert_eq!(pipeline.migrate_target_dirs(false).unwrap(), 1);
+        assert_eq!(
+            paths(&pipeline),
+            ["CHEMBL1/T1/P12341/1ab1.cif", "CHEMBL2/T2/P12342/1ab2.cif"]
+        );
+        for path in paths(&pipeline) {
+            assert!(save_path.join(path).exists());
+        }
+        let _ = std::fs::remove_dir_all(&save_path);
+    }
+
+    #[test]
+    fn migrate_layout_moves_files_of_another_template() {
+        let (pipeline, save_path) = pipeline("layout");
+        download(&pipeline, &save_path, 1);
+        assert_eq!(
+            pipeline
+                .migrate_layout(Some("{index}/{target_name}/{uniprot}/{file}"), false)
+                .unwrap(),
+            1
+        );
+        assert_eq!(paths(&pipeline), ["CHEMBL1/T1/P12341/1ab1.cif"]);
+        assert!(save_path.join("CHEMBL1/T1/P12341/1ab1.cif").exists());
+        let _ = std::fs::remove_dir_all(&save_path);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::config::{MirrorProbe, Source};
+use crate::download;
+use crate::http::HttpError;
+use crate::pipeline::Context;
+use anyhow::Result;
+use futures_util::StreamExt;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time each mirror took to answer its probe, by url template.
+#[derive(Default)]
+pub(crate) struct MirrorRanking {
+    probed: Mutex<HashMap<String, Duration>>,
+}
+
+impl MirrorRanking {
+    /// `sources` with the mirrors of each format in the order of the last probe, pinned ones
+    /// first. Mirrors that failed or weren't probed keep their place after the others.
+    pub fn order(&self, sources: Vec<Source>, pinned: &[String]) -> Vec<Source> {
+        let probed = self.probed.lock().unwrap();
+        if probed.is_empty() && pinned.is_empty() {
+            return sources;
+        }
+        let mut formats = Vec::new();
+        for source in &sources {
+            if !formats.contains(&source.format) {
+                formats.push(source.format.clone());
+            }
+        }
+        let mut sources = sources;
+        sources.sort_by_key(|source| {
+            (
+                formats.iter().position(|format| *format == source.format),
+                pinned
+                    .iter()
+                    .position(|template| *template == source.template)
+                    .unwrap_or(usize::MAX),
+                probed
+                    .get(&source.template)
+                    .copied()
+                    .unwrap_or(Duration::MAX),
+            )
+        });
+        sources
+    }
+}
+
+/// Fetch the start of the probe entry from every mirror and rank them by the time it took.
+pub(crate) async fn probe(ctx: &Context, probe: &MirrorProbe) {
+    let mut results = Vec::new();
+    for source in ctx.config.sources() {
+        match time_mirror(ctx, &source, probe).await {
+            Ok(result) => results.push((source.template, result)),
+            Err(e) => warn!(
+                "Failed to probe mirror {} due to \"{}\"",
+                source.template, e
+            ),
+        }
+    }
+    results.sort_by_key(|(_, (_, elapsed, _))| *elapsed);
+    for (rank, (template, (latency, elapsed, bytes))) in results.iter().enumerate() {
+        info!(
+            "Mirror {} : {} answered in {} ms, {:.1} KB/s",
+            rank + 1,
+            template,
+            latency.as_millis(),
+            *bytes as f64 / 1024.0 / elapsed.as_secs_f64().max(f64::EPSILON)
+        );
+    }
+    let mut probed = ctx.mirrors.probed.lock().unwrap();
+    probed.clear();
+    probed.extend(
+        results
+            .into_iter()
+            .map(|(template, (_, elapsed, _))| (template, elapsed)),
+    );
+}
+
+/// Probe again every `interval_secs` until the task is aborted.
+pub(crate) async fn keep_probing(ctx: &Context, probe: &MirrorProbe) {
+    let mut interval = tokio::time::interval(Duration::from_secs(probe.interval_secs));
+    //The first tick is immediate and the run already probed
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        self::probe(ctx, probe).await;
+    }
+}
+
+//Time to the response and to the last byte read, and the bytes read
+async fn time_mirror(
+    ctx: &Context,
+    source: &Source,
+    probe: &MirrorProbe,
+) -> Result<(Duration, Duration, u64)> {
+    let url: Url = download::format(&source.template, &probe.pdb_id)?.parse()?;
+    //Local mirrors come before any on the network
+    if url.scheme() == "file" {
+        return Ok((Duration::ZERO, Duration::ZERO, probe.bytes));
+    }
+    let started = Instant::now();
+    let response = ctx
+        .http
+        .get_range(&url, 0, probe.bytes.saturating_sub(1))
+        .await?;
+    let latency = started.elapsed();
+    let mut stream = response.bytes_stream();
+    let mut bytes = 0;
+    //Servers ignoring the range send the whole file, which is read no further
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|source| HttpError::Transport {
+            url: url.clone(),
+            source,
+        })?;
+        bytes += chunk.len() as u64;
+        if bytes >= probe.bytes {
+            break;
+        }
+    }
+    Ok((latency, started.elapsed(), bytes))
+}
@@ -0,0 +1,96 @@
+use crate::manifest::ManifestEntry;
+use crate::pipeline::Context;
+use crate::summary::RunSummary;
+use reqwest::header::CONTENT_TYPE;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Tell the webhook that the run finished, with its summary.
+pub(crate) async fn run_finished(ctx: &Context, summary: &RunSummary) {
+    let mut text = format!(
+        "prog_med run on {} {} after {:.0} s: {} targets processed",
+        ctx.config.save_path,
+        if summary.interrupted {
+            "was interrupted"
+        } else {
+            "completed"
+        },
+        summary.elapsed_secs,
+        summary.targets_processed
+    );
+    let _ = write!(
+        text,
+        ", {} without UniProt data, {} accessions without PDB data",
+        summary.targets_without_uniprot.len(),
+        summary.accessions_without_pdb.len()
+    );
+    if summary.failures.is_empty() {
+        text.push_str(", no failures");
+    } else {
+        let failures = summary
+            .failures
+            .iter()
+            .map(|(cause, count)| format!("{} {}", count, cause))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = write!(text, ", failures: {}", failures);
+    }
+    send(ctx, &text).await;
+}
+
+/// Tell the webhook about an error, if it asks for them.
+pub(crate) async fn error(ctx: &Context, error: &anyhow::Error) {
+    if ctx
+        .config
+        .webhook
+        .as_ref()
+        .is_some_and(|webhook| webhook.on_error)
+    {
+        send(
+            ctx,
+            &format!("prog_med run on {} failed: {}", ctx.config.save_path, error),
+        )
+        .await;
+    }
+}
+
+/// Tell the webhook which structures an update of the daemon added.
+pub(crate) async fn update_finished(ctx: &Context, added: &[ManifestEntry]) {
+    let mut by_target = BTreeMap::<&str, Vec<&str>>::new();
+    for entry in added {
+        by_target
+            .entry(&entry.chembl_id)
+            .or_default()
+            .extend(entry.pdb_id.as_deref());
+    }
+    let mut text = format!(
+        "prog_med update of {} added {} files for {} targets",
+        ctx.config.save_path,
+        added.len(),
+        by_target.len()
+    );
+    for (chembl_id, pdb_ids) in &by_target {
+        let _ = write!(text, "\n{}: {}", chembl_id, pdb_ids.join(", "));
+    }
+    send(ctx, &text).await;
+}
+
+//A failing webhook must not fail the run
+async fn send(ctx: &Context, text: &str) {
+    let Some(webhook) = &ctx.config.webhook else {
+        return;
+    };
+    let payload = serde_json::json!({ "text": text }).to_string();
+    let sent = ctx
+        .http
+        .client
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(payload)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = sent {
+        warn!("Failed to call the webhook due to \"{}\"", e);
+    }
+}
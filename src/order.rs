@@ -0,0 +1,67 @@
+use crate::config::TargetOrder;
+use crate::pipeline::{Context, Target};
+use crate::select;
+use crate::uniprot;
+use rand::seq::SliceRandom;
+use std::cmp::Ordering;
+
+/// Sort `targets`, numbered by their position in the input, in the order of `order` of the
+/// config.
+pub(crate) async fn sort(ctx: &Context, targets: &mut Vec<(usize, Target)>) {
+    match ctx.config.order {
+        TargetOrder::Input => {}
+        TargetOrder::Priority => {
+            if targets.iter().all(|(_, target)| target.priority.is_none()) {
+                warn!("No target has a priority, keeping the order of the input");
+            }
+            targets.sort_by(|(_, a), (_, b)| match (a.priority, b.priority) {
+                (Some(a), Some(b)) => b.total_cmp(&a),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            });
+        }
+        TargetOrder::FewestStructures => {
+            let mut counted = Vec::with_capacity(targets.len());
+            for (i, target) in targets.drain(..) {
+                counted.push((structure_count(ctx, &target).await, i, target));
+            }
+            counted.sort_by_key(|(count, _, _)| *count);
+            targets.extend(counted.into_iter().map(|(_, i, target)| (i, target)));
+        }
+        TargetOrder::Shuffle => targets.shuffle(&mut rand::thread_rng()),
+    }
+    info!(
+        "{} targets ordered by {:?}",
+        targets.len(),
+        ctx.config.order
+    );
+}
+
+//PDB entries UniProt lists for the accessions of `target`, none known going last
+async fn structure_count(ctx: &Context, target: &Target) -> usize {
+    if ctx.state.is_target_done(&target.chembl_id) {
+        return 0;
+    }
+    let mut count = 0;
+    for accession in target
+        .uniprot_accession
+        .split('|')
+        .filter(|accession| !accession.is_empty())
+    {
+        match uniprot::fetch_entry(ctx, accession).await {
+            Ok(entry) => {
+                count += entry
+                    .pdb_references()
+                    .iter()
+                    .filter(|reference| select::is_wanted(&ctx.config, reference))
+                    .count();
+            }
+            Err(e) => {
+                debug!(target:"debug","No structure count for {} : {}", accession, e);
+                return usize::MAX;
+            }
+        }
+    }
+    count
+}
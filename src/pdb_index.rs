@@ -0,0 +1,123 @@
+use crate::layout::Layout;
+use crate::manifest::ManifestEntry;
+use anyhow::Result;
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{create_dir_all, read_dir, read_link, remove_dir_all, remove_file, write};
+use std::path::Path;
+
+/// Folder of the save path indexing downloaded files by PDB entry.
+pub(crate) const PDB_INDEX_DIR: &str = "by_pdb";
+const REFERENCES_FILE: &str = "references.json";
+
+/// A copy of a file of an entry, as listed in its `references.json`.
+#[derive(Serialize)]
+struct Reference<'a> {
+    chembl_id: &'a str,
+    target_name: &'a str,
+    accession: &'a str,
+    superseded_by: Option<&'a str>,
+    format: Option<&'a str>,
+    /// Relative to the save path
+    path: &'a str,
+    /// Name of the symlink to it in the folder of the entry
+    link: String,
+}
+
+#[derive(Serialize)]
+struct References<'a> {
+    pdb_id: &'a str,
+    references: Vec<Reference<'a>>,
+}
+
+/// Bring `save_path/by_pdb/` in line with the files of `entries`: a folder for every PDB entry,
+/// holding a symlink to every copy of its files and a `references.json` listing the targets
+/// and accessions they were downloaded for.
+///
+/// Links are relative, so they hold when the save path is moved. Folders of entries no longer
+/// listed are removed, as are their links to files gone. Archived files aren't indexed.
+pub(crate) fn write_index(
+    save_path: &Path,
+    layout: &Layout,
+    entries: &[ManifestEntry],
+) -> Result<()> {
+    let mut by_pdb = BTreeMap::<String, (&str, Vec<&ManifestEntry>)>::new();
+    for entry in entries {
+        if entry.archive.is_some() || entry.skipped.is_some() {
+            continue;
+        }
+        if let Some(pdb_id) = &entry.pdb_id {
+            by_pdb
+                .entry(layout.sanitize(pdb_id))
+                .or_insert((pdb_id, Vec::new()))
+                .1
+                .push(entry);
+        }
+    }
+    let index = save_path.join(PDB_INDEX_DIR);
+    if index.is_dir() {
+        for dir in read_dir(&index)? {
+            let dir = dir?;
+            if !by_pdb.contains_key(dir.file_name().to_string_lossy().as_ref()) {
+                remove_dir_all(dir.path())?;
+            }
+        }
+    }
+
+    for (name, (pdb_id, entries)) in &by_pdb {
+        let dir = index.join(name);
+        create_dir_all(&dir)?;
+        let mut references = Vec::new();
+        for entry in entries {
+            let file = Path::new(&entry.path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let link =
+                layout.sanitize(&format!("{}_{}_{}", entry.chembl_id, entry.accession, file));
+            //From by_pdb/<pdb_id>/ back to the save path
+            let target = Path::new("..").join("..").join(&entry.path);
+            let path = dir.join(&link);
+            if read_link(&path).ok().as_deref() != Some(target.as_path()) {
+                if path.symlink_metadata().is_ok() {
+                    remove_file(&path)?;
+                }
+                symlink(&target, &path)?;
+            }
+            references.push(Reference {
+                chembl_id: &entry.chembl_id,
+                target_name: &entry.target_name,
+                accession: &entry.accession,
+                superseded_by: entry.superseded_by.as_deref(),
+                format: entry.format.as_deref(),
+                path: &entry.path,
+                link,
+            });
+        }
+        let links = references
+            .iter()
+            .map(|reference| reference.link.as_str())
+            .collect::<HashSet<_>>();
+        for file in read_dir(&dir)? {
+            let file = file?;
+            let file_name = file.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name != REFERENCES_FILE && !links.contains(file_name.as_ref()) {
+                remove_file(file.path())?;
+            }
+        }
+        let references = References { pdb_id, references };
+        write(
+            dir.join(REFERENCES_FILE),
+            serde_json::to_string_pretty(&references)?,
+        )?;
+    }
+    Ok(())
+}
+
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(target, link);
+}
@@ -0,0 +1,1223 @@
+use crate::alphafold;
+use crate::archive;
+use crate::assembly;
+use crate::bandwidth::Throttle;
+use crate::breaker::Breaker;
+use crate::checksum;
+use crate::chembl;
+use crate::compress::{self, CompressedStorage};
+use crate::config::{TargetOrder, UserConfig};
+use crate::dashboard::Drawing;
+use crate::database;
+use crate::disk::{self, DiskMonitor};
+use crate::download::Downloaded;
+use crate::email;
+use crate::emdb;
+use crate::esmfold;
+use crate::export::{self, DownloadStat};
+use crate::http::{self, Credentials, Http};
+use crate::input::{self, TargetInput};
+use crate::layout::{self, Layout, LayoutRecord};
+use crate::lock::RunLock;
+use crate::logging;
+use crate::manifest::{self, ManifestEntry};
+use crate::metrics::{self, Metrics};
+use crate::mirrors::{self, MirrorRanking};
+use crate::notify;
+use crate::order;
+use crate::pdb_index;
+use crate::progress::Progress;
+use crate::s3::{S3Storage, S3};
+use crate::select;
+use crate::shutdown;
+use crate::sifts;
+use crate::source::{SourceContext, StructureSource, UniprotPdb};
+use crate::state::{self, StateStore};
+use crate::storage::{LocalStorage, Storage};
+use crate::summary::{self, Summary};
+use crate::sums;
+use crate::swissmodel;
+use crate::transform;
+use crate::uniprot::{self, EntryCache};
+use crate::validation;
+use anyhow::{bail, Result};
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::sync::Semaphore;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinSet};
+use tokio::time::{sleep_until, Instant};
+use tracing::Instrument;
+
+//Targets read ahead of those being mapped and started
+const INPUT_BUFFER: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Target {
+    pub chembl_id: String,
+    pub target_name: String,
+    pub uniprot_accession: String,
+    /// Value of the priority column, for `order = "priority"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<f64>,
+}
+
+/// What a run reports as it goes, to the sender given to [`PipelineBuilder::events`].
+#[derive(Debug, Clone)]
+pub enum RunEvent {
+    /// Processing of a target started
+    TargetStarted { chembl_id: String },
+    /// A file of a target was stored and recorded in the manifest
+    FileDownloaded {
+        chembl_id: String,
+        accession: String,
+        pdb_id: Option<String>,
+        /// Relative to the save path
+        path: String,
+        size: u64,
+    },
+    /// A target was processed, `outcome` being "complete", "incomplete" or "failed"
+    TargetFinished {
+        chembl_id: String,
+        outcome: String,
+        error: Option<String>,
+    },
+}
+
+/// Where the pipeline reads its targets from.
+#[derive(Debug, Clone)]
+pub enum InputSource {
+    /// A ChEMBL target export or another list of targets, see `input_format`, "-" for stdin
+    Path(PathBuf),
+    /// Several lists read one after the other, which may be glob patterns
+    Paths(Vec<PathBuf>),
+    /// Targets built in memory
+    Targets(Vec<Target>),
+    /// Targets matching filters of the ChEMBL target API, e.g. `target_type=SINGLE PROTEIN`
+    Chembl(BTreeMap<String, String>),
+}
+
+/// State shared by every task of a run.
+pub(crate) struct Context {
+    pub config: UserConfig,
+    pub layout: Layout,
+    pub http: Arc<Http>,
+    pub breaker: Breaker,
+    pub mirrors: MirrorRanking,
+    pub throttle: Throttle,
+    pub disk: DiskMonitor,
+    //Permits of `downloader_limit`, shared by the PDB entries of every target
+    downloaders: Arc<Semaphore>,
+    pub state: StateStore,
+    pub progress: Progress,
+    pub summary: Summary,
+    pub metrics: Arc<Metrics>,
+    pub storage: Box<dyn Storage>,
+    pub source: Box<dyn StructureSource>,
+    pub entries: EntryCache,
+    stop: watch::Sender<bool>,
+    locks: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+    //Obsolete PDB IDs and the entries downloaded in their place
+    superseded: Mutex<HashMap<String, String>>,
+    //UniProt accessions and the IDs of the input they were mapped from
+    mapped: Mutex<HashMap<String, String>>,
+    //Structures of accessions left out by ranking or clustering, with the reason
+    left_out: Mutex<HashMap<String, Vec<(String, String)>>>,
+    pub(crate) downloads: Mutex<Vec<DownloadStat>>,
+    //ChEMBL IDs of the targets a retry of failures is limited to
+    pub(crate) retrying: Mutex<Option<HashSet<String>>>,
+    pub(crate) revalidating: AtomicBool,
+    events: Option<mpsc::UnboundedSender<RunEvent>>,
+}
+
+impl Context {
+    //Nobody listening is no reason to fail the run
+    pub fn event(&self, event: RunEvent) {
+        self.progress.event(&event);
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    /// Whether the run is shutting down, so no new work should start.
+    pub fn is_stopping(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    /// Whether an update is asking again for the files of entries done.
+    pub fn is_revalidating(&self) -> bool {
+        self.revalidating.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self) {
+        self.stop.send_replace(true);
+    }
+
+    pub(crate) async fn stopped(&self) {
+        let mut stop = self.stop.subscribe();
+        let _ = stop.wait_for(|stop| *stop).await;
+    }
+
+    /// Lock serializing the tasks writing to `path`.
+    pub fn lock(&self, path: &Path) -> Arc<tokio::sync::Mutex<()>> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone()
+    }
+
+    pub fn record_superseded(&self, pdb_id: &str, superseded_by: &str) {
+        self.superseded
+            .lock()
+            .unwrap()
+            .insert(pdb_id.to_string(), superseded_by.to_string());
+    }
+
+    pub fn record_mapping(&self, accession: &str, mapped_from: &str) {
+        self.mapped
+            .lock()
+            .unwrap()
+            .insert(accession.to_string(), mapped_from.to_string());
+    }
+
+    pub fn record_left_out(&self, accession: &str, skipped: Vec<(String, String)>) {
+        self.left_out
+            .lock()
+            .unwrap()
+            .insert(accession.to_string(), skipped);
+    }
+
+    /// Record the structures of `accession` left out of `target` in the manifest.
+    pub fn record_skipped(&self, target: &Target, accession: &str) -> Result<()> {
+        let skipped = self.left_out.lock().unwrap().remove(accession);
+        for (pdb_id, reason) in skipped.into_iter().flatten() {
+            self.state.record_skipped(ManifestEntry {
+                chembl_id: target.chembl_id.clone(),
+                target_name: target.target_name.clone(),
+                accession: accession.to_string(),
+                mapped_from: self.mapped.lock().unwrap().get(accession).cloned(),
+                pdb_id: Some(pdb_id),
+                superseded_by: None,
+                predicted_by: None,
+                format: None,
+                path: String::new(),
+                archive: None,
+                size: 0,
+                sha256: String::new(),
+                compression: None,
+                source_url: String::new(),
+                etag: None,
+                last_modified: None,
+                downloaded_at: String::new(),
+                skipped: Some(reason),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Store a downloaded file and record it in the state store, and so in the manifest.
+    pub async fn record_download(
+        &self,
+        target: &Target,
+        accession: &str,
+        pdb_id: Option<&str>,
+        downloaded: &Downloaded,
+    ) -> Result<()> {
+        self.record(target, accession, pdb_id, None, downloaded)
+            .await
+    }
+
+    /// Like [`Context::record_download`] for a model of `accession` predicted by `method`.
+    pub async fn record_model(
+        &self,
+        target: &Target,
+        accession: &str,
+        method: &str,
+        downloaded: &Downloaded,
+    ) -> Result<()> {
+        self.record(target, accession, None, Some(method), downloaded)
+            .await
+    }
+
+    async fn record(
+        &self,
+        target: &Target,
+        accession: &str,
+        pdb_id: Option<&str>,
+        predicted_by: Option<&str>,
+        downloaded: &Downloaded,
+    ) -> Result<()> {
+        //The manifest keeps the checksum of the content, storage the one of the file
+        let compressed = self.config.compress_output.and_then(|compression| {
+            Some((
+                compress::compressed_path(&downloaded.path, compression)?,
+                compression,
+            ))
+        });
+        let compression = compressed.as_ref().map(|(_, compression)| *compression);
+        let (stored, sha256) = match compressed {
+            Some((to, compression)) => {
+                let (from, path) = (downloaded.path.clone(), to.clone());
+                let (_, sha256) = tokio::task::spawn_blocking(move || {
+                    compress::compress(&from, &path, compression)
+                })
+                .await??;
+                (to, sha256)
+            }
+            None => (downloaded.path.clone(), downloaded.sha256.clone()),
+        };
+        self.storage.store(&stored, &sha256).await?;
+        if self.config.sha256sums {
+            sums::update(self, &stored, &sha256).await?;
+        }
+        self.storage.release(&stored).await?;
+        let path = stored
+            .strip_prefix(&self.config.save_path)
+            .unwrap_or(&stored);
+        let path = path.to_string_lossy().into_owned();
+        self.state.record_file(ManifestEntry {
+            chembl_id: target.chembl_id.clone(),
+            target_name: target.target_name.clone(),
+            accession: accession.to_string(),
+            mapped_from: self.mapped.lock().unwrap().get(accession).cloned(),
+            pdb_id: pdb_id.map(str::to_string),
+            superseded_by: pdb_id
+                .and_then(|pdb_id| self.superseded.lock().unwrap().get(pdb_id).cloned()),
+            predicted_by: predicted_by.map(str::to_string),
+            format: downloaded.format.clone(),
+            path: path.clone(),
+            archive: None,
+            size: downloaded.size,
+            sha256: downloaded.sha256.clone(),
+            compression,
+            source_url: downloaded.url.to_string(),
+            etag: downloaded.etag.clone(),
+            last_modified: downloaded.last_modified.clone(),
+            downloaded_at: Utc::now().to_rfc3339(),
+            skipped: None,
+        })?;
+        self.event(RunEvent::FileDownloaded {
+            chembl_id: target.chembl_id.clone(),
+            accession: accession.to_string(),
+            pdb_id: pdb_id.map(str::to_string),
+            path,
+            size: downloaded.size,
+        });
+        Ok(())
+    }
+}
+
+pub struct Pipeline {
+    pub(crate) ctx: Arc<Context>,
+    input: InputSource,
+    _lock: RunLock,
+}
+
+pub struct PipelineBuilder {
+    config: UserConfig,
+    input: Option<InputSource>,
+    storage: Option<Box<dyn Storage>>,
+    source: Option<Box<dyn StructureSource>>,
+    resume: bool,
+    progress: bool,
+    refresh: bool,
+    force: bool,
+    events: Option<mpsc::UnboundedSender<RunEvent>>,
+}
+
+impl PipelineBuilder {
+    pub fn new(config: UserConfig) -> Self {
+        PipelineBuilder {
+            config,
+            input: None,
+            storage: None,
+            source: None,
+            resume: false,
+            progress: false,
+            refresh: false,
+            force: false,
+            events: None,
+        }
+    }
+
+    /// Defaults to `chembl.target_query` of the config when set, `read_path` otherwise.
+    pub fn input(mut self, input: InputSource) -> Self {
+        self.input = Some(input);
+        self
+    }
+
+    pub fn save_path(mut self, save_path: impl Into<String>) -> Self {
+        self.config.save_path = save_path.into();
+        self
+    }
+
+    /// Number of targets processed at the same time.
+    pub fn processor_limit(mut self, limit: usize) -> Self {
+        self.config.processor_limit = limit;
+        self
+    }
+
+    /// Number of downloads of the whole run at the same time.
+    pub fn downloader_limit(mut self, limit: usize) -> Self {
+        self.config.downloader_limit = limit;
+        self
+    }
+
+    /// Skip the work recorded as done by a previous run on the same save path.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Keep files in `storage` rather than in `save_path` or the bucket it names.
+    pub fn storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = Some(Box::new(storage));
+        self
+    }
+
+    /// List and download structures with `source` rather than UniProt and the PDB mirrors.
+    ///
+    /// Dry runs still plan with UniProt and the mirrors.
+    pub fn structure_source(mut self, source: impl StructureSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Draw progress bars on stderr.
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Fetch UniProt entries anew, replacing those kept by `uniprot_cache_hours`.
+    pub fn refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Run even if another instance holds the lock of the save path.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Send what the run does to `events`, as it happens.
+    pub fn events(mut self, events: mpsc::UnboundedSender<RunEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Pipeline> {
+        //Files of a bucket go through a local staging folder
+        let s3 = match self.storage {
+            Some(_) => None,
+            None => S3::parse(&self.config)?,
+        };
+        if let Some(s3) = &s3 {
+            if self.config.offline {
+                bail!("offline can't upload to the bucket of save_path");
+            }
+            self.config.save_path = s3.staging.to_string_lossy().into_owned();
+        }
+        let input = self
+            .input
+            .unwrap_or_else(|| match &self.config.chembl.target_query {
+                query if !query.is_empty() => InputSource::Chembl(query.clone()),
+                _ => InputSource::Paths(self.config.read_path.iter().map(PathBuf::from).collect()),
+            });
+        if self.config.parquet {
+            export::check()?;
+        }
+        if self.config.sqlite {
+            database::check()?;
+        }
+        if self.config.archive.is_some() {
+            archive::check()?;
+            if s3.is_some() {
+                bail!("archive needs a local save path");
+            }
+        }
+        let layout = Layout::parse(&self.config)?;
+        create_dir_all(&self.config.save_path)?;
+        let lock = RunLock::acquire(Path::new(&self.config.save_path), self.force)?;
+        let state = StateStore::open(
+            Path::new(&self.config.save_path),
+            self.resume,
+            self.config.sqlite,
+        )?;
+        let breaker = Breaker::new(self.config.circuit_breaker.clone());
+        let entries = EntryCache::new(&self.config, self.refresh);
+        let throttle = Throttle::new(&self.config);
+        let downloaders = Arc::new(Semaphore::new(self.config.downloader_limit));
+        let metrics = Arc::new(Metrics::default());
+        let progress = Progress::new(self.progress, self.config.tui);
+        let http = Arc::new(Http::new(
+            http::build_client(&self.config)?,
+            self.config.retry.clone(),
+            self.config.rate_limit.clone(),
+            Credentials::from_config(&self.config.auth)?,
+            metrics.clone(),
+            self.config.offline,
+        ));
+        let storage = match (self.storage, s3) {
+            (Some(storage), _) => storage,
+            (None, Some(s3)) => Box::new(S3Storage {
+                s3,
+                http: http.clone(),
+            }),
+            (None, None) => Box::new(LocalStorage),
+        };
+        let storage = match self.config.compress_output {
+            Some(compression) => {
+                compress::check(compression)?;
+                Box::new(CompressedStorage {
+                    inner: storage,
+                    compression,
+                })
+            }
+            None => storage,
+        };
+        Ok(Pipeline {
+            ctx: Arc::new(Context {
+                config: self.config,
+                layout,
+                http,
+                breaker,
+                mirrors: MirrorRanking::default(),
+                throttle,
+                disk: DiskMonitor::default(),
+                downloaders,
+                state,
+                progress,
+                summary: Summary::new(),
+                metrics,
+                storage,
+                source: self.source.unwrap_or_else(|| Box::new(UniprotPdb)),
+                entries,
+                stop: watch::Sender::new(false),
+                locks: Mutex::default(),
+                superseded: Mutex::default(),
+                mapped: Mutex::default(),
+                left_out: Mutex::default(),
+                downloads: Mutex::default(),
+                retrying: Mutex::default(),
+                revalidating: AtomicBool::new(false),
+                events: self.events,
+            }),
+            input,
+            _lock: lock,
+        })
+    }
+}
+
+impl Pipeline {
+    pub fn builder(config: UserConfig) -> PipelineBuilder {
+        PipelineBuilder::new(config)
+    }
+
+    pub fn config(&self) -> &UserConfig {
+        &self.ctx.config
+    }
+
+    //Targets of the input with their positions in it
+    pub(crate) async fn targets(&self) -> Result<Vec<(usize, Target)>> {
+        let mut input = self.input()?;
+        let mut targets = Vec::new();
+        loop {
+            let batch = input.next_batch(&self.ctx).await?;
+            if batch.is_empty() {
+                return Ok(targets);
+            }
+            targets.extend(batch);
+        }
+    }
+
+    //Targets of the input as they are read, files and stdin by a thread of their own
+    pub(crate) fn input(&self) -> Result<TargetInput> {
+        let (sender, receiver) = mpsc::channel(INPUT_BUFFER);
+        let ctx = self.ctx.clone();
+        match self.input.clone() {
+            InputSource::Path(path) => {
+                task::spawn_blocking(move || input::send_targets(&ctx.config, &[path], &sender));
+            }
+            InputSource::Paths(patterns) => {
+                task::spawn_blocking(move || match input::expand_paths(&patterns) {
+                    Ok(paths) => input::send_targets(&ctx.config, &paths, &sender),
+                    Err(e) => {
+                        let _ = sender.blocking_send(Err(e));
+                    }
+                });
+            }
+            InputSource::Targets(targets) => {
+                task::spawn(async move {
+                    for target in targets {
+                        if sender.send(Ok(target)).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            InputSource::Chembl(query) => {
+                task::spawn(async move {
+                    match chembl::fetch_targets(&ctx, &query).await {
+                        Ok(targets) => {
+                            for target in targets {
+                                if sender.send(Ok(target)).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(e)).await;
+                        }
+                    }
+                });
+            }
+        }
+        TargetInput::new(receiver, &self.ctx.config)
+    }
+
+    //Lists and patterns the targets are read from, if they are read from files
+    pub(crate) fn input_paths(&self) -> Option<Vec<PathBuf>> {
+        let paths = match &self.input {
+            InputSource::Path(path) => vec![path.clone()],
+            InputSource::Paths(paths) => paths.clone(),
+            InputSource::Targets(_) | InputSource::Chembl(_) => return None,
+        };
+        (!paths.iter().any(|path| path == Path::new("-"))).then_some(paths)
+    }
+
+    //The next batch of the input, with the UniProt entries of its targets prefetched
+    async fn next_targets(&self, input: &mut TargetInput) -> Result<Vec<(usize, Target)>> {
+        let batch = input.next_batch(&self.ctx).await?;
+        if !batch.is_empty() {
+            self.prefetch_entries(&batch).await;
+            self.ctx.progress.set_targets(input.count);
+            self.ctx.metrics.set_targets(input.count);
+        }
+        Ok(batch)
+    }
+
+    //UniProt entries of the targets left to do, in batches rather than one by one
+    pub(crate) async fn prefetch_entries(&self, targets: &[(usize, Target)]) {
+        let accessions = targets
+            .iter()
+            .map(|(_, target)| target)
+            .filter(|target| !self.ctx.state.is_target_done(&target.chembl_id))
+            .flat_map(|target| target.uniprot_accession.split('|'));
+        uniprot::prefetch(&self.ctx, accessions).await;
+    }
+
+    /// Stop starting new work as a Ctrl+C would.
+    pub fn stop(&self) {
+        self.ctx.stop();
+    }
+
+    /// Download structures for every target of the input.
+    ///
+    /// On Ctrl+C or SIGTERM no new work is started, running tasks get `shutdown_timeout`
+    /// seconds to finish and are aborted then, and the state is flushed before returning.
+    pub async fn run(&self) -> Result<()> {
+        let result = self.run_targets().await;
+        if let Err(e) = &result {
+            notify::error(&self.ctx, e).await;
+        }
+        result
+    }
+
+    async fn run_targets(&self) -> Result<()> {
+        self.ctx.summary.start();
+        self.ctx.metrics.start_run();
+        self.ctx.progress.start_run();
+        let _metrics = match &self.ctx.config.metrics_addr {
+            Some(addr) => Some(metrics::serve(self.ctx.metrics.clone(), addr).await?),
+            None => None,
+        };
+        let mut tasks = JoinSet::new();
+        self.restore_state().await?;
+        let save_path = Path::new(&self.ctx.config.save_path);
+        layout::check_recorded(save_path, &self.ctx.config, &self.ctx.state)?;
+        if !save_path.join(layout::LAYOUT_FILE).exists() {
+            layout::record(save_path, &LayoutRecord::of(&self.ctx.config))?;
+        }
+        let probing = match &self.ctx.config.mirror_probe {
+            Some(probe) if !self.ctx.config.offline => {
+                mirrors::probe(&self.ctx, probe).await;
+                let ctx = self.ctx.clone();
+                let probe = probe.clone();
+                (probe.interval_secs > 0)
+                    .then(|| task::spawn(async move { mirrors::keep_probing(&ctx, &probe).await }))
+            }
+            _ => None,
+        };
+
+        let mut input = self.input()?;
+        //The run fails before anything starts when the first batch can't be read
+        let mut pending = match self.ctx.config.order {
+            TargetOrder::Input => VecDeque::from(self.next_targets(&mut input).await?),
+            _ => {
+                let mut targets = Vec::new();
+                loop {
+                    let batch = self.next_targets(&mut input).await?;
+                    if batch.is_empty() {
+                        break;
+                    }
+                    targets.extend(batch);
+                }
+                order::sort(&self.ctx, &mut targets).await;
+                VecDeque::from(targets)
+            }
+        };
+        let mut input_error = None;
+        disk::check(&self.ctx);
+        let checking = (self.ctx.config.disk_space.interval_secs > 0).then(|| {
+            let ctx = self.ctx.clone();
+            task::spawn(async move { disk::keep_checking(&ctx).await })
+        });
+        let drawing = self
+            .ctx
+            .progress
+            .dashboard()
+            .is_some()
+            .then(|| Drawing::spawn(self.ctx.clone()));
+        let signal_ctx = self.ctx.clone();
+        let signal = task::spawn(async move {
+            if shutdown::signal().await.is_ok() {
+                warn!("Interrupted, waiting for running tasks before exiting...");
+                signal_ctx.stop();
+            }
+        });
+        let timeout = Duration::from_secs(self.ctx.config.shutdown_timeout);
+        let mut deadline = None;
+        let mut running = HashMap::new();
+        loop {
+            //Targets are started as processors free up, read from the input as they are needed
+            while tasks.len() < self.ctx.config.processor_limit.max(1) && !self.ctx.is_stopping() {
+                if pending.is_empty() && input_error.is_none() {
+                    match self.next_targets(&mut input).await {
+                        Ok(batch) => pending.extend(batch),
+                        Err(e) => {
+                            error!("Failed to read the targets due to \"{}\"", e);
+                            input_error = Some(e);
+                        }
+                    }
+                }
+                let Some((i, target)) = pending.pop_front() else {
+                    break;
+                };
+                if self.ctx.state.is_target_done(&target.chembl_id) {
+                    debug!(target:"debug","Skipping finished target : {}", target.chembl_id);
+                    self.ctx.progress.target_done();
+                    continue;
+                }
+                let id = self.spawn_target(&mut tasks, i, target.clone());
+                running.insert(id, target);
+            }
+            tokio::select! {
+                result = tasks.join_next_with_id() => match result {
+                    Some(result) => {
+                        let (id, result) = match result {
+                            Ok((id, result)) => (id, result),
+                            //A panicking target fails alone, the others keep going
+                            Err(e) => {
+                                let id = e.id();
+                                let e = anyhow::Error::from(e);
+                                if let Some(target) = running.get(&id) {
+                                    self.target_panicked(target, &e);
+                                }
+                                (id, Err(e))
+                            }
+                        };
+                        running.remove(&id);
+                        if let Err(e) = result {
+                            error!("Failed to process data due to \"{}\"", e);
+                            notify::error(&self.ctx, &e).await;
+                        }
+                    }
+                    None => break,
+                },
+                _ = self.ctx.stopped(), if deadline.is_none() => {
+                    deadline = Some(Instant::now() + timeout);
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    warn!("Aborting {} tasks still running after {:?}", tasks.len(), timeout);
+                    tasks.shutdown().await;
+                    break;
+                }
+            }
+        }
+        signal.abort();
+        if let Some(probing) = probing {
+            probing.abort();
+        }
+        if let Some(checking) = checking {
+            checking.abort();
+        }
+
+        //Checkpoint
+        self.ctx.progress.finish();
+        if let Some(drawing) = drawing {
+            drawing.finish().await;
+        }
+        self.ctx.state.sync()?;
+        self.write_manifest()?;
+        if self.ctx.config.coverage_report {
+            if let Err(e) = self.write_coverage().await {
+                warn!("Failed to write the coverage report due to \"{}\"", e);
+            }
+        }
+        let summary = self.ctx.summary.finish(
+            Path::new(&self.ctx.config.save_path),
+            self.ctx.is_stopping(),
+        )?;
+        self.ctx.state.record_run(&summary)?;
+        self.ctx.state.sync()?;
+        self.upload_state().await?;
+        notify::run_finished(&self.ctx, &summary).await;
+        email::send_report(&self.ctx, &summary).await;
+        if let Some(e) = input_error {
+            return Err(e);
+        }
+        if self.ctx.is_stopping() {
+            info!("Procedure interrupted, run resume to continue. Exiting...");
+        } else {
+            info!("Procedure completed successfully. Exiting...");
+        }
+        Ok(())
+    }
+
+    //Process the target in a task of its own, counting its outcome in the summary
+    fn spawn_target(&self, tasks: &mut JoinSet<Result<()>>, i: usize, target: Target) -> task::Id {
+        let ctx = self.ctx.clone();
+        let path_target = ctx
+            .layout
+            .target_dir(Path::new(&ctx.config.save_path), i, &target);
+        let task = tasks.spawn(async move {
+            let started = Instant::now();
+            let log = ctx
+                .config
+                .target_logs
+                .then(|| logging::target_log(&ctx, &target, &path_target))
+                .flatten();
+            ctx.event(RunEvent::TargetStarted {
+                chembl_id: target.chembl_id.clone(),
+            });
+            let mut result = process_data(ctx.clone(), target.clone(), path_target.clone()).await;
+            //Files left by a stop are archived by the next run
+            if let (Ok(()), Some(format)) = (&result, ctx.config.archive) {
+                if !ctx.is_stopping() {
+                    result = archive::archive_target(&ctx, &target, format, &path_target).await;
+                }
+            }
+            let outcome = match &result {
+                Err(_) => "failed",
+                Ok(()) if ctx.state.is_target_done(&target.chembl_id) => "complete",
+                Ok(()) => "incomplete",
+            };
+            logging::target_event(&ctx, &target, started.elapsed(), outcome);
+            if let Some(log) = &log {
+                log.finished(outcome, result.as_ref().err());
+            }
+            ctx.event(RunEvent::TargetFinished {
+                chembl_id: target.chembl_id.clone(),
+                outcome: outcome.to_string(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+            match &result {
+                Err(e) => {
+                    ctx.summary.failure(&target, None, None, e);
+                    ctx.summary.target_failed(&target, e.to_string());
+                }
+                Ok(()) if outcome == "incomplete" && !ctx.is_stopping() => ctx
+                    .summary
+                    .target_failed(&target, "Some downloads failed".to_string()),
+                Ok(()) => {}
+            }
+            ctx.progress.target_done();
+            ctx.summary.target_processed();
+            ctx.metrics.target_processed();
+            result
+        });
+        task.id()
+    }
+
+    //Count a target whose task panicked as spawn_target counts a failed one
+    fn target_panicked(&self, target: &Target, e: &anyhow::Error) {
+        let ctx = &self.ctx;
+        ctx.event(RunEvent::TargetFinished {
+            chembl_id: target.chembl_id.clone(),
+            outcome: "failed".to_string(),
+            error: Some(e.to_string()),
+        });
+        ctx.summary.failure(target, None, None, e);
+        ctx.summary.target_failed(target, e.to_string());
+        ctx.progress.target_done();
+        ctx.summary.target_processed();
+        ctx.metrics.target_processed();
+    }
+
+    //A fresh staging folder continues from the journal kept by the storage
+    async fn restore_state(&self) -> Result<()> {
+        let path = Path::new(&self.ctx.config.save_path).join(state::JOURNAL_FILE);
+        if !self.ctx.state.is_empty() || path.metadata()?.len() > 0 {
+            return Ok(());
+        }
+        if let Some(journal) = self.ctx.storage.read(&path).await? {
+            if !journal.is_empty() {
+                info!("Continuing from the stored journal");
+                self.ctx.state.restore(&String::from_utf8_lossy(&journal))?;
+            }
+        }
+        Ok(())
+    }
+
+    //The journal, manifest and summary are stored next to the files
+    async fn upload_state(&self) -> Result<()> {
+        //Hashing them would only read the growing journal again
+        if self.ctx.storage.is_local() {
+            return Ok(());
+        }
+        let save_path = Path::new(&self.ctx.config.save_path);
+        for file in [
+            state::JOURNAL_FILE,
+            manifest::MANIFEST_FILE,
+            summary::RUN_FILE,
+            summary::FAILURES_FILE,
+        ] {
+            let path = save_path.join(file);
+            let (_, sha256) = checksum::hash_file(&path)?;
+            self.ctx.storage.store(&path, &sha256).await?;
+        }
+        if self.ctx.config.sqlite {
+            let path = save_path.join(database::DATABASE_FILE);
+            let (_, sha256) = checksum::hash_file(&path)?;
+            self.ctx.storage.store(&path, &sha256).await?;
+        }
+        Ok(())
+    }
+
+    /// Write every downloaded file recorded for the save path to `manifest.csv`, followed by
+    /// the structures skipped on purpose.
+    pub fn write_manifest(&self) -> Result<()> {
+        let mut entries = self.ctx.state.files();
+        entries.extend(self.ctx.state.skipped());
+        let save_path = Path::new(&self.ctx.config.save_path);
+        manifest::write(save_path, &entries)?;
+        if self.ctx.config.pdb_index {
+            pdb_index::write_index(save_path, &self.ctx.layout, &entries)?;
+        }
+        if self.ctx.config.parquet {
+            let stats = std::mem::take(&mut *self.ctx.downloads.lock().unwrap());
+            export::write(save_path, &entries, &stats)?;
+        }
+        Ok(())
+    }
+}
+
+//Files fetched besides the coordinates of a PDB entry
+async fn download_extras(ctx: &Context, pdb_id: &str, save_path: &Path) -> Result<Vec<Downloaded>> {
+    let mut downloaded = assembly::download_assemblies(ctx, pdb_id, save_path).await?;
+    downloaded.extend(validation::download_reports(ctx, pdb_id, save_path).await?);
+    downloaded.extend(emdb::download_maps(ctx, pdb_id, save_path).await?);
+    downloaded.extend(sifts::download_mapping(ctx, pdb_id, save_path).await?);
+    Ok(downloaded)
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(chembl_id = %target.chembl_id, target_name = %target.target_name)
+)]
+async fn process_data(ctx: Arc<Context>, target: Target, path_target: PathBuf) -> Result<()> {
+    let target = Arc::new(target);
+    if !path_target.exists() {
+        if let Err(e) = create_dir_all(&path_target) {
+            error!("Failed to create directory: {}", &path_target.display());
+            return Err(e.into());
+        }
+    }
+
+    let id_file = path_target.join(ctx.layout.sanitize(&target.chembl_id));
+    if !ctx.storage.exists(&id_file).await? {
+        if let Err(e) = File::create(&id_file).await {
+            error!("Failed to create file: {}", &id_file.display());
+            return Err(e.into());
+        }
+        let (_, sha256) = checksum::hash_file(&id_file)?;
+        ctx.storage.store(&id_file, &sha256).await?;
+    }
+
+    let mut complete = true;
+
+    match chembl::download_target_data(&ctx, &target, &path_target).await {
+        Ok(downloaded) => {
+            for downloaded in &downloaded {
+                ctx.record_download(&target, "", None, downloaded).await?;
+            }
+        }
+        Err(e) => {
+            error!("Failed to download ChEMBL data due to \"{}\"", e);
+            ctx.summary.failure(&target, None, None, &e);
+            complete = false;
+        }
+    }
+
+    if target.uniprot_accession.is_empty() {
+        info!("No Uniprot data for {}", target.target_name);
+        ctx.summary.without_uniprot(&target.chembl_id);
+        if complete {
+            ctx.state.mark_target_done(&target.chembl_id)?;
+        }
+        return Ok(());
+    }
+
+    let uniprot_accessions = target.uniprot_accession.split('|').collect::<Vec<_>>();
+    for uniprot_accession in uniprot_accessions {
+        if ctx.is_stopping() {
+            complete = false;
+            break;
+        }
+        if !select::is_allowed_taxon(&ctx, uniprot_accession).await? {
+            continue;
+        }
+        let lines = ctx
+            .source
+            .structures(&SourceContext { ctx: &ctx }, uniprot_accession)
+            .await?;
+        ctx.record_skipped(&target, uniprot_accession)?;
+
+        //Crating folder for target
+        let path_uniprot = ctx.layout.accession_dir(&path_target, uniprot_accession);
+        if !path_uniprot.exists()
+            && (!lines.is_empty()
+                || ctx.config.alphafold_fallback
+                || ctx.config.swissmodel_fallback
+                || ctx.config.esmfold_fallback
+                || ctx.config.fasta)
+        {
+            create_dir_all(&path_uniprot)?;
+        }
+
+        if ctx.config.fasta {
+            match uniprot::download_fasta(&ctx, uniprot_accession, &path_uniprot).await {
+                Ok(Some(downloaded)) => {
+                    ctx.record_download(&target, uniprot_accession, None, &downloaded)
+                        .await?
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to download FASTA sequence due to \"{}\"", e);
+                    ctx.summary
+                        .failure(&target, Some(uniprot_accession), None, &e);
+                    complete = false;
+                }
+            }
+        }
+
+        //Check if there is no PDB data
+        if lines.is_empty() {
+            info!(
+                "No PDB data found for {}:{}",
+                &target.target_name, uniprot_accession
+            );
+            ctx.summary
+                .without_pdb(&target.chembl_id, uniprot_accession);
+            //ESMFold only folds accessions AlphaFold has no model of
+            let mut without_model = true;
+            if ctx.config.alphafold_fallback {
+                match alphafold::download_model(&ctx, uniprot_accession, &path_uniprot).await {
+                    Ok(Some(downloaded)) => {
+                        without_model = false;
+                        for downloaded in &downloaded {
+                            ctx.record_model(&target, uniprot_accession, "alphafold", downloaded)
+                                .await?;
+                        }
+                    }
+                    Ok(None) => info!("No AlphaFold model of {}", uniprot_accession),
+                    Err(e) => {
+                        without_model = false;
+                        error!("Failed to download AlphaFold model due to \"{}\"", e);
+                        ctx.summary
+                            .failure(&target, Some(uniprot_accession), None, &e);
+                        complete = false;
+                    }
+                }
+            }
+            if ctx.config.esmfold_fallback && without_model {
+                match esmfold::download_model(&ctx, uniprot_accession, &path_uniprot).await {
+                    Ok(Some(downloaded)) => {
+                        ctx.record_model(&target, uniprot_accession, "esmfold", &downloaded)
+                            .await?
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Failed to fold with ESMFold due to \"{}\"", e);
+                        ctx.summary
+                            .failure(&target, Some(uniprot_accession), None, &e);
+                        complete = false;
+                    }
+                }
+            }
+            if ctx.config.swissmodel_fallback {
+                match swissmodel::download_models(&ctx, uniprot_accession, &path_uniprot).await {
+                    Ok(downloaded) => {
+                        for downloaded in &downloaded {
+                            ctx.record_model(&target, uniprot_accession, "swissmodel", downloaded)
+                                .await?;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to download SWISS-MODEL models due to \"{}\"", e);
+                        ctx.summary
+                            .failure(&target, Some(uniprot_accession), None, &e);
+                        complete = false;
+                    }
+                }
+            }
+            continue;
+        }
+
+        //Spawn download tasks
+        let bar = ctx.progress.target(&target.target_name, lines.len());
+        //Dropping the set on abort aborts the downloads as well
+        let mut tasks = JoinSet::new();
+        let mut pdb_ids = HashMap::new();
+        for pdb_id in lines {
+            if ctx
+                .state
+                .is_pdb_done(&target.chembl_id, uniprot_accession, &pdb_id)
+                && !ctx.is_revalidating()
+            {
+                bar.inc(1);
+                continue;
+            }
+            debug!(target:"debug","PDB ID : {}", pdb_id);
+            let semaphore = ctx.downloaders.clone();
+            let path_uniprot = path_uniprot.clone();
+            let ctx = ctx.clone();
+            let target = target.clone();
+            let accession = uniprot_accession.to_string();
+            let bar = bar.clone();
+            let span = tracing::info_span!("pdb_entry", accession = %accession, pdb_id = %pdb_id);
+            let id = pdb_id.clone();
+            let task = tasks.spawn(
+                async move {
+                    let permit = semaphore.acquire_owned().await.unwrap();
+                    if ctx.is_stopping() {
+                        return Ok(());
+                    }
+                    let started = Instant::now();
+                    let cx = SourceContext { ctx: &ctx };
+                    let entry_id = match ctx.source.resolve(&cx, &pdb_id).await {
+                        Ok(Some(entry_id)) => entry_id,
+                        Ok(None) => {
+                            drop(permit);
+                            bar.inc(1);
+                            logging::pdb_event(
+                                &ctx,
+                                &target,
+                                &accession,
+                                &pdb_id,
+                                None,
+                                started.elapsed(),
+                                "obsolete",
+                            );
+                            return ctx
+                                .state
+                                .mark_pdb_done(&target.chembl_id, &accession, &pdb_id);
+                        }
+                        Err(e) => {
+                            drop(permit);
+                            bar.inc(1);
+                            logging::pdb_event(
+                                &ctx,
+                                &target,
+                                &accession,
+                                &pdb_id,
+                                None,
+                                started.elapsed(),
+                                "failed",
+                            );
+                            return Err(e);
+                        }
+                    };
+                    let downloaded = ctx.source.fetch(&cx, &entry_id, &path_uniprot).await;
+                    let extras = match downloaded {
+                        Ok(_) => download_extras(&ctx, &entry_id, &path_uniprot).await,
+                        Err(_) => Ok(Vec::new()),
+                    };
+                    drop(permit);
+                    bar.inc(1);
+                    let (file, outcome) = match (&downloaded, &extras) {
+                        (Ok(Some(downloaded)), Ok(_)) => (Some(downloaded), "downloaded"),
+                        (Ok(None), Ok(_)) => (None, "present"),
+                        _ => (None, "failed"),
+                    };
+                    logging::pdb_event(
+                        &ctx,
+                        &target,
+                        &accession,
+                        &pdb_id,
+                        file,
+                        started.elapsed(),
+                        outcome,
+                    );
+                    if let Some(downloaded) = downloaded? {
+                        //Derived files are written before storage may take the coordinates away
+                        let derived =
+                            transform::process(&ctx, &accession, &entry_id, &downloaded).await;
+                        ctx.record_download(&target, &accession, Some(&pdb_id), &downloaded)
+                            .await?;
+                        for derived in derived {
+                            ctx.record_download(&target, &accession, Some(&pdb_id), &derived)
+                                .await?;
+                        }
+                    }
+                    for downloaded in extras? {
+                        ctx.record_download(&target, &accession, Some(&pdb_id), &downloaded)
+                            .await?;
+                    }
+                    ctx.state
+                        .mark_pdb_done(&target.chembl_id, &accession, &pdb_id)?;
+                    Result::<()>::Ok(())
+                }
+                .instrument(span),
+            );
+            pdb_ids.insert(task.id(), id);
+        }
+
+        //Wait until download done
+        while let Some(result) = tasks.join_next_with_id().await {
+            //A panicking download fails alone, the others keep going
+            let (id, result) = match result {
+                Ok((id, result)) => (id, result),
+                Err(e) => (e.id(), Err(e.into())),
+            };
+            if let Err(e) = result {
+                error!("Failed to download due to \"{}\"", e);
+                let pdb_id = pdb_ids.get(&id).map(String::as_str);
+                ctx.summary
+                    .failure(&target, Some(uniprot_accession), pdb_id, &e);
+                complete = false;
+            }
+        }
+        if ctx.is_stopping() {
+            complete = false;
+        }
+        bar.finish_and_clear();
+    }
+
+    //Targets with failed downloads are retried on resume
+    if complete {
+        ctx.state.mark_target_done(&target.chembl_id)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,190 @@
+use crate::alphafold;
+use crate::assembly;
+use crate::chembl;
+use crate::download::{mirror_file, stored_path};
+use crate::emdb;
+use crate::esmfold;
+use crate::layout;
+use crate::pipeline::{Context, Pipeline, Target};
+use crate::select;
+use crate::sifts;
+use crate::status;
+use crate::swissmodel;
+use crate::uniprot;
+use crate::validation;
+use anyhow::Result;
+use serde_derive::Serialize;
+use std::path::Path;
+use tokio::task::JoinSet;
+
+pub(crate) const PLAN_FILE: &str = "plan.csv";
+
+/// A file a run would download.
+#[derive(Serialize, Debug, Clone)]
+pub struct PlannedFile {
+    pub chembl_id: String,
+    pub target_name: String,
+    pub accession: String,
+    pub pdb_id: Option<String>,
+    pub path: String,
+    pub url: String,
+}
+
+impl Pipeline {
+    /// Work out which files a run would download, without downloading or creating anything.
+    ///
+    /// Only the first format and mirror is listed for every PDB entry, with its assemblies
+    /// validation reports, EMDB maps and SIFTS mapping.
+    pub async fn plan(&self) -> Result<Vec<PlannedFile>> {
+        let mut tasks = JoinSet::new();
+        let save_path = Path::new(&self.config().save_path);
+        layout::check_recorded(save_path, self.config(), &self.ctx.state)?;
+        let targets = self.targets().await?;
+        self.prefetch_entries(&targets).await;
+        let mut pending = targets
+            .into_iter()
+            .filter(|(_, target)| !self.ctx.state.is_target_done(&target.chembl_id));
+
+        let mut plan = Vec::new();
+        loop {
+            while tasks.len() < self.config().processor_limit.max(1) {
+                let Some((i, target)) = pending.next() else {
+                    break;
+                };
+                let ctx = self.ctx.clone();
+                tasks.spawn(async move {
+                    let planned = plan_target(&ctx, i, &target).await;
+                    (i, target, planned)
+                });
+            }
+            let Some(result) = tasks.join_next().await else {
+                break;
+            };
+            let (i, target, planned) = result?;
+            match planned {
+                Ok(planned) => {
+                    info!(
+                        "{} ({}): {} files to download",
+                        target.target_name,
+                        target.chembl_id,
+                        planned.len()
+                    );
+                    plan.push((i, planned));
+                }
+                Err(e) => error!("Failed to plan {} due to \"{}\"", target.chembl_id, e),
+            }
+        }
+        plan.sort_by_key(|(i, _)| *i);
+        let plan = plan
+            .into_iter()
+            .flat_map(|(_, planned)| planned)
+            .collect::<Vec<_>>();
+        info!("{} files to download in total", plan.len());
+        Ok(plan)
+    }
+
+    /// Write a plan to `plan.csv` in the save path.
+    pub fn write_plan(&self, plan: &[PlannedFile]) -> Result<()> {
+        let mut writer =
+            csv::Writer::from_path(Path::new(&self.config().save_path).join(PLAN_FILE))?;
+        for planned in plan {
+            writer.serialize(planned)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+async fn plan_target(ctx: &Context, index: usize, target: &Target) -> Result<Vec<PlannedFile>> {
+    let path_target = ctx
+        .layout
+        .target_dir(Path::new(&ctx.config.save_path), index, target);
+    let mut planned = Vec::new();
+    let mut chembl_files = Vec::new();
+    if ctx.config.chembl.activities {
+        chembl_files.push(chembl::activities_file(ctx, &path_target));
+    }
+    if ctx.config.chembl.compounds {
+        chembl_files.push(chembl::compounds_file(ctx, &path_target));
+    }
+    for path in chembl_files {
+        if !path.exists() {
+            planned.push(PlannedFile {
+                chembl_id: target.chembl_id.clone(),
+                target_name: target.target_name.clone(),
+                accession: String::new(),
+                pdb_id: None,
+                path: path.to_string_lossy().into_owned(),
+                url: chembl::activity_url(ctx, &target.chembl_id)?.to_string(),
+            });
+        }
+    }
+    for accession in target
+        .uniprot_accession
+        .split('|')
+        .filter(|accession| !accession.is_empty())
+    {
+        if !select::is_allowed_taxon(ctx, accession).await? {
+            continue;
+        }
+        let path_uniprot = ctx.layout.accession_dir(&path_target, accession);
+        let pdb_ids = select::wanted_pdb_ids(ctx, accession).await?;
+        let mut files = Vec::new();
+        if ctx.config.fasta {
+            let (url, path) = uniprot::fasta_file(&ctx.config, accession, &path_uniprot)?;
+            files.push((None, url, path));
+        }
+        if pdb_ids.is_empty() && ctx.config.alphafold_fallback {
+            for (url, path) in alphafold::model_files(accession, &path_uniprot)? {
+                files.push((None, url, path));
+            }
+        }
+        //Whether AlphaFold has a model is only known by downloading it
+        if pdb_ids.is_empty() && ctx.config.esmfold_fallback && !ctx.config.alphafold_fallback {
+            let (url, path) = esmfold::model_file(accession, &path_uniprot)?;
+            files.push((None, url, path));
+        }
+        if pdb_ids.is_empty() && ctx.config.swissmodel_fallback {
+            for (url, path) in swissmodel::model_files(ctx, accession, &path_uniprot).await? {
+                files.push((None, url, path));
+            }
+        }
+        for pdb_id in pdb_ids {
+            if ctx.state.is_pdb_done(&target.chembl_id, accession, &pdb_id) {
+                continue;
+            }
+            let Some(entry_id) = status::current_id(ctx, &pdb_id).await? else {
+                continue;
+            };
+            if let Some(source) = ctx.config.sources().first() {
+                let (url, path) = mirror_file(&ctx.layout, source, &entry_id, &path_uniprot)?;
+                files.push((Some(pdb_id.clone()), url, stored_path(&ctx.config, &path)));
+            }
+            let mut extras = assembly::assembly_files(ctx, &entry_id, &path_uniprot).await?;
+            extras.extend(validation::report_files(
+                &ctx.config.validation_reports,
+                &entry_id,
+                &path_uniprot,
+            )?);
+            extras.extend(emdb::map_files(ctx, &entry_id, &path_uniprot).await?);
+            extras.extend(sifts::mapping_file(&ctx.config, &entry_id, &path_uniprot)?);
+            for (url, path) in extras {
+                files.push((Some(pdb_id.clone()), url, stored_path(&ctx.config, &path)));
+            }
+        }
+        for (pdb_id, url, path) in files {
+            if path.exists() {
+                continue;
+            }
+            planned.push(PlannedFile {
+                chembl_id: target.chembl_id.clone(),
+                target_name: target.target_name.clone(),
+                accession: accession.to_string(),
+                pdb_id,
+                path: path.to_string_lossy().into_owned(),
+                url: url.to_string(),
+            });
+        }
+    }
+    Ok(planned)
+}
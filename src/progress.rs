@@ -0,0 +1,125 @@
+use crate::dashboard::Dashboard;
+use crate::pipeline::RunEvent;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::Url;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Progress bars of a run, hidden unless enabled, or the dashboard instead of them.
+pub(crate) struct Progress {
+    multi: MultiProgress,
+    targets: ProgressBar,
+    downloads: ProgressBar,
+    active: AtomicUsize,
+    dashboard: Option<Dashboard>,
+    finished: AtomicBool,
+}
+
+impl Progress {
+    pub fn new(enabled: bool, dashboard: bool) -> Self {
+        let multi = MultiProgress::with_draw_target(if enabled && !dashboard {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        });
+        let targets = multi.add(ProgressBar::new(0));
+        targets.set_style(
+            ProgressStyle::with_template("{bar:40} {pos}/{len} targets, ETA {eta} {msg}").unwrap(),
+        );
+        let downloads = multi.add(ProgressBar::new_spinner());
+        downloads.set_style(
+            ProgressStyle::with_template(
+                "{spinner} {binary_bytes} downloaded ({binary_bytes_per_sec}) {msg}",
+            )
+            .unwrap(),
+        );
+        downloads.set_message("0 active downloads");
+        Progress {
+            multi,
+            targets,
+            downloads,
+            active: AtomicUsize::new(0),
+            dashboard: dashboard.then(Dashboard::default),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    pub fn dashboard(&self) -> Option<&Dashboard> {
+        self.dashboard.as_ref()
+    }
+
+    pub fn event(&self, event: &RunEvent) {
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.event(event);
+        }
+    }
+
+    pub fn set_targets(&self, total: usize) {
+        self.targets.set_length(total as u64);
+    }
+
+    /// Bar of the structures of one target, to finish once done.
+    pub fn target(&self, name: &str, structures: usize) -> ProgressBar {
+        let bar = self
+            .multi
+            .insert_before(&self.targets, ProgressBar::new(structures as u64));
+        bar.set_style(ProgressStyle::with_template("  {bar:30} {pos}/{len} {msg}").unwrap());
+        bar.set_message(name.to_string());
+        bar
+    }
+
+    pub fn target_done(&self) {
+        self.targets.inc(1);
+    }
+
+    /// Count a download of `url` as active until the guard is dropped.
+    pub fn download<'a>(&'a self, url: &'a Url) -> ActiveDownload<'a> {
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.downloads
+            .set_message(format!("{} active downloads", active));
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.transfer_started(url.as_str());
+        }
+        ActiveDownload(self, url)
+    }
+
+    pub fn bytes(&self, url: &Url, bytes: u64) {
+        self.downloads.inc(bytes);
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.bytes(url.as_str(), bytes);
+        }
+    }
+
+    /// Show the next run of the same pipeline from the start, the last one being finished.
+    pub fn start_run(&self) {
+        self.targets.reset();
+        self.downloads.reset();
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.start_run();
+        }
+        self.finished.store(false, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self) {
+        self.targets.finish();
+        self.downloads.finish();
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct ActiveDownload<'a>(&'a Progress, &'a Url);
+
+impl Drop for ActiveDownload<'_> {
+    fn drop(&mut self) {
+        let active = self.0.active.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.0
+            .downloads
+            .set_message(format!("{} active downloads", active));
+        if let Some(dashboard) = &self.0.dashboard {
+            dashboard.transfer_finished(self.1.as_str());
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use crate::pipeline::Context;
+use anyhow::Result;
+use reqwest::Url;
+use serde_json::Value;
+
+const RCSB_ENTRY_URL: &str = "https://data.rcsb.org/rest/v1/core/entry/";
+const RCSB_ENTITY_URL: &str = "https://data.rcsb.org/rest/v1/core/polymer_entity/";
+
+async fn entry(ctx: &Context, pdb_id: &str) -> Result<Value> {
+    let url: Url = format!("{}{}", RCSB_ENTRY_URL, pdb_id).parse()?;
+    Ok(serde_json::from_str(&ctx.http.get_text(&url).await?)?)
+}
+
+/// Identifiers listed under `rcsb_entry_container_identifiers.{key}` of the RCSB entry of `pdb_id`.
+pub(crate) async fn entry_identifiers(
+    ctx: &Context,
+    pdb_id: &str,
+    key: &str,
+) -> Result<Vec<String>> {
+    let entry = entry(ctx, pdb_id).await?;
+    Ok(entry["rcsb_entry_container_identifiers"][key]
+        .as_array()
+        .map(|ids| {
+            ids.iter()
+                .filter_map(|id| id.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// First release of `pdb_id`, e.g. "1998-04-08T00:00:00+0000".
+pub(crate) async fn release_date(ctx: &Context, pdb_id: &str) -> Result<Option<String>> {
+    let entry = entry(ctx, pdb_id).await?;
+    Ok(entry["rcsb_accession_info"]["initial_release_date"]
+        .as_str()
+        .map(str::to_string))
+}
+
+/// Sequence cluster of entity `entity_id` of `pdb_id` at `identity` percent identity.
+pub(crate) async fn sequence_cluster(
+    ctx: &Context,
+    pdb_id: &str,
+    entity_id: u64,
+    identity: u8,
+) -> Result<Option<u64>> {
+    let url: Url = format!("{}{}/{}", RCSB_ENTITY_URL, pdb_id, entity_id).parse()?;
+    let entity: Value = serde_json::from_str(&ctx.http.get_text(&url).await?)?;
+    Ok(entity["rcsb_cluster_membership"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|membership| membership["identity"].as_u64() == Some(identity.into()))
+        .and_then(|membership| membership["cluster_id"].as_u64()))
+}
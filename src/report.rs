@@ -0,0 +1,272 @@
+use crate::manifest::ManifestEntry;
+use crate::pipeline::Pipeline;
+use crate::summary::RunSummary;
+use crate::verify::structure_files;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Format of the report written by [`Pipeline::write_report`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// `report.md`
+    Markdown,
+    /// `report.html`, linking to the accession folders
+    Html,
+}
+
+impl Pipeline {
+    /// Log a summary of the save path.
+    pub fn report(&self) -> Result<()> {
+        let files = structure_files(Path::new(&self.config().save_path), self.ctx.layout.depth())?;
+        let mut bytes = 0;
+        for file in &files {
+            bytes += file.metadata()?.len();
+        }
+        let accessions = files
+            .iter()
+            .filter_map(|file| file.parent())
+            .collect::<HashSet<_>>();
+        info!(
+            "{} structure files ({} bytes) for {} Uniprot accessions",
+            files.len(),
+            bytes,
+            accessions.len()
+        );
+        Ok(())
+    }
+
+    /// Write a report of the last run and of the files downloaded into the save path.
+    pub fn write_report(&self, format: ReportFormat) -> Result<PathBuf> {
+        let save_path = Path::new(&self.config().save_path);
+        let run = RunSummary::load(save_path)?;
+        let files = self.ctx.state.files();
+        let report = match format {
+            ReportFormat::Markdown => markdown(run.as_ref(), &files)?,
+            ReportFormat::Html => html(run.as_ref(), &files)?,
+        };
+        let path = save_path.join(match format {
+            ReportFormat::Markdown => "report.md",
+            ReportFormat::Html => "report.html",
+        });
+        std::fs::write(&path, report)?;
+        info!("Report written to {}", path.display());
+        Ok(path)
+    }
+}
+
+//Files of each target, by accession folder
+struct TargetFiles<'a> {
+    target_name: &'a str,
+    structures: usize,
+    files: usize,
+    bytes: u64,
+    folders: BTreeMap<String, usize>,
+}
+
+fn by_target(files: &[ManifestEntry]) -> BTreeMap<&str, TargetFiles<'_>> {
+    let mut targets = BTreeMap::new();
+    for file in files {
+        let target = targets
+            .entry(file.chembl_id.as_str())
+            .or_insert_with(|| TargetFiles {
+                target_name: &file.target_name,
+                structures: 0,
+                files: 0,
+                bytes: 0,
+                folders: BTreeMap::new(),
+            });
+        target.files += 1;
+        target.bytes += file.size;
+        if file.pdb_id.is_some() {
+            target.structures += 1;
+            let folder = Path::new(&file.path)
+                .parent()
+                .map(|folder| folder.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            *target.folders.entry(folder).or_default() += 1;
+        }
+    }
+    targets
+}
+
+/// The report of [`ReportFormat::Markdown`].
+pub(crate) fn markdown(run: Option<&RunSummary>, files: &[ManifestEntry]) -> Result<String> {
+    let mut out = String::from("# prog_med report\n\n");
+    if let Some(run) = run {
+        writeln!(out, "## Last run\n")?;
+        writeln!(out, "- Started: {}", run.started_at)?;
+        writeln!(out, "- Finished: {}", run.finished_at)?;
+        writeln!(out, "- Elapsed: {:.0} s", run.elapsed_secs)?;
+        if run.interrupted {
+            writeln!(out, "- **Interrupted**, run resume to continue")?;
+        }
+        writeln!(out, "- Targets processed: {}", run.targets_processed)?;
+        writeln!(
+            out,
+            "- Targets without UniProt data: {}",
+            list(&run.targets_without_uniprot)
+        )?;
+        writeln!(
+            out,
+            "- Accessions without PDB data: {}",
+            list(&run.accessions_without_pdb)
+        )?;
+        writeln!(out, "- Failed targets: {}", list(&failed_ids(run)))?;
+        writeln!(out, "\n### Failures\n")?;
+        if run.failures.is_empty() {
+            writeln!(out, "None")?;
+        } else {
+            writeln!(out, "| Cause | Count |\n|---|---|")?;
+            for (cause, count) in &run.failures {
+                writeln!(out, "| {} | {} |", cause, count)?;
+            }
+        }
+        writeln!(out)?;
+    }
+
+    let targets = by_target(files);
+    let bytes = files.iter().map(|file| file.size).sum::<u64>();
+    writeln!(out, "## Downloads\n")?;
+    writeln!(
+        out,
+        "{} files ({} bytes) for {} targets\n",
+        files.len(),
+        bytes,
+        targets.len()
+    )?;
+    writeln!(
+        out,
+        "| ChEMBL ID | Target | Structures | Files | Bytes | Folders |\n|---|---|---|---|---|---|"
+    )?;
+    for (chembl_id, target) in &targets {
+        let folders = target
+            .folders
+            .iter()
+            .map(|(folder, count)| format!("[{}]({}) ({})", folder, folder, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            out,
+            "| {} | {} | {} | {} | {} | {} |",
+            chembl_id,
+            target.target_name.replace('|', "\\|"),
+            target.structures,
+            target.files,
+            target.bytes,
+            folders
+        )?;
+    }
+    Ok(out)
+}
+
+fn html(run: Option<&RunSummary>, files: &[ManifestEntry]) -> Result<String> {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>prog_med report</title>\n\
+         <style>table{border-collapse:collapse}td,th{border:1px solid #ccc;padding:2px 6px}</style>\n\
+         </head>\n<body>\n<h1>prog_med report</h1>\n",
+    );
+    if let Some(run) = run {
+        writeln!(out, "<h2>Last run</h2>\n<ul>")?;
+        writeln!(out, "<li>Started: {}</li>", escape(&run.started_at))?;
+        writeln!(out, "<li>Finished: {}</li>", escape(&run.finished_at))?;
+        writeln!(out, "<li>Elapsed: {:.0} s</li>", run.elapsed_secs)?;
+        if run.interrupted {
+            writeln!(
+                out,
+                "<li><strong>Interrupted</strong>, run resume to continue</li>"
+            )?;
+        }
+        writeln!(out, "<li>Targets processed: {}</li>", run.targets_processed)?;
+        writeln!(
+            out,
+            "<li>Targets without UniProt data: {}</li>",
+            escape(&list(&run.targets_without_uniprot))
+        )?;
+        writeln!(
+            out,
+            "<li>Accessions without PDB data: {}</li>",
+            escape(&list(&run.accessions_without_pdb))
+        )?;
+        writeln!(
+            out,
+            "<li>Failed targets: {}</li>\n</ul>",
+            escape(&list(&failed_ids(run)))
+        )?;
+        writeln!(out, "<h3>Failures</h3>")?;
+        if run.failures.is_empty() {
+            writeln!(out, "<p>None</p>")?;
+        } else {
+            writeln!(out, "<table>\n<tr><th>Cause</th><th>Count</th></tr>")?;
+            for (cause, count) in &run.failures {
+                writeln!(out, "<tr><td>{}</td><td>{}</td></tr>", escape(cause), count)?;
+            }
+            writeln!(out, "</table>")?;
+        }
+    }
+
+    let targets = by_target(files);
+    let bytes = files.iter().map(|file| file.size).sum::<u64>();
+    writeln!(out, "<h2>Downloads</h2>")?;
+    writeln!(
+        out,
+        "<p>{} files ({} bytes) for {} targets</p>",
+        files.len(),
+        bytes,
+        targets.len()
+    )?;
+    writeln!(
+        out,
+        "<table>\n<tr><th>ChEMBL ID</th><th>Target</th><th>Structures</th><th>Files</th><th>Bytes</th><th>Folders</th></tr>"
+    )?;
+    for (chembl_id, target) in &targets {
+        let folders = target
+            .folders
+            .iter()
+            .map(|(folder, count)| {
+                format!(
+                    "<a href=\"{}\">{}</a> ({})",
+                    escape(folder),
+                    escape(folder),
+                    count
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(chembl_id),
+            escape(target.target_name),
+            target.structures,
+            target.files,
+            target.bytes,
+            folders
+        )?;
+    }
+    writeln!(out, "</table>\n</body>\n</html>")?;
+    Ok(out)
+}
+
+fn failed_ids(run: &RunSummary) -> Vec<String> {
+    run.failed_targets
+        .iter()
+        .map(|target| target.chembl_id.clone())
+        .collect()
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        format!("{} ({})", items.len(), items.join(", "))
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,33 @@
+use crate::pipeline::Pipeline;
+use crate::summary::{FailedItem, FAILURES_FILE};
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::path::Path;
+
+impl Pipeline {
+    /// Process again the targets with items listed in `failures.csv` by the last run.
+    ///
+    /// Work recorded as done is skipped, so only what failed is fetched again. `failures.csv`
+    /// then lists what still fails.
+    pub async fn retry_failed(&self) -> Result<()> {
+        let save_path = Path::new(&self.config().save_path);
+        let Some(failed) = FailedItem::load(save_path)? else {
+            bail!("No {} in {}", FAILURES_FILE, save_path.display());
+        };
+        if failed.is_empty() {
+            info!("Nothing failed in the last run");
+            return Ok(());
+        }
+        let targets = failed
+            .iter()
+            .map(|item| item.chembl_id.clone())
+            .collect::<HashSet<_>>();
+        info!(
+            "Retrying {} failed items of {} targets",
+            failed.len(),
+            targets.len()
+        );
+        *self.ctx.retrying.lock().unwrap() = Some(targets);
+        self.run().await
+    }
+}
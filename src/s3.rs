@@ -0,0 +1,515 @@
+use crate::config::UserConfig;
+use crate::http::{Http, HttpError};
+use crate::storage::Storage;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, ETAG};
+use reqwest::{Body, Method, StatusCode, Url};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+//Largest object of a single PUT
+const MAX_PUT: u64 = 5 * 1024 * 1024 * 1024;
+//Smallest part of a multipart upload, grown for objects of more than MAX_PARTS of them
+const PART_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+const CONTAINER_CREDENTIALS_HOST: &str = "http://169.254.170.2";
+
+/// Bucket and prefix of a `save_path` like `s3://bucket/prefix`, written through a local
+/// staging folder file by file.
+pub(crate) struct S3 {
+    bucket: String,
+    prefix: String,
+    endpoint: Url,
+    region: String,
+    path_style: bool,
+    /// Local folder files are downloaded to before they are uploaded
+    pub staging: PathBuf,
+    credentials: Mutex<Option<Credentials>>,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    token: Option<String>,
+    expires: Option<DateTime<Utc>>,
+}
+
+//As answered by the credentials endpoint of ECS and AWS Batch containers
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+    expiration: Option<DateTime<Utc>>,
+}
+
+impl S3 {
+    /// The bucket `save_path` points to, if it is an `s3://` url.
+    pub fn parse(config: &UserConfig) -> Result<Option<S3>> {
+        let Some(location) = config.save_path.strip_prefix("s3://") else {
+            return Ok(None);
+        };
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            bail!("save_path \"{}\" has no bucket", config.save_path);
+        }
+        let region = config
+            .s3
+            .region
+            .clone()
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = match &config.s3.endpoint {
+            Some(endpoint) => endpoint.parse()?,
+            None => format!("https://s3.{}.amazonaws.com", region).parse()?,
+        };
+        let staging = match &config.s3.staging_path {
+            Some(staging) => PathBuf::from(staging),
+            None => std::env::temp_dir().join("prog_med").join(bucket),
+        };
+        Ok(Some(S3 {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            endpoint,
+            region,
+            path_style: config.s3.path_style,
+            staging: staging.join(prefix.trim_matches('/')),
+            credentials: Mutex::new(None),
+        }))
+    }
+
+    /// Key of the file at `path` in the staging folder.
+    pub fn key(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.staging).unwrap_or(path);
+        let relative = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if self.prefix.is_empty() {
+            relative
+        } else {
+            format!("{}/{}", self.prefix, relative)
+        }
+    }
+
+    /// Whether `key` is in the bucket, asking with HEAD.
+    pub async fn exists(&self, http: &Http, key: &str) -> Result<bool> {
+        let url = self.url(key)?;
+        let result = http
+            .with_retry_expecting(&url, &[StatusCode::NOT_FOUND], || async {
+                self.send(http, Method::HEAD, &url, EMPTY_SHA256, None)
+                    .await
+            })
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(HttpError::Status {
+                status: StatusCode::NOT_FOUND,
+                ..
+            }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Content of `key`, or None if it isn't in the bucket.
+    pub async fn get(&self, http: &Http, key: &str) -> Result<Option<Vec<u8>>> {
+        let url = self.url(key)?;
+        let result = http
+            .with_retry_expecting(&url, &[StatusCode::NOT_FOUND], || async {
+                let response = self
+                    .send(http, Method::GET, &url, EMPTY_SHA256, None)
+                    .await?;
+                response
+                    .bytes()
+                    .await
+                    .map_err(|source| HttpError::Transport {
+                        url: url.clone(),
+                        source,
+                    })
+            })
+            .await;
+        match result {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(HttpError::Status {
+                status: StatusCode::NOT_FOUND,
+                ..
+            }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Upload the file at `path` as `key`, `sha256` being the hex hash of its content.
+    ///
+    /// Files of more than 5 GB, which single PUT requests don't take, are uploaded in parts.
+    pub async fn put_file(&self, http: &Http, key: &str, path: &Path, sha256: &str) -> Result<()> {
+        let url = self.url(key)?;
+        let size = tokio::fs::metadata(path).await?.len();
+        if size > MAX_PUT {
+            self.put_parts(http, &url, path, size).await?;
+            debug!(target:"debug","Uploaded {} to {} in parts", path.display(), url);
+            return Ok(());
+        }
+        http.with_retry(&url, || async {
+            let file = tokio::fs::File::open(path).await?;
+            let size = file.metadata().await?.len();
+            self.send(
+                http,
+                Method::PUT,
+                &url,
+                sha256,
+                Some((Body::from(file), size)),
+            )
+            .await
+        })
+        .await?;
+        debug!(target:"debug","Uploaded {} to {}", path.display(), url);
+        Ok(())
+    }
+
+    //Multipart upload, read part by part from the file and aborted if a part fails
+    async fn put_parts(&self, http: &Http, url: &Url, path: &Path, size: u64) -> Result<()> {
+        let created = self
+            .post(http, &with_query(url, "uploads="), String::new())
+            .await?;
+        let upload_id = element(&created, "UploadId")
+            .ok_or_else(|| anyhow!("{} started no multipart upload", url))?;
+        let upload_query = format!("uploadId={}", encode(&upload_id));
+        let result = async {
+            let part_size = PART_SIZE.max(size.div_ceil(MAX_PARTS));
+            let mut file = tokio::fs::File::open(path).await?;
+            let mut parts = String::new();
+            for number in 1..=size.div_ceil(part_size) {
+                let mut part = Vec::new();
+                (&mut file).take(part_size).read_to_end(&mut part).await?;
+                let part = Bytes::from(part);
+                let part_sha256 = format!("{:x}", Sha256::digest(&part));
+                let part_url = with_query(url, &format!("partNumber={}&{}", number, upload_query));
+                let response = http
+                    .with_retry(&part_url, || async {
+                        self.send(
+                            http,
+                            Method::PUT,
+                            &part_url,
+                            &part_sha256,
+                            Some((Body::from(part.clone()), part.len() as u64)),
+                        )
+                        .await
+                    })
+                    .await?;
+                let etag = response
+                    .headers()
+                    .get(ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .ok_or_else(|| anyhow!("{} sent no ETag of part {}", url, number))?;
+                parts.push_str(&format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    number, etag
+                ));
+            }
+            let completed = self
+                .post(
+                    http,
+                    &with_query(url, &upload_query),
+                    format!(
+                        "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+                        parts
+                    ),
+                )
+                .await?;
+            //Failures to complete may come with 200 OK
+            if let Some(code) = element(&completed, "Code") {
+                bail!("{} failed to complete the upload: {}", url, code);
+            }
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            let abort_url = with_query(url, &upload_query);
+            if let Err(e) = self
+                .send(http, Method::DELETE, &abort_url, EMPTY_SHA256, None)
+                .await
+            {
+                warn!(target:"warn","Failed to abort the upload to {} due to \"{}\"", url, e);
+            }
+        }
+        result
+    }
+
+    //POST `body` to `url` and read the answer as text, retrying transient failures
+    async fn post(&self, http: &Http, url: &Url, body: String) -> Result<String, HttpError> {
+        let sha256 = format!("{:x}", Sha256::digest(body.as_bytes()));
+        http.with_retry(url, || async {
+            self.send(
+                http,
+                Method::POST,
+                url,
+                &sha256,
+                Some((Body::from(body.clone()), body.len() as u64)),
+            )
+            .await?
+            .text()
+            .await
+            .map_err(|source| HttpError::Transport {
+                url: url.clone(),
+                source,
+            })
+        })
+        .await
+    }
+
+    fn url(&self, key: &str) -> Result<Url> {
+        let path = key.split('/').map(encode).collect::<Vec<_>>().join("/");
+        let mut url = self.endpoint.clone();
+        if self.path_style {
+            url.set_path(&format!("{}/{}", encode(&self.bucket), path));
+        } else {
+            let host = url
+                .host_str()
+                .ok_or_else(|| anyhow!("S3 endpoint {} has no host", self.endpoint))?;
+            let host = format!("{}.{}", self.bucket, host);
+            url.set_host(Some(&host))?;
+            url.set_path(&path);
+        }
+        Ok(url)
+    }
+
+    //Signed with AWS Signature Version 4
+    async fn send(
+        &self,
+        http: &Http,
+        method: Method,
+        url: &Url,
+        payload_sha256: &str,
+        body: Option<(Body, u64)>,
+    ) -> Result<reqwest::Response, HttpError> {
+        let credentials = self.credentials(http).await?;
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_sha256.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &credentials.token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect::<String>();
+        //Every query is built with encoded names and values, so only their order changes
+        let mut query = url
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .collect::<Vec<_>>();
+        query.sort_unstable();
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            url.path(),
+            query.join("&"),
+            canonical_headers,
+            signed_headers,
+            payload_sha256
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+        let mut key = hmac(
+            format!("AWS4{}", credentials.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers.into_iter().skip(1) {
+            header_map.insert(name, header_value(url, &value)?);
+        }
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key, scope, signed_headers, signature
+        );
+        header_map.insert(AUTHORIZATION, header_value(url, &authorization)?);
+        let mut request = http.client.request(method, url.clone()).headers(header_map);
+        if let Some((body, size)) = body {
+            request = request.header(CONTENT_LENGTH, size).body(body);
+        }
+        http.send_signed(url, request).await
+    }
+
+    //From the environment, or the credentials endpoint of the container
+    async fn credentials(&self, http: &Http) -> Result<Credentials, HttpError> {
+        let mut credentials = self.credentials.lock().await;
+        if let Some(current) = &*credentials {
+            if current
+                .expires
+                .is_none_or(|expires| expires - Duration::minutes(5) > Utc::now())
+            {
+                return Ok(current.clone());
+            }
+        }
+        let fresh = match (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key), Ok(secret_key)) => Credentials {
+                access_key,
+                secret_key,
+                token: std::env::var("AWS_SESSION_TOKEN").ok(),
+                expires: None,
+            },
+            _ => container_credentials(http).await?,
+        };
+        *credentials = Some(fresh.clone());
+        Ok(fresh)
+    }
+}
+
+/// Files uploaded to a bucket as they complete, `save_path` being the staging folder.
+pub(crate) struct S3Storage {
+    pub s3: S3,
+    pub http: Arc<Http>,
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        if tokio::fs::try_exists(path).await? {
+            return Ok(true);
+        }
+        self.s3.exists(&self.http, &self.s3.key(path)).await
+    }
+
+    async fn store(&self, path: &Path, sha256: &str) -> Result<()> {
+        self.s3
+            .put_file(&self.http, &self.s3.key(path), path, sha256)
+            .await
+    }
+
+    async fn release(&self, path: &Path) -> Result<()> {
+        Ok(tokio::fs::remove_file(path).await?)
+    }
+
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        self.s3.get(&self.http, &self.s3.key(path)).await
+    }
+}
+
+async fn container_credentials(http: &Http) -> Result<Credentials, HttpError> {
+    let url = match (
+        std::env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+        std::env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+    ) {
+        (Ok(relative), _) => format!("{}{}", CONTAINER_CREDENTIALS_HOST, relative),
+        (_, Ok(full)) => full,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No S3 credentials, set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY",
+            )
+            .into())
+        }
+    };
+    let url: Url = url.parse().map_err(std::io::Error::other)?;
+    let page = http
+        .with_retry(&url, || async {
+            let mut request = http.client.get(url.clone());
+            if let Ok(token) = std::env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+                request = request.header(AUTHORIZATION, token);
+            }
+            http.send_signed(&url, request)
+                .await?
+                .text()
+                .await
+                .map_err(|source| HttpError::Transport {
+                    url: url.clone(),
+                    source,
+                })
+        })
+        .await?;
+    let credentials: ContainerCredentials = serde_json::from_str(&page)
+        .map_err(|e| HttpError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+    Ok(Credentials {
+        access_key: credentials.access_key_id,
+        secret_key: credentials.secret_access_key,
+        token: credentials.token,
+        expires: credentials.expiration,
+    })
+}
+
+//`url` with the already encoded `query`
+fn with_query(url: &Url, query: &str) -> Url {
+    let mut url = url.clone();
+    url.set_query(Some(query));
+    url
+}
+
+//Text of the first `name` element of an XML answer
+fn element(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].to_string())
+}
+
+fn header_value(url: &Url, value: &str) -> Result<HeaderValue, HttpError> {
+    HeaderValue::from_str(value).map_err(|e| {
+        HttpError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid header for {}: {}", url, e),
+        ))
+    })
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+//Percent-encoding of path segments as S3 signs them
+fn encode(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
@@ -0,0 +1,208 @@
+use crate::cluster;
+use crate::config::{IsoformPolicy, Ranking, UserConfig};
+use crate::pipeline::Context;
+use crate::rcsb;
+use crate::sifts;
+use crate::uniprot::{self, PdbReference, UniprotEntry};
+use anyhow::Result;
+use std::cmp::{Ordering, Reverse};
+
+/// PDB IDs of `accession` passing the filters of the config.
+///
+/// Isoforms use the structures of their canonical entry, or when the config includes them,
+/// only those SIFTS maps to the isoform itself. With `max_structures_per_accession` only the
+/// best ranked are kept, the others being recorded as skipped.
+pub(crate) async fn wanted_pdb_ids(ctx: &Context, accession: &str) -> Result<Vec<String>> {
+    let entry = uniprot::fetch_entry(ctx, accession).await?;
+    let mut references = entry
+        .pdb_references()
+        .into_iter()
+        .filter(|reference| is_wanted(&ctx.config, reference))
+        .collect::<Vec<_>>();
+    if let (Some(allowed_taxa), true) = (&ctx.config.allowed_taxa, ctx.config.check_chain_taxa) {
+        let mut kept = Vec::new();
+        for reference in references {
+            let taxa = sifts::chain_taxa(ctx, &reference.pdb_id, accession).await?;
+            if taxa.iter().all(|taxon| allowed_taxa.contains(taxon)) {
+                kept.push(reference);
+            } else {
+                debug!(target:"debug","Chains of {} in {} come from taxa {:?}, skipped", accession, reference.pdb_id, taxa);
+            }
+        }
+        references = kept;
+    }
+    if uniprot::split_isoform(accession).1.is_some() {
+        match ctx.config.isoforms {
+            IsoformPolicy::Canonical => {
+                info!("{} is an isoform, using its canonical entry", accession);
+            }
+            IsoformPolicy::Include => {
+                let mut mapped = Vec::new();
+                for reference in references {
+                    if sifts::isoforms(ctx, &reference.pdb_id)
+                        .await?
+                        .iter()
+                        .any(|isoform| isoform == accession)
+                    {
+                        mapped.push(reference);
+                    } else {
+                        debug!(target:"debug","{} is not mapped to {}, skipped", reference.pdb_id, accession);
+                    }
+                }
+                references = mapped;
+            }
+        }
+    }
+    let mut left_out = Vec::new();
+    if let Some(min_coverage) = ctx.config.min_site_coverage {
+        references = covering_sites(&entry, min_coverage, references, &mut left_out);
+    }
+    if let Some(clustering) = &ctx.config.clustering {
+        references =
+            cluster::representatives(ctx, clustering, accession, references, &mut left_out).await?;
+    }
+    let pdb_ids = best_ranked(ctx, accession, references, &mut left_out).await?;
+    if !left_out.is_empty() {
+        ctx.record_left_out(accession, left_out);
+    }
+    Ok(pdb_ids)
+}
+
+//Structures whose chains cover `min_coverage` of the binding and active site residues, all of
+//them for entries without any site
+fn covering_sites(
+    entry: &UniprotEntry,
+    min_coverage: f64,
+    references: Vec<PdbReference>,
+    left_out: &mut Vec<(String, String)>,
+) -> Vec<PdbReference> {
+    let sites = entry.site_residues();
+    if sites.is_empty() {
+        debug!(target:"debug","{} has no annotated site, structures are kept", entry.primary_accession);
+        return references;
+    }
+    let mut kept = Vec::new();
+    for reference in references {
+        let ranges = reference.ranges();
+        let covered = sites
+            .iter()
+            .filter(|&&residue| {
+                ranges
+                    .iter()
+                    .any(|&(start, end)| (start..=end).contains(&residue))
+            })
+            .count();
+        let coverage = covered as f64 / sites.len() as f64;
+        if coverage >= min_coverage {
+            kept.push(reference);
+        } else {
+            left_out.push((
+                reference.pdb_id,
+                format!("covers {} of {} site residues", covered, sites.len()),
+            ));
+        }
+    }
+    kept
+}
+
+//The first `max_structures_per_accession` structures in the order of `structure_ranking`
+async fn best_ranked(
+    ctx: &Context,
+    accession: &str,
+    mut references: Vec<PdbReference>,
+    left_out: &mut Vec<(String, String)>,
+) -> Result<Vec<String>> {
+    let max = match ctx.config.max_structures_per_accession {
+        Some(max) if references.len() > max => max,
+        _ => {
+            return Ok(references
+                .into_iter()
+                .map(|reference| reference.pdb_id)
+                .collect())
+        }
+    };
+    //Sorts are stable, ties keep the order of UniProt
+    let ranking = ctx.config.structure_ranking;
+    match ranking {
+        Ranking::Resolution => references.sort_by(by_resolution),
+        Ranking::Coverage => references.sort_by_key(|reference| Reverse(reference.coverage())),
+        Ranking::Released => {
+            let mut dated = Vec::new();
+            for reference in references {
+                dated.push((rcsb::release_date(ctx, &reference.pdb_id).await?, reference));
+            }
+            //Entries without a date come last
+            dated.sort_by(|a, b| b.0.cmp(&a.0));
+            references = dated.into_iter().map(|(_, reference)| reference).collect();
+        }
+    }
+    let skipped = references.split_off(max);
+    info!(
+        "Keeping {} of {} structures of {} by {:?}",
+        max,
+        max + skipped.len(),
+        accession,
+        ranking
+    );
+    left_out.extend(skipped.into_iter().enumerate().map(|(i, reference)| {
+        (
+            reference.pdb_id,
+            format!("ranked {} by {:?}", max + i + 1, ranking).to_lowercase(),
+        )
+    }));
+    Ok(references
+        .into_iter()
+        .map(|reference| reference.pdb_id)
+        .collect())
+}
+
+/// Best resolution first, structures without one last.
+pub(crate) fn by_resolution(a: &PdbReference, b: &PdbReference) -> Ordering {
+    let resolution = |reference: &PdbReference| reference.resolution.unwrap_or(f64::INFINITY);
+    resolution(a).total_cmp(&resolution(b))
+}
+
+/// Whether `accession` is from an organism of `allowed_taxa`, always true without the filter.
+pub(crate) async fn is_allowed_taxon(ctx: &Context, accession: &str) -> Result<bool> {
+    let Some(allowed_taxa) = &ctx.config.allowed_taxa else {
+        return Ok(true);
+    };
+    let organism = uniprot::fetch_entry(ctx, accession).await?.organism;
+    match organism {
+        Some(organism) if allowed_taxa.contains(&organism.taxon_id) => Ok(true),
+        organism => {
+            info!(
+                "{} is from taxon {}, skipped",
+                accession,
+                match organism {
+                    Some(organism) => organism.taxon_id.to_string(),
+                    None => "unknown".to_string(),
+                }
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Whether a structure passes the filters of the config.
+pub(crate) fn is_wanted(config: &UserConfig, reference: &PdbReference) -> bool {
+    if let Some(allowed_methods) = &config.allowed_methods {
+        if !allowed_methods
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(&reference.method))
+        {
+            debug!(target:"debug","Method of {} : {}, skipped", reference.pdb_id, reference.method);
+            return false;
+        }
+    }
+    if let Some(max_resolution) = config.max_resolution {
+        match reference.resolution {
+            Some(resolution) if resolution <= max_resolution => {}
+            _ => {
+                debug!(target:"debug","Resolution of {} : {:?}, skipped", reference.pdb_id, reference.resolution);
+                return false;
+            }
+        }
+    }
+    true
+}
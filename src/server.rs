@@ -0,0 +1,444 @@
+use crate::config::InputFormat;
+use crate::input;
+use crate::logging;
+use crate::manifest::MANIFEST_FILE;
+use crate::pipeline::{Context, InputSource, Pipeline, Target};
+use crate::shutdown;
+use anyhow::{bail, Result};
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+//Folder of the save path each job gets a save path of its own in
+pub(crate) const JOBS_FOLDER: &str = "jobs";
+//Largest target list accepted, larger ones are better read from read_path
+pub(crate) const MAX_BODY: usize = 64 << 20;
+const MAX_HEAD: usize = 16 << 10;
+
+//What the handlers of requests share
+struct Api {
+    jobs: Arc<Jobs>,
+    sender: mpsc::UnboundedSender<String>,
+    save_path: PathBuf,
+    format: InputFormat,
+    delimiter: Option<char>,
+    columns: crate::config::Columns,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Interrupted,
+    Failed,
+}
+
+/// A job of the API as kept in the state of the save path, so it outlives the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    submitted_at: chrono::DateTime<chrono::Local>,
+    started_at: Option<chrono::DateTime<chrono::Local>>,
+    finished_at: Option<chrono::DateTime<chrono::Local>>,
+    targets: usize,
+    targets_processed: u64,
+    downloaded_bytes: u64,
+    error: Option<String>,
+    //The targets until the job is over
+    #[serde(default)]
+    input: Vec<Target>,
+}
+
+struct Jobs {
+    ctx: Arc<Context>,
+    by_id: Mutex<BTreeMap<String, Job>>,
+    //The job running and its pipeline, for its progress
+    running: Mutex<Option<(String, Arc<Pipeline>)>>,
+    submitted: AtomicUsize,
+    //Set on shutdown, the jobs still queued are not started
+    stopping: AtomicBool,
+}
+
+impl Jobs {
+    //The job with the counters of its pipeline if it runs
+    fn current(&self, job: &Job) -> serde_json::Value {
+        let mut value = serde_json::to_value(job).unwrap_or_default();
+        if let Some(object) = value.as_object_mut() {
+            object.remove("input");
+        }
+        if let Some((id, pipeline)) = &*self.running.lock().unwrap() {
+            if *id == job.id {
+                let metrics = &pipeline.ctx.metrics;
+                value["targets_processed"] = metrics.processed().into();
+                value["downloaded_bytes"] = metrics.downloaded_bytes().into();
+            }
+        }
+        value
+    }
+
+    //A job that can't be recorded is still run, it only won't come back after a restart
+    fn record(&self, job: &Job) {
+        if let Err(e) = self.ctx.state.record_job(job) {
+            warn!("Failed to record job {} due to \"{}\"", job.id, e);
+        }
+    }
+}
+
+struct Response {
+    status: StatusCode,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: StatusCode, value: serde_json::Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: StatusCode, message: impl ToString) -> Self {
+        Self::json(status, serde_json::json!({ "error": message.to_string() }))
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl Pipeline {
+    /// Serve a REST API starting downloads of the targets posted to it, until Ctrl+C or SIGTERM.
+    ///
+    /// - `POST /jobs` queues a job for the targets of the body, a JSON array of targets or a list
+    ///   read as `input_format` would read a file, CSV unless the content type says TSV, JSON
+    ///   lines or a workbook
+    /// - `GET /jobs` and `GET /jobs/<id>` tell the status and progress of jobs
+    /// - `GET /jobs/<id>/manifest` returns the manifest of a job once it ran
+    /// - `GET /log_filter` tells the levels of the log and `PUT /log_filter` replaces them with
+    ///   the directives of the body, in the syntax of `RUST_LOG`
+    ///
+    /// Jobs run one after the other, each into `jobs/<id>/` of the save path with the config of
+    /// the pipeline. On shutdown the running job is interrupted as a run would be. Jobs are kept
+    /// in the state of the save path, so those queued, running or interrupted when the server
+    /// stopped or crashed are queued again when it starts, resuming from their checkpoints.
+    pub async fn serve(&self, addr: &str) -> Result<()> {
+        let listener = listen(addr)?;
+        let known = self.ctx.state.jobs();
+        let jobs = Arc::new(Jobs {
+            ctx: self.ctx.clone(),
+            submitted: AtomicUsize::new(known.len()),
+            by_id: Mutex::new(known.into_iter().map(|job| (job.id.clone(), job)).collect()),
+            running: Mutex::default(),
+            stopping: AtomicBool::new(false),
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+        for job in jobs.by_id.lock().unwrap().values() {
+            if matches!(
+                job.status,
+                JobStatus::Queued | JobStatus::Running | JobStatus::Interrupted
+            ) {
+                info!("Queuing job {} again", job.id);
+                let _ = sender.send(job.id.clone());
+            }
+        }
+        let worker = tokio::spawn(run_jobs(self.config().clone(), jobs.clone(), receiver));
+        let api = Arc::new(Api {
+            jobs: jobs.clone(),
+            sender,
+            save_path: PathBuf::from(&self.config().save_path),
+            format: self.config().input_format,
+            delimiter: self.config().input_delimiter,
+            columns: self.config().columns.clone(),
+        });
+        let make_service = make_service_fn(move |_| {
+            let api = api.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let api = api.clone();
+                    async move { Ok::<_, Infallible>(respond(&api, request).await) }
+                }))
+            }
+        });
+        //Dropping the server drops the last sender, ending the worker once the queue is done
+        let server = hyper::Server::from_tcp(listener)?
+            .http1_max_buf_size(MAX_HEAD)
+            .serve(make_service);
+        info!("Serving the job API on http://{}/jobs", server.local_addr());
+        server
+            .with_graceful_shutdown(async {
+                let _ = shutdown::signal().await;
+            })
+            .await?;
+        info!("Stopped serving. Waiting for the running job...");
+        jobs.stopping.store(true, Ordering::Relaxed);
+        worker.await?;
+        Ok(())
+    }
+}
+
+/// A listener for `addr`, which may name its host, such as "localhost:8080".
+pub(crate) fn listen(addr: &str) -> Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    //As tokio wants it
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+async fn respond(api: &Api, request: hyper::Request<Body>) -> hyper::Response<Body> {
+    let response = match read_request(request).await {
+        Ok(request) => match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/jobs") => {
+                match parse_targets(&request, api.format, api.delimiter, &api.columns) {
+                    Ok(targets) => submit(&api.jobs, &api.sender, targets),
+                    Err(e) => Response::error(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+                }
+            }
+            ("GET", "/log_filter") => Response::json(
+                StatusCode::OK,
+                serde_json::json!({ "filter": logging::log_filter() }),
+            ),
+            ("PUT", "/log_filter") => {
+                let directives = String::from_utf8_lossy(&request.body);
+                match logging::set_log_filter(directives.trim()) {
+                    Ok(()) => Response::json(
+                        StatusCode::OK,
+                        serde_json::json!({ "filter": logging::log_filter() }),
+                    ),
+                    Err(e) => Response::error(StatusCode::BAD_REQUEST, e),
+                }
+            }
+            ("GET", path) => get(&api.jobs, &api.save_path, path),
+            _ => Response::error(StatusCode::METHOD_NOT_ALLOWED, "Unknown route"),
+        },
+        Err(response) => response,
+    };
+    hyper::Response::builder()
+        .status(response.status)
+        .header(CONTENT_TYPE, response.content_type)
+        .body(Body::from(response.body))
+        .unwrap_or_default()
+}
+
+//Run the queued jobs one at a time, until the server stops
+async fn run_jobs(
+    config: crate::config::UserConfig,
+    jobs: Arc<Jobs>,
+    mut receiver: mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(id) = receiver.recv().await {
+        if jobs.stopping.load(Ordering::Relaxed) {
+            break;
+        }
+        //A job started before resumes from its checkpoint
+        let Some((targets, resume)) = jobs.by_id.lock().unwrap().get_mut(&id).map(|job| {
+            let resume = job.started_at.is_some();
+            job.started_at.get_or_insert_with(chrono::Local::now);
+            (job.input.clone(), resume)
+        }) else {
+            continue;
+        };
+        let save_path = Path::new(&config.save_path).join(JOBS_FOLDER).join(&id);
+        let pipeline = Pipeline::builder(config.clone())
+            .save_path(save_path.to_string_lossy())
+            .input(InputSource::Targets(targets))
+            .resume(resume)
+            .build()
+            .map(Arc::new);
+        let result = match pipeline {
+            Ok(pipeline) => {
+                info!("Running job {}", id);
+                if let Some(job) = jobs.by_id.lock().unwrap().get_mut(&id) {
+                    job.status = JobStatus::Running;
+                    jobs.record(job);
+                }
+                *jobs.running.lock().unwrap() = Some((id.clone(), pipeline.clone()));
+                let result = pipeline.run().await;
+                jobs.running.lock().unwrap().take();
+                result.map(|()| pipeline)
+            }
+            Err(e) => Err(e),
+        };
+        let mut by_id = jobs.by_id.lock().unwrap();
+        let Some(job) = by_id.get_mut(&id) else {
+            continue;
+        };
+        job.finished_at = Some(chrono::Local::now());
+        if let Ok(pipeline) = &result {
+            job.targets_processed = pipeline.ctx.metrics.processed();
+            job.downloaded_bytes = pipeline.ctx.metrics.downloaded_bytes();
+        }
+        match result {
+            Ok(pipeline) if pipeline.ctx.is_stopping() => {
+                job.status = JobStatus::Interrupted;
+                info!("Job {} was interrupted", id);
+            }
+            Ok(_) => {
+                job.status = JobStatus::Completed;
+                info!("Job {} completed", id);
+            }
+            Err(e) => {
+                warn!("Failed to run job {} due to \"{}\"", id, e);
+                job.status = JobStatus::Failed;
+                job.error = Some(format!("{:#}", e));
+            }
+        }
+        if job.status != JobStatus::Interrupted {
+            job.input.clear();
+        }
+        jobs.record(job);
+    }
+}
+
+/// ID of a new job from the time and the count of jobs submitted before, `counter`.
+pub(crate) fn job_id(counter: &AtomicUsize) -> String {
+    format!(
+        "{}-{}",
+        chrono::Local::now().format("%Y%m%d%H%M%S"),
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    )
+}
+
+fn submit(jobs: &Jobs, sender: &mpsc::UnboundedSender<String>, targets: Vec<Target>) -> Response {
+    let id = job_id(&jobs.submitted);
+    let job = Job {
+        id: id.clone(),
+        status: JobStatus::Queued,
+        submitted_at: chrono::Local::now(),
+        started_at: None,
+        finished_at: None,
+        targets: targets.len(),
+        targets_processed: 0,
+        downloaded_bytes: 0,
+        error: None,
+        input: targets,
+    };
+    let value = jobs.current(&job);
+    jobs.record(&job);
+    jobs.by_id.lock().unwrap().insert(id.clone(), job);
+    if sender.send(id.clone()).is_err() {
+        return Response::error(StatusCode::SERVICE_UNAVAILABLE, "The server is stopping");
+    }
+    info!("Queued job {} of {} targets", id, value["targets"]);
+    Response::json(StatusCode::CREATED, value)
+}
+
+fn get(jobs: &Jobs, save_path: &Path, path: &str) -> Response {
+    let by_id = jobs.by_id.lock().unwrap();
+    let parts = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    match parts[..] {
+        ["jobs"] => Response::json(
+            StatusCode::OK,
+            by_id
+                .values()
+                .map(|job| jobs.current(job))
+                .collect::<Vec<_>>()
+                .into(),
+        ),
+        ["jobs", id] => match by_id.get(id) {
+            Some(job) => Response::json(StatusCode::OK, jobs.current(job)),
+            None => Response::error(StatusCode::NOT_FOUND, format!("No job {}", id)),
+        },
+        ["jobs", id, "manifest"] if by_id.contains_key(id) => {
+            let manifest = save_path.join(JOBS_FOLDER).join(id).join(MANIFEST_FILE);
+            match std::fs::read(manifest) {
+                Ok(body) => Response {
+                    status: StatusCode::OK,
+                    content_type: "text/csv",
+                    body,
+                },
+                Err(_) => Response::error(
+                    StatusCode::NOT_FOUND,
+                    format!("Job {} has no manifest yet", id),
+                ),
+            }
+        }
+        _ => Response::error(StatusCode::NOT_FOUND, "Unknown route"),
+    }
+}
+
+//A JSON array of targets, or a list read as a file of the content type
+fn parse_targets(
+    request: &Request,
+    format: InputFormat,
+    delimiter: Option<char>,
+    columns: &crate::config::Columns,
+) -> Result<Vec<Target>> {
+    let content_type = request
+        .content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim();
+    let format = match content_type {
+        "application/json" => return Ok(serde_json::from_slice(&request.body)?),
+        "text/tab-separated-values" => InputFormat::Tsv,
+        "application/x-ndjson" | "application/jsonl" => InputFormat::Jsonl,
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => InputFormat::Xlsx,
+        _ if format == InputFormat::Auto => InputFormat::Csv,
+        _ => format,
+    };
+    let targets = input::read_targets(&request.body[..], format, delimiter, columns)?
+        .collect::<Result<Vec<_>>>()?;
+    if targets.is_empty() {
+        bail!("The body lists no target");
+    }
+    Ok(targets)
+}
+
+//The body is read as it comes, chunked or not, hyper answering "Expect: 100-continue" once it's
+//polled, so bodies declared too large are turned down before the client sends them
+async fn read_request(request: hyper::Request<Body>) -> Result<Request, Response> {
+    let too_large = |length| {
+        Response::error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body of {} bytes is too large", length),
+        )
+    };
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+    let length = header(CONTENT_LENGTH).and_then(|length| length.parse::<usize>().ok());
+    if let Some(length) = length.filter(|length| *length > MAX_BODY) {
+        return Err(too_large(length));
+    }
+    let content_type = header(CONTENT_TYPE)
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let mut body = request.into_body();
+    let mut data = Vec::with_capacity(length.unwrap_or_default());
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Response::error(StatusCode::BAD_REQUEST, e))?;
+        if data.len() + chunk.len() > MAX_BODY {
+            return Err(too_large(data.len() + chunk.len()));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Request {
+        method,
+        path,
+        content_type,
+        body: data,
+    })
+}
@@ -0,0 +1,138 @@
+use crate::config::UserConfig;
+use crate::download::{fetch, Downloaded};
+use crate::http::HttpError;
+use crate::pipeline::Context;
+use crate::uniprot;
+use anyhow::Result;
+use reqwest::{StatusCode, Url};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+const SIFTS_URL: &str = "https://www.ebi.ac.uk/pdbe/api/mappings/uniprot/";
+const ISOFORMS_URL: &str = "https://www.ebi.ac.uk/pdbe/api/mappings/isoforms/";
+const MOLECULES_URL: &str = "https://www.ebi.ac.uk/pdbe/api/pdb/entry/molecules/";
+
+/// Url of the SIFTS UniProt mapping of `pdb_id` and the file it is saved to, if the config asks for it.
+pub(crate) fn mapping_file(
+    config: &UserConfig,
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<Option<(Url, PathBuf)>> {
+    if !config.sifts_mapping {
+        return Ok(None);
+    }
+    let id = pdb_id.to_lowercase();
+    let url: Url = format!("{}{}", SIFTS_URL, id).parse()?;
+    Ok(Some((url, save_path.join(format!("{}_sifts.json", id)))))
+}
+
+/// Download the chains and residue ranges of `pdb_id` mapped to UniProt, next to its coordinates.
+pub(crate) async fn download_mapping(
+    ctx: &Context,
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<Option<Downloaded>> {
+    match mapping_file(&ctx.config, pdb_id, save_path)? {
+        Some((url, save_filepath)) if !ctx.storage.exists(&save_filepath).await? => {
+            debug!(target:"debug","SIFTS url : {}", url);
+            Ok(Some(fetch(ctx, &url, &save_filepath).await?))
+        }
+        _ => Ok(None),
+    }
+}
+
+//GET a PDBe API page, none for the 404 of entries it has nothing on
+async fn get_json(ctx: &Context, url: &Url) -> Result<Option<Value>> {
+    match ctx.http.get_text(url).await {
+        Ok(page) => Ok(Some(serde_json::from_str(&page)?)),
+        Err(HttpError::Status {
+            status: StatusCode::NOT_FOUND,
+            ..
+        }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+//SIFTS mappings of the chains of `pdb_id` to `accession`
+async fn mappings(ctx: &Context, pdb_id: &str, accession: &str) -> Result<Vec<Value>> {
+    let id = pdb_id.to_lowercase();
+    let (canonical, _) = uniprot::split_isoform(accession);
+    let Some(mut mappings) = get_json(ctx, &format!("{}{}", SIFTS_URL, id).parse()?).await? else {
+        return Ok(Vec::new());
+    };
+    Ok(
+        match mappings[&id]["UniProt"][canonical]["mappings"].take() {
+            Value::Array(mappings) => mappings,
+            _ => Vec::new(),
+        },
+    )
+}
+
+/// Entities of `pdb_id` SIFTS maps to `accession`.
+pub(crate) async fn entities(ctx: &Context, pdb_id: &str, accession: &str) -> Result<Vec<u64>> {
+    let mut entities = Vec::new();
+    for mapping in mappings(ctx, pdb_id, accession).await? {
+        if let Some(entity) = mapping["entity_id"].as_u64() {
+            if !entities.contains(&entity) {
+                entities.push(entity);
+            }
+        }
+    }
+    Ok(entities)
+}
+
+/// Author IDs of the chains of `pdb_id` SIFTS maps to `accession`.
+pub(crate) async fn chains(ctx: &Context, pdb_id: &str, accession: &str) -> Result<Vec<String>> {
+    let mut chains = Vec::new();
+    for mapping in mappings(ctx, pdb_id, accession).await? {
+        if let Some(chain) = mapping["chain_id"].as_str() {
+            if !chains.iter().any(|known| known == chain) {
+                chains.push(chain.to_string());
+            }
+        }
+    }
+    Ok(chains)
+}
+
+/// Taxonomy IDs of the source organisms of the chains of `pdb_id` SIFTS maps to `accession`.
+pub(crate) async fn chain_taxa(ctx: &Context, pdb_id: &str, accession: &str) -> Result<Vec<u64>> {
+    let id = pdb_id.to_lowercase();
+    let entities = entities(ctx, pdb_id, accession).await?;
+    if entities.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(molecules) = get_json(ctx, &format!("{}{}", MOLECULES_URL, id).parse()?).await? else {
+        return Ok(Vec::new());
+    };
+    let mut taxa = Vec::new();
+    for molecule in molecules[&id].as_array().into_iter().flatten() {
+        if !molecule["entity_id"]
+            .as_u64()
+            .is_some_and(|entity| entities.contains(&entity))
+        {
+            continue;
+        }
+        for source in molecule["source"].as_array().into_iter().flatten() {
+            if let Some(taxon) = source["tax_id"].as_u64() {
+                if !taxa.contains(&taxon) {
+                    taxa.push(taxon);
+                }
+            }
+        }
+    }
+    Ok(taxa)
+}
+
+/// UniProt isoforms, e.g. `P12345-2`, that chains of `pdb_id` are mapped to by SIFTS.
+pub(crate) async fn isoforms(ctx: &Context, pdb_id: &str) -> Result<Vec<String>> {
+    let id = pdb_id.to_lowercase();
+    let url: Url = format!("{}{}", ISOFORMS_URL, id).parse()?;
+    //Entries without any mapping are answered with 404
+    let Some(mappings) = get_json(ctx, &url).await? else {
+        return Ok(Vec::new());
+    };
+    Ok(mappings[&id]["UniProt"]
+        .as_object()
+        .map(|accessions| accessions.keys().cloned().collect())
+        .unwrap_or_default())
+}
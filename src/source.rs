@@ -0,0 +1,81 @@
+use crate::config::UserConfig;
+use crate::download::{self, Downloaded};
+use crate::pipeline::Context;
+use crate::select;
+use crate::status;
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+
+/// Where the structures of UniProt accessions come from.
+///
+/// Extra files such as assemblies and validation reports are still fetched from the PDB
+/// archive by PDB ID, as the config asks.
+#[async_trait]
+pub trait StructureSource: Send + Sync {
+    /// IDs of the structures of `accession` to download.
+    async fn structures(&self, cx: &SourceContext<'_>, accession: &str) -> Result<Vec<String>>;
+
+    /// The ID to download in place of `id`, or none to skip it.
+    async fn resolve(&self, _cx: &SourceContext<'_>, id: &str) -> Result<Option<String>> {
+        Ok(Some(id.to_string()))
+    }
+
+    /// Download structure `id` into the folder `save_path`, or nothing if it is there already.
+    async fn fetch(
+        &self,
+        cx: &SourceContext<'_>,
+        id: &str,
+        save_path: &Path,
+    ) -> Result<Option<Downloaded>>;
+}
+
+/// What a [`StructureSource`] gets to use of the run.
+pub struct SourceContext<'a> {
+    pub(crate) ctx: &'a Context,
+}
+
+impl SourceContext<'_> {
+    pub fn config(&self) -> &UserConfig {
+        &self.ctx.config
+    }
+
+    /// Whether a complete file is saved at `path`.
+    pub async fn exists(&self, path: &Path) -> Result<bool> {
+        self.ctx.storage.exists(path).await
+    }
+
+    /// Download `url` into `path` with the retries, rate limits and cache of the run.
+    pub async fn download(&self, url: &Url, path: &Path) -> Result<Downloaded> {
+        download::fetch(self.ctx, url, path).await
+    }
+
+    /// Where `config` saves a downloaded file named `path`, e.g. once unpacked.
+    pub fn stored_path(&self, path: &Path) -> PathBuf {
+        download::stored_path(&self.ctx.config, path)
+    }
+}
+
+/// PDB entries listed by UniProt and downloaded from the mirrors of the config, the default.
+pub struct UniprotPdb;
+
+#[async_trait]
+impl StructureSource for UniprotPdb {
+    async fn structures(&self, cx: &SourceContext<'_>, accession: &str) -> Result<Vec<String>> {
+        select::wanted_pdb_ids(cx.ctx, accession).await
+    }
+
+    async fn resolve(&self, cx: &SourceContext<'_>, id: &str) -> Result<Option<String>> {
+        status::current_id(cx.ctx, id).await
+    }
+
+    async fn fetch(
+        &self,
+        cx: &SourceContext<'_>,
+        id: &str,
+        save_path: &Path,
+    ) -> Result<Option<Downloaded>> {
+        download::download_pdb(cx.ctx, id.to_string(), save_path.to_path_buf()).await
+    }
+}
@@ -0,0 +1,363 @@
+use crate::database::Database;
+use crate::manifest::ManifestEntry;
+use crate::server::Job;
+use crate::summary::RunSummary;
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+pub(crate) const JOURNAL_FILE: &str = "state.jsonl";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Event {
+    Target {
+        chembl_id: String,
+    },
+    Pdb {
+        chembl_id: String,
+        accession: String,
+        pdb_id: String,
+    },
+    File(ManifestEntry),
+    //A structure left out on purpose, listed in the manifest without a file
+    Skipped(ManifestEntry),
+    //Undo the work of a file found broken
+    Redo {
+        chembl_id: String,
+        accession: String,
+        pdb_id: Option<String>,
+        path: String,
+    },
+    //A file found at another path, as recorded there now
+    Moved {
+        from: String,
+        record: ManifestEntry,
+    },
+    //A job of the API as it was last
+    Job(Job),
+}
+
+#[derive(Default)]
+struct Done {
+    targets: HashSet<String>,
+    pdbs: HashSet<(String, String, String)>,
+    files: BTreeMap<String, ManifestEntry>,
+    skipped: BTreeMap<(String, String, Option<String>), ManifestEntry>,
+    jobs: BTreeMap<String, Job>,
+}
+
+impl Done {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Target { chembl_id } => {
+                self.targets.insert(chembl_id);
+            }
+            Event::Pdb {
+                chembl_id,
+                accession,
+                pdb_id,
+            } => {
+                self.pdbs.insert((chembl_id, accession, pdb_id));
+            }
+            Event::File(record) => {
+                self.skipped.remove(&skipped_key(&record));
+                self.files.insert(record.path.clone(), record);
+            }
+            Event::Skipped(record) => {
+                self.skipped.insert(skipped_key(&record), record);
+            }
+            Event::Redo {
+                chembl_id,
+                accession,
+                pdb_id,
+                path,
+            } => {
+                self.targets.remove(&chembl_id);
+                if let Some(pdb_id) = pdb_id {
+                    self.pdbs.remove(&(chembl_id, accession, pdb_id));
+                }
+                self.files.remove(&path);
+            }
+            Event::Moved { from, record } => {
+                self.files.remove(&from);
+                self.files.insert(record.path.clone(), record);
+            }
+            Event::Job(job) => {
+                self.jobs.insert(job.id.clone(), job);
+            }
+        }
+    }
+
+    //Events replaying what is held, files first and jobs last
+    fn events(&self) -> Vec<Event> {
+        let files = self.files.values().cloned().map(Event::File);
+        let skipped = self.skipped.values().cloned().map(Event::Skipped);
+        let pdbs = self
+            .pdbs
+            .iter()
+            .map(|(chembl_id, accession, pdb_id)| Event::Pdb {
+                chembl_id: chembl_id.clone(),
+                accession: accession.clone(),
+                pdb_id: pdb_id.clone(),
+            });
+        let targets = self.targets.iter().map(|chembl_id| Event::Target {
+            chembl_id: chembl_id.clone(),
+        });
+        let jobs = self.jobs.values().cloned().map(Event::Job);
+        files
+            .chain(skipped)
+            .chain(pdbs)
+            .chain(targets)
+            .chain(jobs)
+            .collect()
+    }
+}
+
+fn skipped_key(record: &ManifestEntry) -> (String, String, Option<String>) {
+    (
+        record.chembl_id.clone(),
+        record.accession.clone(),
+        record.pdb_id.clone(),
+    )
+}
+
+/// Journal of finished work, appended to `save_path/state.jsonl` as the run goes.
+pub(crate) struct StateStore {
+    journal: Mutex<File>,
+    done: Mutex<Done>,
+    resume: bool,
+    database: Option<Database>,
+}
+
+impl StateStore {
+    /// Open the journal of `save_path`, loading it when `resume` is set and starting over otherwise.
+    ///
+    /// Checksums of downloaded files are kept either way. With `sqlite`, every event is also
+    /// applied to `save_path/state.sqlite`, which stands in for a missing journal.
+    pub fn open(save_path: &Path, resume: bool, sqlite: bool) -> Result<Self> {
+        let path = save_path.join(JOURNAL_FILE);
+        let database = if sqlite {
+            Some(Database::open(save_path)?)
+        } else {
+            None
+        };
+        let journal_found = path.exists();
+        let mut done = Done::default();
+        if journal_found {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                //A crash can leave the last line half written
+                match serde_json::from_str(&line) {
+                    Ok(event) => done.apply(event),
+                    Err(e) => warn!("Skipping broken journal line \"{}\": {}", line, e),
+                }
+            }
+        } else if let Some(database) = &database {
+            for event in database.events()? {
+                done.apply(event);
+            }
+        }
+        if resume {
+            info!(
+                "Resuming with {} targets and {} PDB entries done",
+                done.targets.len(),
+                done.pdbs.len()
+            );
+        } else {
+            done.targets.clear();
+            done.pdbs.clear();
+            done.skipped.clear();
+            if let Some(database) = &database {
+                database.start_over()?;
+            }
+        }
+        let journal = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(&path)?;
+        let store = StateStore {
+            journal: Mutex::new(journal),
+            done: Mutex::new(Done::default()),
+            resume,
+            database,
+        };
+        //Also writes back the journal rebuilt from the database
+        if !resume || !journal_found {
+            for event in done.events() {
+                store.append(&event)?;
+            }
+        }
+        *store.done.lock().unwrap() = done;
+        Ok(store)
+    }
+
+    /// Whether nothing has been done or downloaded yet.
+    pub fn is_empty(&self) -> bool {
+        let done = self.done.lock().unwrap();
+        done.targets.is_empty() && done.pdbs.is_empty() && done.files.is_empty()
+    }
+
+    /// Replay a journal kept elsewhere, as [`StateStore::open`] would have loaded it.
+    pub fn restore(&self, journal: &str) -> Result<()> {
+        for line in journal.lines() {
+            let event: Event = match serde_json::from_str(line) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Skipping broken journal line \"{}\": {}", line, e);
+                    continue;
+                }
+            };
+            if !self.resume && !matches!(event, Event::File(_) | Event::Moved { .. }) {
+                continue;
+            }
+            self.append(&event)?;
+            self.done.lock().unwrap().apply(event);
+        }
+        Ok(())
+    }
+
+    fn append(&self, event: &Event) -> Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        let mut journal = self.journal.lock().unwrap();
+        journal.write_all(line.as_bytes())?;
+        journal.flush()?;
+        if let Some(database) = &self.database {
+            database.apply(event)?;
+        }
+        Ok(())
+    }
+
+    /// Make sure the journal, and the database if any, are on disk.
+    pub fn sync(&self) -> Result<()> {
+        self.journal.lock().unwrap().sync_all()?;
+        if let Some(database) = &self.database {
+            database.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Add a row for the run of `summary` to the database, if any.
+    pub fn record_run(&self, summary: &RunSummary) -> Result<()> {
+        match &self.database {
+            Some(database) => database.record_run(summary),
+            None => Ok(()),
+        }
+    }
+
+    /// Record the job as it is now, replacing what was recorded of it before.
+    pub fn record_job(&self, job: &Job) -> Result<()> {
+        let event = Event::Job(job.clone());
+        self.append(&event)?;
+        self.done.lock().unwrap().apply(event);
+        Ok(())
+    }
+
+    /// Every job recorded, by ID.
+    pub fn jobs(&self) -> Vec<Job> {
+        self.done.lock().unwrap().jobs.values().cloned().collect()
+    }
+
+    pub fn is_target_done(&self, chembl_id: &str) -> bool {
+        self.done.lock().unwrap().targets.contains(chembl_id)
+    }
+
+    /// Treat every target as not done for the rest of the run, so they are queried again.
+    pub fn forget_targets(&self) {
+        self.done.lock().unwrap().targets.clear();
+    }
+
+    pub fn mark_target_done(&self, chembl_id: &str) -> Result<()> {
+        let event = Event::Target {
+            chembl_id: chembl_id.to_string(),
+        };
+        self.append(&event)?;
+        self.done.lock().unwrap().apply(event);
+        Ok(())
+    }
+
+    pub fn is_pdb_done(&self, chembl_id: &str, accession: &str, pdb_id: &str) -> bool {
+        self.done.lock().unwrap().pdbs.contains(&(
+            chembl_id.to_string(),
+            accession.to_string(),
+            pdb_id.to_string(),
+        ))
+    }
+
+    pub fn mark_pdb_done(&self, chembl_id: &str, accession: &str, pdb_id: &str) -> Result<()> {
+        let event = Event::Pdb {
+            chembl_id: chembl_id.to_string(),
+            accession: accession.to_string(),
+            pdb_id: pdb_id.to_string(),
+        };
+        self.append(&event)?;
+        self.done.lock().unwrap().apply(event);
+        Ok(())
+    }
+
+    pub fn record_file(&self, record: ManifestEntry) -> Result<()> {
+        let event = Event::File(record);
+        self.append(&event)?;
+        self.done.lock().unwrap().apply(event);
+        Ok(())
+    }
+
+    /// The downloaded file recorded at `path`, relative to the save path.
+    pub fn file(&self, path: &str) -> Option<ManifestEntry> {
+        self.done.lock().unwrap().files.get(path).cloned()
+    }
+
+    /// Every downloaded file, by path.
+    pub fn files(&self) -> Vec<ManifestEntry> {
+        self.done.lock().unwrap().files.values().cloned().collect()
+    }
+
+    pub fn record_skipped(&self, record: ManifestEntry) -> Result<()> {
+        let event = Event::Skipped(record);
+        self.append(&event)?;
+        self.done.lock().unwrap().apply(event);
+        Ok(())
+    }
+
+    /// Every structure left out on purpose.
+    pub fn skipped(&self) -> Vec<ManifestEntry> {
+        self.done
+            .lock()
+            .unwrap()
+            .skipped
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Record the file recorded at `from` as moved to the path of `record`.
+    pub fn move_file(&self, from: &str, record: ManifestEntry) -> Result<()> {
+        let event = Event::Moved {
+            from: from.to_string(),
+            record,
+        };
+        self.append(&event)?;
+        self.done.lock().unwrap().apply(event);
+        Ok(())
+    }
+
+    /// Forget a broken file so the next resume downloads it again.
+    pub fn redo_file(&self, record: &ManifestEntry) -> Result<()> {
+        let event = Event::Redo {
+            chembl_id: record.chembl_id.clone(),
+            accession: record.accession.clone(),
+            pdb_id: record.pdb_id.clone(),
+            path: record.path.clone(),
+        };
+        self.append(&event)?;
+        self.done.lock().unwrap().apply(event);
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use crate::config::ObsoletePolicy;
+use crate::http::HttpError;
+use crate::pipeline::Context;
+use anyhow::Result;
+use reqwest::{StatusCode, Url};
+use serde_derive::Deserialize;
+
+const STATUS_URL: &str = "https://data.rcsb.org/rest/v1/holdings/status/";
+
+#[derive(Deserialize, Debug)]
+struct Holdings {
+    status: Option<String>,
+    id_code_replaced_by_latest: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Status {
+    rcsb_repository_holdings_combined: Holdings,
+}
+
+/// ID to download for `pdb_id`: the entry itself, its latest replacement if it is obsolete,
+/// or none if it is obsolete and left out by the config.
+pub(crate) async fn current_id(ctx: &Context, pdb_id: &str) -> Result<Option<String>> {
+    //Offline runs take the entries they have for current
+    if ctx.config.obsolete == ObsoletePolicy::Keep || ctx.config.offline {
+        return Ok(Some(pdb_id.to_string()));
+    }
+    let url: Url = format!("{}{}", STATUS_URL, pdb_id).parse()?;
+    let status: Status = match ctx.http.get_text(&url).await {
+        Ok(page) => serde_json::from_str(&page)?,
+        //Unknown to the holdings, try the files anyway
+        Err(HttpError::Status {
+            status: StatusCode::NOT_FOUND,
+            ..
+        }) => return Ok(Some(pdb_id.to_string())),
+        Err(e) => return Err(e.into()),
+    };
+    let holdings = status.rcsb_repository_holdings_combined;
+    if holdings.status.as_deref() != Some("OBSOLETE") {
+        return Ok(Some(pdb_id.to_string()));
+    }
+    match (ctx.config.obsolete, holdings.id_code_replaced_by_latest) {
+        (ObsoletePolicy::Follow, Some(replacement)) => {
+            //The holdings give IDs in upper case, the mirrors and the rest of the tree in lower
+            let replacement = replacement.to_lowercase();
+            info!("{} is obsolete, superseded by {}", pdb_id, replacement);
+            ctx.record_superseded(pdb_id, &replacement);
+            Ok(Some(replacement))
+        }
+        (_, replacement) => {
+            warn!(
+                "{} is obsolete{}, skipped",
+                pdb_id,
+                match replacement {
+                    Some(replacement) => format!(" (superseded by {})", replacement),
+                    None => String::new(),
+                }
+            );
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::Path;
+
+/// Where the files of a run are kept.
+///
+/// The pipeline always writes to the local `save_path` first; a backend decides whether files
+/// stay there or are moved elsewhere once complete. Paths are those below `save_path`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Whether a complete file is saved at `path`.
+    async fn exists(&self, path: &Path) -> Result<bool>;
+
+    /// Keep the complete file written at `path`, its content hashing to `sha256` (hex).
+    async fn store(&self, path: &Path, sha256: &str) -> Result<()>;
+
+    /// Drop the local copy of a stored file that isn't needed by the run any more.
+    async fn release(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Content of the file saved at `path`, if any.
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>>;
+
+    /// Whether files only ever stay in `save_path`, so storing them does nothing.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// Files stay in `save_path`, the default.
+pub struct LocalStorage;
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(tokio::fs::try_exists(path).await?)
+    }
+
+    async fn store(&self, _path: &Path, _sha256: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    async fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,557 @@
+use crate::compress;
+use crate::config::Cleanup;
+use crate::download::part_path;
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const WATERS: &[&str] = &["HOH", "DOD", "WAT"];
+const IONS: &[&str] = &[
+    "LI", "NA", "K", "RB", "CS", "MG", "CA", "SR", "BA", "MN", "FE", "FE2", "CO", "NI", "CU",
+    "CU1", "ZN", "CD", "HG", "F", "CL", "BR", "IOD",
+];
+//Buffer, cryoprotectant and precipitant molecules that come with the crystal
+const ADDITIVES: &[&str] = &[
+    "SO4", "PO4", "NO3", "ACT", "ACY", "FMT", "EDO", "GOL", "PEG", "PG4", "PGE", "1PE", "MPD",
+    "DMS", "TRS", "MES", "EPE", "BME", "IPA", "EOH", "CIT", "MLI", "TAR", "IMD", "SCN", "AZI",
+];
+//Modified residues of polymer chains, written as HETATM records
+const MODIFIED: &[&str] = &[
+    "MSE", "SEP", "TPO", "PTR", "CSO", "CSD", "CME", "OCS", "HYP", "MLY", "M3L", "KCX", "LLP",
+    "PCA", "CGU", "SEC", "PYL", "ALY", "NEP", "TYS",
+];
+
+//Columns of the `_atom_site` loop written, in the order of their values
+const CIF_COLUMNS: &[&str] = &[
+    "group_PDB",
+    "id",
+    "type_symbol",
+    "label_atom_id",
+    "label_alt_id",
+    "label_comp_id",
+    "label_asym_id",
+    "label_seq_id",
+    "pdbx_PDB_ins_code",
+    "Cartn_x",
+    "Cartn_y",
+    "Cartn_z",
+    "occupancy",
+    "B_iso_or_equiv",
+    "pdbx_formal_charge",
+    "auth_seq_id",
+    "auth_comp_id",
+    "auth_asym_id",
+    "auth_atom_id",
+    "pdbx_PDB_model_num",
+];
+
+/// An atom of the coordinates of a structure, with author numbering.
+#[derive(Debug, Clone)]
+pub(crate) struct Atom {
+    pub hetero: bool,
+    pub name: String,
+    pub alt_loc: Option<String>,
+    pub res_name: String,
+    pub chain: String,
+    pub res_seq: i64,
+    pub insertion: Option<String>,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub occupancy: f64,
+    pub b_factor: f64,
+    pub element: String,
+    pub charge: i32,
+    pub model: u32,
+    /// Whether the atom belongs to a residue of a polymer chain
+    pub polymer: bool,
+}
+
+impl Atom {
+    /// What tells the residue of the atom apart within its structure.
+    pub fn residue(&self) -> (u32, String, i64, Option<String>) {
+        (
+            self.model,
+            self.chain.clone(),
+            self.res_seq,
+            self.insertion.clone(),
+        )
+    }
+}
+
+/// Parse the atoms of the structure at `path`, compressed or not, if `format` is "pdb" or "cif".
+pub(crate) fn read(path: &Path, format: Option<&str>) -> Result<Option<Vec<Atom>>> {
+    if !matches!(format, Some("pdb" | "cif")) {
+        return Ok(None);
+    }
+    let mut text = String::new();
+    compress::reader(path)?.read_to_string(&mut text)?;
+    let atoms = match format {
+        Some("pdb") => parse_pdb(&text),
+        _ => parse_cif(&text)?,
+    };
+    if atoms.is_empty() {
+        bail!("No atoms found in {}", path.display());
+    }
+    Ok(Some(atoms))
+}
+
+fn parse_pdb(text: &str) -> Vec<Atom> {
+    let field = |line: &str, start: usize, end: usize| -> String {
+        line.get(start..end.min(line.len()))
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+    let mut atoms = Vec::new();
+    let mut model = 1;
+    for line in text.lines() {
+        if line.starts_with("MODEL") {
+            model = field(line, 10, 14).parse().unwrap_or(model);
+            continue;
+        }
+        let hetero = line.starts_with("HETATM");
+        if !hetero && !line.starts_with("ATOM  ") {
+            continue;
+        }
+        let number = |start, end| field(line, start, end).parse().unwrap_or_default();
+        let optional = |start, end| Some(field(line, start, end)).filter(|value| !value.is_empty());
+        //"2+" or "1-"
+        let charge = field(line, 78, 80);
+        let charge = match charge.as_bytes() {
+            [digit, b'-'] => -((digit - b'0') as i32),
+            [digit, b'+'] => (digit - b'0') as i32,
+            _ => 0,
+        };
+        let res_name = field(line, 17, 20);
+        atoms.push(Atom {
+            hetero,
+            name: field(line, 12, 16),
+            alt_loc: optional(16, 17),
+            res_name: res_name.clone(),
+            chain: field(line, 21, 22),
+            res_seq: number(22, 26) as i64,
+            insertion: optional(26, 27),
+            x: number(30, 38),
+            y: number(38, 46),
+            z: number(46, 54),
+            occupancy: number(54, 60),
+            b_factor: number(60, 66),
+            element: field(line, 76, 78),
+            charge,
+            model,
+            polymer: !hetero || MODIFIED.contains(&res_name.as_str()),
+        });
+    }
+    atoms
+}
+
+//Values of a line of a mmCIF loop, quoted ones included
+fn cif_tokens(line: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let quote = rest.chars().next().filter(|c| *c == '\'' || *c == '"');
+        let (token, next) = match quote {
+            //A quote only closes the value when followed by a space
+            Some(quote) => match rest[1..]
+                .match_indices(quote)
+                .map(|(i, _)| i + 1)
+                .find(|&i| {
+                    rest[i + 1..].is_empty() || rest[i + 1..].starts_with(char::is_whitespace)
+                }) {
+                Some(end) => (&rest[1..end], &rest[end + 1..]),
+                None => (rest, ""),
+            },
+            None => match rest.find(char::is_whitespace) {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, ""),
+            },
+        };
+        tokens.push(token);
+        rest = next.trim_start();
+    }
+    tokens
+}
+
+fn parse_cif(text: &str) -> Result<Vec<Atom>> {
+    let mut lines = text.lines().peekable();
+    let mut columns = Vec::new();
+    while let Some(line) = lines.next() {
+        if line.trim() != "loop_"
+            || !lines
+                .peek()
+                .is_some_and(|next| next.starts_with("_atom_site."))
+        {
+            continue;
+        }
+        while let Some(column) = lines
+            .peek()
+            .and_then(|line| line.strip_prefix("_atom_site."))
+        {
+            columns.push(column.trim().to_string());
+            lines.next();
+        }
+        break;
+    }
+    if columns.is_empty() {
+        return Ok(Vec::new());
+    }
+    let index = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| (column.as_str(), i))
+        .collect::<HashMap<_, _>>();
+    let mut values = Vec::new();
+    for line in lines {
+        if line.starts_with('#')
+            || line.starts_with('_')
+            || line.starts_with("loop_")
+            || line.starts_with("data_")
+        {
+            break;
+        }
+        values.extend(cif_tokens(line));
+    }
+    if values.len() % columns.len() != 0 {
+        bail!("Truncated _atom_site loop");
+    }
+
+    let mut atoms = Vec::new();
+    for row in values.chunks(columns.len()) {
+        //Author fields when present, label fields otherwise
+        let get = |names: &[&str]| -> Option<&str> {
+            names
+                .iter()
+                .filter_map(|name| index.get(name).map(|&i| row[i]))
+                .find(|value| *value != "?" && *value != ".")
+        };
+        let text = |names: &[&str]| get(names).unwrap_or_default().to_string();
+        let number = |names: &[&str]| {
+            get(names)
+                .and_then(|value| value.parse::<f64>().ok())
+                .unwrap_or_default()
+        };
+        let hetero = get(&["group_PDB"]) == Some("HETATM");
+        let res_name = text(&["auth_comp_id", "label_comp_id"]);
+        //Only residues of polymer entities have a label sequence number
+        let polymer = match index.get("label_seq_id") {
+            Some(&i) => row[i] != "." && row[i] != "?",
+            None => !hetero || MODIFIED.contains(&res_name.as_str()),
+        };
+        atoms.push(Atom {
+            hetero,
+            name: text(&["auth_atom_id", "label_atom_id"]),
+            alt_loc: get(&["label_alt_id"]).map(str::to_string),
+            res_name,
+            chain: text(&["auth_asym_id", "label_asym_id"]),
+            res_seq: number(&["auth_seq_id", "label_seq_id"]) as i64,
+            insertion: get(&["pdbx_PDB_ins_code"]).map(str::to_string),
+            x: number(&["Cartn_x"]),
+            y: number(&["Cartn_y"]),
+            z: number(&["Cartn_z"]),
+            occupancy: number(&["occupancy"]),
+            b_factor: number(&["B_iso_or_equiv"]),
+            element: text(&["type_symbol"]),
+            charge: number(&["pdbx_formal_charge"]) as i32,
+            model: get(&["pdbx_PDB_model_num"])
+                .and_then(|model| model.parse().ok())
+                .unwrap_or(1),
+            polymer,
+        });
+    }
+    Ok(atoms)
+}
+
+/// `path` with its extensions replaced by `_{suffix}` and the extension of what [write] writes
+/// for `format`, e.g. `1abc_clean.pdb` or `1abc_clean.cif`.
+pub(crate) fn derived_path(path: &Path, suffix: &str, format: Option<&str>) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem = name.split('.').next().unwrap_or_default();
+    path.with_file_name(format!("{}_{}.{}", stem, suffix, written_format(format)))
+}
+
+/// Format [write] writes atoms read from a file of `format` in: mmCIF for mmCIF, as chain IDs
+/// of entries too large for PDB files take more than one character, PDB otherwise.
+pub(crate) fn written_format(format: Option<&str>) -> &'static str {
+    match format {
+        Some("cif") => "cif",
+        _ => "pdb",
+    }
+}
+
+/// Write `atoms` read from a file of `format` in the format [written_format] tells.
+pub(crate) fn write(atoms: &[Atom], path: &Path, format: Option<&str>) -> Result<()> {
+    match written_format(format) {
+        "cif" => write_cif(atoms, path),
+        _ => write_pdb(atoms, path),
+    }
+}
+
+/// Write `atoms` as ATOM and HETATM records of a PDB file, through a part file like downloads.
+///
+/// Atoms are numbered anew, chains must have single character IDs.
+fn write_pdb(atoms: &[Atom], path: &Path) -> Result<()> {
+    if atoms.is_empty() {
+        bail!("Nothing is left to write to {}", path.display());
+    }
+    if let Some(atom) = atoms.iter().find(|atom| atom.chain.chars().count() > 1) {
+        bail!("Chain {} can't be written in PDB format", atom.chain);
+    }
+    let multiple_models = atoms.iter().any(|atom| atom.model != atoms[0].model);
+    let mut text = String::new();
+    let mut model = None;
+    for (i, atom) in atoms.iter().enumerate() {
+        if multiple_models && model != Some(atom.model) {
+            if model.is_some() {
+                text.push_str("ENDMDL\n");
+            }
+            writeln!(text, "MODEL     {:>4}", atom.model)?;
+            model = Some(atom.model);
+        }
+        //Names start in column 14 unless they take all four or the element has two letters
+        let name = if atom.name.len() < 4 && atom.element.len() < 2 {
+            format!(" {}", atom.name)
+        } else {
+            atom.name.clone()
+        };
+        let charge = match atom.charge {
+            0 => String::new(),
+            charge if charge > 0 => format!("{}+", charge),
+            charge => format!("{}-", -charge),
+        };
+        writeln!(
+            text,
+            "{:<6}{:>5} {:<4}{:1}{:>3} {:1}{:>4}{:1}   {:>8.3}{:>8.3}{:>8.3}{:>6.2}{:>6.2}          {:>2}{:>2}",
+            if atom.hetero { "HETATM" } else { "ATOM" },
+            (i + 1) % 100_000,
+            name,
+            atom.alt_loc.as_deref().unwrap_or_default(),
+            atom.res_name,
+            atom.chain,
+            atom.res_seq,
+            atom.insertion.as_deref().unwrap_or_default(),
+            atom.x,
+            atom.y,
+            atom.z,
+            atom.occupancy,
+            atom.b_factor,
+            atom.element,
+            charge
+        )?;
+    }
+    if multiple_models {
+        text.push_str("ENDMDL\n");
+    }
+    text.push_str("END\n");
+    write_part(path, text)
+}
+
+/// Write `atoms` as the `_atom_site` loop of a mmCIF file, through a part file like downloads.
+///
+/// Atoms are numbered anew. Label fields take the author ones, which is what is known of them,
+/// and only residues of polymer chains get a `label_seq_id`, as reading tells them apart by it.
+fn write_cif(atoms: &[Atom], path: &Path) -> Result<()> {
+    if atoms.is_empty() {
+        bail!("Nothing is left to write to {}", path.display());
+    }
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut text = format!(
+        "data_{}\n#\nloop_\n",
+        cif_value(name.trim_end_matches(".cif"))
+    );
+    for column in CIF_COLUMNS {
+        writeln!(text, "_atom_site.{}", column)?;
+    }
+    for (i, atom) in atoms.iter().enumerate() {
+        let res_seq = atom.res_seq.to_string();
+        let values = [
+            if atom.hetero { "HETATM" } else { "ATOM" },
+            &(i + 1).to_string(),
+            &atom.element,
+            &atom.name,
+            atom.alt_loc.as_deref().unwrap_or("."),
+            &atom.res_name,
+            &atom.chain,
+            if atom.polymer { &res_seq } else { "." },
+            atom.insertion.as_deref().unwrap_or("?"),
+            &format!("{:.3}", atom.x),
+            &format!("{:.3}", atom.y),
+            &format!("{:.3}", atom.z),
+            &format!("{:.2}", atom.occupancy),
+            &format!("{:.2}", atom.b_factor),
+            &atom.charge.to_string(),
+            &res_seq,
+            &atom.res_name,
+            &atom.chain,
+            &atom.name,
+            &atom.model.to_string(),
+        ];
+        let values = values.map(cif_value);
+        writeln!(text, "{}", values.join(" "))?;
+    }
+    text.push_str("#\n");
+    write_part(path, text)
+}
+
+//A value of a mmCIF loop, quoted when it would be read otherwise, such as O5' or a blank
+fn cif_value(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value.contains(char::is_whitespace)
+        && !value.contains(['\'', '"'])
+        && !value.starts_with(['_', '#', '$', ';', '[', ']']);
+    if plain {
+        value.to_string()
+    } else if value.contains('"') {
+        format!("'{}'", value)
+    } else {
+        format!("\"{}\"", value)
+    }
+}
+
+fn write_part(path: &Path, text: String) -> Result<()> {
+    let part = part_path(path);
+    std::fs::write(&part, text)?;
+    std::fs::rename(&part, path)?;
+    Ok(())
+}
+
+/// Remove the atoms `cleanups` ask for.
+pub(crate) fn clean(atoms: &mut Vec<Atom>, cleanups: &[Cleanup]) {
+    if cleanups.contains(&Cleanup::Waters) {
+        atoms.retain(|atom| !WATERS.contains(&atom.res_name.as_str()));
+    }
+    if cleanups.contains(&Cleanup::Ions) {
+        let mut sizes = HashMap::new();
+        for atom in atoms.iter() {
+            *sizes.entry(atom.residue()).or_insert(0) += 1;
+        }
+        atoms.retain(|atom| sizes[&atom.residue()] > 1 || !IONS.contains(&atom.res_name.as_str()));
+    }
+    if cleanups.contains(&Cleanup::Altlocs) {
+        let mut first = HashMap::new();
+        for atom in atoms.iter() {
+            if let Some(alt_loc) = &atom.alt_loc {
+                first.entry(atom.residue()).or_insert(alt_loc.clone());
+            }
+        }
+        atoms.retain(|atom| match &atom.alt_loc {
+            Some(alt_loc) => first.get(&atom.residue()) == Some(alt_loc),
+            None => true,
+        });
+        for atom in atoms.iter_mut() {
+            atom.alt_loc = None;
+        }
+    }
+}
+
+//Whether the atom belongs to a small molecule other than solvent and additives
+fn is_ligand(atom: &Atom) -> bool {
+    let code = atom.res_name.as_str();
+    atom.hetero
+        && !atom.polymer
+        && !WATERS.contains(&code)
+        && !IONS.contains(&code)
+        && !ADDITIVES.contains(&code)
+}
+
+/// A small molecule bound to a structure, outside of its polymer chains.
+#[derive(Debug)]
+pub(crate) struct Ligand {
+    pub code: String,
+    pub chain: String,
+    pub res_seq: i64,
+    pub insertion: Option<String>,
+    pub atoms: Vec<Atom>,
+}
+
+/// Ligands of the first model of `atoms`, in the order of the file, leaving out waters, ions
+/// and common additives of crystallization.
+pub(crate) fn ligands(atoms: &[Atom]) -> Vec<Ligand> {
+    let Some(first) = atoms.first().map(|atom| atom.model) else {
+        return Vec::new();
+    };
+    let mut ligands: Vec<Ligand> = Vec::new();
+    let mut index = HashMap::new();
+    for atom in atoms
+        .iter()
+        .filter(|atom| atom.model == first && is_ligand(atom))
+    {
+        let i = *index.entry(atom.residue()).or_insert_with(|| {
+            ligands.push(Ligand {
+                code: atom.res_name.clone(),
+                chain: atom.chain.clone(),
+                res_seq: atom.res_seq,
+                insertion: atom.insertion.clone(),
+                atoms: Vec::new(),
+            });
+            ligands.len() - 1
+        });
+        ligands[i].atoms.push(atom.clone());
+    }
+    ligands
+}
+
+/// Keep the polymer atoms of `chains` only, with the ligands that have an atom within
+/// `ligand_cutoff` angstroms of them when given.
+pub(crate) fn select_chains(atoms: &mut Vec<Atom>, chains: &[String], ligand_cutoff: Option<f64>) {
+    let kept = |atom: &Atom| atom.polymer && chains.contains(&atom.chain);
+    let mut near = HashSet::new();
+    if let Some(cutoff) = ligand_cutoff {
+        let mut residues: HashMap<_, Vec<&Atom>> = HashMap::new();
+        for atom in atoms.iter().filter(|atom| is_ligand(atom)) {
+            residues.entry(atom.residue()).or_default().push(atom);
+        }
+        let chain_atoms: Vec<&Atom> = atoms.iter().filter(|atom| kept(atom)).collect();
+        let squared = cutoff * cutoff;
+        for (residue, ligand) in residues {
+            if ligand.iter().any(|atom| {
+                chain_atoms.iter().any(|other| {
+                    other.model == atom.model
+                        && (other.x - atom.x).powi(2)
+                            + (other.y - atom.y).powi(2)
+                            + (other.z - atom.z).powi(2)
+                            <= squared
+                })
+            }) {
+                near.insert(residue);
+            }
+        }
+    }
+    atoms.retain(|atom| kept(atom) || near.contains(&atom.residue()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cif_tokens_split_on_whitespace() {
+        assert_eq!(
+            cif_tokens("ATOM   1    N N   . MET A 1 1   ? 12.345 -6.789 0.5"),
+            ["ATOM", "1", "N", "N", ".", "MET", "A", "1", "1", "?", "12.345", "-6.789", "0.5"]
+        );
+        assert_eq!(cif_tokens("  \t "), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn cif_tokens_unquote_values() {
+        assert_eq!(cif_tokens("1 \"C5'\" DA"), ["1", "C5'", "DA"]);
+        assert_eq!(cif_tokens("'two words' x"), ["two words", "x"]);
+        //A quote followed by other than a space doesn't close the value
+        assert_eq!(cif_tokens("'it's' y"), ["it's", "y"]);
+        assert_eq!(cif_tokens("x 'end'"), ["x", "end"]);
+        //A quote left open takes the rest of the line as it is
+        assert_eq!(cif_tokens("x 'open value"), ["x", "'open value"]);
+        //Quotes within a value are part of it
+        assert_eq!(cif_tokens("O5' H5''"), ["O5'", "H5''"]);
+    }
+}
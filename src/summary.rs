@@ -0,0 +1,223 @@
+use crate::http::HttpError;
+use crate::pipeline::Target;
+use anyhow::Result;
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub(crate) const RUN_FILE: &str = "run.json";
+pub(crate) const FAILURES_FILE: &str = "failures.csv";
+
+/// What the last run did, as written to `save_path/run.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RunSummary {
+    /// RFC 3339
+    pub started_at: String,
+    pub finished_at: String,
+    pub elapsed_secs: f64,
+    pub interrupted: bool,
+    pub targets_processed: usize,
+    /// ChEMBL IDs of targets without a UniProt accession
+    pub targets_without_uniprot: Vec<String>,
+    /// `chembl_id:accession` of accessions without wanted PDB entries
+    pub accessions_without_pdb: Vec<String>,
+    /// Number of failed tasks by cause, e.g. "HTTP 404" or "timeout"
+    pub failures: BTreeMap<String, usize>,
+    /// Targets left unfinished by errors, to be retried on resume
+    #[serde(default)]
+    pub failed_targets: Vec<FailedTarget>,
+}
+
+/// A target that failed or has failed downloads.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailedTarget {
+    pub chembl_id: String,
+    pub target_name: String,
+    pub error: String,
+}
+
+/// A failed task of the last run, as listed in `save_path/failures.csv`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailedItem {
+    pub chembl_id: String,
+    pub target_name: String,
+    /// None for the target itself
+    pub accession: Option<String>,
+    /// None for target or accession data, e.g. a FASTA sequence
+    pub pdb_id: Option<String>,
+    /// Request that failed, if the error came from one
+    pub url: Option<String>,
+    pub error: String,
+}
+
+impl FailedItem {
+    /// The failures of the last run on `save_path`, if any.
+    pub(crate) fn load(save_path: &Path) -> Result<Option<Vec<FailedItem>>> {
+        let path = save_path.join(FAILURES_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut reader = csv::Reader::from_path(path)?;
+        Ok(Some(reader.deserialize().collect::<Result<_, _>>()?))
+    }
+}
+
+impl RunSummary {
+    /// The summary of the last run on `save_path`, if any.
+    pub(crate) fn load(save_path: &Path) -> Result<Option<RunSummary>> {
+        let path = save_path.join(RUN_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(File::open(path)?)?))
+    }
+}
+
+/// Collects the [`RunSummary`] of a run as its tasks go.
+pub(crate) struct Summary {
+    started: Mutex<Instant>,
+    summary: Mutex<RunSummary>,
+    failed: Mutex<Vec<FailedItem>>,
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Summary {
+            started: Mutex::new(Instant::now()),
+            summary: Mutex::default(),
+            failed: Mutex::default(),
+        }
+    }
+
+    pub fn start(&self) {
+        *self.started.lock().unwrap() = Instant::now();
+        *self.summary.lock().unwrap() = RunSummary {
+            started_at: Utc::now().to_rfc3339(),
+            ..RunSummary::default()
+        };
+        self.failed.lock().unwrap().clear();
+    }
+
+    pub fn target_processed(&self) {
+        self.summary.lock().unwrap().targets_processed += 1;
+    }
+
+    pub fn without_uniprot(&self, chembl_id: &str) {
+        self.summary
+            .lock()
+            .unwrap()
+            .targets_without_uniprot
+            .push(chembl_id.to_string());
+    }
+
+    pub fn without_pdb(&self, chembl_id: &str, accession: &str) {
+        self.summary
+            .lock()
+            .unwrap()
+            .accessions_without_pdb
+            .push(format!("{}:{}", chembl_id, accession));
+    }
+
+    pub fn target_failed(&self, target: &Target, error: String) {
+        self.summary
+            .lock()
+            .unwrap()
+            .failed_targets
+            .push(FailedTarget {
+                chembl_id: target.chembl_id.clone(),
+                target_name: target.target_name.clone(),
+                error,
+            });
+    }
+
+    /// Count `error` by cause and list it in `failures.csv`.
+    pub fn failure(
+        &self,
+        target: &Target,
+        accession: Option<&str>,
+        pdb_id: Option<&str>,
+        error: &anyhow::Error,
+    ) {
+        self.failed.lock().unwrap().push(FailedItem {
+            chembl_id: target.chembl_id.clone(),
+            target_name: target.target_name.clone(),
+            accession: accession.map(str::to_string),
+            pdb_id: pdb_id.map(str::to_string),
+            url: failed_url(error),
+            error: error.to_string(),
+        });
+        *self
+            .summary
+            .lock()
+            .unwrap()
+            .failures
+            .entry(cause(error))
+            .or_default() += 1;
+    }
+
+    /// Write the summary of the run to `run.json` of `save_path`, and its failures to
+    /// `failures.csv`.
+    pub fn finish(&self, save_path: &Path, interrupted: bool) -> Result<RunSummary> {
+        let mut summary = self.summary.lock().unwrap().clone();
+        summary.finished_at = Utc::now().to_rfc3339();
+        summary.elapsed_secs = self.started.lock().unwrap().elapsed().as_secs_f64();
+        summary.interrupted = interrupted;
+        summary.targets_without_uniprot.sort();
+        summary.accessions_without_pdb.sort();
+        summary
+            .failed_targets
+            .sort_by(|a, b| a.chembl_id.cmp(&b.chembl_id));
+        serde_json::to_writer_pretty(File::create(save_path.join(RUN_FILE))?, &summary)?;
+
+        let mut failed = self.failed.lock().unwrap().clone();
+        failed.sort_by(|a, b| {
+            (&a.chembl_id, &a.accession, &a.pdb_id).cmp(&(&b.chembl_id, &b.accession, &b.pdb_id))
+        });
+        let mut writer = csv::Writer::from_path(save_path.join(FAILURES_FILE))?;
+        for item in &failed {
+            writer.serialize(item)?;
+        }
+        writer.flush()?;
+        Ok(summary)
+    }
+}
+
+//The request behind `error`, if any
+fn failed_url(error: &anyhow::Error) -> Option<String> {
+    error
+        .chain()
+        .find_map(|source| match source.downcast_ref::<HttpError>()? {
+            HttpError::Status { url, .. }
+            | HttpError::Transport { url, .. }
+            | HttpError::Truncated { url, .. }
+            | HttpError::Offline { url } => Some(url.to_string()),
+            HttpError::Io(_) => None,
+        })
+}
+
+//A short, stable name for what went wrong
+fn cause(error: &anyhow::Error) -> String {
+    for source in error.chain() {
+        if let Some(error) = source.downcast_ref::<HttpError>() {
+            return match error {
+                HttpError::Status { status, .. } => format!("HTTP {}", status.as_u16()),
+                HttpError::Transport { source, .. } if source.is_timeout() => "timeout".into(),
+                HttpError::Transport { .. } => "network".into(),
+                HttpError::Truncated { .. } => "truncated".into(),
+                HttpError::Offline { .. } => "offline".into(),
+                HttpError::Io(_) => "I/O".into(),
+            };
+        }
+        if source.is::<std::io::Error>() {
+            return "I/O".into();
+        }
+        if source.is::<serde_json::Error>() {
+            return "unexpected response".into();
+        }
+    }
+    "other".into()
+}
@@ -0,0 +1,69 @@
+use crate::checksum;
+use crate::download::part_path;
+use crate::pipeline::Context;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+pub(crate) const SUMS_FILE: &str = "SHA256SUMS";
+
+/// List `path` hashing to `sha256` in the `SHA256SUMS` of its folder, as `sha256sum -c` reads it,
+/// replacing the line of a file of the same name.
+pub(crate) async fn update(ctx: &Context, path: &Path, sha256: &str) -> Result<()> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(());
+    };
+    let name = name.to_string_lossy();
+    let sums = dir.join(SUMS_FILE);
+    let lock = ctx.lock(&sums);
+    let _guard = lock.lock().await;
+    let mut lines = match tokio::fs::read_to_string(&sums).await {
+        Ok(text) => parse(&text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    lines.insert(name.into_owned(), sha256.to_string());
+    let part = part_path(&sums);
+    tokio::fs::write(&part, render(&lines)?).await?;
+    tokio::fs::rename(&part, &sums).await?;
+    let hashed = sums.clone();
+    let (_, sha256) = tokio::task::spawn_blocking(move || checksum::hash_file(&hashed)).await??;
+    ctx.storage.store(&sums, &sha256).await
+}
+
+/// Move the `SHA256SUMS` at `from` to `to`, naming its files anew by `renamed`, merged with the
+/// lines of the one at `to` if any.
+pub(crate) fn rename(from: &Path, to: &Path, renamed: &BTreeMap<String, String>) -> Result<()> {
+    let mut lines = match std::fs::read_to_string(to) {
+        Ok(text) if from != to => parse(&text),
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => BTreeMap::new(),
+    };
+    for (file, sha256) in parse(&std::fs::read_to_string(from)?) {
+        lines.insert(renamed.get(&file).cloned().unwrap_or(file), sha256);
+    }
+    let part = part_path(to);
+    std::fs::write(&part, render(&lines)?)?;
+    std::fs::rename(&part, to)?;
+    if from != to {
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+//Checksums by file name
+fn parse(text: &str) -> BTreeMap<String, String> {
+    text.lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(sha256, file)| (file.to_string(), sha256.to_string()))
+        .collect()
+}
+
+fn render(lines: &BTreeMap<String, String>) -> Result<String> {
+    let mut text = String::new();
+    for (file, sha256) in lines {
+        writeln!(text, "{}  {}", sha256, file)?;
+    }
+    Ok(text)
+}
@@ -0,0 +1,169 @@
+use crate::checksum;
+use crate::download::{download_file, part_path, Downloaded};
+use crate::http::HttpError;
+use crate::pipeline::Context;
+use anyhow::Result;
+use reqwest::{StatusCode, Url};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+const REPOSITORY_URL: &str = "https://swissmodel.expasy.org/repository/uniprot/";
+const MODELS_FOLDER: &str = "models";
+const SCORES_FILE: &str = "swissmodel.csv";
+
+#[derive(Deserialize, Debug)]
+struct Repository {
+    result: RepositoryResult,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepositoryResult {
+    #[serde(default)]
+    structures: Vec<Structure>,
+}
+
+//A model of the repository, experimental structures are listed as well
+#[derive(Deserialize, Debug)]
+struct Structure {
+    provider: String,
+    coordinates: String,
+    #[serde(default)]
+    template: Option<String>,
+    #[serde(default)]
+    from: Option<u64>,
+    #[serde(default)]
+    to: Option<u64>,
+    #[serde(default)]
+    identity: Option<f64>,
+    #[serde(default)]
+    gmqe: Option<f64>,
+    #[serde(default)]
+    qmean: Option<Qmean>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Qmean {
+    #[serde(default)]
+    avg_local_score: Option<f64>,
+}
+
+//Quality of a downloaded model, as listed in `models/swissmodel.csv`
+#[derive(Serialize, Debug)]
+struct Scores<'a> {
+    file: String,
+    template: Option<&'a str>,
+    from: Option<u64>,
+    to: Option<u64>,
+    identity: Option<f64>,
+    gmqe: Option<f64>,
+    qmean: Option<f64>,
+}
+
+//Homology models of `accession` in the repository, best GMQE first
+async fn models(ctx: &Context, accession: &str) -> Result<Vec<Structure>> {
+    let url: Url = format!("{}{}.json", REPOSITORY_URL, accession).parse()?;
+    //Accessions unknown to the repository are answered with 404
+    let page = match ctx.http.get_text(&url).await {
+        Ok(page) => page,
+        Err(HttpError::Status {
+            status: StatusCode::NOT_FOUND,
+            ..
+        }) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let repository: Repository = serde_json::from_str(&page)?;
+    let mut models = repository
+        .result
+        .structures
+        .into_iter()
+        .filter(|structure| structure.provider == "SWISSMODEL")
+        .collect::<Vec<_>>();
+    models.sort_by(|a, b| {
+        b.gmqe
+            .unwrap_or_default()
+            .total_cmp(&a.gmqe.unwrap_or_default())
+    });
+    models.truncate(ctx.config.swissmodel_models);
+    Ok(models)
+}
+
+fn model_path(accession: &str, rank: usize, save_path: &Path) -> PathBuf {
+    save_path
+        .join(MODELS_FOLDER)
+        .join(format!("SM-{}-{}.pdb", accession, rank))
+}
+
+/// Urls of the best SWISS-MODEL models of `accession`, with the files they are saved to.
+pub(crate) async fn model_files(
+    ctx: &Context,
+    accession: &str,
+    save_path: &Path,
+) -> Result<Vec<(Url, PathBuf)>> {
+    let mut files = Vec::new();
+    for (i, model) in models(ctx, accession).await?.iter().enumerate() {
+        files.push((
+            model.coordinates.parse()?,
+            model_path(accession, i + 1, save_path),
+        ));
+    }
+    Ok(files)
+}
+
+/// Download the best SWISS-MODEL models of `accession` into `save_path/models/`, with their
+/// quality scores listed in `swissmodel.csv`.
+pub(crate) async fn download_models(
+    ctx: &Context,
+    accession: &str,
+    save_path: &Path,
+) -> Result<Vec<Downloaded>> {
+    let models = models(ctx, accession).await?;
+    if models.is_empty() {
+        info!("No SWISS-MODEL model of {}", accession);
+        return Ok(Vec::new());
+    }
+    let folder = save_path.join(MODELS_FOLDER);
+    create_dir_all(&folder)?;
+    let mut downloaded = Vec::new();
+    let scores_path = folder.join(SCORES_FILE);
+    let part = part_path(&scores_path);
+    let mut scores = csv::Writer::from_path(&part)?;
+    for (i, model) in models.iter().enumerate() {
+        let url: Url = model.coordinates.parse()?;
+        let save_filepath = model_path(accession, i + 1, save_path);
+        if !ctx.storage.exists(&save_filepath).await? {
+            debug!(target:"debug","SWISS-MODEL url : {}", url);
+            downloaded.push(Downloaded {
+                format: Some("pdb".to_string()),
+                ..download_file(ctx, &url, &save_filepath).await?
+            });
+        }
+        scores.serialize(Scores {
+            file: save_filepath
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            template: model.template.as_deref(),
+            from: model.from,
+            to: model.to,
+            identity: model.identity,
+            gmqe: model.gmqe,
+            qmean: model.qmean.as_ref().and_then(|qmean| qmean.avg_local_score),
+        })?;
+    }
+    scores.flush()?;
+    drop(scores);
+    std::fs::rename(&part, &scores_path)?;
+    let (size, sha256) = checksum::hash_file(&scores_path)?;
+    downloaded.push(Downloaded {
+        url: format!("{}{}.json", REPOSITORY_URL, accession).parse()?,
+        format: None,
+        path: scores_path,
+        size,
+        sha256,
+        etag: None,
+        last_modified: None,
+    });
+    Ok(downloaded)
+}
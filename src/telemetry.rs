@@ -0,0 +1,102 @@
+use crate::config::UserConfig;
+use anyhow::Result;
+use tracing_subscriber::{Layer, Registry};
+
+/// Export of tracing spans, flushed when dropped.
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+//The provider of the layer init_logging added, until init_telemetry takes it
+#[cfg(feature = "otel")]
+static PROVIDER: std::sync::Mutex<Option<opentelemetry_sdk::trace::SdkTracerProvider>> =
+    std::sync::Mutex::new(None);
+
+#[cfg(feature = "otel")]
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[cfg(feature = "otel")]
+fn exporter(endpoint: &str) -> Result<(opentelemetry_sdk::trace::SdkTracerProvider, BoxedLayer)> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("prog_med").build())
+        .build();
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("prog_med"))
+        .boxed();
+    Ok((provider, layer))
+}
+
+/// The layer exporting spans to `otlp_endpoint`, for the subscriber of `init_logging`.
+#[cfg(feature = "otel")]
+pub(crate) fn layer(config: &UserConfig) -> Result<Option<BoxedLayer>> {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+    let (provider, layer) = exporter(endpoint)?;
+    *PROVIDER.lock().unwrap() = Some(provider);
+    Ok(Some(layer))
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn layer(
+    _config: &UserConfig,
+) -> Result<Option<Box<dyn Layer<Registry> + Send + Sync>>> {
+    Ok(None)
+}
+
+/// Export tracing spans to `otlp_endpoint` over OTLP/HTTP, for builds with the `otel` feature.
+///
+/// Spans cover targets, UniProt requests, PDB entries, downloads and the disk work after them.
+#[cfg(feature = "otel")]
+pub fn init_telemetry(config: &UserConfig) -> Result<Telemetry> {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(Telemetry::default());
+    };
+    let provider = PROVIDER.lock().unwrap().take();
+    let provider = match provider {
+        Some(provider) => provider,
+        //Without init_logging, spans get a subscriber of their own
+        None => {
+            let (provider, layer) = exporter(endpoint)?;
+            tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))?;
+            provider
+        }
+    };
+    info!("Exporting traces to {}", endpoint);
+    Ok(Telemetry {
+        provider: Some(provider),
+    })
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init_telemetry(config: &UserConfig) -> Result<Telemetry> {
+    if config.otlp_endpoint.is_some() {
+        warn!("otlp_endpoint is ignored, build with the \"otel\" feature to export traces");
+    }
+    Ok(Telemetry::default())
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                warn!("Failed to export the last traces due to \"{}\"", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::checksum;
+use crate::config::{ChainSelection, Cleanup};
+use crate::download::{part_path, Downloaded};
+use crate::pipeline::Context;
+use crate::sifts;
+use crate::structure::{self, Atom};
+use anyhow::Result;
+use serde_derive::Serialize;
+use std::path::{Path, PathBuf};
+
+const LIGANDS_FOLDER: &str = "ligands";
+
+#[derive(Serialize, Debug)]
+struct LigandRow<'a> {
+    file: String,
+    code: &'a str,
+    chain: &'a str,
+    res_seq: i64,
+    insertion: Option<&'a str>,
+    atoms: usize,
+}
+
+/// Files derived from the new coordinates `downloaded` by the post-processing of the config,
+/// written next to them.
+///
+/// A structure that can't be processed is logged, its download is kept.
+pub(crate) async fn process(
+    ctx: &Context,
+    accession: &str,
+    pdb_id: &str,
+    downloaded: &Downloaded,
+) -> Vec<Downloaded> {
+    if ctx.config.cleanups.is_empty()
+        && !ctx.config.extract_ligands
+        && ctx.config.chain_selection.is_none()
+    {
+        return Vec::new();
+    }
+    //Chains are looked up before parsing, a failed lookup only skips the selection
+    let selection = match &ctx.config.chain_selection {
+        Some(selection) => match sifts::chains(ctx, pdb_id, accession).await {
+            Ok(chains) if chains.is_empty() => {
+                warn!("SIFTS maps no chain of {} to {}", pdb_id, accession);
+                None
+            }
+            Ok(chains) => Some((chains, selection.clone())),
+            Err(e) => {
+                warn!("Failed to get the chains of {} due to \"{}\"", pdb_id, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let cleanups = ctx.config.cleanups.clone();
+    let extract_ligands = ctx.config.extract_ligands;
+    let downloaded = downloaded.clone();
+    let path = downloaded.path.clone();
+    let result = tokio::task::spawn_blocking(move || -> Result<Vec<Downloaded>> {
+        let Some(atoms) = structure::read(&downloaded.path, downloaded.format.as_deref())? else {
+            debug!(target:"debug","Not processing {}, only PDB and mmCIF files are parsed", downloaded.path.display());
+            return Ok(Vec::new());
+        };
+        let mut derived = Vec::new();
+        if !cleanups.is_empty() {
+            derived.push(write_clean(&downloaded, atoms.clone(), &cleanups)?);
+        }
+        if let Some((chains, selection)) = &selection {
+            derived.push(write_chains(&downloaded, atoms.clone(), chains, selection)?);
+        }
+        if extract_ligands {
+            derived.extend(write_ligands(&downloaded, &atoms)?);
+        }
+        Ok(derived)
+    })
+    .await;
+    match result
+        .map_err(anyhow::Error::from)
+        .and_then(|derived| derived)
+    {
+        Ok(derived) => derived,
+        Err(e) => {
+            warn!("Failed to process {} due to \"{}\"", path.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+fn derived(downloaded: &Downloaded, format: Option<&str>, path: PathBuf) -> Result<Downloaded> {
+    let (size, sha256) = checksum::hash_file(&path)?;
+    Ok(Downloaded {
+        url: downloaded.url.clone(),
+        format: format.map(str::to_string),
+        path,
+        size,
+        sha256,
+        etag: None,
+        last_modified: None,
+    })
+}
+
+fn write_clean(
+    downloaded: &Downloaded,
+    mut atoms: Vec<Atom>,
+    cleanups: &[Cleanup],
+) -> Result<Downloaded> {
+    structure::clean(&mut atoms, cleanups);
+    let format = downloaded.format.as_deref();
+    let path = structure::derived_path(&downloaded.path, "clean", format);
+    structure::write(&atoms, &path, format)?;
+    derived(downloaded, Some(structure::written_format(format)), path)
+}
+
+fn write_chains(
+    downloaded: &Downloaded,
+    mut atoms: Vec<Atom>,
+    chains: &[String],
+    selection: &ChainSelection,
+) -> Result<Downloaded> {
+    structure::select_chains(&mut atoms, chains, selection.ligand_cutoff);
+    let format = downloaded.format.as_deref();
+    let path = structure::derived_path(&downloaded.path, "chains", format);
+    structure::write(&atoms, &path, format)?;
+    derived(downloaded, Some(structure::written_format(format)), path)
+}
+
+//Each ligand into `ligands/<file>_<code>_<chain><number>.pdb` (.cif from mmCIF), listed in
+//`<file>_ligands.csv`
+fn write_ligands(downloaded: &Downloaded, atoms: &[Atom]) -> Result<Vec<Downloaded>> {
+    let format = downloaded.format.as_deref();
+    let ligands = structure::ligands(atoms);
+    if ligands.is_empty() {
+        debug!(target:"debug","No ligand in {}", downloaded.path.display());
+        return Ok(Vec::new());
+    }
+    let folder = downloaded
+        .path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(LIGANDS_FOLDER);
+    std::fs::create_dir_all(&folder)?;
+    let summary = folder.join(
+        structure::derived_path(&downloaded.path, "ligands", format)
+            .with_extension("csv")
+            .file_name()
+            .unwrap_or_default(),
+    );
+
+    let mut derived_files = Vec::new();
+    let part = part_path(&summary);
+    let mut writer = csv::Writer::from_path(&part)?;
+    for ligand in &ligands {
+        let suffix = format!(
+            "{}_{}{}{}",
+            ligand.code,
+            ligand.chain,
+            ligand.res_seq,
+            ligand.insertion.as_deref().unwrap_or_default()
+        );
+        let path = folder.join(
+            structure::derived_path(&downloaded.path, &suffix, format)
+                .file_name()
+                .unwrap_or_default(),
+        );
+        //A ligand that can't be written is left out of the summary alone
+        if let Err(e) = structure::write(&ligand.atoms, &path, format) {
+            warn!("Failed to write {} due to \"{}\"", path.display(), e);
+            continue;
+        }
+        writer.serialize(LigandRow {
+            file: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            code: &ligand.code,
+            chain: &ligand.chain,
+            res_seq: ligand.res_seq,
+            insertion: ligand.insertion.as_deref(),
+            atoms: ligand.atoms.len(),
+        })?;
+        derived_files.push(derived(
+            downloaded,
+            Some(structure::written_format(format)),
+            path,
+        )?);
+    }
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(&part, &summary)?;
+    derived_files.push(derived(downloaded, None, summary)?);
+    Ok(derived_files)
+}
@@ -0,0 +1,383 @@
+use crate::cache::CACHE_DIR;
+use crate::config::{IsoformPolicy, UserConfig};
+use crate::download::{download_file, part_path, Downloaded};
+use crate::pipeline::Context;
+use anyhow::Result;
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const UNIPROT_URL: &str = "https://rest.uniprot.org/uniprotkb/";
+//What the pipeline reads of an entry, keeping batches small
+const ENTRY_FIELDS: &str = "accession,organism_id,sequence,xref_pdb,ft_binding,ft_act_site";
+//Folder of the entries kept in the cache
+const CACHE_FOLDER: &str = "uniprot";
+//Largest page UniProt answers
+const MAX_BATCH_SIZE: usize = 500;
+
+/// The parts of a UniProtKB JSON entry used by the pipeline.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UniprotEntry {
+    pub primary_accession: String,
+    #[serde(default)]
+    pub organism: Option<Organism>,
+    #[serde(default)]
+    pub sequence: Option<Sequence>,
+    #[serde(default, rename = "uniProtKBCrossReferences")]
+    pub cross_references: Vec<CrossReference>,
+    /// Binding and active sites
+    #[serde(default)]
+    pub features: Vec<Feature>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Organism {
+    /// NCBI taxonomy ID, e.g. 9606 for humans
+    pub taxon_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sequence {
+    /// Number of residues of the canonical sequence
+    pub length: u64,
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Feature {
+    /// e.g. "Binding site" or "Active site"
+    #[serde(rename = "type")]
+    pub feature_type: String,
+    pub location: Location,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Location {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Position {
+    /// Missing for unknown positions
+    #[serde(default)]
+    pub value: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CrossReference {
+    pub database: String,
+    pub id: String,
+    #[serde(default)]
+    pub properties: Vec<Property>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Property {
+    pub key: String,
+    pub value: String,
+}
+
+impl CrossReference {
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|property| property.key == key)
+            .map(|property| property.value.as_str())
+    }
+}
+
+/// A PDB cross-reference of a UniProt entry.
+#[derive(Debug, Clone)]
+pub struct PdbReference {
+    /// Lowercase PDB ID
+    pub pdb_id: String,
+    /// Experimental method, e.g. "X-ray", "EM" or "NMR"
+    pub method: String,
+    /// Resolution in Å, missing for methods without one
+    pub resolution: Option<f64>,
+    /// Chains and residue ranges, e.g. "A/B=25-642"
+    pub chains: Option<String>,
+}
+
+impl From<&CrossReference> for PdbReference {
+    fn from(reference: &CrossReference) -> Self {
+        PdbReference {
+            pdb_id: reference.id.to_lowercase(),
+            method: reference.property("Method").unwrap_or_default().to_string(),
+            //"1.80 A", or "-" without resolution
+            resolution: reference
+                .property("Resolution")
+                .and_then(|value| value.split_whitespace().next())
+                .and_then(|value| value.parse().ok()),
+            chains: reference.property("Chains").map(str::to_string),
+        }
+    }
+}
+
+impl PdbReference {
+    /// Residues of the accession covered by any chain, told by the `chains` ranges.
+    pub fn coverage(&self) -> u64 {
+        self.ranges()
+            .iter()
+            .map(|(start, end)| end - start + 1)
+            .sum()
+    }
+
+    /// Sorted and disjoint residue ranges of the accession covered by any chain.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        let ranges = self
+            .chains
+            .iter()
+            .flat_map(|chains| chains.split(','))
+            .filter_map(|chains| chains.split_once('=')?.1.trim().split_once('-'))
+            .filter_map(|(start, end)| Some((start.parse::<u64>().ok()?, end.parse::<u64>().ok()?)))
+            .filter(|(start, end)| start <= end)
+            .collect();
+        merge_ranges(ranges)
+    }
+}
+
+/// Sort residue ranges and merge those that overlap or touch.
+pub(crate) fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+impl UniprotEntry {
+    /// Residues of binding and active sites, sorted.
+    pub fn site_residues(&self) -> Vec<u64> {
+        let mut residues = self
+            .features
+            .iter()
+            .filter(|feature| {
+                matches!(
+                    feature.feature_type.as_str(),
+                    "Binding site" | "Active site"
+                )
+            })
+            .filter_map(|feature| Some(feature.location.start.value?..=feature.location.end.value?))
+            .flatten()
+            .collect::<Vec<_>>();
+        residues.sort_unstable();
+        residues.dedup();
+        residues
+    }
+
+    /// PDB cross-references of the entry.
+    pub fn pdb_references(&self) -> Vec<PdbReference> {
+        self.cross_references
+            .iter()
+            .filter(|reference| reference.database == "PDB")
+            .map(PdbReference::from)
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct Results {
+    results: Vec<UniprotEntry>,
+}
+
+/// Entries fetched ahead in batches, by canonical accession, kept on disk as well when
+/// `uniprot_cache_hours` asks for it.
+#[derive(Default)]
+pub(crate) struct EntryCache {
+    entries: Mutex<HashMap<String, UniprotEntry>>,
+    disk: Option<DiskCache>,
+}
+
+struct DiskCache {
+    dir: PathBuf,
+    ttl: Duration,
+    //Entries on disk are replaced without being read
+    refresh: bool,
+}
+
+impl EntryCache {
+    pub fn new(config: &UserConfig, refresh: bool) -> Self {
+        EntryCache {
+            entries: Mutex::default(),
+            //Offline runs take whatever is cached
+            disk: (config.uniprot_cache_hours > 0 || config.offline).then(|| DiskCache {
+                dir: Path::new(&config.save_path)
+                    .join(CACHE_DIR)
+                    .join(CACHE_FOLDER),
+                ttl: match config.offline {
+                    true => Duration::MAX,
+                    false => Duration::from_secs(config.uniprot_cache_hours * 3600),
+                },
+                refresh: refresh && !config.offline,
+            }),
+        }
+    }
+
+    //The entry of `canonical` in memory, or on disk if it is recent enough
+    fn get(&self, canonical: &str) -> Option<UniprotEntry> {
+        if let Some(entry) = self.entries.lock().unwrap().get(canonical) {
+            return Some(entry.clone());
+        }
+        let disk = self.disk.as_ref().filter(|disk| !disk.refresh)?;
+        let path = disk.dir.join(format!("{}.json", canonical));
+        let age = path.metadata().ok()?.modified().ok()?.elapsed().ok()?;
+        if age > disk.ttl {
+            return None;
+        }
+        match std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice::<UniprotEntry>(&data)?))
+        {
+            Ok(entry) => {
+                self.entries
+                    .lock()
+                    .unwrap()
+                    .insert(canonical.to_string(), entry.clone());
+                Some(entry)
+            }
+            Err(e) => {
+                warn!("Failed to read {} due to \"{}\"", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn insert(&self, entry: UniprotEntry) {
+        if let Some(disk) = &self.disk {
+            if let Err(e) = disk.write(&entry) {
+                warn!(
+                    "Failed to cache UniProt entry {} due to \"{}\"",
+                    entry.primary_accession, e
+                );
+            }
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.primary_accession.clone(), entry);
+    }
+}
+
+impl DiskCache {
+    fn write(&self, entry: &UniprotEntry) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{}.json", entry.primary_accession));
+        let part = part_path(&path);
+        std::fs::write(&part, serde_json::to_vec(entry)?)?;
+        std::fs::rename(&part, &path)?;
+        Ok(())
+    }
+}
+
+/// Split `P12345-2` into its canonical accession `P12345` and isoform `2`.
+pub fn split_isoform(accession: &str) -> (&str, Option<&str>) {
+    match accession.split_once('-') {
+        Some((canonical, isoform)) => (canonical, Some(isoform)),
+        None => (accession, None),
+    }
+}
+
+/// Fetch the entry of `accession`, which is the canonical entry for isoforms as well.
+#[tracing::instrument(skip(ctx))]
+pub(crate) async fn fetch_entry(ctx: &Context, accession: &str) -> Result<UniprotEntry> {
+    let (canonical, _) = split_isoform(accession);
+    if let Some(entry) = ctx.entries.get(canonical) {
+        return Ok(entry);
+    }
+    let url: Url = format!("{}{}.json?fields={}", UNIPROT_URL, canonical, ENTRY_FIELDS).parse()?;
+    let page = ctx.http.get_text(&url).await?;
+    let entry: UniprotEntry = serde_json::from_str(&page)?;
+    ctx.entries.insert(entry.clone());
+    Ok(entry)
+}
+
+/// Fetch the entries of `accessions` `uniprot_batch_size` at a time, for [`fetch_entry`].
+///
+/// Failed batches are left to single requests.
+pub(crate) async fn prefetch<'a>(ctx: &Context, accessions: impl IntoIterator<Item = &'a str>) {
+    let batch_size = ctx.config.uniprot_batch_size.min(MAX_BATCH_SIZE);
+    if batch_size <= 1 {
+        return;
+    }
+    let mut seen = HashSet::new();
+    let canonical = accessions
+        .into_iter()
+        .map(|accession| split_isoform(accession).0)
+        .filter(|accession| !accession.is_empty() && seen.insert(*accession))
+        .filter(|accession| ctx.entries.get(accession).is_none())
+        .collect::<Vec<_>>();
+    for batch in canonical.chunks(batch_size) {
+        if ctx.is_stopping() {
+            return;
+        }
+        match fetch_batch(ctx, batch).await {
+            Ok(entries) => {
+                debug!(target:"debug","Fetched {} of {} UniProt entries", entries.len(), batch.len());
+                for entry in entries {
+                    ctx.entries.insert(entry);
+                }
+            }
+            Err(e) => warn!(
+                "Failed to fetch {} UniProt entries at once due to \"{}\"",
+                batch.len(),
+                e
+            ),
+        }
+    }
+}
+
+async fn fetch_batch(ctx: &Context, accessions: &[&str]) -> Result<Vec<UniprotEntry>> {
+    let url = Url::parse_with_params(
+        &format!("{}accessions", UNIPROT_URL),
+        &[
+            ("accessions", accessions.join(",").as_str()),
+            ("fields", ENTRY_FIELDS),
+            ("size", accessions.len().to_string().as_str()),
+            ("format", "json"),
+        ],
+    )?;
+    let page = ctx.http.get_text(&url).await?;
+    Ok(serde_json::from_str::<Results>(&page)?.results)
+}
+
+/// Url of the sequence of `accession` and the file it is saved to.
+///
+/// Isoforms get their own sequence only when the config includes them.
+pub(crate) fn fasta_file(
+    config: &UserConfig,
+    accession: &str,
+    save_path: &Path,
+) -> Result<(Url, PathBuf)> {
+    let accession = match config.isoforms {
+        IsoformPolicy::Canonical => split_isoform(accession).0,
+        IsoformPolicy::Include => accession,
+    };
+    let url: Url = format!("{}{}.fasta", UNIPROT_URL, accession).parse()?;
+    Ok((url, save_path.join(format!("{}.fasta", accession))))
+}
+
+/// Download the sequence of `accession` into its folder, unless it is there already.
+pub(crate) async fn download_fasta(
+    ctx: &Context,
+    accession: &str,
+    save_path: &Path,
+) -> Result<Option<Downloaded>> {
+    let (url, save_filepath) = fasta_file(&ctx.config, accession, save_path)?;
+    if ctx.storage.exists(&save_filepath).await? {
+        return Ok(None);
+    }
+    debug!(target:"debug","FASTA url : {}", url);
+    Ok(Some(download_file(ctx, &url, &save_filepath).await?))
+}
@@ -0,0 +1,61 @@
+use crate::manifest::ManifestEntry;
+use crate::pipeline::Pipeline;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+pub(crate) const UPDATE_FILE: &str = "update.csv";
+
+impl Pipeline {
+    /// Query every target again and download only what is not on disk yet.
+    ///
+    /// PDB entries recorded as done are skipped, so only newly released ones are fetched,
+    /// unless `revalidate_on_update` asks for their coordinates again when they changed.
+    /// The files added or changed are logged by target, written to `update.csv` and returned.
+    pub async fn update(&self) -> Result<Vec<ManifestEntry>> {
+        let known = self
+            .ctx
+            .state
+            .files()
+            .into_iter()
+            .map(|entry| (entry.path, entry.sha256))
+            .collect::<HashSet<_>>();
+        self.ctx.state.forget_targets();
+        self.ctx
+            .revalidating
+            .store(self.config().revalidate_on_update, Ordering::Relaxed);
+        self.run().await?;
+
+        let added = self
+            .ctx
+            .state
+            .files()
+            .into_iter()
+            .filter(|entry| !known.contains(&(entry.path.clone(), entry.sha256.clone())))
+            .collect::<Vec<_>>();
+        let mut by_target = BTreeMap::<&str, Vec<&str>>::new();
+        for entry in &added {
+            by_target
+                .entry(&entry.chembl_id)
+                .or_default()
+                .extend(entry.pdb_id.as_deref());
+        }
+        for (chembl_id, pdb_ids) in &by_target {
+            info!("{} : {} new files {:?}", chembl_id, pdb_ids.len(), pdb_ids);
+        }
+        info!(
+            "Update added {} files for {} targets",
+            added.len(),
+            by_target.len()
+        );
+
+        let mut writer =
+            csv::Writer::from_path(Path::new(&self.config().save_path).join(UPDATE_FILE))?;
+        for entry in &added {
+            writer.serialize(entry)?;
+        }
+        writer.flush()?;
+        Ok(added)
+    }
+}
@@ -0,0 +1,55 @@
+use crate::download::{fetch, stored_path, Downloaded};
+use crate::pipeline::Context;
+use anyhow::{bail, Result};
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+
+const VALIDATION_URL: &str = "https://files.wwpdb.org/pub/pdb/validation_reports/";
+/// Kinds of validation reports `validation_reports` may list.
+pub(crate) const REPORT_KINDS: &[&str] = &["pdf", "xml"];
+
+/// Urls of the wanted validation reports of `pdb_id`, with the files they are saved to.
+pub(crate) fn report_files(
+    kinds: &[String],
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<Vec<(Url, PathBuf)>> {
+    let id = pdb_id.to_lowercase();
+    //Reports are sorted by the middle two characters of the ID
+    let hash = id.get(1..3).unwrap_or_default();
+    let mut files = Vec::new();
+    for kind in kinds {
+        let file_name = match kind.as_str() {
+            "pdf" => format!("{}_full_validation.pdf.gz", id),
+            "xml" => format!("{}_validation.xml.gz", id),
+            kind => bail!(
+                "Unknown validation report \"{}\", use \"pdf\" or \"xml\"",
+                kind
+            ),
+        };
+        let url: Url = format!("{}{}/{}/{}", VALIDATION_URL, hash, id, file_name).parse()?;
+        files.push((url, save_path.join(file_name)));
+    }
+    Ok(files)
+}
+
+/// Download the wanted validation reports of `pdb_id` next to its coordinates in `save_path`.
+pub(crate) async fn download_reports(
+    ctx: &Context,
+    pdb_id: &str,
+    save_path: &Path,
+) -> Result<Vec<Downloaded>> {
+    let mut downloaded = Vec::new();
+    for (url, save_filepath) in report_files(&ctx.config.validation_reports, pdb_id, save_path)? {
+        if ctx
+            .storage
+            .exists(&stored_path(&ctx.config, &save_filepath))
+            .await?
+        {
+            continue;
+        }
+        debug!(target:"debug","Validation report url : {}", url);
+        downloaded.push(fetch(ctx, &url, &save_filepath).await?);
+    }
+    Ok(downloaded)
+}
@@ -0,0 +1,194 @@
+use crate::checksum::{hash_file, hash_reader};
+use crate::compress;
+use crate::pdb_index::PDB_INDEX_DIR;
+use crate::pipeline::Pipeline;
+use crate::server::JOBS_FOLDER;
+use anyhow::Result;
+use serde_derive::Serialize;
+use std::collections::HashSet;
+use std::fs::remove_file;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+//Structure files live in the accession folders, `depth` folders below the save path
+pub(crate) fn structure_files(save_path: &Path, depth: usize) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(save_path)? {
+        let path = entry?.path();
+        //Links of the index of PDB entries aren't files of their own, and jobs of the APIs
+        //are save paths of their own
+        let skipped = path
+            .file_name()
+            .is_some_and(|name| name == PDB_INDEX_DIR || name == JOBS_FOLDER);
+        if path.is_dir() && !skipped {
+            files_below(&path, depth, &mut files)?;
+        }
+    }
+    Ok(files)
+}
+
+fn files_below(dir: &Path, depth: usize, files: &mut Vec<PathBuf>) -> Result<()> {
+    if depth <= 1 {
+        return collect_files(dir, files);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files_below(&path, depth - 1, files)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) const VERIFY_FILE: &str = "verify.csv";
+
+/// A file found bad by `verify`, as listed in `save_path/verify.csv`.
+#[derive(Serialize)]
+struct BadFile<'a> {
+    path: &'a str,
+    chembl_id: &'a str,
+    pdb_id: Option<&'a str>,
+    problem: &'a str,
+}
+
+//Whether a coordinate file starts the way its format does, told by the record or the name
+fn header_problem(path: &Path, format: Option<&str>) -> Result<Option<String>> {
+    let name = path.to_string_lossy();
+    let name = name.trim_end_matches(".gz").trim_end_matches(".zst");
+    let format = match format {
+        Some(format) => format,
+        None if name.ends_with(".cif") => "cif",
+        None if name.ends_with(".pdb") || name.ends_with(".ent") => "pdb",
+        None => return Ok(None),
+    };
+    let mut reader = BufReader::new(compress::reader(path)?);
+    let mut line = String::new();
+    while line.trim().is_empty() {
+        line.clear();
+        //Unreadable gzip or text counts as corrupt rather than failing the audit
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(Some("no content".to_string())),
+            Ok(_) => {}
+            Err(e) => return Ok(Some(format!("unreadable ({})", e))),
+        }
+    }
+    let record = line.split_whitespace().next().unwrap_or_default();
+    let valid = match format {
+        "cif" => record.starts_with("data_"),
+        "pdb" => PDB_RECORDS.contains(&record),
+        _ => true,
+    };
+    Ok((!valid).then(|| format!("not a {} file, starts with \"{}\"", format, record)))
+}
+
+//Records a PDB file may start with
+const PDB_RECORDS: &[&str] = &[
+    "HEADER", "OBSLTE", "TITLE", "SPLIT", "CAVEAT", "COMPND", "SOURCE", "KEYWDS", "EXPDTA",
+    "AUTHOR", "REMARK", "CRYST1", "MODEL", "ATOM", "HETATM",
+];
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+impl Pipeline {
+    /// Re-hash downloaded files against the checksums recorded when they were downloaded.
+    ///
+    /// Missing, empty, truncated and mismatching files are logged, as are PDB and mmCIF files
+    /// not starting as such and empty files without a recorded checksum, and listed in
+    /// `verify.csv`. With `repair` they are removed and forgotten by the state store, so that
+    /// `resume` downloads them again. Returns the number of bad files.
+    pub fn verify(&self, repair: bool) -> Result<usize> {
+        let save_path = Path::new(&self.config().save_path);
+        let records = self.ctx.state.files();
+        let mut report = csv::Writer::from_path(save_path.join(VERIFY_FILE))?;
+        let mut bad = 0;
+        for record in &records {
+            let path = save_path.join(&record.path);
+            //Members were hashed before being archived
+            let problem = if let Some(archive) = &record.archive {
+                (!save_path.join(archive).exists()).then(|| format!("{} missing", archive))
+            } else if !path.exists() {
+                Some("missing".to_string())
+            } else {
+                let (size, sha256) = match record.compression {
+                    Some(_) => match hash_reader(compress::reader(&path)?) {
+                        Ok(hashed) => hashed,
+                        Err(e) => (0, format!("unreadable ({})", e)),
+                    },
+                    None => hash_file(&path)?,
+                };
+                if size == 0 {
+                    Some("empty".to_string())
+                } else if size < record.size {
+                    Some(format!("truncated ({} of {} bytes)", size, record.size))
+                } else if sha256 != record.sha256 {
+                    Some("checksum mismatch".to_string())
+                } else {
+                    header_problem(&path, record.format.as_deref())?
+                }
+            };
+            if let Some(problem) = problem {
+                warn!("Bad structure file {}: {}", path.display(), problem);
+                report.serialize(BadFile {
+                    path: &record.path,
+                    chembl_id: &record.chembl_id,
+                    pdb_id: record.pdb_id.as_deref(),
+                    problem: &problem,
+                })?;
+                bad += 1;
+                if repair {
+                    if path.exists() {
+                        remove_file(&path)?;
+                    }
+                    self.ctx.state.redo_file(record)?;
+                }
+            }
+        }
+
+        let tracked = records
+            .iter()
+            .map(|record| save_path.join(&record.path))
+            .collect::<HashSet<_>>();
+        for file in structure_files(save_path, self.ctx.layout.depth())? {
+            if !tracked.contains(&file) && file.metadata()?.len() == 0 {
+                warn!("Empty structure file: {}", file.display());
+                let path = file
+                    .strip_prefix(save_path)
+                    .unwrap_or(&file)
+                    .to_string_lossy();
+                report.serialize(BadFile {
+                    path: &path,
+                    chembl_id: "",
+                    pdb_id: None,
+                    problem: "empty, untracked",
+                })?;
+                bad += 1;
+                if repair {
+                    remove_file(&file)?;
+                }
+            }
+        }
+
+        report.flush()?;
+        if bad == 0 {
+            info!("All {} recorded structure files are intact", records.len());
+        } else if repair {
+            warn!(
+                "{} bad structure files removed, run resume to download them again",
+                bad
+            );
+        } else {
+            warn!("{} bad structure files found", bad);
+        }
+        Ok(bad)
+    }
+}
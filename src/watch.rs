@@ -0,0 +1,60 @@
+use crate::input;
+use crate::pipeline::Pipeline;
+use crate::shutdown;
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+//Length and modification time of every list read, changing as rows or lists are added
+type Signature = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+impl Pipeline {
+    /// Download structures for the targets of the input, then again whenever its lists change.
+    ///
+    /// Targets done are skipped, so each pass processes the rows appended to the lists and the
+    /// lists added to a watched pattern or folder since the last one. A change is only acted on
+    /// once the lists stayed the same for `watch_interval_secs`, so rows are not read half
+    /// written. Returns on Ctrl+C or SIGTERM.
+    pub async fn watch(&self) -> Result<()> {
+        let Some(patterns) = self.input_paths() else {
+            bail!("Watching needs target lists in read_path, not stdin nor a ChEMBL query");
+        };
+        let interval = Duration::from_secs(self.config().watch_interval_secs.max(1));
+        loop {
+            let seen = signature(&patterns)?;
+            self.run().await?;
+            if self.ctx.is_stopping() {
+                return Ok(());
+            }
+            info!("Watching {:?} for new targets", patterns);
+            let mut last = seen.clone();
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = shutdown::signal() => {
+                        info!("Stopped watching. Exiting...");
+                        return Ok(());
+                    }
+                }
+                let current = signature(&patterns)?;
+                if current != seen && current == last {
+                    break;
+                }
+                last = current;
+            }
+            info!("Target lists changed, processing new targets");
+        }
+    }
+}
+
+fn signature(patterns: &[PathBuf]) -> Result<Signature> {
+    let mut signature = Vec::new();
+    for path in input::expand_paths(patterns)? {
+        //A list being replaced may be missing for a moment
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            continue;
+        };
+        signature.push((path, metadata.len(), metadata.modified().ok()));
+    }
+    Ok(signature)
+}